@@ -0,0 +1,888 @@
+//! Monkey's Audio (`.ape`) reading and decoding for symphonia, which supports neither.
+//!
+//! Files from version 3.99 on decode here, which covers everything Monkey's Audio has
+//! written since 2004, with one or two channels of 8 to 24 bits. Every frame starts its
+//! range coder, filters and predictor from scratch, so a frame decodes on its own and the
+//! seek table takes us straight to the one we want.
+
+use std::io::{Seek, SeekFrom};
+
+use symphonia::core::audio::{
+    AsAudioBufferRef, AudioBuffer, AudioBufferRef, Channels, Signal, SignalSpec,
+};
+use symphonia::core::codecs::{
+    decl_codec_type, CodecDescriptor, CodecParameters, CodecType, Decoder, DecoderOptions,
+    FinalizeResult,
+};
+use symphonia::core::errors::{
+    decode_error, end_of_stream_error, seek_error, unsupported_error, Result, SeekErrorKind,
+};
+use symphonia::core::formats::{
+    Cue, FormatOptions, FormatReader, Packet, SeekMode, SeekTo, SeekedTo, Track,
+};
+use symphonia::core::io::{MediaSource, MediaSourceStream, ReadBytes, SeekBuffered};
+use symphonia::core::meta::{Metadata, MetadataLog};
+use symphonia::core::probe::{Descriptor, Instantiate, QueryDescriptor};
+use symphonia::core::sample::SampleFormat;
+use symphonia::core::units::TimeBase;
+use symphonia::core::{support_codec, support_format};
+
+pub const CODEC_TYPE_MONKEYS_AUDIO: CodecType = decl_codec_type(b"ape");
+
+/// 3.99 changed the entropy coding, older files are rare enough not to bother with.
+const MIN_VERSION: u16 = 3990;
+
+const DESCRIPTOR_LEN: usize = 52;
+const HEADER_LEN: usize = 24;
+
+// Frame flags
+const MONO_SILENCE: u32 = 0x1;
+const STEREO_SILENCE: u32 = 0x3;
+const PSEUDO_STEREO: u32 = 0x4;
+
+/// The filters of each compression level (fast, normal, high, extra high and insane), as
+/// order and fraction bits, in the order they're undone.
+const FILTERS: [&[(usize, u32)]; 5] = [
+    &[],
+    &[(16, 11)],
+    &[(64, 11)],
+    &[(32, 10), (256, 13)],
+    &[(16, 11), (256, 13), (1280, 15)],
+];
+
+/// The fields of the descriptor and header we need.
+#[derive(Debug, PartialEq)]
+pub struct ApeHeader {
+    pub version: u16,
+    /// 1000 (fast) to 5000 (insane)
+    pub compression: u16,
+    pub blocks_per_frame: u32,
+    pub final_frame_blocks: u32,
+    pub total_frames: u32,
+    pub bits_per_sample: u16,
+    pub channels: u16,
+    pub sample_rate: u32,
+    /// The seek table follows the header, with an offset for each frame
+    pub seek_table_len: u32,
+    /// Bytes from the descriptor to the first frame
+    pub first_frame: u32,
+    /// Bytes of the original file after the audio, which are kept after the frames
+    pub wav_tail_len: u32,
+}
+
+impl ApeHeader {
+    /// Parses the descriptor and header of a file from version 3.98 on, starting at its
+    /// "MAC " marker.
+    pub fn parse(buf: &[u8]) -> Option<ApeHeader> {
+        if buf.len() < DESCRIPTOR_LEN || &buf[..4] != b"MAC " {
+            return None;
+        }
+        let u16_at = |i: usize| u16::from_le_bytes([buf[i], buf[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+        let descriptor_len = u32_at(8);
+        let header_len = u32_at(12);
+        if (descriptor_len as usize) < DESCRIPTOR_LEN
+            || (header_len as usize) < HEADER_LEN
+            || buf.len() < descriptor_len as usize + HEADER_LEN
+        {
+            return None;
+        }
+        let seek_table_len = u32_at(16);
+        let wav_header_len = u32_at(20);
+
+        let header = descriptor_len as usize;
+        Some(ApeHeader {
+            version: u16_at(4),
+            compression: u16_at(header),
+            blocks_per_frame: u32_at(header + 4),
+            final_frame_blocks: u32_at(header + 8),
+            total_frames: u32_at(header + 12),
+            bits_per_sample: u16_at(header + 16),
+            channels: u16_at(header + 18),
+            sample_rate: u32_at(header + 20),
+            seek_table_len,
+            first_frame: descriptor_len
+                .checked_add(header_len)?
+                .checked_add(seek_table_len)?
+                .checked_add(wav_header_len)?,
+            wav_tail_len: u32_at(32),
+        })
+    }
+
+    pub fn total_blocks(&self) -> u64 {
+        (self.total_frames as u64).saturating_sub(1) * self.blocks_per_frame as u64
+            + self.final_frame_blocks as u64
+    }
+}
+
+/// Checks the parts of the stream the decoder handles, for the reader and decoder both.
+fn check_supported(version: u16, compression: u16, channels: u16, bits: u16) -> Result<()> {
+    if version < MIN_VERSION {
+        return unsupported_error("ape: files before version 3.99 are not supported");
+    }
+    if compression % 1000 != 0 || !(1000..=5000).contains(&compression) {
+        return unsupported_error("ape: unknown compression level");
+    }
+    if !(1..=2).contains(&channels) {
+        return unsupported_error("ape: only mono and stereo are supported");
+    }
+    if !matches!(bits, 8 | 16 | 24) {
+        return unsupported_error("ape: only 8, 16 and 24-bit samples are supported");
+    }
+    Ok(())
+}
+
+/// Where a frame is, and how many blocks (samples of every channel) it holds.
+#[derive(Clone, Copy, Debug)]
+struct Frame {
+    /// Frames are read in 32-bit words counted from the first one, so this is rounded
+    /// down to a word and `skip` is what it was rounded by
+    pos: u64,
+    size: u64,
+    skip: u32,
+    blocks: u32,
+}
+
+pub struct ApeReader {
+    reader: MediaSourceStream,
+    tracks: Vec<Track>,
+    cues: Vec<Cue>,
+    metadata: MetadataLog,
+    frames: Vec<Frame>,
+    blocks_per_frame: u32,
+    /// The frame `next_packet` reads
+    next: usize,
+}
+
+impl QueryDescriptor for ApeReader {
+    fn query() -> &'static [Descriptor] {
+        &[support_format!(
+            "ape",
+            "Monkey's Audio",
+            &["ape"],
+            &["audio/x-ape", "audio/ape"],
+            &[b"MAC "]
+        )]
+    }
+
+    fn score(_context: &[u8]) -> u8 {
+        255
+    }
+}
+
+impl FormatReader for ApeReader {
+    fn try_new(mut source: MediaSourceStream, _options: &FormatOptions) -> Result<Self> {
+        // Offsets in the file are from the marker, not whatever tag comes before it
+        let start = source.pos();
+        let mut buf = vec![0u8; 16];
+        source.read_buf_exact(&mut buf)?;
+        if &buf[..4] != b"MAC " {
+            return unsupported_error("ape: missing marker");
+        }
+        let version = u16::from_le_bytes([buf[4], buf[5]]);
+        if version < MIN_VERSION {
+            return unsupported_error("ape: files before version 3.99 are not supported");
+        }
+        let descriptor_len = u32::from_le_bytes([buf[8], buf[9], buf[10], buf[11]]) as usize;
+        let header_len = u32::from_le_bytes([buf[12], buf[13], buf[14], buf[15]]) as usize;
+        if descriptor_len + header_len > 1 << 16 {
+            return decode_error("ape: invalid descriptor");
+        }
+        buf.resize((descriptor_len + header_len).max(16), 0);
+        source.read_buf_exact(&mut buf[16..])?;
+        let header = match ApeHeader::parse(&buf) {
+            Some(header) => header,
+            None => return decode_error("ape: invalid descriptor"),
+        };
+        check_supported(
+            header.version,
+            header.compression,
+            header.channels,
+            header.bits_per_sample,
+        )?;
+        let count = header.total_frames as usize;
+        if count == 0
+            || header.blocks_per_frame == 0
+            || header.final_frame_blocks > header.blocks_per_frame
+            || header.seek_table_len / 4 < header.total_frames
+        {
+            return decode_error("ape: invalid header");
+        }
+
+        // The seek table follows the header, though the first frame is simply the one
+        // after the headers
+        let mut offsets = Vec::with_capacity(count);
+        for _ in 0..count {
+            offsets.push(start + source.read_u32()? as u64);
+        }
+        offsets[0] = start + header.first_frame as u64;
+        if offsets.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return decode_error("ape: invalid seek table");
+        }
+        // Anything after the last frame is the original file's tail and the tags
+        let end = source
+            .byte_len()
+            .map(|len| len.saturating_sub(header.wav_tail_len as u64));
+        let frames = offsets
+            .iter()
+            .enumerate()
+            .map(|(i, &offset)| {
+                let skip = ((offset - offsets[0]) & 3) as u32;
+                let next = offsets.get(i + 1).copied().or(end);
+                let size = match next {
+                    Some(next) if next > offset => next - offset,
+                    _ => header.final_frame_blocks as u64 * 8,
+                };
+                Frame {
+                    pos: offset - skip as u64,
+                    size: (size + skip as u64 + 3) & !3,
+                    skip,
+                    blocks: if i + 1 == count {
+                        header.final_frame_blocks
+                    } else {
+                        header.blocks_per_frame
+                    },
+                }
+            })
+            .collect();
+
+        let rate = header.sample_rate;
+        let channels = if header.channels == 1 {
+            Channels::FRONT_LEFT
+        } else {
+            Channels::FRONT_LEFT | Channels::FRONT_RIGHT
+        };
+        let mut extra_data = header.version.to_le_bytes().to_vec();
+        extra_data.extend_from_slice(&header.compression.to_le_bytes());
+
+        let mut params = CodecParameters::new();
+        params
+            .for_codec(CODEC_TYPE_MONKEYS_AUDIO)
+            .with_sample_rate(rate)
+            .with_time_base(TimeBase::new(1, rate))
+            .with_bits_per_sample(header.bits_per_sample as u32)
+            .with_sample_format(SampleFormat::S32)
+            .with_channels(channels)
+            .with_max_frames_per_packet(header.blocks_per_frame as u64)
+            .with_n_frames(header.total_blocks())
+            .with_extra_data(extra_data.into_boxed_slice());
+
+        Ok(ApeReader {
+            reader: source,
+            tracks: vec![Track::new(0, params)],
+            cues: Vec::new(),
+            metadata: Default::default(),
+            frames,
+            blocks_per_frame: header.blocks_per_frame,
+            next: 0,
+        })
+    }
+
+    fn next_packet(&mut self) -> Result<Packet> {
+        let frame = match self.frames.get(self.next) {
+            Some(frame) => *frame,
+            None => return end_of_stream_error(),
+        };
+        // Neighbouring frames can share a word, so the next one may start behind us
+        if self.reader.seek_buffered(frame.pos) != frame.pos {
+            self.reader.seek(SeekFrom::Start(frame.pos))?;
+        }
+        let size = match self.reader.byte_len() {
+            Some(len) => frame.size.min(len.saturating_sub(frame.pos)),
+            None => frame.size,
+        };
+
+        // The decoder needs the block count and the skip along with the frame
+        let mut data = vec![0u8; 8 + size as usize];
+        data[..4].copy_from_slice(&frame.blocks.to_le_bytes());
+        data[4..8].copy_from_slice(&frame.skip.to_le_bytes());
+        self.reader.read_buf_exact(&mut data[8..])?;
+
+        let ts = self.next as u64 * self.blocks_per_frame as u64;
+        self.next += 1;
+        Ok(Packet::new_from_boxed_slice(
+            0,
+            ts,
+            frame.blocks as u64,
+            data.into_boxed_slice(),
+        ))
+    }
+
+    fn metadata(&mut self) -> Metadata<'_> {
+        self.metadata.metadata()
+    }
+
+    fn cues(&self) -> &[Cue] {
+        &self.cues
+    }
+
+    fn tracks(&self) -> &[Track] {
+        &self.tracks
+    }
+
+    fn seek(&mut self, _mode: SeekMode, to: SeekTo) -> Result<SeekedTo> {
+        if !self.reader.is_seekable() {
+            return seek_error(SeekErrorKind::Unseekable);
+        }
+        let params = &self.tracks[0].codec_params;
+        let ts = match to {
+            SeekTo::TimeStamp { ts, .. } => ts,
+            SeekTo::Time { time, .. } => {
+                TimeBase::new(1, params.sample_rate.unwrap()).calc_timestamp(time)
+            }
+        };
+        if params.n_frames.map_or(false, |n| ts > n) {
+            return seek_error(SeekErrorKind::OutOfRange);
+        }
+
+        // Every frame but the last is the same length, so no need to look at the file
+        let frame = ((ts / self.blocks_per_frame as u64) as usize).min(self.frames.len() - 1);
+        self.next = frame;
+        Ok(SeekedTo {
+            track_id: 0,
+            actual_ts: frame as u64 * self.blocks_per_frame as u64,
+            required_ts: ts,
+        })
+    }
+
+    fn into_inner(self: Box<Self>) -> MediaSourceStream {
+        self.reader
+    }
+}
+
+const CODE_BITS: u32 = 32;
+const TOP_VALUE: u32 = 1 << (CODE_BITS - 1);
+const EXTRA_BITS: u32 = (CODE_BITS - 2) % 8 + 1;
+const BOTTOM_VALUE: u32 = TOP_VALUE >> 8;
+
+/// Cumulative frequencies of the overflow symbols, out of 65536. Those past the end each
+/// have a frequency of 1, up to the escape symbol.
+const COUNTS: [u32; 22] = [
+    0, 19578, 36160, 48417, 56323, 60899, 63265, 64435, 64971, 65232, 65351, 65416, 65447, 65466,
+    65476, 65482, 65485, 65488, 65490, 65491, 65492, 65493,
+];
+const ESCAPE: u32 = 63;
+
+/// The adaptive Rice parameter the residuals of a channel are coded with.
+struct Rice {
+    k: u32,
+    ksum: u32,
+}
+
+impl Rice {
+    fn new() -> Self {
+        Rice {
+            k: 10,
+            ksum: (1 << 10) * 16,
+        }
+    }
+
+    fn update(&mut self, x: u32) {
+        let lim = if self.k > 0 { 1 << (self.k + 4) } else { 0 };
+        self.ksum = self
+            .ksum
+            .wrapping_add(x.wrapping_add(1) / 2)
+            .wrapping_sub(self.ksum.wrapping_add(16) >> 5);
+        if self.ksum < lim {
+            self.k -= 1;
+        } else if self.ksum >= 1 << (self.k + 5) && self.k < 24 {
+            self.k += 1;
+        }
+    }
+}
+
+/// Decodes the residuals: a range coded overflow and remainder for each, against a
+/// pivot from the channel's Rice parameter. Reading past the end gives zeros and marks
+/// the frame as invalid.
+struct RangeDecoder<'a> {
+    data: &'a [u8],
+    pos: usize,
+    low: u32,
+    range: u32,
+    buffer: u32,
+    help: u32,
+    invalid: bool,
+}
+
+impl<'a> RangeDecoder<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        let mut rc = RangeDecoder {
+            data,
+            pos: 0,
+            low: 0,
+            range: 1 << EXTRA_BITS,
+            buffer: 0,
+            help: 0,
+            invalid: false,
+        };
+        rc.buffer = rc.byte();
+        rc.low = rc.buffer >> (8 - EXTRA_BITS);
+        rc
+    }
+
+    fn byte(&mut self) -> u32 {
+        match self.data.get(self.pos) {
+            Some(byte) => {
+                self.pos += 1;
+                *byte as u32
+            }
+            None => {
+                self.invalid = true;
+                0
+            }
+        }
+    }
+
+    fn normalize(&mut self) {
+        while self.range <= BOTTOM_VALUE {
+            self.buffer = self.buffer << 8 | self.byte();
+            self.low = self.low << 8 | ((self.buffer >> 1) & 0xff);
+            self.range <<= 8;
+        }
+    }
+
+    fn freq(&mut self, total: u32) -> u32 {
+        self.normalize();
+        self.help = self.range / total;
+        self.low / self.help
+    }
+
+    fn shift(&mut self, shift: u32) -> u32 {
+        self.normalize();
+        self.help = self.range >> shift;
+        self.low / self.help
+    }
+
+    fn update(&mut self, freq: u32, cumulative: u32) {
+        self.low = self.low.wrapping_sub(self.help.wrapping_mul(cumulative));
+        self.range = self.help.wrapping_mul(freq);
+    }
+
+    fn bits(&mut self, bits: u32) -> u32 {
+        let value = self.shift(bits);
+        self.update(1, value);
+        value
+    }
+
+    /// A value below `total`, with every value equally likely.
+    fn uniform(&mut self, total: u32) -> u32 {
+        let value = self.freq(total);
+        if value >= total {
+            self.invalid = true;
+        }
+        self.update(1, value);
+        value
+    }
+
+    fn overflow(&mut self) -> u32 {
+        let cf = self.shift(16);
+        if cf >= COUNTS[21] {
+            if cf > 0xffff {
+                self.invalid = true;
+            }
+            self.update(1, cf);
+            return (cf + ESCAPE).wrapping_sub(0xffff);
+        }
+        let symbol = COUNTS
+            .iter()
+            .skip(1)
+            .take_while(|count| **count <= cf)
+            .count();
+        self.update(COUNTS[symbol + 1] - COUNTS[symbol], COUNTS[symbol]);
+        symbol as u32
+    }
+
+    fn value(&mut self, rice: &mut Rice) -> i32 {
+        let pivot = (rice.ksum >> 5).max(1);
+
+        let mut overflow = self.overflow();
+        if overflow == ESCAPE {
+            overflow = self.bits(16) << 16;
+            overflow |= self.bits(16);
+        }
+
+        let base = if pivot < 0x10000 {
+            self.uniform(pivot)
+        } else {
+            // The range coder only takes 16-bit totals, send the top and bottom apart
+            let bits = 32 - (pivot >> 16).leading_zeros();
+            let high = self.uniform((pivot >> bits) + 1);
+            let low = self.uniform(1 << bits);
+            (high << bits) + low
+        };
+
+        let x = base.wrapping_add(overflow.wrapping_mul(pivot));
+        rice.update(x);
+
+        // Zero, then alternating positive and negative
+        ((x >> 1) ^ (x & 1).wrapping_sub(1)).wrapping_add(1) as i32
+    }
+}
+
+/// The inverse sign, as the filters and predictor adapt against it.
+fn inv_sign(x: i32) -> i32 {
+    (x < 0) as i32 - (x > 0) as i32
+}
+
+const HISTORY_SIZE: usize = 512;
+
+/// A sign-sign LMS filter over the last `order` outputs.
+struct NNFilter {
+    order: usize,
+    shift: u32,
+    average: i32,
+    coeffs: Vec<i16>,
+    /// The past outputs, with the adaption of the coefficients for each trailing `order`
+    /// behind them, rolled back to the start when full
+    history: Vec<i16>,
+    pos: usize,
+}
+
+impl NNFilter {
+    fn new(order: usize, shift: u32) -> Self {
+        NNFilter {
+            order,
+            shift,
+            average: 0,
+            coeffs: vec![0; order],
+            history: vec![0; HISTORY_SIZE + order * 2],
+            pos: order * 2,
+        }
+    }
+
+    fn decompress(&mut self, input: i32) -> i32 {
+        let order = self.order;
+        let (deltas, delay) = self.history[self.pos - order * 2..self.pos].split_at(order);
+
+        let dot = self
+            .coeffs
+            .iter()
+            .zip(delay)
+            .fold(0i32, |dot, (c, d)| dot.wrapping_add(*c as i32 * *d as i32));
+        let sign = inv_sign(input) as i16;
+        if sign != 0 {
+            for (c, d) in self.coeffs.iter_mut().zip(deltas) {
+                *c = c.wrapping_add(d.wrapping_mul(sign));
+            }
+        }
+
+        let output = input.wrapping_add(dot.wrapping_add(1 << (self.shift - 1)) >> self.shift);
+        self.history[self.pos] = output.clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+
+        // Adapt harder the further the output is from the running average
+        let abs = output.wrapping_abs() as i64;
+        let average = self.average as i64;
+        let step = if abs > average * 3 {
+            32
+        } else if abs > average * 4 / 3 {
+            16
+        } else if abs > 0 {
+            8
+        } else {
+            0
+        };
+        let delta = self.pos - order;
+        self.history[delta] = (inv_sign(output) * step) as i16;
+        self.average = self
+            .average
+            .wrapping_add((abs as i32).wrapping_sub(self.average) / 16);
+        self.history[delta - 1] >>= 1;
+        self.history[delta - 2] >>= 1;
+        self.history[delta - 8] >>= 1;
+
+        self.pos += 1;
+        if self.pos == self.history.len() {
+            self.history.copy_within(HISTORY_SIZE.., 0);
+            self.pos = order * 2;
+        }
+        output
+    }
+}
+
+const PREDICTOR_SIZE: usize = 50;
+
+/// Offsets into the predictor's history of each channel's values (Y is the first channel
+/// coded, X the second) and the signs they adapt with.
+const Y_DELAY_A: usize = 50;
+const Y_DELAY_B: usize = 42;
+const X_DELAY_A: usize = 34;
+const X_DELAY_B: usize = 26;
+const Y_ADAPT_A: usize = 18;
+const X_ADAPT_A: usize = 14;
+const Y_ADAPT_B: usize = 10;
+const X_ADAPT_B: usize = 5;
+
+const INITIAL_COEFFS: [i32; 4] = [360, 317, -109, 98];
+
+/// Predicts each channel from its own past (A) and the other channel's (B).
+struct Predictor {
+    history: [i32; HISTORY_SIZE + PREDICTOR_SIZE],
+    pos: usize,
+    last_a: [i32; 2],
+    filter_a: [i32; 2],
+    filter_b: [i32; 2],
+    coeffs_a: [[i32; 4]; 2],
+    coeffs_b: [[i32; 5]; 2],
+}
+
+/// The newest value is last in `history`, and goes with the first coefficient.
+fn predict(history: &[i32], coeffs: &[i32]) -> i32 {
+    history
+        .iter()
+        .rev()
+        .zip(coeffs)
+        .fold(0i32, |sum, (h, c)| sum.wrapping_add(h.wrapping_mul(*c)))
+}
+
+fn adapt(coeffs: &mut [i32], signs: &[i32], sign: i32) {
+    for (c, s) in coeffs.iter_mut().zip(signs.iter().rev()) {
+        *c = c.wrapping_add(s * sign);
+    }
+}
+
+impl Predictor {
+    fn new() -> Self {
+        Predictor {
+            history: [0; HISTORY_SIZE + PREDICTOR_SIZE],
+            pos: 0,
+            last_a: [0; 2],
+            filter_a: [0; 2],
+            filter_b: [0; 2],
+            coeffs_a: [INITIAL_COEFFS; 2],
+            coeffs_b: [[0; 5]; 2],
+        }
+    }
+
+    /// Undoes the prediction for one sample of a channel. Mono goes through here too,
+    /// with nothing from the other channel its B part stays at zero.
+    fn decompress(
+        &mut self,
+        input: i32,
+        ch: usize,
+        delay_a: usize,
+        delay_b: usize,
+        adapt_a: usize,
+        adapt_b: usize,
+    ) -> i32 {
+        let buf = &mut self.history[self.pos..];
+
+        buf[delay_a] = self.last_a[ch];
+        buf[adapt_a] = inv_sign(buf[delay_a]);
+        buf[delay_a - 1] = buf[delay_a].wrapping_sub(buf[delay_a - 1]);
+        buf[adapt_a - 1] = inv_sign(buf[delay_a - 1]);
+        let prediction_a = predict(&buf[delay_a - 3..=delay_a], &self.coeffs_a[ch]);
+
+        // A scaled first-order filter of the other channel's output
+        let other = self.filter_a[ch ^ 1];
+        buf[delay_b] = other.wrapping_sub(self.filter_b[ch].wrapping_mul(31) >> 5);
+        buf[adapt_b] = inv_sign(buf[delay_b]);
+        buf[delay_b - 1] = buf[delay_b].wrapping_sub(buf[delay_b - 1]);
+        buf[adapt_b - 1] = inv_sign(buf[delay_b - 1]);
+        self.filter_b[ch] = other;
+        let prediction_b = predict(&buf[delay_b - 4..=delay_b], &self.coeffs_b[ch]);
+
+        self.last_a[ch] = input.wrapping_add(prediction_a.wrapping_add(prediction_b >> 1) >> 10);
+        self.filter_a[ch] = self.last_a[ch].wrapping_add(self.filter_a[ch].wrapping_mul(31) >> 5);
+
+        let sign = inv_sign(input);
+        adapt(&mut self.coeffs_a[ch], &buf[adapt_a - 3..=adapt_a], sign);
+        adapt(&mut self.coeffs_b[ch], &buf[adapt_b - 4..=adapt_b], sign);
+
+        self.filter_a[ch]
+    }
+
+    fn advance(&mut self) {
+        self.pos += 1;
+        if self.pos == HISTORY_SIZE {
+            self.history.copy_within(HISTORY_SIZE.., 0);
+            self.pos = 0;
+        }
+    }
+}
+
+pub struct ApeDecoder {
+    params: CodecParameters,
+    filters: &'static [(usize, u32)],
+    channels: usize,
+    /// Left shift from the file's bits per sample to 32
+    shift: u32,
+    /// The frame with its words byte-swapped, to read in order
+    data: Vec<u8>,
+    decoded: [Vec<i32>; 2],
+    buf: AudioBuffer<i32>,
+}
+
+impl ApeDecoder {
+    /// Range decodes the residuals of a frame, then undoes the filters and prediction.
+    fn decode_frame(&mut self, blocks: usize, skip: usize) -> Result<()> {
+        for decoded in self.decoded.iter_mut() {
+            decoded.clear();
+            decoded.resize(blocks, 0);
+        }
+
+        let data = match self.data.get(skip..) {
+            Some(data) if data.len() >= 4 => data,
+            _ => return decode_error("ape: truncated frame"),
+        };
+        let crc = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+        let mut pos = 4;
+        let mut flags = 0;
+        if crc & 0x8000_0000 != 0 {
+            match data.get(4..8) {
+                Some(b) => flags = u32::from_be_bytes([b[0], b[1], b[2], b[3]]),
+                None => return decode_error("ape: truncated frame"),
+            }
+            pos = 8;
+        }
+
+        let mono = self.channels == 1 || flags & PSEUDO_STEREO != 0;
+        let silent = if mono {
+            flags & MONO_SILENCE != 0 || flags & STEREO_SILENCE != 0
+        } else {
+            flags & STEREO_SILENCE == STEREO_SILENCE
+        };
+        if silent {
+            return Ok(());
+        }
+
+        // The first byte after the frame header isn't part of the range coded data
+        let mut rc = RangeDecoder::new(data.get(pos + 1..).unwrap_or_default());
+        let (mut rice_x, mut rice_y) = (Rice::new(), Rice::new());
+        let [y, x] = &mut self.decoded;
+        if mono {
+            for value in y.iter_mut() {
+                *value = rc.value(&mut rice_y);
+            }
+        } else {
+            for (y, x) in y.iter_mut().zip(x.iter_mut()) {
+                *y = rc.value(&mut rice_y);
+                *x = rc.value(&mut rice_x);
+            }
+        }
+        if rc.invalid {
+            return decode_error("ape: invalid frame");
+        }
+
+        let channels = if mono { 1 } else { 2 };
+        for &(order, shift) in self.filters {
+            for decoded in self.decoded.iter_mut().take(channels) {
+                let mut filter = NNFilter::new(order, shift);
+                for value in decoded.iter_mut() {
+                    *value = filter.decompress(*value);
+                }
+            }
+        }
+
+        let mut predictor = Predictor::new();
+        let [y, x] = &mut self.decoded;
+        if mono {
+            for y in y.iter_mut() {
+                *y = predictor.decompress(*y, 0, Y_DELAY_A, Y_DELAY_B, Y_ADAPT_A, Y_ADAPT_B);
+                predictor.advance();
+            }
+            if self.channels == 2 {
+                x.copy_from_slice(y);
+            }
+        } else {
+            for (y, x) in y.iter_mut().zip(x.iter_mut()) {
+                *y = predictor.decompress(*y, 0, Y_DELAY_A, Y_DELAY_B, Y_ADAPT_A, Y_ADAPT_B);
+                *x = predictor.decompress(*x, 1, X_DELAY_A, X_DELAY_B, X_ADAPT_A, X_ADAPT_B);
+                predictor.advance();
+
+                // Y is the difference of the channels, X the right less half of it
+                let left = x.wrapping_sub(*y / 2);
+                let right = left.wrapping_add(*y);
+                *y = left;
+                *x = right;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Decoder for ApeDecoder {
+    fn try_new(params: &CodecParameters, _options: &DecoderOptions) -> Result<Self> {
+        if params.codec != CODEC_TYPE_MONKEYS_AUDIO {
+            return unsupported_error("ape: invalid codec type");
+        }
+        let (version, compression) = match params.extra_data.as_deref() {
+            Some([v0, v1, c0, c1, ..]) => (
+                u16::from_le_bytes([*v0, *v1]),
+                u16::from_le_bytes([*c0, *c1]),
+            ),
+            _ => return decode_error("ape: missing version and compression level"),
+        };
+        let (rate, layout, bits) =
+            match (params.sample_rate, params.channels, params.bits_per_sample) {
+                (Some(rate), Some(channels), Some(bits)) => (rate, channels, bits),
+                _ => return decode_error("ape: unknown sample rate, channels or bit depth"),
+            };
+        let channels = layout.count();
+        check_supported(version, compression, channels as u16, bits as u16)?;
+        let frames = params.max_frames_per_packet.unwrap_or(0);
+
+        Ok(ApeDecoder {
+            params: params.clone(),
+            filters: FILTERS[compression as usize / 1000 - 1],
+            channels,
+            shift: 32 - bits,
+            data: Vec::new(),
+            decoded: [Vec::new(), Vec::new()],
+            buf: AudioBuffer::new(frames, SignalSpec::new(rate, layout)),
+        })
+    }
+
+    fn supported_codecs() -> &'static [CodecDescriptor] {
+        &[support_codec!(
+            CODEC_TYPE_MONKEYS_AUDIO,
+            "ape",
+            "Monkey's Audio"
+        )]
+    }
+
+    fn reset(&mut self) {}
+
+    fn codec_params(&self) -> &CodecParameters {
+        &self.params
+    }
+
+    fn decode(&mut self, packet: &Packet) -> Result<AudioBufferRef<'_>> {
+        self.buf.clear();
+
+        let buf = packet.buf();
+        if buf.len() < 8 {
+            return decode_error("ape: truncated frame");
+        }
+        let blocks = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+        let skip = u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]) as usize;
+        if skip > 3 || blocks > self.buf.capacity() {
+            return decode_error("ape: invalid frame");
+        }
+
+        // The frame is little-endian 32-bit words, read from their most significant byte
+        self.data.clear();
+        for word in buf[8..].chunks(4) {
+            let mut bytes = [0u8; 4];
+            bytes[..word.len()].copy_from_slice(word);
+            self.data.extend(bytes.iter().rev());
+        }
+        self.decode_frame(blocks, skip)?;
+
+        self.buf.render_reserved(Some(blocks));
+        for ch in 0..self.channels {
+            let shift = self.shift;
+            for (dst, src) in self.buf.chan_mut(ch).iter_mut().zip(&self.decoded[ch]) {
+                *dst = src << shift;
+            }
+        }
+        Ok(self.buf.as_audio_buffer_ref())
+    }
+
+    fn finalize(&mut self) -> FinalizeResult {
+        Default::default()
+    }
+
+    fn last_decoded(&self) -> AudioBufferRef<'_> {
+        self.buf.as_audio_buffer_ref()
+    }
+}
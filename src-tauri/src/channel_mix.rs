@@ -0,0 +1,221 @@
+//! Maps the decoded channels onto the output's, by speaker position, then applies the
+//! user's mono, channel swap and balance.
+//!
+//! Channels the output doesn't have are folded into the front left and right at -3 dB
+//! (ITU-R BS.775), LFE is dropped, and the result is scaled down so it can't clip.
+
+use std::f32::consts::FRAC_1_SQRT_2;
+
+use serde::{Deserialize, Serialize};
+use symphonia::core::audio::{AudioBuffer, AudioBufferRef, Channels, Signal, SignalSpec};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ChannelMixSettings {
+    /// Play the same mix on both sides
+    pub mono: bool,
+    pub swap: bool,
+    pub balance: f64, // -1 (left) to 1 (right)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Side {
+    Left,
+    Right,
+    Centre,
+    Lfe,
+}
+
+fn side(position: Channels) -> Side {
+    let left = Channels::FRONT_LEFT
+        | Channels::REAR_LEFT
+        | Channels::FRONT_LEFT_CENTRE
+        | Channels::SIDE_LEFT
+        | Channels::TOP_FRONT_LEFT
+        | Channels::TOP_REAR_LEFT
+        | Channels::REAR_LEFT_CENTRE
+        | Channels::FRONT_LEFT_WIDE
+        | Channels::FRONT_LEFT_HIGH;
+    let right = Channels::FRONT_RIGHT
+        | Channels::REAR_RIGHT
+        | Channels::FRONT_RIGHT_CENTRE
+        | Channels::SIDE_RIGHT
+        | Channels::TOP_FRONT_RIGHT
+        | Channels::TOP_REAR_RIGHT
+        | Channels::REAR_RIGHT_CENTRE
+        | Channels::FRONT_RIGHT_WIDE
+        | Channels::FRONT_RIGHT_HIGH;
+
+    if left.contains(position) {
+        Side::Left
+    } else if right.contains(position) {
+        Side::Right
+    } else if (Channels::LFE1 | Channels::LFE2).contains(position) {
+        Side::Lfe
+    } else {
+        Side::Centre
+    }
+}
+
+/// Pairs of outputs that trade places when swapping channels.
+const PAIRS: [(Channels, Channels); 3] = [
+    (Channels::FRONT_LEFT, Channels::FRONT_RIGHT),
+    (Channels::REAR_LEFT, Channels::REAR_RIGHT),
+    (Channels::SIDE_LEFT, Channels::SIDE_RIGHT),
+];
+
+/// Gains from each input channel to each output channel, as `matrix[output][input]`.
+pub fn mix_matrix(
+    input: Channels,
+    output: Channels,
+    settings: &ChannelMixSettings,
+) -> Vec<Vec<f32>> {
+    let inputs: Vec<Channels> = input.iter().collect();
+    let outputs: Vec<Channels> = output.iter().collect();
+    // Mix for at least a stereo pair, a mono output takes the average of the two
+    let mixed = output | Channels::FRONT_LEFT | Channels::FRONT_RIGHT;
+    let positions: Vec<Channels> = mixed.iter().collect();
+    let index = |position: Channels| positions.iter().position(|p| *p == position);
+    let (left, right) = (
+        index(Channels::FRONT_LEFT).unwrap(),
+        index(Channels::FRONT_RIGHT).unwrap(),
+    );
+
+    let mut matrix = vec![vec![0f32; inputs.len()]; positions.len()];
+    if inputs.len() == 1 {
+        // Mono goes to both sides at full level
+        matrix[left][0] = 1.0;
+        matrix[right][0] = 1.0;
+    } else {
+        for (i, position) in inputs.iter().enumerate() {
+            if let Some(o) = index(*position) {
+                matrix[o][i] = 1.0;
+                continue;
+            }
+            match side(*position) {
+                Side::Left => matrix[left][i] = FRAC_1_SQRT_2,
+                Side::Right => matrix[right][i] = FRAC_1_SQRT_2,
+                Side::Centre => {
+                    matrix[left][i] = FRAC_1_SQRT_2;
+                    matrix[right][i] = FRAC_1_SQRT_2;
+                }
+                Side::Lfe => {}
+            }
+        }
+    }
+
+    // Keep the downmix from clipping, scaling every output by the same amount
+    let loudest = matrix
+        .iter()
+        .map(|row| row.iter().sum::<f32>())
+        .fold(1.0, f32::max);
+    matrix
+        .iter_mut()
+        .for_each(|row| row.iter_mut().for_each(|g| *g /= loudest));
+
+    if settings.mono || outputs.len() == 1 {
+        let average: Vec<f32> = matrix[left]
+            .iter()
+            .zip(&matrix[right])
+            .map(|(l, r)| (l + r) / 2.0)
+            .collect();
+        matrix[left] = average.clone();
+        matrix[right] = average;
+    }
+
+    if settings.swap {
+        for (l, r) in PAIRS.iter() {
+            if let (Some(l), Some(r)) = (index(*l), index(*r)) {
+                matrix.swap(l, r);
+            }
+        }
+    }
+
+    // Balance turns the other side down, never up
+    let balance = settings.balance.clamp(-1.0, 1.0) as f32;
+    for (o, position) in positions.iter().enumerate() {
+        let gain = match side(*position) {
+            Side::Left => (1.0 - balance).min(1.0),
+            Side::Right => (1.0 + balance).min(1.0),
+            _ => 1.0,
+        };
+        matrix[o].iter_mut().for_each(|g| *g *= gain);
+    }
+
+    // Drop the stereo pair we added for a mono output
+    positions
+        .iter()
+        .zip(matrix)
+        .filter(|(position, _)| output.contains(**position))
+        .map(|(_, row)| row)
+        .collect()
+}
+
+/// Mixes decoded buffers onto the output's channels, keeping the matrix until the
+/// input layout or the settings change.
+pub struct ChannelMixer {
+    output: Channels,
+    settings: ChannelMixSettings,
+    input: Option<Channels>,
+    matrix: Vec<Vec<f32>>,
+}
+
+impl ChannelMixer {
+    pub fn new(output: Channels, settings: ChannelMixSettings) -> Self {
+        ChannelMixer {
+            output,
+            settings,
+            input: None,
+            matrix: Vec::new(),
+        }
+    }
+
+    pub fn set_settings(&mut self, settings: ChannelMixSettings) {
+        if settings != self.settings {
+            self.settings = settings;
+            self.input = None;
+        }
+    }
+
+    fn prepare(&mut self, input: Channels) {
+        if self.input != Some(input) {
+            self.matrix = mix_matrix(input, self.output, &self.settings);
+            self.input = Some(input);
+        }
+    }
+
+    /// Whether the input would come out unchanged.
+    pub fn is_passthrough(&mut self, input: Channels) -> bool {
+        self.prepare(input);
+        input == self.output
+            && self.matrix.iter().enumerate().all(|(o, row)| {
+                row.iter()
+                    .enumerate()
+                    .all(|(i, g)| *g == if i == o { 1.0 } else { 0.0 })
+            })
+    }
+
+    pub fn mix(&mut self, decoded: &AudioBufferRef<'_>) -> AudioBuffer<f32> {
+        let mut input = decoded.make_equivalent::<f32>();
+        decoded.convert(&mut input);
+        self.prepare(input.spec().channels);
+
+        let spec = SignalSpec::new(input.spec().rate, self.output);
+        let mut output = AudioBuffer::<f32>::new(input.capacity() as u64, spec);
+        output.render_silence(Some(input.frames()));
+
+        for (o, row) in self.matrix.iter().enumerate() {
+            let dst = output.chan_mut(o);
+            for (i, gain) in row.iter().enumerate() {
+                if *gain == 0.0 {
+                    continue;
+                }
+                for (d, s) in dst.iter_mut().zip(input.chan(i)) {
+                    *d += s * gain;
+                }
+            }
+        }
+
+        output
+    }
+}
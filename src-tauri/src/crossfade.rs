@@ -0,0 +1,165 @@
+use std::f32::consts::FRAC_PI_2;
+
+use log::info;
+use serde::{Deserialize, Serialize};
+use symphonia::core::audio::{AudioBuffer, AudioBufferRef, Signal, SignalSpec};
+use symphonia::core::codecs::Decoder;
+use symphonia::core::formats::FormatReader;
+
+use crate::output::remap_channels;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CrossfadeCurve {
+    Linear,
+    EqualPower,
+    SCurve,
+}
+
+impl Default for CrossfadeCurve {
+    fn default() -> Self {
+        CrossfadeCurve::EqualPower
+    }
+}
+
+impl CrossfadeCurve {
+    /// Gains for the outgoing and incoming track at `progress` (0 to 1) through the fade.
+    pub fn gains(&self, progress: f32) -> (f32, f32) {
+        let p = progress.clamp(0.0, 1.0);
+        match self {
+            CrossfadeCurve::Linear => (1.0 - p, p),
+            CrossfadeCurve::EqualPower => ((p * FRAC_PI_2).cos(), (p * FRAC_PI_2).sin()),
+            CrossfadeCurve::SCurve => {
+                let s = p * p * (3.0 - 2.0 * p);
+                (1.0 - s, s)
+            }
+        }
+    }
+}
+
+/// The next track, decoded alongside the end of the current one and mixed into it.
+pub struct Crossfade {
+    pub path: String,
+    reader: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    /// Timestamp (in frames of the outgoing track) where the fade starts
+    start_ts: u64,
+    /// Length of the fade in frames
+    length: u64,
+    curve: CrossfadeCurve,
+    /// Decoded frames of the incoming track that haven't been mixed yet (planar)
+    pending: Vec<Vec<f32>>,
+    /// Number of incoming frames that have been mixed
+    consumed: u64,
+    finished: bool,
+    /// Gain of the incoming track relative to the outgoing one (ReplayGain)
+    relative_gain: f32,
+}
+
+impl Crossfade {
+    pub fn new(
+        path: String,
+        reader: Box<dyn FormatReader>,
+        decoder: Box<dyn Decoder>,
+        track_id: u32,
+        start_ts: u64,
+        length: u64,
+        curve: CrossfadeCurve,
+        spec: SignalSpec,
+    ) -> Self {
+        info!(
+            "crossfade: starting {:?} fade of {} frames into {}",
+            curve, length, path
+        );
+        Crossfade {
+            path,
+            reader,
+            decoder,
+            track_id,
+            start_ts,
+            length,
+            curve,
+            pending: vec![Vec::new(); spec.channels.count()],
+            consumed: 0,
+            finished: false,
+            relative_gain: 1.0,
+        }
+    }
+
+    /// The output applies the outgoing track's gain to the mix, so the incoming
+    /// track is scaled by the difference.
+    pub fn set_relative_gain(&mut self, relative_gain: f32) {
+        self.relative_gain = relative_gain;
+    }
+
+    /// How far into the incoming track the fade has got, in frames.
+    pub fn consumed_frames(&self) -> u64 {
+        self.consumed
+    }
+
+    /// Mixes the outgoing packet (starting at `ts`) with the start of the incoming track.
+    pub fn mix(&mut self, outgoing: &AudioBufferRef<'_>, ts: u64) -> AudioBuffer<f32> {
+        let mut mixed = outgoing.make_equivalent::<f32>();
+        outgoing.convert(&mut mixed);
+
+        let frames = mixed.frames();
+        self.fill(frames, *mixed.spec());
+
+        for ch in 0..self.pending.len().min(mixed.spec().channels.count()) {
+            let dst = mixed.chan_mut(ch);
+            for (i, (out_sample, in_sample)) in dst.iter_mut().zip(&self.pending[ch]).enumerate()
+            {
+                let position = (ts + i as u64).saturating_sub(self.start_ts);
+                let (out_gain, in_gain) = self.curve.gains(position as f32 / self.length as f32);
+                *out_sample = *out_sample * out_gain + in_sample * in_gain * self.relative_gain;
+            }
+        }
+
+        for channel in self.pending.iter_mut() {
+            channel.drain(0..frames.min(channel.len()));
+        }
+        self.consumed += frames as u64;
+
+        mixed
+    }
+
+    /// Decodes the incoming track until `frames` frames are pending, padding with
+    /// silence if it ends first.
+    fn fill(&mut self, frames: usize, spec: SignalSpec) {
+        while !self.finished && self.pending[0].len() < frames {
+            let packet = match self.reader.next_packet() {
+                Ok(packet) => packet,
+                Err(err) => {
+                    info!("crossfade: incoming track ended early: {}", err);
+                    self.finished = true;
+                    break;
+                }
+            };
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+            match self.decoder.decode(&packet) {
+                Ok(decoded) => {
+                    let remapped = remap_channels(&decoded, spec.channels);
+                    for (ch, channel) in self.pending.iter_mut().enumerate() {
+                        channel.extend_from_slice(remapped.chan(ch));
+                    }
+                }
+                Err(symphonia::core::errors::Error::DecodeError(err)) => {
+                    info!("crossfade: decode error: {}", err)
+                }
+                Err(err) => {
+                    info!("crossfade: incoming track failed: {}", err);
+                    self.finished = true;
+                }
+            }
+        }
+
+        for channel in self.pending.iter_mut() {
+            if channel.len() < frames {
+                channel.resize(frames, 0.0);
+            }
+        }
+    }
+}
@@ -0,0 +1,266 @@
+//! CUE sheets, for albums ripped to a single file.
+//!
+//! Each track of the sheet becomes a song of its own, with a path of the album's file and
+//! the track number after a `#`. The player opens the file at the track's start and ends
+//! the track where the next one starts, so playing through the album stays gapless.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use lofty::file::{TaggedFile, TaggedFileExt};
+use lofty::read_from_path;
+use lofty::tag::ItemKey;
+
+/// CUE times are in minutes, seconds and frames of a CD, of which there are 75 a second.
+const FRAMES_PER_SECOND: f64 = 75.0;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct CueSheet {
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub genre: Option<String>,
+    pub year: Option<i32>,
+    pub files: Vec<CueFile>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct CueFile {
+    pub name: String,
+    pub tracks: Vec<CueTrack>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct CueTrack {
+    pub number: u32,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    /// Seconds from the start of the file, at INDEX 01
+    pub start: f64,
+}
+
+/// Where a track is in its album's file.
+#[derive(Debug, PartialEq)]
+pub struct TrackBounds {
+    pub file: PathBuf,
+    pub start: f64,
+    /// None for the last track, which plays to the end of the file
+    pub end: Option<f64>,
+}
+
+/// Parses a CUE sheet. Anything not about the audio tracks is skipped.
+pub fn parse(text: &str) -> Option<CueSheet> {
+    let mut sheet = CueSheet::default();
+    // Data tracks are skipped along with their titles and indexes
+    let mut in_audio_track = false;
+    for line in text.trim_start_matches('\u{feff}').lines() {
+        let line = line.trim();
+        let (command, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest = rest.trim();
+        match command.to_ascii_uppercase().as_str() {
+            "REM" => {
+                let (field, value) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+                match field.to_ascii_uppercase().as_str() {
+                    "GENRE" => sheet.genre = Some(unquote(value)),
+                    "DATE" => sheet.year = value.trim().get(..4).and_then(|y| y.parse().ok()),
+                    _ => {}
+                }
+            }
+            "FILE" => {
+                // The file type follows the name, which is only quoted if it has spaces
+                let name = match rest.strip_prefix('"') {
+                    Some(quoted) => quoted.rsplit_once('"').map_or(quoted, |(name, _)| name),
+                    None => rest.rsplit_once(' ').map_or(rest, |(name, _)| name),
+                };
+                sheet.files.push(CueFile {
+                    name: name.to_string(),
+                    tracks: Vec::new(),
+                });
+                in_audio_track = false;
+            }
+            "TRACK" => {
+                let (number, kind) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+                in_audio_track = kind.trim().eq_ignore_ascii_case("AUDIO");
+                if let (true, Some(file), Ok(number)) =
+                    (in_audio_track, sheet.files.last_mut(), number.parse())
+                {
+                    file.tracks.push(CueTrack {
+                        number,
+                        title: None,
+                        performer: None,
+                        start: -1.0,
+                    });
+                }
+            }
+            "TITLE" | "PERFORMER" => {
+                let value = Some(unquote(rest));
+                let track = sheet
+                    .files
+                    .last_mut()
+                    .and_then(|file| file.tracks.last_mut());
+                match (track, command.eq_ignore_ascii_case("TITLE")) {
+                    (Some(track), true) if in_audio_track => track.title = value,
+                    (Some(track), false) if in_audio_track => track.performer = value,
+                    (None, true) => sheet.title = value,
+                    (None, false) => sheet.performer = value,
+                    _ => {}
+                }
+            }
+            "INDEX" => {
+                let (index, time) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+                let track = sheet
+                    .files
+                    .last_mut()
+                    .and_then(|file| file.tracks.last_mut());
+                if let (Some(track), Ok(1), Some(start)) =
+                    (track, index.parse::<u32>(), parse_time(time.trim()))
+                {
+                    if in_audio_track {
+                        track.start = start;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // A track without an INDEX 01 can't be played
+    for file in sheet.files.iter_mut() {
+        file.tracks.retain(|track| track.start >= 0.0);
+    }
+    sheet.files.retain(|file| !file.tracks.is_empty());
+    (!sheet.files.is_empty()).then_some(sheet)
+}
+
+fn unquote(value: &str) -> String {
+    let value = value.trim();
+    value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(value)
+        .to_string()
+}
+
+/// Parses `mm:ss:ff` into seconds.
+fn parse_time(time: &str) -> Option<f64> {
+    let mut parts = time.split(':').map(|part| part.parse::<u32>().ok());
+    let (minutes, seconds, frames) = (parts.next()??, parts.next()??, parts.next()??);
+    if parts.next().is_some() || seconds >= 60 || frames >= 75 {
+        return None;
+    }
+    Some((minutes * 60 + seconds) as f64 + frames as f64 / FRAMES_PER_SECOND)
+}
+
+/// Sheets are often in the encoding of whoever ripped the CD, Latin-1 is the usual one.
+pub fn read_text(path: &Path) -> Option<String> {
+    let bytes = fs::read(path).ok()?;
+    Some(
+        String::from_utf8(bytes)
+            .unwrap_or_else(|err| err.into_bytes().iter().map(|&b| b as char).collect()),
+    )
+}
+
+/// The CUESHEET tag FLAC, APE and WavPack rips can carry.
+pub fn embedded_sheet(tagged_file: &TaggedFile) -> Option<String> {
+    tagged_file.tags().iter().find_map(|tag| {
+        tag.items().find_map(|item| match item.key() {
+            ItemKey::Unknown(key) if key.eq_ignore_ascii_case("CUESHEET") => {
+                item.value().text().map(String::from)
+            }
+            _ => None,
+        })
+    })
+}
+
+/// Which of the sheet's files is `audio`. Sheets often name the WAV file the CD was ripped
+/// to, so a file of the same name with another extension matches too, as does the only
+/// file of a sheet named after the audio file.
+fn match_file(sheet: &CueSheet, audio: &Path, sheet_path: &Path) -> Option<usize> {
+    let name = audio.file_name()?.to_str()?;
+    let stem = audio.file_stem()?.to_str()?;
+    let same_stem = |other: &Path| {
+        other
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .map_or(false, |s| s.eq_ignore_ascii_case(stem))
+    };
+    sheet
+        .files
+        .iter()
+        .position(|file| {
+            let file_name = file.name.rsplit(['/', '\\']).next().unwrap_or(&file.name);
+            file_name.eq_ignore_ascii_case(name) || same_stem(Path::new(file_name))
+        })
+        .or_else(|| (sheet.files.len() == 1 && same_stem(sheet_path)).then_some(0))
+}
+
+/// A sheet that splits an album's file into tracks.
+#[derive(Debug)]
+pub struct AlbumSheet {
+    pub sheet: CueSheet,
+    /// Which of the sheet's files it is
+    file: usize,
+}
+
+impl AlbumSheet {
+    pub fn tracks(&self) -> &[CueTrack] {
+        &self.sheet.files[self.file].tracks
+    }
+
+    /// Where a track starts and ends, in seconds. Each track runs until the next one
+    /// starts, so any gap before a track is heard at the end of the one before.
+    pub fn bounds(&self, number: u32) -> Option<(f64, Option<f64>)> {
+        let tracks = self.tracks();
+        let i = tracks.iter().position(|track| track.number == number)?;
+        Some((tracks[i].start, tracks.get(i + 1).map(|next| next.start)))
+    }
+}
+
+/// The sheet that splits `audio` into tracks: its own CUESHEET tag if it has one, else a
+/// `.cue` file next to it. A sheet of one track is a file per track, which needs no
+/// splitting.
+pub fn find_sheet(audio: &Path, embedded: Option<&str>) -> Option<AlbumSheet> {
+    let album = match embedded.and_then(parse) {
+        // Embedded sheets describe their own file, whatever they call it
+        Some(sheet) => AlbumSheet { sheet, file: 0 },
+        None => fs::read_dir(audio.parent()?)
+            .ok()?
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.extension()
+                    .and_then(|ext| ext.to_str())
+                    .map_or(false, |ext| ext.eq_ignore_ascii_case("cue"))
+            })
+            .find_map(|path| {
+                let sheet = parse(&read_text(&path)?)?;
+                let file = match_file(&sheet, audio, &path)?;
+                Some(AlbumSheet { sheet, file })
+            })?,
+    };
+    (album.tracks().len() > 1).then_some(album)
+}
+
+/// The path of a track of an album's file.
+pub fn track_path(audio: &str, number: u32) -> String {
+    format!("{}#{}", audio, number)
+}
+
+/// Splits a track's path into the album's file and the track number.
+pub fn split_track_path(path: &str) -> Option<(&str, u32)> {
+    let (audio, number) = path.rsplit_once('#')?;
+    if number.is_empty() || !number.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some((audio, number.parse().ok()?))
+}
+
+/// Where the track at `path` is in its album's file, or None if it's a file of its own.
+pub fn track_bounds(path: &str) -> Option<TrackBounds> {
+    let (audio, number) = split_track_path(path)?;
+    let file = PathBuf::from(audio);
+    let embedded = read_from_path(&file)
+        .ok()
+        .and_then(|tagged_file| embedded_sheet(&tagged_file));
+    let (start, end) = find_sheet(&file, embedded.as_deref())?.bounds(number)?;
+    Some(TrackBounds { file, start, end })
+}
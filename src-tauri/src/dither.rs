@@ -0,0 +1,86 @@
+//! TPDF dither for integer output formats, with optional noise shaping.
+//!
+//! Samples stay in float until they're requantized here, so the rounding error becomes
+//! a constant noise floor instead of distortion that follows the signal.
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DitherSettings {
+    pub enabled: bool,
+    /// Push the dither noise towards high frequencies, where it's harder to hear
+    pub noise_shaping: bool,
+}
+
+/// Noise transfer function 1 - z^-1 + 0.5z^-2: -6 dB at DC, +8 dB at Nyquist.
+const SHAPING: [f32; 2] = [1.0, -0.5];
+
+pub struct Dither {
+    /// Bits of the output format, None for float output which doesn't need dither
+    bits: Option<u32>,
+    channels: usize,
+    settings: DitherSettings,
+    /// Last two quantization errors of each channel, in steps
+    errors: Vec<[f32; 2]>,
+    seed: u32,
+}
+
+impl Dither {
+    pub fn new(bits: Option<u32>, channels: usize) -> Self {
+        Dither {
+            bits,
+            channels,
+            settings: DitherSettings::default(),
+            errors: vec![[0.0; 2]; channels],
+            seed: 0x9E37_79B9,
+        }
+    }
+
+    pub fn set(&mut self, settings: DitherSettings) {
+        if settings != self.settings {
+            self.settings = settings;
+            self.errors.iter_mut().for_each(|e| *e = [0.0; 2]);
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.settings.enabled && self.bits.is_some()
+    }
+
+    /// Rounds interleaved samples to the output's step size, adding dither first.
+    pub fn process(&mut self, samples: &mut [f32]) {
+        let bits = match self.bits {
+            Some(bits) if self.settings.enabled => bits,
+            _ => return,
+        };
+        let scale = (1u32 << (bits - 1)) as f32;
+        let (min, max) = (-scale, scale - 1.0);
+
+        for (i, sample) in samples.iter_mut().enumerate() {
+            // Two uniform values of one step each make triangular noise of +/- one step
+            let noise = self.uniform() + self.uniform();
+
+            let errors = &mut self.errors[i % self.channels];
+            let mut wanted = *sample * scale;
+            if self.settings.noise_shaping {
+                wanted -= SHAPING[0] * errors[0] + SHAPING[1] * errors[1];
+            }
+            let quantized = (wanted + noise).round().clamp(min, max);
+
+            if self.settings.noise_shaping {
+                // Clipped samples would feed back a huge error, keep it to what
+                // rounding and dither can add
+                errors[1] = errors[0];
+                errors[0] = (quantized - wanted).clamp(-1.5, 1.5);
+            }
+            *sample = quantized / scale;
+        }
+    }
+
+    /// Uniform noise in [-0.5, 0.5), from a xorshift generator that's cheap enough for
+    /// the output callback.
+    fn uniform(&mut self) -> f32 {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 17;
+        self.seed ^= self.seed << 5;
+        (self.seed >> 8) as f32 / (1u32 << 24) as f32 - 0.5
+    }
+}
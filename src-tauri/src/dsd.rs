@@ -0,0 +1,692 @@
+//! DSD (`.dsf` and `.dff`) reading for symphonia, converted to PCM as it decodes.
+//!
+//! The 1-bit stream is low-pass filtered and decimated to 88.2 or 176.4kHz (96 or 192kHz
+//! for the 48kHz family of rates), which keeps the whole audible band and leaves out the
+//! noise DSD shapes up above it. The reader hands the decoder each channel's bytes
+//! separately with the earliest bit first, whichever way the file stores them. DFF files
+//! compressed with DST aren't supported.
+
+use std::f64::consts::PI;
+use std::fs::File;
+use std::io::{Seek, SeekFrom};
+use std::path::Path;
+
+use symphonia::core::audio::{
+    AsAudioBufferRef, AudioBuffer, AudioBufferRef, Channels, Signal, SignalSpec,
+};
+use symphonia::core::codecs::{
+    decl_codec_type, CodecDescriptor, CodecParameters, CodecType, Decoder, DecoderOptions,
+    FinalizeResult,
+};
+use symphonia::core::errors::{
+    decode_error, end_of_stream_error, seek_error, unsupported_error, Result, SeekErrorKind,
+};
+use symphonia::core::formats::{
+    Cue, FormatOptions, FormatReader, Packet, SeekMode, SeekTo, SeekedTo, Track,
+};
+use symphonia::core::io::{MediaSource, MediaSourceStream, ReadBytes, SeekBuffered};
+use symphonia::core::meta::{Metadata, MetadataLog};
+use symphonia::core::probe::{Descriptor, Instantiate, QueryDescriptor};
+use symphonia::core::sample::SampleFormat;
+use symphonia::core::units::TimeBase;
+use symphonia::core::{support_codec, support_format};
+
+pub const CODEC_TYPE_DSD: CodecType = decl_codec_type(b"dsd");
+
+/// Bytes of each channel in a packet. DSF files say theirs, which is this in practice.
+const BLOCK: usize = 4096;
+
+/// The highest PCM rate we decimate to.
+const MAX_PCM_RATE: u32 = 192_000;
+
+/// Where the low-pass filter starts cutting, past hearing but before DSD's noise climbs.
+const CUTOFF: f64 = 30_000.0;
+
+/// Filter taps for every DSD sample of decimation, so the filter's length in time, and
+/// with it the steepness of the cutoff, is the same at every rate.
+const TAPS_PER_RATIO: usize = 64;
+
+/// The idle pattern DSD uses for silence, which has as many ones as zeros.
+const SILENCE: u8 = 0x69;
+
+/// Longest ID3 tag we read, they can hold artwork but not this much.
+const MAX_ID3_LEN: u64 = 1 << 26;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Storage {
+    /// A block of each channel after the other, with the earliest bit either end
+    Dsf { block: usize, lsb_first: bool },
+    /// A byte of each channel at a time
+    Dff,
+}
+
+/// The fields of the headers we need.
+#[derive(Debug)]
+pub struct DsdHeader {
+    storage: Storage,
+    /// The speaker of each channel, in the order they're stored
+    positions: Vec<Channels>,
+    /// DSD samples per second, 2.8224MHz for DSD64
+    pub rate: u32,
+    /// DSD samples of each channel
+    pub samples: u64,
+    data_start: u64,
+    /// Where the ID3 tag is and how long it is, if there is one
+    id3: Option<(u64, u64)>,
+}
+
+impl DsdHeader {
+    pub fn read(source: &mut MediaSourceStream) -> Result<DsdHeader> {
+        let start = source.pos();
+        match &source.read_quad_bytes()? {
+            b"DSD " => read_dsf(source, start),
+            b"FRM8" => read_dff(source, start),
+            _ => unsupported_error("dsd: missing marker"),
+        }
+    }
+
+    pub fn channels(&self) -> usize {
+        self.positions.len()
+    }
+}
+
+/// DSF is little-endian chunks: `DSD ` with the whole file's length and where the ID3 tag
+/// is, `fmt ` and `data`, in that order.
+fn read_dsf(source: &mut MediaSourceStream, start: u64) -> Result<DsdHeader> {
+    let chunk_len = source.read_u64()?;
+    let _file_len = source.read_u64()?;
+    let metadata = source.read_u64()?;
+    source.ignore_bytes(chunk_len.saturating_sub(28))?;
+
+    if &source.read_quad_bytes()? != b"fmt " {
+        return decode_error("dsf: missing fmt chunk");
+    }
+    let fmt_len = source.read_u64()?;
+    let _version = source.read_u32()?;
+    if source.read_u32()? != 0 {
+        return unsupported_error("dsf: only raw DSD is supported");
+    }
+    let channel_type = source.read_u32()?;
+    let count = source.read_u32()? as usize;
+    let rate = source.read_u32()?;
+    let lsb_first = match source.read_u32()? {
+        1 => true,
+        8 => false,
+        _ => return decode_error("dsf: invalid bits per sample"),
+    };
+    let samples = source.read_u64()?;
+    let block = source.read_u32()? as usize;
+    source.ignore_bytes(fmt_len.saturating_sub(48))?;
+    if count == 0 || count > 6 || block == 0 || block > 1 << 20 {
+        return decode_error("dsf: invalid fmt chunk");
+    }
+
+    if &source.read_quad_bytes()? != b"data" {
+        return decode_error("dsf: missing data chunk");
+    }
+    let _data_len = source.read_u64()?;
+    let data_start = source.pos();
+
+    // The tag runs to the end of the file
+    let id3 = match (metadata, source.byte_len()) {
+        (0, _) | (_, None) => None,
+        (offset, Some(len)) => Some((start + offset, len.saturating_sub(start + offset))),
+    };
+
+    Ok(DsdHeader {
+        storage: Storage::Dsf { block, lsb_first },
+        positions: positions_or_default(dsf_positions(channel_type), count),
+        rate,
+        samples,
+        data_start,
+        id3,
+    })
+}
+
+/// DFF is big-endian IFF chunks inside a `FRM8`, padded to an even length. The sound
+/// properties are in a `PROP` chunk, the sound itself in a `DSD ` chunk.
+fn read_dff(source: &mut MediaSourceStream, start: u64) -> Result<DsdHeader> {
+    let form_len = source.read_be_u64()?;
+    if &source.read_quad_bytes()? != b"DSD " {
+        return unsupported_error("dff: not a DSD file");
+    }
+    let end = start + 12 + form_len;
+
+    let mut rate = None;
+    let mut ids = Vec::new();
+    let mut data = None;
+    let mut id3 = None;
+    while source.pos() + 12 <= end {
+        // Some files are shorter than they say
+        let (id, len) = match (source.read_quad_bytes(), source.read_be_u64()) {
+            (Ok(id), Ok(len)) => (id, len),
+            _ => break,
+        };
+        let pos = source.pos();
+        match &id {
+            b"PROP" => {
+                if &source.read_quad_bytes()? != b"SND " {
+                    return unsupported_error("dff: not a sound file");
+                }
+                while source.pos() + 12 <= pos + len {
+                    let id = source.read_quad_bytes()?;
+                    let len = source.read_be_u64()?;
+                    let pos = source.pos();
+                    match &id {
+                        b"FS  " => rate = Some(source.read_be_u32()?),
+                        b"CHNL" => {
+                            let count = source.read_be_u16()?;
+                            for _ in 0..count {
+                                ids.push(source.read_quad_bytes()?);
+                            }
+                        }
+                        b"CMPR" => {
+                            if &source.read_quad_bytes()? != b"DSD " {
+                                return unsupported_error("dff: DST compression is not supported");
+                            }
+                        }
+                        _ => {}
+                    }
+                    skip_to(source, pos + len + (len & 1))?;
+                }
+            }
+            b"DSD " => data = Some((pos, len)),
+            b"DST " => return unsupported_error("dff: DST compression is not supported"),
+            b"ID3 " => id3 = Some((pos, len)),
+            _ => {}
+        }
+        if !skip_to(source, pos + len + (len & 1))? {
+            break;
+        }
+    }
+
+    let (data_start, data_len) = match data {
+        Some(data) => data,
+        None => return decode_error("dff: missing sound data"),
+    };
+    let rate = match rate {
+        Some(rate) => rate,
+        None => return decode_error("dff: missing sample rate"),
+    };
+    if ids.is_empty() {
+        return decode_error("dff: missing channels");
+    }
+    let positions = ids.iter().map(dff_position).collect();
+
+    Ok(DsdHeader {
+        storage: Storage::Dff,
+        positions: positions_or_default(positions, ids.len()),
+        rate,
+        samples: data_len / ids.len() as u64 * 8,
+        data_start,
+        id3,
+    })
+}
+
+/// Moves on to `pos`, unless that's a long way on and the source can't seek.
+fn skip_to(source: &mut MediaSourceStream, pos: u64) -> Result<bool> {
+    let current = source.pos();
+    if pos < current {
+        return decode_error("dsd: invalid chunk length");
+    }
+    if source.is_seekable() {
+        source.seek(SeekFrom::Start(pos))?;
+    } else if pos - current <= 1 << 16 {
+        source.ignore_bytes(pos - current)?;
+    } else {
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+fn dsf_positions(channel_type: u32) -> Option<Vec<Channels>> {
+    let (l, r, c) = (
+        Channels::FRONT_LEFT,
+        Channels::FRONT_RIGHT,
+        Channels::FRONT_CENTRE,
+    );
+    let (lfe, ls, rs) = (Channels::LFE1, Channels::REAR_LEFT, Channels::REAR_RIGHT);
+    Some(match channel_type {
+        1 => vec![l],
+        2 => vec![l, r],
+        3 => vec![l, r, c],
+        4 => vec![l, r, ls, rs],
+        5 => vec![l, r, c, lfe],
+        6 => vec![l, r, c, ls, rs],
+        7 => vec![l, r, c, lfe, ls, rs],
+        _ => return None,
+    })
+}
+
+fn dff_position(id: &[u8; 4]) -> Option<Channels> {
+    match id {
+        b"SLFT" | b"MLFT" => Some(Channels::FRONT_LEFT),
+        b"SRGT" | b"MRGT" => Some(Channels::FRONT_RIGHT),
+        b"C   " => Some(Channels::FRONT_CENTRE),
+        b"LFE " => Some(Channels::LFE1),
+        b"LS  " => Some(Channels::REAR_LEFT),
+        b"RS  " => Some(Channels::REAR_RIGHT),
+        _ => None,
+    }
+}
+
+/// The speakers the file names, unless they're unknown, repeated or don't match the
+/// channel count, when the channels just take the first positions in order.
+fn positions_or_default(positions: Option<Vec<Channels>>, count: usize) -> Vec<Channels> {
+    match positions {
+        Some(positions)
+            if positions.len() == count
+                && positions
+                    .iter()
+                    .fold(Channels::empty(), |all, &position| all | position)
+                    .count()
+                    == count =>
+        {
+            positions
+        }
+        _ => (0..count)
+            .map(|i| Channels::from_bits_truncate(1 << i))
+            .collect(),
+    }
+}
+
+/// How many DSD samples make each PCM one, the smallest power of two from 32 that brings
+/// the rate down to 192kHz or under.
+fn decimation(rate: u32) -> u32 {
+    let mut ratio = 32;
+    while rate / ratio > MAX_PCM_RATE {
+        ratio *= 2;
+    }
+    ratio
+}
+
+/// What the library needs to know of a DSD file, which lofty can't read.
+pub struct DsdInfo {
+    pub channels: usize,
+    /// DSD samples per second
+    pub rate: u32,
+    /// DSD samples of each channel
+    pub samples: u64,
+    /// The ID3v2 tag DSF files end with, and DFF files may have a chunk of
+    pub id3: Option<Vec<u8>>,
+}
+
+pub fn read_info(path: &Path) -> Option<DsdInfo> {
+    let file = File::open(path).ok()?;
+    let mut source = MediaSourceStream::new(Box::new(file), Default::default());
+    let header = DsdHeader::read(&mut source).ok()?;
+    let id3 = header.id3.and_then(|(pos, len)| {
+        source.seek(SeekFrom::Start(pos)).ok()?;
+        let mut tag = vec![0u8; len.min(MAX_ID3_LEN) as usize];
+        source.read_buf_exact(&mut tag).ok()?;
+        Some(tag)
+    });
+    Some(DsdInfo {
+        channels: header.channels(),
+        rate: header.rate,
+        samples: header.samples,
+        id3,
+    })
+}
+
+pub struct DsdReader {
+    reader: MediaSourceStream,
+    tracks: Vec<Track>,
+    cues: Vec<Cue>,
+    metadata: MetadataLog,
+    header: DsdHeader,
+    /// Where each stored channel goes in a packet, which is in symphonia's channel order
+    order: Vec<usize>,
+    /// Bytes of each channel for every PCM frame
+    step: u64,
+    /// Bytes of each channel read so far
+    pos: u64,
+    block: Vec<u8>,
+}
+
+impl DsdReader {
+    /// Bytes of each channel in a packet, which seeking rounds down to a multiple of.
+    fn stride(&self) -> usize {
+        match self.header.storage {
+            Storage::Dsf { block, .. } => block,
+            Storage::Dff => BLOCK,
+        }
+    }
+}
+
+impl QueryDescriptor for DsdReader {
+    fn query() -> &'static [Descriptor] {
+        &[
+            support_format!(
+                "dsf",
+                "DSD Stream File",
+                &["dsf"],
+                &["audio/x-dsf", "audio/dsf"],
+                &[b"DSD "]
+            ),
+            support_format!(
+                "dff",
+                "DSD Interchange File Format",
+                &["dff"],
+                &["audio/x-dff", "audio/dff"],
+                &[b"FRM8"]
+            ),
+        ]
+    }
+
+    fn score(_context: &[u8]) -> u8 {
+        255
+    }
+}
+
+impl FormatReader for DsdReader {
+    fn try_new(mut source: MediaSourceStream, _options: &FormatOptions) -> Result<Self> {
+        let header = DsdHeader::read(&mut source)?;
+        let ratio = decimation(header.rate);
+        let rate = header.rate / ratio;
+        if rate < 8000 {
+            return unsupported_error("dsd: unsupported sample rate");
+        }
+        let step = ratio as u64 / 8;
+        if let Storage::Dsf { block, .. } = header.storage {
+            if block as u64 % step != 0 {
+                return unsupported_error("dsf: unsupported block size");
+            }
+        }
+
+        // Packets are planar, so the channels go in the order of their positions
+        let positions = &header.positions;
+        let order = positions
+            .iter()
+            .map(|position| {
+                positions
+                    .iter()
+                    .filter(|other| other.bits() < position.bits())
+                    .count()
+            })
+            .collect();
+        let channels = positions
+            .iter()
+            .fold(Channels::empty(), |all, &position| all | position);
+
+        let mut params = CodecParameters::new();
+        params
+            .for_codec(CODEC_TYPE_DSD)
+            .with_sample_rate(rate)
+            .with_time_base(TimeBase::new(1, rate))
+            .with_sample_format(SampleFormat::F32)
+            .with_channels(channels)
+            .with_n_frames(header.samples / ratio as u64)
+            .with_extra_data(header.rate.to_le_bytes().to_vec().into_boxed_slice());
+
+        let mut reader = DsdReader {
+            reader: source,
+            tracks: Vec::new(),
+            cues: Vec::new(),
+            metadata: Default::default(),
+            header,
+            order,
+            step,
+            pos: 0,
+            block: Vec::new(),
+        };
+        params.with_max_frames_per_packet(reader.stride() as u64 / step);
+        reader.tracks.push(Track::new(0, params));
+        Ok(reader)
+    }
+
+    fn next_packet(&mut self) -> Result<Packet> {
+        let total = self.header.samples.div_ceil(8);
+        if self.pos >= total {
+            return end_of_stream_error();
+        }
+        let channels = self.order.len();
+        let stride = self.stride();
+        let len = (total - self.pos).min(stride as u64) as usize;
+
+        // Both keep every channel the same length, so where we are is the same sum
+        let offset = self.header.data_start + self.pos * channels as u64;
+        if self.reader.seek_buffered(offset) != offset {
+            self.reader.seek(SeekFrom::Start(offset))?;
+        }
+        // The last DSF block is padded, though the padding of the last channel may be cut
+        let read_len = match self.header.storage {
+            Storage::Dsf { block, .. } => block * (channels - 1) + len,
+            Storage::Dff => len * channels,
+        };
+        self.block.resize(read_len, 0);
+        self.reader.read_buf_exact(&mut self.block)?;
+
+        let mut data = vec![0u8; len * channels];
+        match self.header.storage {
+            Storage::Dsf { block, lsb_first } => {
+                for (ch, &planar) in self.order.iter().enumerate() {
+                    let src = &self.block[ch * block..ch * block + len];
+                    let dst = &mut data[planar * len..(planar + 1) * len];
+                    for (dst, src) in dst.iter_mut().zip(src) {
+                        *dst = if lsb_first { src.reverse_bits() } else { *src };
+                    }
+                }
+            }
+            Storage::Dff => {
+                for (i, bytes) in self.block.chunks_exact(channels).enumerate() {
+                    for (byte, &planar) in bytes.iter().zip(&self.order) {
+                        data[planar * len + i] = *byte;
+                    }
+                }
+            }
+        }
+
+        let ts = self.pos / self.step;
+        self.pos += len as u64;
+        Ok(Packet::new_from_boxed_slice(
+            0,
+            ts,
+            len as u64 / self.step,
+            data.into_boxed_slice(),
+        ))
+    }
+
+    fn metadata(&mut self) -> Metadata<'_> {
+        self.metadata.metadata()
+    }
+
+    fn cues(&self) -> &[Cue] {
+        &self.cues
+    }
+
+    fn tracks(&self) -> &[Track] {
+        &self.tracks
+    }
+
+    fn seek(&mut self, _mode: SeekMode, to: SeekTo) -> Result<SeekedTo> {
+        if !self.reader.is_seekable() {
+            return seek_error(SeekErrorKind::Unseekable);
+        }
+        let params = &self.tracks[0].codec_params;
+        let ts = match to {
+            SeekTo::TimeStamp { ts, .. } => ts,
+            SeekTo::Time { time, .. } => {
+                TimeBase::new(1, params.sample_rate.unwrap()).calc_timestamp(time)
+            }
+        };
+        if params.n_frames.map_or(false, |n| ts > n) {
+            return seek_error(SeekErrorKind::OutOfRange);
+        }
+
+        let stride = self.stride() as u64;
+        self.pos = ts * self.step / stride * stride;
+        Ok(SeekedTo {
+            track_id: 0,
+            actual_ts: self.pos / self.step,
+            required_ts: ts,
+        })
+    }
+
+    fn into_inner(self: Box<Self>) -> MediaSourceStream {
+        self.reader
+    }
+}
+
+/// A windowed-sinc low-pass filter as a lookup table for each byte it spans, oldest
+/// first, of what each of the 256 bytes there adds to the output.
+fn filter_tables(rate: u32, ratio: usize) -> Vec<[f32; 256]> {
+    let taps = TAPS_PER_RATIO * ratio;
+    let cutoff = CUTOFF / rate as f64;
+    let centre = (taps - 1) as f64 / 2.0;
+    let mut h: Vec<f64> = (0..taps)
+        .map(|i| {
+            let x = i as f64 - centre;
+            let sinc = (2.0 * PI * cutoff * x).sin() / (PI * x);
+            let phase = 2.0 * PI * i as f64 / (taps - 1) as f64;
+            let blackman = 0.42 - 0.5 * phase.cos() + 0.08 * (2.0 * phase).cos();
+            sinc * blackman
+        })
+        .collect();
+    // Unity gain at DC, so a stream of all ones comes out at full scale
+    let sum: f64 = h.iter().sum();
+    h.iter_mut().for_each(|tap| *tap /= sum);
+
+    h.chunks_exact(8)
+        .map(|taps| {
+            let mut table = [0f32; 256];
+            for (byte, value) in table.iter_mut().enumerate() {
+                // The most significant bit is the earliest sample
+                *value = taps
+                    .iter()
+                    .enumerate()
+                    .map(|(i, tap)| if byte >> (7 - i) & 1 == 1 { *tap } else { -tap })
+                    .sum::<f64>() as f32;
+            }
+            table
+        })
+        .collect()
+}
+
+/// The last bytes of a channel, kept twice over so they're always in one slice.
+struct History {
+    bytes: Vec<u8>,
+    pos: usize,
+}
+
+impl History {
+    fn new(len: usize) -> Self {
+        History {
+            bytes: vec![SILENCE; len * 2],
+            pos: 0,
+        }
+    }
+
+    fn push(&mut self, byte: u8) {
+        let len = self.bytes.len() / 2;
+        self.bytes[self.pos] = byte;
+        self.bytes[self.pos + len] = byte;
+        self.pos = (self.pos + 1) % len;
+    }
+
+    /// The bytes, oldest first.
+    fn window(&self) -> &[u8] {
+        &self.bytes[self.pos..self.pos + self.bytes.len() / 2]
+    }
+}
+
+pub struct DsdDecoder {
+    params: CodecParameters,
+    tables: Vec<[f32; 256]>,
+    /// Bytes of each channel for every PCM frame
+    step: usize,
+    history: Vec<History>,
+    buf: AudioBuffer<f32>,
+}
+
+impl Decoder for DsdDecoder {
+    fn try_new(params: &CodecParameters, _options: &DecoderOptions) -> Result<Self> {
+        if params.codec != CODEC_TYPE_DSD {
+            return unsupported_error("dsd: invalid codec type");
+        }
+        let dsd_rate = match params.extra_data.as_deref() {
+            Some([r0, r1, r2, r3, ..]) => u32::from_le_bytes([*r0, *r1, *r2, *r3]),
+            _ => return decode_error("dsd: missing DSD sample rate"),
+        };
+        let (rate, layout) = match (params.sample_rate, params.channels) {
+            (Some(rate), Some(channels)) => (rate, channels),
+            _ => return decode_error("dsd: unknown sample rate or channels"),
+        };
+        let ratio = dsd_rate / rate.max(1);
+        if ratio < 8 || !ratio.is_power_of_two() {
+            return unsupported_error("dsd: unsupported decimation");
+        }
+        let tables = filter_tables(dsd_rate, ratio as usize);
+        let frames = params.max_frames_per_packet.unwrap_or(0);
+
+        Ok(DsdDecoder {
+            params: params.clone(),
+            step: ratio as usize / 8,
+            history: (0..layout.count())
+                .map(|_| History::new(tables.len()))
+                .collect(),
+            tables,
+            buf: AudioBuffer::new(frames, SignalSpec::new(rate, layout)),
+        })
+    }
+
+    fn supported_codecs() -> &'static [CodecDescriptor] {
+        &[support_codec!(
+            CODEC_TYPE_DSD,
+            "dsd",
+            "Direct Stream Digital"
+        )]
+    }
+
+    fn reset(&mut self) {
+        // Whatever came before a seek mustn't bleed into what comes after
+        for history in self.history.iter_mut() {
+            *history = History::new(self.tables.len());
+        }
+    }
+
+    fn codec_params(&self) -> &CodecParameters {
+        &self.params
+    }
+
+    fn decode(&mut self, packet: &Packet) -> Result<AudioBufferRef<'_>> {
+        self.buf.clear();
+
+        let data = packet.buf();
+        let len = data.len() / self.history.len();
+        let frames = len / self.step;
+        if frames > self.buf.capacity() {
+            return decode_error("dsd: packet too long");
+        }
+
+        self.buf.render_reserved(Some(frames));
+        for (ch, history) in self.history.iter_mut().enumerate() {
+            let bytes = &data[ch * len..ch * len + frames * self.step];
+            for (dst, chunk) in self
+                .buf
+                .chan_mut(ch)
+                .iter_mut()
+                .zip(bytes.chunks_exact(self.step))
+            {
+                for &byte in chunk {
+                    history.push(byte);
+                }
+                *dst = history
+                    .window()
+                    .iter()
+                    .zip(&self.tables)
+                    .map(|(&byte, table)| table[byte as usize])
+                    .sum();
+            }
+        }
+        Ok(self.buf.as_audio_buffer_ref())
+    }
+
+    fn finalize(&mut self) -> FinalizeResult {
+        Default::default()
+    }
+
+    fn last_decoded(&self) -> AudioBufferRef<'_> {
+        self.buf.as_audio_buffer_ref()
+    }
+}
@@ -0,0 +1,262 @@
+//! Parametric equalizer, applied in the output callback before the volume.
+//!
+//! Filter coefficients follow the Audio EQ Cookbook (R. Bristow-Johnson).
+
+use std::f64::consts::PI;
+
+use serde::{Deserialize, Serialize};
+
+use crate::dsp::{db_to_linear, Biquad};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum EqFilterType {
+    Peaking,
+    LowShelf,
+    HighShelf,
+    LowPass,
+    HighPass,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct EqBand {
+    pub filter_type: EqFilterType,
+    pub frequency: f64, // Hz
+    pub gain: f64,      // dB, ignored by the pass filters
+    pub q: f64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct EqualizerSettings {
+    pub enabled: bool,
+    pub preamp: f64, // dB
+    pub preset: Option<String>,
+    pub bands: Vec<EqBand>,
+}
+
+impl Default for EqualizerSettings {
+    fn default() -> Self {
+        EqualizerSettings {
+            enabled: false,
+            preamp: 0.0,
+            preset: Some(String::from("Flat")),
+            bands: graphic_bands(&[0.0; 10]),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct EqualizerPreset {
+    pub name: String,
+    pub preamp: f64,
+    pub bands: Vec<EqBand>,
+}
+
+/// Centre frequencies of the default 10 band layout
+const GRAPHIC_FREQUENCIES: [f64; 10] = [
+    31.0, 62.0, 125.0, 250.0, 500.0, 1000.0, 2000.0, 4000.0, 8000.0, 16000.0,
+];
+
+/// 10 bands an octave apart, with shelves at either end.
+fn graphic_bands(gains: &[f64; 10]) -> Vec<EqBand> {
+    GRAPHIC_FREQUENCIES
+        .iter()
+        .zip(gains.iter())
+        .enumerate()
+        .map(|(i, (frequency, gain))| EqBand {
+            filter_type: match i {
+                0 => EqFilterType::LowShelf,
+                9 => EqFilterType::HighShelf,
+                _ => EqFilterType::Peaking,
+            },
+            frequency: *frequency,
+            gain: *gain,
+            q: 1.41, // one octave
+        })
+        .collect()
+}
+
+pub fn presets() -> Vec<EqualizerPreset> {
+    let preset = |name: &str, preamp: f64, gains: [f64; 10]| EqualizerPreset {
+        name: String::from(name),
+        preamp,
+        bands: graphic_bands(&gains),
+    };
+    vec![
+        preset("Flat", 0.0, [0.0; 10]),
+        preset(
+            "Bass Boost",
+            -6.0,
+            [6.0, 5.0, 4.0, 2.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+        ),
+        preset(
+            "Treble Boost",
+            -6.0,
+            [0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 2.0, 4.0, 5.0, 6.0],
+        ),
+        preset(
+            "Vocal",
+            -3.0,
+            [-2.0, -2.0, -1.0, 1.0, 3.0, 3.0, 2.0, 1.0, 0.0, -1.0],
+        ),
+        preset(
+            "Loudness",
+            -5.0,
+            [5.0, 4.0, 2.0, 0.0, -1.0, 0.0, 0.0, 1.0, 3.0, 4.0],
+        ),
+        preset(
+            "Rock",
+            -4.0,
+            [4.0, 3.0, 2.0, 0.0, -1.0, -1.0, 1.0, 2.0, 3.0, 4.0],
+        ),
+        preset(
+            "Classical",
+            -3.0,
+            [3.0, 2.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 2.0, 3.0],
+        ),
+        preset(
+            "Electronic",
+            -5.0,
+            [5.0, 4.0, 1.0, 0.0, -2.0, 1.0, 0.0, 1.0, 4.0, 5.0],
+        ),
+    ]
+}
+
+/// Biquad coefficients (b, a) for a band at the given sample rate.
+fn band_coefficients(band: &EqBand, rate: u32) -> ([f64; 3], [f64; 3]) {
+    // Keep the frequency below Nyquist, and Q sane
+    let frequency = band.frequency.clamp(10.0, rate as f64 * 0.45);
+    let q = band.q.max(0.05);
+
+    let w0 = 2.0 * PI * frequency / rate as f64;
+    let (sin_w0, cos_w0) = w0.sin_cos();
+    let alpha = sin_w0 / (2.0 * q);
+    let a = 10f64.powf(band.gain / 40.0);
+
+    match band.filter_type {
+        EqFilterType::Peaking => (
+            [1.0 + alpha * a, -2.0 * cos_w0, 1.0 - alpha * a],
+            [1.0 + alpha / a, -2.0 * cos_w0, 1.0 - alpha / a],
+        ),
+        EqFilterType::LowShelf => {
+            let two_sqrt_a_alpha = 2.0 * a.sqrt() * alpha;
+            (
+                [
+                    a * ((a + 1.0) - (a - 1.0) * cos_w0 + two_sqrt_a_alpha),
+                    2.0 * a * ((a - 1.0) - (a + 1.0) * cos_w0),
+                    a * ((a + 1.0) - (a - 1.0) * cos_w0 - two_sqrt_a_alpha),
+                ],
+                [
+                    (a + 1.0) + (a - 1.0) * cos_w0 + two_sqrt_a_alpha,
+                    -2.0 * ((a - 1.0) + (a + 1.0) * cos_w0),
+                    (a + 1.0) + (a - 1.0) * cos_w0 - two_sqrt_a_alpha,
+                ],
+            )
+        }
+        EqFilterType::HighShelf => {
+            let two_sqrt_a_alpha = 2.0 * a.sqrt() * alpha;
+            (
+                [
+                    a * ((a + 1.0) + (a - 1.0) * cos_w0 + two_sqrt_a_alpha),
+                    -2.0 * a * ((a - 1.0) + (a + 1.0) * cos_w0),
+                    a * ((a + 1.0) + (a - 1.0) * cos_w0 - two_sqrt_a_alpha),
+                ],
+                [
+                    (a + 1.0) - (a - 1.0) * cos_w0 + two_sqrt_a_alpha,
+                    2.0 * ((a - 1.0) - (a + 1.0) * cos_w0),
+                    (a + 1.0) - (a - 1.0) * cos_w0 - two_sqrt_a_alpha,
+                ],
+            )
+        }
+        EqFilterType::LowPass => (
+            [(1.0 - cos_w0) / 2.0, 1.0 - cos_w0, (1.0 - cos_w0) / 2.0],
+            [1.0 + alpha, -2.0 * cos_w0, 1.0 - alpha],
+        ),
+        EqFilterType::HighPass => (
+            [(1.0 + cos_w0) / 2.0, -(1.0 + cos_w0), (1.0 + cos_w0) / 2.0],
+            [1.0 + alpha, -2.0 * cos_w0, 1.0 - alpha],
+        ),
+    }
+}
+
+/// A bank of biquads per channel, processing interleaved samples.
+pub struct Equalizer {
+    rate: u32,
+    channels: usize,
+    settings: EqualizerSettings,
+    preamp: f32,
+    /// filters[channel][band]
+    filters: Vec<Vec<Biquad>>,
+}
+
+impl Equalizer {
+    pub fn new(rate: u32, channels: usize, settings: EqualizerSettings) -> Self {
+        let mut equalizer = Equalizer {
+            rate,
+            channels,
+            settings: EqualizerSettings::default(),
+            preamp: 1.0,
+            filters: Vec::new(),
+        };
+        equalizer.update(settings);
+        equalizer
+    }
+
+    /// Applies new settings. If only gains/frequencies changed the filters keep their state,
+    /// so changes are heard without a gap or click.
+    pub fn update(&mut self, settings: EqualizerSettings) {
+        let coefficients: Vec<([f64; 3], [f64; 3])> = settings
+            .bands
+            .iter()
+            .map(|band| band_coefficients(band, self.rate))
+            .collect();
+
+        if self.filters.len() == self.channels
+            && self.filters.iter().all(|f| f.len() == coefficients.len())
+        {
+            for channel in self.filters.iter_mut() {
+                for (filter, (b, a)) in channel.iter_mut().zip(coefficients.iter()) {
+                    filter.set_coefficients(*b, *a);
+                }
+            }
+        } else {
+            self.filters = (0..self.channels)
+                .map(|_| {
+                    coefficients
+                        .iter()
+                        .map(|(b, a)| Biquad::new(*b, *a))
+                        .collect()
+                })
+                .collect();
+        }
+
+        self.preamp = db_to_linear(settings.preamp) as f32;
+        if !self.settings.enabled && settings.enabled {
+            self.filters.iter_mut().flatten().for_each(|f| f.reset());
+        }
+        self.settings = settings;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.settings.enabled
+    }
+
+    /// Filters interleaved samples in place.
+    pub fn process(&mut self, samples: &mut [f32]) {
+        if !self.settings.enabled || self.channels == 0 {
+            return;
+        }
+        for frame in samples.chunks_mut(self.channels) {
+            for (sample, filters) in frame.iter_mut().zip(self.filters.iter_mut()) {
+                let mut x = (*sample * self.preamp) as f64;
+                for filter in filters.iter_mut() {
+                    x = filter.process(x);
+                }
+                *sample = x as f32;
+            }
+        }
+    }
+}
@@ -0,0 +1,422 @@
+//! An output "device" that writes the processed samples to a WAV file instead of a sound
+//! card, for headless playback, tests and recording what you hear. It writes to anything
+//! seekable, so tests can keep what was played in memory.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{error, info};
+use symphonia::core::audio::{AudioBufferRef, SignalSpec};
+use tauri::{AppHandle, Emitter};
+use tokio::sync::Mutex;
+
+use crate::channel_mix::ChannelMixSettings;
+use crate::dither::DitherSettings;
+use crate::equalizer::{Equalizer, EqualizerSettings};
+use crate::output::{channels_for_count, AudioOutput, AudioOutputError, Result};
+use crate::output_stage::{equalize_and_set_volume, OutputStage};
+use crate::spectrum::SpectrumSettings;
+use crate::timestretch::TempoMap;
+use crate::{EqualizerControlEvent, SampleOffsetEvent, VolumeControlEvent};
+
+/// The name the file output is listed and selected by, next to the real devices.
+pub const FILE_OUTPUT_DEVICE: &str = "Write to file (WAV)";
+
+#[derive(Clone, Debug)]
+pub struct FileOutputOptions {
+    pub path: PathBuf,
+    /// Pace the output like a sound card would, otherwise write as fast as we can decode
+    pub realtime: bool,
+}
+
+/// Writes 32-bit float WAV, patching the sizes in the header when finished.
+pub struct WavWriter<W: Write + Seek> {
+    writer: W,
+    data_len: u32, // bytes
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut writer: W, sample_rate: u32, channels: usize) -> io::Result<Self> {
+        let block_align = channels as u16 * 4;
+        writer.write_all(b"RIFF")?;
+        writer.write_all(&36u32.to_le_bytes())?;
+        writer.write_all(b"WAVE")?;
+        writer.write_all(b"fmt ")?;
+        writer.write_all(&16u32.to_le_bytes())?;
+        writer.write_all(&3u16.to_le_bytes())?; // IEEE float
+        writer.write_all(&(channels as u16).to_le_bytes())?;
+        writer.write_all(&sample_rate.to_le_bytes())?;
+        writer.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        writer.write_all(&block_align.to_le_bytes())?;
+        writer.write_all(&32u16.to_le_bytes())?;
+        writer.write_all(b"data")?;
+        writer.write_all(&0u32.to_le_bytes())?;
+        Ok(WavWriter {
+            writer,
+            data_len: 0,
+        })
+    }
+
+    pub fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        for s in samples {
+            self.writer.write_all(&s.to_le_bytes())?;
+        }
+        // WAV can't describe more than 4 GiB, keep what fits in the header
+        self.data_len = self.data_len.saturating_add(samples.len() as u32 * 4);
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.seek(SeekFrom::Start(4))?;
+        self.writer
+            .write_all(&self.data_len.saturating_add(36).to_le_bytes())?;
+        self.writer.seek(SeekFrom::Start(40))?;
+        self.writer.write_all(&self.data_len.to_le_bytes())?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Creates `path`, or the first of "name (2).wav", "name (3).wav"... that doesn't exist
+/// yet, so reopening the output doesn't overwrite what it wrote before.
+pub fn create_unused(path: &Path) -> io::Result<(File, PathBuf)> {
+    let stem = path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("output");
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("wav");
+    let mut candidate = path.to_path_buf();
+    let mut n = 1;
+    loop {
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&candidate)
+        {
+            Ok(file) => return Ok((file, candidate)),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                n += 1;
+                candidate = path.with_file_name(format!("{} ({}).{}", stem, n, extension));
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// What the player sends the output, and where the output reports the position.
+pub struct OutputControls {
+    pub volume_control_receiver: Arc<Mutex<Receiver<VolumeControlEvent>>>,
+    pub equalizer_control_receiver: Arc<Mutex<Receiver<EqualizerControlEvent>>>,
+    pub sample_offset_receiver: Arc<Mutex<Receiver<SampleOffsetEvent>>>,
+    pub playback_state_receiver: Arc<Mutex<Receiver<bool>>>,
+    pub reset_control_receiver: Arc<Mutex<Receiver<bool>>>,
+    pub device_change_receiver: Arc<Mutex<Receiver<String>>>,
+    pub app_handle: AppHandle,
+}
+
+/// Writes what would be played straight to a WAV, at the track's rate and channels and in
+/// float so nothing is lost. There's no ring buffer: `write` returns once the samples are
+/// written, after waiting for them to be due if the output is paced.
+pub struct FileAudioOutput<W: Write + Seek> {
+    writer: Option<WavWriter<W>>,
+    sample_rate: u32,
+    channels: usize,
+    realtime: bool,
+    /// When the samples written so far would have finished playing
+    deadline: Option<Instant>,
+    stage: OutputStage,
+    volume: f64,
+    equalizer: Equalizer,
+    tempo_map: TempoMap,
+    output_buf: Vec<f32>,
+    position: Arc<RwLock<u64>>,
+    /// Whole seconds last reported
+    elapsed: u64,
+    /// None when nobody's listening, like in tests
+    controls: Option<OutputControls>,
+}
+
+impl FileAudioOutput<BufWriter<File>> {
+    pub fn try_open(
+        options: FileOutputOptions,
+        spec: SignalSpec,
+        vol: Option<f64>,
+        equalizer: EqualizerSettings,
+        controls: OutputControls,
+    ) -> Result<Arc<Mutex<dyn AudioOutput>>> {
+        let spec = SignalSpec::new(spec.rate, channels_for_count(spec.channels.count()));
+        let (file, path) = create_unused(&options.path).map_err(|err| {
+            error!("file output open error: {}", err);
+            AudioOutputError::OpenStreamError
+        })?;
+        info!("file output: writing to {:?}", path);
+        let output = FileAudioOutput::new(
+            BufWriter::new(file),
+            spec,
+            options.realtime,
+            vol.unwrap_or(1.0),
+            equalizer,
+            Some(controls),
+        )
+        .map_err(|err| {
+            error!("file output open error: {}", err);
+            AudioOutputError::OpenStreamError
+        })?;
+        Ok(Arc::new(Mutex::new(output)))
+    }
+}
+
+impl<W: Write + Seek> FileAudioOutput<W> {
+    pub fn new(
+        writer: W,
+        spec: SignalSpec,
+        realtime: bool,
+        volume: f64,
+        equalizer: EqualizerSettings,
+        controls: Option<OutputControls>,
+    ) -> io::Result<Self> {
+        let channels = spec.channels.count();
+        Ok(FileAudioOutput {
+            writer: Some(WavWriter::new(writer, spec.rate, channels)?),
+            sample_rate: spec.rate,
+            channels,
+            realtime,
+            deadline: None,
+            stage: OutputStage::new(spec, 4096),
+            volume,
+            equalizer: Equalizer::new(spec.rate, channels, equalizer),
+            tempo_map: TempoMap::default(),
+            output_buf: Vec::new(),
+            position: Arc::new(RwLock::new(0)),
+            elapsed: 0,
+            controls,
+        })
+    }
+
+    /// Patches the WAV header and hands back what it was written to.
+    pub fn finish(mut self) -> io::Result<W> {
+        match self.writer.take() {
+            Some(writer) => writer.finish(),
+            None => Err(io::Error::other("file output failed")),
+        }
+    }
+
+    /// Takes in what the player sent since the last write, as the sound card's callback
+    /// does.
+    fn receive_controls(&mut self) {
+        let Some(controls) = &self.controls else {
+            return;
+        };
+        if let Ok(Ok(true)) = controls
+            .reset_control_receiver
+            .try_lock()
+            .map(|r| r.try_recv())
+        {
+            *self.position.write().unwrap() = 0;
+            self.elapsed = 0;
+            let _ = controls.app_handle.emit("timestamp", Some(0f64));
+        }
+        if let Ok(Ok(event)) = controls
+            .volume_control_receiver
+            .try_lock()
+            .map(|r| r.try_recv())
+        {
+            self.volume = event.volume.unwrap_or(self.volume);
+        }
+        if let Ok(Ok(event)) = controls
+            .equalizer_control_receiver
+            .try_lock()
+            .map(|r| r.try_recv())
+        {
+            self.equalizer.update(event.settings);
+        }
+        // Writes happen on the decode thread, which already waits while paused, and there's
+        // no other device's callback to ignore
+        if let Ok(receiver) = controls.playback_state_receiver.try_lock() {
+            while receiver.try_recv().is_ok() {}
+        }
+        if let Ok(receiver) = controls.device_change_receiver.try_lock() {
+            while receiver.try_recv().is_ok() {}
+        }
+        if let Ok(Ok(event)) = controls
+            .sample_offset_receiver
+            .try_lock()
+            .map(|r| r.try_recv())
+        {
+            if let Some(sample_offset) = event.sample_offset {
+                *self.position.write().unwrap() = sample_offset;
+                // Report the exact position we seeked to
+                let seconds = self.seconds(sample_offset);
+                self.elapsed = seconds as u64;
+                let _ = controls.app_handle.emit("timestamp", Some(seconds));
+            }
+        }
+    }
+
+    fn seconds(&self, samples: u64) -> f64 {
+        samples as f64 / (self.sample_rate as f64 * self.channels as f64)
+    }
+
+    /// Applies the equalizer and volume to `output_buf`, stretched to `tempo`, and writes
+    /// it, then moves the position on and waits until the samples are due.
+    fn write_output(&mut self, tempo: f64) {
+        if self.output_buf.is_empty() {
+            return;
+        }
+        equalize_and_set_volume(&mut self.equalizer, self.volume, &mut self.output_buf);
+        if let Some(writer) = &mut self.writer {
+            if let Err(err) = writer.write_samples(&self.output_buf) {
+                error!("file output write error: {}", err);
+                self.writer = None;
+            }
+        }
+
+        let written = self.output_buf.len();
+        self.tempo_map.push(written, tempo);
+        let (position, jumped) = {
+            let mut position = self.position.write().unwrap();
+            let jumped = self.tempo_map.advance(written, &mut position);
+            (*position, jumped)
+        };
+        let seconds = self.seconds(position);
+        if jumped || seconds as u64 != self.elapsed {
+            self.elapsed = seconds as u64;
+            // The exact position after looping back, whole seconds otherwise
+            let reported = if jumped { seconds } else { self.elapsed as f64 };
+            if let Some(controls) = &self.controls {
+                let _ = controls.app_handle.emit("timestamp", Some(reported));
+            }
+        }
+
+        if self.realtime {
+            let now = Instant::now();
+            let deadline = self.deadline.get_or_insert(now);
+            *deadline +=
+                Duration::from_secs_f64((written / self.channels) as f64 / self.sample_rate as f64);
+            if *deadline > now {
+                thread::sleep(*deadline - now);
+            } else if now - *deadline > Duration::from_millis(100) {
+                // We were starved or paused, don't rush to catch up
+                *deadline = now;
+            }
+        }
+    }
+}
+
+impl<W: Write + Seek> Drop for FileAudioOutput<W> {
+    fn drop(&mut self) {
+        if let Some(writer) = self.writer.take() {
+            if let Err(err) = writer.finish() {
+                error!("file output finish error: {}", err);
+            }
+        }
+        info!("file output: closed");
+    }
+}
+
+impl<W: Write + Seek + Send> AudioOutput for FileAudioOutput<W> {
+    fn write(&mut self, decoded: AudioBufferRef<'_>, ramp_up_samples: u64, ramp_down_samples: u64) {
+        if decoded.frames() == 0 {
+            return;
+        }
+        self.receive_controls();
+
+        self.output_buf.clear();
+        let tempo = match self.stage.process(decoded, ramp_up_samples, ramp_down_samples) {
+            Some(processed) => {
+                self.output_buf.extend_from_slice(processed.samples);
+                processed.tempo
+            }
+            None => return,
+        };
+        self.write_output(tempo);
+    }
+
+    fn flush(&mut self) {
+        self.stage.flush();
+        self.tempo_map.clear();
+    }
+
+    fn get_sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn get_channels(&self) -> usize {
+        self.channels
+    }
+
+    /// Nothing to do, the decode thread stops writing while paused, and the pacing
+    /// doesn't catch up on the time paused.
+    fn pause(&self) {}
+
+    fn resume(&self) {}
+
+    fn update_resampler(&mut self, spec: SignalSpec, max_frames: u64) -> bool {
+        self.output_buf.clear();
+        let mut tempo = 1.0;
+        let output_buf = &mut self.output_buf;
+        let resampling = self.stage.update_resampler(spec, max_frames, |drained| {
+            output_buf.extend_from_slice(drained.samples);
+            tempo = drained.tempo;
+        });
+        self.write_output(tempo);
+        resampling
+    }
+
+    fn set_gain(&mut self, gain: f32) {
+        self.stage.set_gain(gain);
+    }
+
+    fn set_channel_mix(&mut self, settings: ChannelMixSettings) {
+        self.stage.set_channel_mix(settings);
+    }
+
+    /// The file is float, there's nothing to dither.
+    fn set_dither(&mut self, _source_bits: u32, _settings: DitherSettings) {}
+
+    /// Nothing is heard, so there's nothing to show.
+    fn set_spectrum(&mut self, _settings: SpectrumSettings) {}
+
+    fn set_meters(&mut self, _enabled: bool) {}
+
+    fn set_playback_rate(&mut self, tempo: f64, semitones: f64) {
+        self.stage.set_playback_rate(tempo, semitones);
+    }
+
+    /// Position in the track (source time) of what was written last, in seconds.
+    fn get_position(&self) -> f64 {
+        self.seconds(*self.position.read().unwrap())
+    }
+
+    /// The samples written from now on start at `sample_offset` in the track.
+    fn mark_position(&mut self, sample_offset: u64) {
+        self.tempo_map.mark_position(sample_offset);
+    }
+
+    fn get_position_handle(&self) -> Arc<RwLock<u64>> {
+        self.position.clone()
+    }
+
+    fn get_sample_format(&self) -> String {
+        String::from("f32")
+    }
+
+    /// Everything is written as it comes.
+    fn has_remaining_samples(&self) -> bool {
+        false
+    }
+
+    fn ramp_down(&mut self, buffer: AudioBufferRef, num_samples: usize) {
+        self.stage.ramp_down(buffer, num_samples);
+    }
+
+    fn ramp_up(&mut self, buffer: AudioBufferRef, num_samples: usize) {
+        self.stage.ramp_up(buffer, num_samples);
+    }
+}
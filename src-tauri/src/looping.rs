@@ -0,0 +1,144 @@
+use log::info;
+use symphonia::core::audio::{AudioBuffer, AudioBufferRef, Signal};
+
+use crate::crossfade::CrossfadeCurve;
+
+#[derive(Debug, PartialEq)]
+pub enum LoopAction {
+    Continue,
+    /// The end of the region was reached, seek back to the start
+    Wrap,
+    /// The last pass went past the end, carry on playing normally
+    Finished,
+}
+
+/// An A-B loop, wrapped in the decode loop without reopening the file.
+///
+/// With a seam crossfade, the last frames before the end are held back and
+/// mixed into the first frames after the start.
+pub struct RegionLoop {
+    start_ts: u64,
+    end_ts: u64,
+    seam: usize,
+    /// Total number of passes, None to loop until told otherwise
+    count: Option<u32>,
+    passes: u32,
+    /// Frames before the end, held back for the next seam (planar)
+    held: Vec<Vec<f32>>,
+    /// Frames from the last seam still being mixed in
+    mixing: Vec<Vec<f32>>,
+    mixed: usize,
+}
+
+impl RegionLoop {
+    pub fn new(start_ts: u64, end_ts: u64, seam: usize, count: Option<u32>) -> Self {
+        // The seam can't be longer than half the loop
+        let seam = seam.min((end_ts.saturating_sub(start_ts) / 2) as usize);
+        info!(
+            "loop: {} to {}, seam of {} frames, {:?} passes",
+            start_ts, end_ts, seam, count
+        );
+        RegionLoop {
+            start_ts,
+            end_ts,
+            seam,
+            count: count.filter(|c| *c > 0),
+            passes: 1,
+            held: Vec::new(),
+            mixing: Vec::new(),
+            mixed: 0,
+        }
+    }
+
+    /// Where each pass after the first starts.
+    pub fn start_ts(&self) -> u64 {
+        self.start_ts
+    }
+
+    fn is_last_pass(&self) -> bool {
+        self.count.map_or(false, |count| self.passes >= count)
+    }
+
+    /// Cuts the decoded frames (starting at `ts`, after dropping the first `skip`) at the end
+    /// of the region and applies the seam crossfade. Returns the frames to write.
+    pub fn process(
+        &mut self,
+        decoded: &AudioBufferRef<'_>,
+        ts: u64,
+        skip: usize,
+    ) -> (AudioBuffer<f32>, LoopAction) {
+        let mut buffer = decoded.make_equivalent::<f32>();
+        decoded.convert(&mut buffer);
+        buffer.trim(skip.min(buffer.frames()), 0);
+        let ts = ts + skip as u64;
+        let channels = buffer.spec().channels.count();
+
+        if self.mixed < self.seam && !self.mixing.is_empty() {
+            self.mix_seam(&mut buffer);
+        }
+
+        let end_of_buffer = ts + buffer.frames() as u64;
+        if self.is_last_pass() {
+            let action = if end_of_buffer >= self.end_ts {
+                info!("loop: finished after {} passes", self.passes);
+                LoopAction::Finished
+            } else {
+                LoopAction::Continue
+            };
+            return (buffer, action);
+        }
+
+        // Cut at the end of the region
+        if end_of_buffer > self.end_ts {
+            let excess = (end_of_buffer - self.end_ts).min(buffer.frames() as u64) as usize;
+            buffer.trim(0, excess);
+        }
+
+        // Hold back the frames that overlap the start of the next pass
+        let hold_from = self.end_ts - self.seam as u64;
+        let end_of_buffer = ts + buffer.frames() as u64;
+        if self.seam > 0 && end_of_buffer > hold_from {
+            let hold = (end_of_buffer - hold_from.max(ts)) as usize;
+            let keep = buffer.frames() - hold;
+            self.held.resize(channels, Vec::new());
+            for (ch, held) in self.held.iter_mut().enumerate() {
+                held.extend_from_slice(&buffer.chan(ch)[keep..]);
+            }
+            buffer.trim(0, hold);
+        }
+
+        let action = if end_of_buffer >= self.end_ts {
+            LoopAction::Wrap
+        } else {
+            LoopAction::Continue
+        };
+        (buffer, action)
+    }
+
+    /// Called after seeking back to the start.
+    pub fn wrap(&mut self) {
+        self.passes += 1;
+        self.mixing = std::mem::take(&mut self.held);
+        self.mixed = 0;
+    }
+
+    fn mix_seam(&mut self, buffer: &mut AudioBuffer<f32>) {
+        let held = self.mixing.first().map_or(0, |ch| ch.len());
+        let frames = buffer.frames().min(held - self.mixed.min(held));
+        for ch in 0..buffer.spec().channels.count().min(self.mixing.len()) {
+            let tail = &self.mixing[ch];
+            for (i, sample) in buffer.chan_mut(ch)[..frames].iter_mut().enumerate() {
+                let position = self.mixed + i;
+                // Linear, as both sides are often the same material
+                let (out_gain, in_gain) =
+                    CrossfadeCurve::Linear.gains((position as f32 + 0.5) / held as f32);
+                *sample = *sample * in_gain + tail[position] * out_gain;
+            }
+        }
+        self.mixed += frames;
+        if self.mixed >= held {
+            self.mixing.clear();
+            self.mixed = self.seam;
+        }
+    }
+}
@@ -0,0 +1,528 @@
+//! Loudness analysis (EBU R128 / ReplayGain 2.0)
+//!
+//! Integrated loudness and loudness range follow ITU-R BS.1770-4 and EBU Tech 3342,
+//! true peak is measured with 4x oversampling.
+
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use log::{info, warn};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use symphonia::core::audio::{AudioBufferRef, Signal};
+use tauri::{AppHandle, Emitter, State};
+use tokio_util::sync::CancellationToken;
+
+use crate::dsp::Biquad;
+use crate::metadata::{self, ReplayGain, WriteMetatadaEvent};
+use crate::player::{self, AudioStreamer};
+
+/// ReplayGain 2.0 reference level
+const REFERENCE_LOUDNESS: f64 = -18.0;
+const ABSOLUTE_GATE: f64 = -70.0;
+const TRUE_PEAK_OVERSAMPLING: usize = 4;
+const TRUE_PEAK_TAPS_PER_PHASE: usize = 12;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AnalyseLoudnessRequest {
+    paths: Vec<String>,
+    write_tags: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct LoudnessResult {
+    integrated_loudness: Option<f64>, // LUFS, None for silence
+    loudness_range: Option<f64>,      // LU
+    sample_peak: f64,                 // linear
+    true_peak: f64,                   // linear
+    replay_gain: Option<f64>,         // dB
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TrackLoudness {
+    path: String,
+    loudness: LoudnessResult,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AlbumLoudness {
+    paths: Vec<String>,
+    loudness: LoudnessResult,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct LoudnessAnalysisEvent {
+    track: Option<TrackLoudness>,
+    album: Option<AlbumLoudness>,
+    progress: u8,
+    done: bool,
+    error: Option<String>,
+}
+
+/// K-weighting filter (BS.1770 pre-filter and RLB high-pass) for a sample rate.
+fn k_weighting(rate: u32) -> [Biquad; 2] {
+    let rate = rate as f64;
+
+    let f0 = 1681.974450955533;
+    let gain = 3.999843853973347;
+    let q = 0.7071752369554196;
+    let k = (PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let pre_filter = Biquad::new(
+        [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    let f0 = 38.13547087602444;
+    let q = 0.5003270373238773;
+    let k = (PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad::new(
+        [1.0, -2.0, 1.0],
+        [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    [pre_filter, high_pass]
+}
+
+/// BS.1770 channel weights, assuming the usual channel order (L, R, C, LFE, Ls, Rs).
+fn channel_weight(channel: usize, num_channels: usize) -> f64 {
+    match (num_channels, channel) {
+        (6, 3) | (8, 3) => 0.0,
+        (5, 3) | (5, 4) | (6, 4) | (6, 5) | (8, 4..=7) => 1.41,
+        _ => 1.0,
+    }
+}
+
+fn energy_to_loudness(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+/// Lowpass for the 4x true peak interpolator (windowed sinc), laid out by phase.
+fn true_peak_filter() -> Vec<Vec<f64>> {
+    let length = TRUE_PEAK_OVERSAMPLING * TRUE_PEAK_TAPS_PER_PHASE;
+    let centre = (length - 1) as f64 / 2.0;
+    let mut phases = vec![Vec::with_capacity(TRUE_PEAK_TAPS_PER_PHASE); TRUE_PEAK_OVERSAMPLING];
+    for i in 0..length {
+        let x = (i as f64 - centre) / TRUE_PEAK_OVERSAMPLING as f64;
+        let sinc = if x == 0.0 {
+            1.0
+        } else {
+            (PI * x).sin() / (PI * x)
+        };
+        let window = 0.5 - 0.5 * (2.0 * PI * (i as f64 + 0.5) / length as f64).cos();
+        phases[i % TRUE_PEAK_OVERSAMPLING].push(sinc * window);
+    }
+    phases
+}
+
+pub struct LoudnessMeter {
+    num_channels: usize,
+    filters: Vec<[Biquad; 2]>,
+    segment_len: usize,
+    segment_pos: usize,
+    segment_sums: Vec<f64>,
+    /// Weighted mean square of each 100ms segment
+    segments: Vec<f64>,
+    /// Energies of the 400ms momentary blocks (75% overlap)
+    blocks: Vec<f64>,
+    /// Energies of the 3s short-term blocks, for the loudness range
+    short_term_blocks: Vec<f64>,
+    true_peak_filter: Vec<Vec<f64>>,
+    true_peak_history: Vec<Vec<f64>>,
+    sample_peak: f64,
+    true_peak: f64,
+}
+
+impl LoudnessMeter {
+    pub fn new(rate: u32, num_channels: usize) -> Self {
+        LoudnessMeter {
+            num_channels,
+            filters: (0..num_channels).map(|_| k_weighting(rate)).collect(),
+            segment_len: (rate / 10).max(1) as usize,
+            segment_pos: 0,
+            segment_sums: vec![0.0; num_channels],
+            segments: Vec::new(),
+            blocks: Vec::new(),
+            short_term_blocks: Vec::new(),
+            true_peak_filter: true_peak_filter(),
+            true_peak_history: vec![vec![0.0; TRUE_PEAK_TAPS_PER_PHASE]; num_channels],
+            sample_peak: 0.0,
+            true_peak: 0.0,
+        }
+    }
+
+    /// Measures planar samples, one slice per channel.
+    pub fn process(&mut self, channels: &[&[f32]]) {
+        let frames = channels.iter().map(|c| c.len()).min().unwrap_or(0);
+        for i in 0..frames {
+            for (ch, channel) in channels.iter().enumerate().take(self.num_channels) {
+                let sample = channel[i] as f64;
+
+                self.sample_peak = self.sample_peak.max(sample.abs());
+                self.measure_true_peak(ch, sample);
+
+                let [pre_filter, high_pass] = &mut self.filters[ch];
+                let weighted = high_pass.process(pre_filter.process(sample));
+                self.segment_sums[ch] += weighted * weighted;
+            }
+
+            self.segment_pos += 1;
+            if self.segment_pos == self.segment_len {
+                self.end_segment();
+            }
+        }
+    }
+
+    pub fn process_buffer(&mut self, decoded: &AudioBufferRef<'_>) {
+        let mut buffer = decoded.make_equivalent::<f32>();
+        decoded.convert(&mut buffer);
+        let channels: Vec<&[f32]> = (0..buffer.spec().channels.count())
+            .map(|ch| buffer.chan(ch))
+            .collect();
+        self.process(&channels);
+    }
+
+    fn measure_true_peak(&mut self, ch: usize, sample: f64) {
+        let history = &mut self.true_peak_history[ch];
+        history.rotate_right(1);
+        history[0] = sample;
+        for phase in self.true_peak_filter.iter() {
+            let interpolated: f64 = phase.iter().zip(history.iter()).map(|(h, x)| h * x).sum();
+            self.true_peak = self.true_peak.max(interpolated.abs());
+        }
+    }
+
+    fn end_segment(&mut self) {
+        let energy: f64 = self
+            .segment_sums
+            .iter()
+            .enumerate()
+            .map(|(ch, sum)| channel_weight(ch, self.num_channels) * sum / self.segment_len as f64)
+            .sum();
+        self.segments.push(energy);
+        self.segment_sums.iter_mut().for_each(|s| *s = 0.0);
+        self.segment_pos = 0;
+
+        let count = self.segments.len();
+        if count >= 4 {
+            self.blocks
+                .push(self.segments[count - 4..].iter().sum::<f64>() / 4.0);
+        }
+        if count >= 30 {
+            self.short_term_blocks
+                .push(self.segments[count - 30..].iter().sum::<f64>() / 30.0);
+        }
+    }
+
+    /// Combines the measurements of an album's tracks, as if they were played back to back.
+    pub fn album<'a>(tracks: impl Iterator<Item = &'a LoudnessMeter>) -> Self {
+        let mut album = LoudnessMeter::new(0, 0);
+        tracks.for_each(|track| album.merge(track));
+        album
+    }
+
+    fn merge(&mut self, other: &LoudnessMeter) {
+        self.blocks.extend_from_slice(&other.blocks);
+        self.short_term_blocks
+            .extend_from_slice(&other.short_term_blocks);
+        self.sample_peak = self.sample_peak.max(other.sample_peak);
+        self.true_peak = self.true_peak.max(other.true_peak);
+    }
+
+    /// Gated integrated loudness in LUFS.
+    pub fn integrated_loudness(&self) -> Option<f64> {
+        let above_absolute: Vec<f64> = self
+            .blocks
+            .iter()
+            .cloned()
+            .filter(|e| energy_to_loudness(*e) > ABSOLUTE_GATE)
+            .collect();
+        if above_absolute.is_empty() {
+            return None;
+        }
+        let relative_gate = energy_to_loudness(
+            above_absolute.iter().sum::<f64>() / above_absolute.len() as f64,
+        ) - 10.0;
+        let gated: Vec<f64> = above_absolute
+            .into_iter()
+            .filter(|e| energy_to_loudness(*e) > relative_gate)
+            .collect();
+        if gated.is_empty() {
+            return None;
+        }
+        Some(energy_to_loudness(
+            gated.iter().sum::<f64>() / gated.len() as f64,
+        ))
+    }
+
+    /// Loudness range in LU.
+    pub fn loudness_range(&self) -> Option<f64> {
+        let above_absolute: Vec<f64> = self
+            .short_term_blocks
+            .iter()
+            .cloned()
+            .filter(|e| energy_to_loudness(*e) > ABSOLUTE_GATE)
+            .collect();
+        if above_absolute.is_empty() {
+            return None;
+        }
+        let relative_gate = energy_to_loudness(
+            above_absolute.iter().sum::<f64>() / above_absolute.len() as f64,
+        ) - 20.0;
+        let mut gated: Vec<f64> = above_absolute
+            .into_iter()
+            .map(energy_to_loudness)
+            .filter(|l| *l > relative_gate)
+            .collect();
+        if gated.is_empty() {
+            return None;
+        }
+        gated.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let percentile = |p: f64| gated[((gated.len() - 1) as f64 * p).round() as usize];
+        Some(percentile(0.95) - percentile(0.10))
+    }
+
+    pub fn result(&self) -> LoudnessResult {
+        let integrated_loudness = self.integrated_loudness();
+        LoudnessResult {
+            integrated_loudness,
+            loudness_range: self.loudness_range(),
+            sample_peak: self.sample_peak,
+            true_peak: self.true_peak.max(self.sample_peak),
+            replay_gain: integrated_loudness.map(|l| REFERENCE_LOUDNESS - l),
+        }
+    }
+}
+
+#[tauri::command]
+pub fn analyse_loudness(
+    event: AnalyseLoudnessRequest,
+    state: State<AudioStreamer>,
+    app_handle: tauri::AppHandle,
+) {
+    info!("Analyse loudness of {} files", event.paths.len());
+
+    // Only one analysis runs at a time, a new one cancels the previous
+    let token = CancellationToken::new();
+    let token_clone = token.clone();
+    let mut current = state
+        .loudness_cancel_token
+        .lock()
+        .unwrap_or_else(|err| err.into_inner());
+    if let Some(previous) = current.replace(token) {
+        previous.cancel();
+    }
+    drop(current);
+
+    std::thread::spawn(move || {
+        analyse_paths(event, &app_handle, token_clone);
+    });
+}
+
+#[tauri::command]
+pub fn cancel_loudness_analysis(state: State<AudioStreamer>) {
+    info!("Cancel loudness analysis");
+    let token = state
+        .loudness_cancel_token
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .take();
+    if let Some(token) = token {
+        token.cancel();
+    }
+}
+
+fn analyse_paths(event: AnalyseLoudnessRequest, app_handle: &AppHandle, token: CancellationToken) {
+    // Group the tracks into albums, tracks without an album tag are on their own
+    let mut albums: HashMap<String, Vec<String>> = HashMap::new();
+    let mut singles: Vec<String> = Vec::new();
+    for path in event.paths.iter() {
+        match metadata::album_key(Path::new(path)) {
+            Some(key) => albums.entry(key).or_default().push(path.clone()),
+            None => singles.push(path.clone()),
+        }
+    }
+
+    let total = event.paths.len();
+    let analysed = AtomicUsize::new(0);
+
+    let progress = |analysed: &AtomicUsize| -> u8 {
+        let count = analysed.fetch_add(1, Ordering::Relaxed) + 1;
+        ((count as f64 / total.max(1) as f64) * 100.0).min(99.0) as u8
+    };
+
+    let analyse_track = |path: &String| -> Option<(String, LoudnessMeter)> {
+        if token.is_cancelled() {
+            return None;
+        }
+        let result = analyse_file(Path::new(path), &token);
+        match result {
+            Ok(meter) => {
+                let _ = app_handle.emit(
+                    "loudness_analysis",
+                    LoudnessAnalysisEvent {
+                        track: Some(TrackLoudness {
+                            path: path.clone(),
+                            loudness: meter.result(),
+                        }),
+                        album: None,
+                        progress: progress(&analysed),
+                        done: false,
+                        error: None,
+                    },
+                );
+                Some((path.clone(), meter))
+            }
+            Err(err) => {
+                warn!("Loudness analysis failed for {}: {}", path, err);
+                let _ = app_handle.emit(
+                    "loudness_analysis",
+                    LoudnessAnalysisEvent {
+                        track: None,
+                        album: None,
+                        progress: progress(&analysed),
+                        done: false,
+                        error: Some(format!("{}: {}", path, err)),
+                    },
+                );
+                None
+            }
+        }
+    };
+
+    albums.par_iter().for_each(|(_, paths)| {
+        let meters: Vec<(String, LoudnessMeter)> =
+            paths.par_iter().filter_map(&analyse_track).collect();
+        if token.is_cancelled() || meters.is_empty() {
+            return;
+        }
+
+        let album = LoudnessMeter::album(meters.iter().map(|(_, m)| m)).result();
+
+        if event.write_tags {
+            for (path, meter) in meters.iter() {
+                write_tags(path, &meter.result(), Some(&album));
+            }
+        }
+
+        let _ = app_handle.emit(
+            "loudness_analysis",
+            LoudnessAnalysisEvent {
+                track: None,
+                album: Some(AlbumLoudness {
+                    paths: meters.iter().map(|(p, _)| p.clone()).collect(),
+                    loudness: album,
+                }),
+                progress: ((analysed.load(Ordering::Relaxed) as f64 / total.max(1) as f64)
+                    * 100.0)
+                    .min(99.0) as u8,
+                done: false,
+                error: None,
+            },
+        );
+    });
+
+    singles.par_iter().for_each(|path| {
+        if let Some((path, meter)) = analyse_track(path) {
+            if event.write_tags && !token.is_cancelled() {
+                write_tags(&path, &meter.result(), None);
+            }
+        }
+    });
+
+    let cancelled = token.is_cancelled();
+    info!("Loudness analysis finished (cancelled: {})", cancelled);
+    let _ = app_handle.emit(
+        "loudness_analysis",
+        LoudnessAnalysisEvent {
+            track: None,
+            album: None,
+            progress: 100,
+            done: true,
+            error: if cancelled {
+                Some(String::from("cancelled"))
+            } else {
+                None
+            },
+        },
+    );
+}
+
+fn write_tags(path: &str, track: &LoudnessResult, album: Option<&LoudnessResult>) {
+    if track.replay_gain.is_none() {
+        return;
+    }
+    let replay_gain = ReplayGain {
+        track_gain: track.replay_gain,
+        track_peak: Some(track.true_peak),
+        album_gain: album.and_then(|a| a.replay_gain),
+        album_peak: album.map(|a| a.true_peak),
+    };
+    let event = WriteMetatadaEvent::replay_gain(path, replay_gain);
+    if let Err(err) = metadata::write_metadata_track(&event) {
+        warn!("Couldn't write ReplayGain tags to {}: {}", path, err);
+    }
+}
+
+/// Decodes the whole file through a loudness meter.
+pub fn analyse_file(
+    path: &Path,
+    cancel_token: &CancellationToken,
+) -> Result<LoudnessMeter, symphonia::core::errors::Error> {
+    let (mut reader, mut decoder, track_id) = player::open_track(path)?;
+
+    let mut meter: Option<LoudnessMeter> = None;
+
+    let err = loop {
+        if cancel_token.is_cancelled() {
+            break symphonia::core::errors::Error::LimitError("cancelled");
+        }
+
+        let packet = match reader.next_packet() {
+            Ok(packet) => packet,
+            Err(err) => break err,
+        };
+
+        // If the packet does not belong to the selected track, skip over it.
+        if packet.track_id() != track_id {
+            continue;
+        }
+
+        match decoder.decode(&packet) {
+            Ok(decoded) => {
+                let spec = *decoded.spec();
+                meter
+                    .get_or_insert_with(|| LoudnessMeter::new(spec.rate, spec.channels.count()))
+                    .process_buffer(&decoded);
+            }
+            Err(symphonia::core::errors::Error::DecodeError(err)) => {
+                info!("decode error: {}", err)
+            }
+            Err(err) => break err,
+        }
+    };
+
+    match err {
+        symphonia::core::errors::Error::IoError(err)
+            if err.kind() == std::io::ErrorKind::UnexpectedEof
+                && err.to_string() == "end of stream" =>
+        {
+            meter.ok_or(symphonia::core::errors::Error::DecodeError("no audio"))
+        }
+        err => Err(err),
+    }
+}
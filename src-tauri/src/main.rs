@@ -4,7 +4,7 @@
 )]
 
 use futures_util::StreamExt;
-use log::info;
+use log::{info, warn};
 use metadata::FileInfo;
 use player::AudioStreamer;
 use reqwest;
@@ -12,7 +12,7 @@ use reqwest::Client;
 use scraper::{Html, Selector};
 use serde::{Deserialize, Serialize};
 use std::error::Error;
-use std::sync::Mutex;
+use std::sync::{Mutex, OnceLock};
 use std::{env, fs};
 use std::{io::Write, path::Path};
 use tauri::menu::{
@@ -24,13 +24,38 @@ use tempfile::Builder;
 use tokio_util::sync::CancellationToken;
 use window_vibrancy::{apply_vibrancy, NSVisualEffectMaterial};
 
+mod ape;
+mod channel_mix;
+mod crossfade;
+mod cue;
+mod dither;
+mod dsd;
 mod dsp;
+mod equalizer;
+mod file_output;
+mod looping;
+mod loudness;
 mod metadata;
+mod meter;
+mod opus;
 mod output;
+mod output_stage;
+mod playback_error;
 mod player;
+mod player_state;
+mod playlist_file;
+mod podcast;
+mod queue;
+mod radio;
+mod registry;
 mod resampler;
 mod scrape;
+mod spectrum;
 mod store;
+mod timestretch;
+mod waveform_cache;
+mod wavpack;
+mod xml;
 
 #[cfg(test)]
 mod tests;
@@ -131,6 +156,7 @@ pub struct StreamFileRequest {
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct GetWaveformRequest {
     path: Option<String>,
+    resolution: Option<usize>, // how many of the finest peaks to merge into each, 1 if not given
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -152,25 +178,25 @@ fn stream_file(
     state.resume();
 }
 
-#[tauri::command]
-fn queue_next(
-    event: StreamFileRequest,
-    state: State<AudioStreamer>,
-    _app_handle: tauri::AppHandle,
-) {
-    info!("Queue next file {:?}", event);
-    // If we receive a null path - the queue will be cleared
-    let _ = state.next_track_sender.send(event);
-}
-
 #[tauri::command]
 fn get_waveform(
     event: GetWaveformRequest,
     state: State<AudioStreamer>,
     _app_handle: tauri::AppHandle,
-) -> () {
+) -> Option<GetWaveformResponse> {
     info!("Get waveform {:?}", event);
 
+    // Known files don't need decoding again
+    let path = event.path.clone()?;
+    let resolution = event.resolution.unwrap_or(1);
+    let cache = waveform_cache::WaveformCache::open(&_app_handle);
+    if let Some(waveform) = cache.as_ref().and_then(|cache| cache.get(&path)) {
+        info!("Waveform cached for {}", path);
+        return Some(GetWaveformResponse {
+            data: Some(waveform.peaks(resolution)),
+        });
+    }
+
     let evt = event.clone();
     let token = CancellationToken::new();
     let token_clone = token.clone();
@@ -186,10 +212,24 @@ fn get_waveform(
     }
 
     std::thread::spawn(move || {
-        // Handle client's offer
-        let _ = player::get_peaks(evt, &_app_handle, token_clone);
-        // info!("Waveform: {:?}", result);
+        let emit = |data: Vec<f32>| {
+            let _ = _app_handle.emit(
+                "waveform",
+                GetWaveformResponse {
+                    data: Some(waveform_cache::downsample(&data, resolution)),
+                },
+            );
+        };
+        if let Ok(peaks) = player::get_peaks(evt, token_clone, emit) {
+            // The last peaks weren't in any progress event
+            emit(peaks.clone());
+            let waveform = waveform_cache::Waveform::from_peaks(&peaks);
+            if let Some(Err(err)) = cache.map(|cache| cache.put(&path, &waveform)) {
+                warn!("Couldn't cache the waveform of {}: {}", path, err);
+            }
+        }
     });
+    None
 }
 
 #[derive(Clone, Debug)]
@@ -197,6 +237,24 @@ pub struct SampleOffsetEvent {
     pub sample_offset: Option<u64>,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct EqualizerControlEvent {
+    pub settings: equalizer::EqualizerSettings,
+}
+
+#[tauri::command]
+fn equalizer_control(event: EqualizerControlEvent, state: State<AudioStreamer>) {
+    info!("Received equalizer_control event");
+    if let Err(_err) = state.equalizer_control_sender.send(event) {
+        info!("Error sending equalizer control info (channel inactive");
+    }
+}
+
+#[tauri::command]
+fn get_equalizer_presets() -> Vec<equalizer::EqualizerPreset> {
+    equalizer::presets()
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct VolumeControlEvent {
     volume: Option<f64>, // 0 to 1
@@ -205,6 +263,9 @@ pub struct VolumeControlEvent {
 #[tauri::command]
 fn volume_control(event: VolumeControlEvent, state: State<AudioStreamer>) {
     info!("Received volume_control event");
+    state
+        .player_state
+        .update(|player_state| player_state.volume = event.volume);
     match state.volume_control_sender.send(event) {
         Ok(_) => {
             // info!("Sent control flow info");
@@ -262,18 +323,56 @@ async fn download_file(
     path: String,
     _app_handle: tauri::AppHandle,
 ) -> Result<(), String> {
-    let client = Client::new();
+    download(&url, Path::new(&path), |progress| {
+        // Emit progress to the frontend
+        let _ = _app_handle.emit("download-progress", progress);
+    })
+    .await
+}
 
-    // Start the request
-    let response = client.get(&url).send().await.map_err(|e| e.to_string())?;
-    let total_size = response
-        .content_length()
-        .ok_or("Failed to get content length")?;
+/// How long a server gets to accept a connection before the request fails
+const CONNECT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// The app's HTTP client, shared so connections are pooled. Clones are cheap.
+pub(crate) fn http_client() -> Client {
+    static CLIENT: OnceLock<Client> = OnceLock::new();
+    CLIENT
+        .get_or_init(|| {
+            Client::builder()
+                .connect_timeout(CONNECT_TIMEOUT)
+                .build()
+                .unwrap_or_default()
+        })
+        .clone()
+}
+
+/// Downloads `url` to `path`, calling `on_progress` with the percentage done if the server
+/// says how much there is. The file only appears at `path` once it's complete.
+pub(crate) async fn download(
+    url: &str,
+    path: &Path,
+    on_progress: impl Fn(f64),
+) -> Result<(), String> {
+    let client = http_client();
 
-    // Create a temporary file
+    // Start the request
+    let response = client
+        .get(url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| e.to_string())?;
+    let total_size = response.content_length();
+
+    // Create a temporary file next to the destination, so it can be moved there
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    fs::create_dir_all(dir).map_err(|e| e.to_string())?;
     let mut temp_file = Builder::new()
         .prefix("download_")
-        .tempfile()
+        .tempfile_in(dir)
         .map_err(|e| e.to_string())?;
 
     let mut downloaded = 0;
@@ -284,11 +383,9 @@ async fn download_file(
         temp_file.write_all(&chunk).map_err(|e| e.to_string())?;
         downloaded += chunk.len() as u64;
 
-        // Emit progress to the frontend
-        let progress = (downloaded as f64 / total_size as f64) * 100.0;
-        _app_handle
-            .emit("download-progress", progress)
-            .map_err(|e| e.to_string())?;
+        if let Some(total_size) = total_size {
+            on_progress((downloaded as f64 / total_size as f64) * 100.0);
+        }
     }
 
     // Flush and sync the file
@@ -300,8 +397,7 @@ async fn download_file(
 
     // Move the temporary file to the final destination
     let temp_path = temp_file.into_temp_path();
-    let final_path = Path::new(&path);
-    temp_path.persist(&final_path).map_err(|e| e.to_string())?;
+    temp_path.persist(path).map_err(|e| e.to_string())?;
 
     Ok(())
 }
@@ -314,6 +410,16 @@ struct Payload {
     cwd: String,
 }
 
+/// An opened file, given as either a URL or a path to an existing file.
+fn file_url(arg: &str, cwd: Option<&Path>) -> Option<url::Url> {
+    let path = cwd.map_or(Path::new(arg).to_path_buf(), |cwd| cwd.join(arg));
+    if path.is_file() {
+        return url::Url::from_file_path(fs::canonicalize(path).ok()?).ok();
+    }
+    // Drive letters parse as a URL scheme
+    url::Url::parse(arg).ok().filter(|url| url.scheme().len() > 1)
+}
+
 #[tokio::main]
 async fn main() {
     info!("Starting Musicat");
@@ -365,6 +471,8 @@ async fn main() {
                 for arg in env::args().skip(1) {
                     if let Ok(url) = url::Url::parse(&arg) {
                         urls.push(url);
+                    } else if let Some(url) = file_url(&arg, None) {
+                        urls.push(url);
                     }
                 }
 
@@ -373,25 +481,22 @@ async fn main() {
                 }
             }
 
+            // A JSON array, file names can have commas in them
             let opened_urls = if let Some(urls) = &*file_urls.0.lock().unwrap() {
                 urls.iter()
-                    .map(|u| {
-                        urlencoding::decode(u.as_str())
-                            .unwrap()
-                            .replace("\\", "\\\\")
-                    })
+                    .map(|u| urlencoding::decode(u.as_str()).unwrap().into_owned())
                     .collect::<Vec<_>>()
-                    .join(", ")
             } else {
-                "".into()
+                Vec::new()
             };
 
             info!("Initial opened urls: {:?}", opened_urls);
+            let opened_urls = serde_json::to_string(&opened_urls).unwrap();
 
             let mut window_builder =
                 tauri::WebviewWindowBuilder::new(app, "main", Default::default())
-                    .initialization_script(&format!("window.openedUrls = `{opened_urls}`"))
-                    .initialization_script(&format!("console.log(`{opened_urls}`)"))
+                    .initialization_script(&format!("window.openedUrls = {opened_urls}"))
+                    .initialization_script(&format!("console.log({opened_urls})"))
                     .theme(Some(tauri::Theme::Dark))
                     .fullscreen(false)
                     .inner_size(1200f64, 780f64)
@@ -554,18 +659,57 @@ async fn main() {
             get_lyrics,
             get_file_size,
             stream_file,
-            queue_next,
             init_streamer,
             decode_control,
             volume_control,
+            equalizer_control,
+            get_equalizer_presets,
             get_waveform,
             player::loop_region,
+            player::playback_rate,
+            player::meters,
+            player::get_player_state,
             player::change_audio_device,
+            queue::get_queue,
+            queue::queue_set,
+            queue::queue_insert,
+            queue::queue_move,
+            queue::queue_remove,
+            queue::queue_select,
+            queue::queue_clear,
+            queue::queue_shuffle,
+            queue::queue_repeat,
+            queue::queue_next_track,
+            queue::queue_previous_track,
+            loudness::analyse_loudness,
+            loudness::cancel_loudness_analysis,
             download_file,
-            scrape::get_wikipedia
+            scrape::get_wikipedia,
+            playlist_file::read_playlist,
+            playlist_file::write_playlist,
+            podcast::subscribe_podcast,
+            podcast::unsubscribe_podcast,
+            podcast::get_podcasts,
+            podcast::refresh_podcasts,
+            podcast::download_episode,
+            podcast::save_episode_position,
+            podcast::get_episode_position
         ])
         .plugin(tauri_plugin_single_instance::init(|app, argv, cwd| {
             info!("{}, {argv:?}, {cwd}", app.package_info().name);
+            // Files opened while the app is running are played in the running instance
+            let paths = argv
+                .iter()
+                .skip(1)
+                .filter_map(|arg| file_url(arg, Some(Path::new(&cwd))))
+                .filter_map(|url| url.to_file_path().ok())
+                .map(|path| path.to_string_lossy().to_string())
+                .collect::<Vec<_>>();
+            if let Some(w) = app.get_webview_window("main").filter(|_| !paths.is_empty()) {
+                let urls = serde_json::to_string(&paths).unwrap();
+                let _ = w.eval(&format!("window.onFileOpen({urls})"));
+                let _ = w.set_focus();
+            }
             app.emit("single-instance", Payload { args: argv, cwd })
                 .unwrap();
         }))
@@ -585,9 +729,9 @@ async fn main() {
                     let urls = urls
                         .iter()
                         .map(|u| urlencoding::decode(u.as_str()).unwrap())
-                        .collect::<Vec<_>>()
-                        .join(",");
-                    let _ = w.eval(&format!("window.onFileOpen(`{urls}`)"));
+                        .collect::<Vec<_>>();
+                    let urls = serde_json::to_string(&urls).unwrap();
+                    let _ = w.eval(&format!("window.onFileOpen({urls})"));
                 }
 
                 let opened_urls = app.try_state::<OpenedUrls>();
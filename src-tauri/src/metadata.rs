@@ -1,12 +1,16 @@
 use artwork_cacher::look_for_art;
 use chksum_md5::MD5;
-use lofty::config::WriteOptions;
-use lofty::file::{AudioFile, FileType, TaggedFileExt};
-use lofty::id3::v2::{upgrade_v2, upgrade_v3};
+use lofty::config::{ParseOptions, WriteOptions};
+use lofty::file::{AudioFile, FileType, TaggedFile, TaggedFileExt};
+use lofty::id3::v2::read::parse_id3v2;
+use lofty::id3::v2::{upgrade_v2, upgrade_v3, Id3v2Header, Id3v2Tag};
+use lofty::mp4::{Mp4Codec, Mp4File};
 use lofty::picture::Picture;
+use lofty::properties::FileProperties;
 use lofty::probe::Probe;
 use lofty::read_from_path;
 use lofty::tag::{Accessor, ItemKey, ItemValue, TagItem, TagType};
+use lofty::wavpack::WavPackFile;
 use log::info;
 use rayon::iter::IntoParallelRefIterator;
 use rayon::prelude::*;
@@ -14,7 +18,7 @@ use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::collections::HashMap;
 use std::fs::{self, File};
-use std::io::{BufReader, ErrorKind};
+use std::io::{BufReader, Cursor, ErrorKind};
 use std::ops::{Deref, Mul};
 use std::path::Path;
 use std::sync::{Arc, Mutex};
@@ -22,6 +26,9 @@ use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use std::{thread, time};
 use tauri::{AppHandle, Emitter};
 
+use crate::dsp;
+use crate::playlist_file::{self, PlaylistFile};
+
 mod artwork_cacher;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -36,6 +43,22 @@ pub struct WriteMetatadaEvent {
     tag_type: Option<String>,
     file_path: String,
     artwork_file_to_set: String,
+    /// Written along with the metadata, or on their own when there's none
+    #[serde(default)]
+    replay_gain: Option<ReplayGain>,
+}
+
+impl WriteMetatadaEvent {
+    /// Sets the ReplayGain values of a file, keeping its other tags.
+    pub fn replay_gain(file_path: &str, replay_gain: ReplayGain) -> Self {
+        WriteMetatadaEvent {
+            metadata: Vec::new(),
+            tag_type: None,
+            file_path: file_path.to_string(),
+            artwork_file_to_set: String::new(),
+            replay_gain: Some(replay_gain),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -49,6 +72,9 @@ pub struct ScanPathsEvent {
     recursive: bool,
     process_albums: bool,
     is_async: bool,
+    /// Read the playlist files found in folders too
+    #[serde(default)]
+    include_playlists: bool,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -63,6 +89,208 @@ pub struct FileInfo {
     lossless: bool,
     tag_type: Option<String>,
     codec: Option<String>,
+    replay_gain: Option<ReplayGain>,
+}
+
+/// ReplayGain values in dB (gain) and linear full scale (peak), normalised to the
+/// ReplayGain reference level. R128 gains from Opus files are converted on read.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplayGain {
+    pub track_gain: Option<f64>,
+    pub track_peak: Option<f64>,
+    pub album_gain: Option<f64>,
+    pub album_peak: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReplayGainMode {
+    Off,
+    Track,
+    Album,
+}
+
+// Off until users opt in, so upgrading doesn't change how loud anything plays
+impl Default for ReplayGainMode {
+    fn default() -> Self {
+        ReplayGainMode::Off
+    }
+}
+
+impl ReplayGain {
+    /// The linear gain to apply for the given mode, pre-amp (dB), and clipping prevention.
+    /// Album mode falls back to track values when the album ones are missing.
+    pub fn linear_gain(&self, mode: ReplayGainMode, preamp: f64, prevent_clipping: bool) -> f32 {
+        let (gain, peak) = match mode {
+            ReplayGainMode::Off => return 1.0,
+            ReplayGainMode::Track => (
+                self.track_gain.or(self.album_gain),
+                self.track_peak.or(self.album_peak),
+            ),
+            ReplayGainMode::Album => (
+                self.album_gain.or(self.track_gain),
+                self.album_peak.or(self.track_peak),
+            ),
+        };
+        let gain = match gain {
+            Some(gain) => gain,
+            None => return 1.0,
+        };
+
+        let mut linear = dsp::db_to_linear(gain + preamp);
+        if prevent_clipping {
+            if let Some(peak) = peak.filter(|p| *p > 0.0) {
+                linear = linear.min(1.0 / peak);
+            }
+        }
+        linear as f32
+    }
+}
+
+/// Parses values like "-6.20 dB" or "0.988553".
+fn parse_replay_gain_value(value: &str) -> Option<f64> {
+    let value = value.trim();
+    let value = value
+        .strip_suffix("dB")
+        .or(value.strip_suffix("db"))
+        .or(value.strip_suffix("DB"))
+        .unwrap_or(value);
+    value.trim().parse::<f64>().ok()
+}
+
+/// Reads ReplayGain values from any tag in the file: REPLAYGAIN_* from ID3 TXXX frames,
+/// Vorbis comments, APE tags and MP4 freeform atoms, or R128_* from Opus files (Q7.8 dB
+/// relative to -23 LUFS).
+pub fn read_replay_gain(tagged_file: &TaggedFile) -> Option<ReplayGain> {
+    let mut replay_gain = ReplayGain::default();
+
+    for tag in tagged_file.tags() {
+        let find = |key: ItemKey, name: &str| -> Option<String> {
+            if let Some(value) = tag.get_string(&key) {
+                return Some(value.to_string());
+            }
+            tag.items().find_map(|item| match item.key() {
+                ItemKey::Unknown(unknown) => {
+                    let unknown = unknown
+                        .strip_prefix("TXXX:")
+                        .or(unknown.strip_prefix("----:com.apple.iTunes:"))
+                        .unwrap_or(unknown);
+                    if unknown.eq_ignore_ascii_case(name) {
+                        item.value().text().map(String::from)
+                    } else {
+                        None
+                    }
+                }
+                _ => None,
+            })
+        };
+
+        let r128 = |name: &str| -> Option<f64> {
+            find(ItemKey::Unknown(name.to_string()), name)
+                .and_then(|v| v.trim().parse::<i32>().ok())
+                .map(|q| q as f64 / 256.0 + 5.0)
+        };
+
+        replay_gain.track_gain = replay_gain
+            .track_gain
+            .or(find(ItemKey::ReplayGainTrackGain, "REPLAYGAIN_TRACK_GAIN")
+                .and_then(|v| parse_replay_gain_value(&v)))
+            .or(r128("R128_TRACK_GAIN"));
+        replay_gain.album_gain = replay_gain
+            .album_gain
+            .or(find(ItemKey::ReplayGainAlbumGain, "REPLAYGAIN_ALBUM_GAIN")
+                .and_then(|v| parse_replay_gain_value(&v)))
+            .or(r128("R128_ALBUM_GAIN"));
+        replay_gain.track_peak = replay_gain.track_peak.or(find(
+            ItemKey::ReplayGainTrackPeak,
+            "REPLAYGAIN_TRACK_PEAK",
+        )
+        .and_then(|v| parse_replay_gain_value(&v)));
+        replay_gain.album_peak = replay_gain.album_peak.or(find(
+            ItemKey::ReplayGainAlbumPeak,
+            "REPLAYGAIN_ALBUM_PEAK",
+        )
+        .and_then(|v| parse_replay_gain_value(&v)));
+    }
+
+    if replay_gain == ReplayGain::default() {
+        None
+    } else {
+        Some(replay_gain)
+    }
+}
+
+/// MP4 can hold AAC or ALAC (among others), which lofty only tells apart when the file is
+/// read as MP4.
+fn read_mp4_codec(path: &Path) -> Option<&'static str> {
+    let mut file = File::open(path).ok()?;
+    let mp4 = Mp4File::read_from(&mut file, ParseOptions::new()).ok()?;
+    match mp4.properties().codec() {
+        Mp4Codec::AAC => Some("AAC"),
+        Mp4Codec::ALAC => Some("ALAC"),
+        Mp4Codec::FLAC => Some("FLAC"),
+        Mp4Codec::MP3 => Some("MPEG"),
+        _ => None,
+    }
+}
+
+/// Hybrid WavPack files are lossy unless there's a correction file next to them, which
+/// we don't play.
+fn read_wavpack_lossless(path: &Path) -> bool {
+    File::open(path)
+        .ok()
+        .and_then(|mut file| WavPackFile::read_from(&mut file, ParseOptions::new()).ok())
+        .map_or(false, |wv| wv.properties().is_lossless())
+}
+
+/// Lofty can't read DSD files, so the ID3v2 tag they carry is parsed on its own. Lofty has
+/// no file type for DSD; AIFF's primary tag is ID3v2 as well, which is what's asked of it.
+pub(crate) fn read_dsd_tags(id3: Option<&[u8]>) -> Option<TaggedFile> {
+    let tags = match id3 {
+        Some(id3) => {
+            let mut reader = Cursor::new(id3);
+            let header = Id3v2Header::parse(&mut reader).ok()?;
+            let tag: Id3v2Tag = parse_id3v2(&mut reader, header, ParseOptions::new()).ok()?;
+            vec![lofty::tag::Tag::from(tag)]
+        }
+        None => Vec::new(),
+    };
+    Some(TaggedFile::new(FileType::Aiff, FileProperties::default(), tags))
+}
+
+/// DSD's properties come from its own headers, as 1-bit audio at a megahertz rate. The
+/// tags aren't written back, so there's no tag type.
+fn dsd_file_info(info: &crate::dsd::DsdInfo, tagged_file: &TaggedFile) -> FileInfo {
+    let bitrate = (info.rate as u64 * info.channels as u64 / 1000) as u32;
+    FileInfo {
+        duration: Some(info.samples as f64 / info.rate.max(1) as f64),
+        channels: Some(info.channels as u8),
+        bit_depth: Some(1),
+        sample_rate: Some(info.rate),
+        audio_bitrate: Some(bitrate),
+        overall_bitrate: Some(bitrate),
+        lossless: true,
+        tag_type: None,
+        codec: Some("DSD".to_string()),
+        replay_gain: read_replay_gain(tagged_file),
+    }
+}
+
+/// Reads the ReplayGain values for a file being played.
+pub fn read_replay_gain_from_path(path: &Path) -> Option<ReplayGain> {
+    let is_dsd = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map_or(false, |ext| {
+            ext.eq_ignore_ascii_case("dsf") || ext.eq_ignore_ascii_case("dff")
+        });
+    let tagged_file = if is_dsd {
+        read_dsd_tags(crate::dsd::read_info(path)?.id3.as_deref())?
+    } else {
+        read_from_path(path).ok()?
+    };
+    read_replay_gain(&tagged_file)
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -96,6 +324,9 @@ pub struct Song {
     artwork: Option<Artwork>,
     origin_country: Option<String>,
     date_added: Option<u128>,
+    /// Where a track from a CUE sheet is in its album's file, in seconds
+    start_pos: Option<f64>,
+    end_pos: Option<f64>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -120,6 +351,8 @@ pub struct ToImportEvent {
     progress: u8,
     done: bool,
     error: Option<String>,
+    #[serde(default)]
+    playlists: Vec<PlaylistFile>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -142,12 +375,18 @@ pub async fn write_metadatas(
         Arc::new(Mutex::new(HashMap::new()));
 
     let mut error: Option<String> = None;
+    let waveform_cache = crate::waveform_cache::WaveformCache::open(&_app_handle);
 
     // let v: WriteMetatadaEvent = serde_json::from_str(payload).unwrap();
     for track in event.tracks.iter() {
         let write_result = write_metadata_track(&track.clone());
         match write_result {
             Ok(()) => {
+                // The file's new mtime would usually make its peaks stale anyway, but not
+                // on filesystems with coarse times
+                if let Some(cache) = &waveform_cache {
+                    cache.remove(&track.file_path);
+                }
                 if let Some(song) = crate::metadata::extract_metadata(
                     Path::new(&track.file_path),
                     true,
@@ -202,6 +441,7 @@ pub async fn write_metadatas(
         progress: 100,
         done: true,
         error: error,
+        playlists: vec![],
     };
     return to_import;
 }
@@ -234,15 +474,42 @@ pub async fn scan_paths(
     let songs: Arc<std::sync::Mutex<Vec<Song>>> = Arc::new(Mutex::new(Vec::new()));
     let albums: Arc<std::sync::Mutex<HashMap<String, Album>>> =
         Arc::new(Mutex::new(HashMap::new()));
+    let playlists: Arc<Mutex<Vec<PlaylistFile>>> = Arc::new(Mutex::new(Vec::new()));
 
     event.paths.par_iter().for_each(|p| {
         let path = Path::new(p.as_str());
         // info!("{:?}", path);
 
         if path.is_file() {
-            if let Some(mut song) =
-                crate::metadata::extract_metadata(&path, true, false, &app_handle)
-            {
+            let found = if playlist_file::is_playlist(path) {
+                // A playlist brings its entries, in its order
+                let Some(mut playlist) = playlist_file::read(path) else {
+                    return;
+                };
+                let found = playlist
+                    .entries
+                    .iter()
+                    .flat_map(|entry| {
+                        let entry_path = Path::new(&entry.path);
+                        if entry_path.is_file() {
+                            extract_songs(entry_path, true, false, &app_handle)
+                        } else {
+                            // A track of an album's file, or a file that's gone
+                            extract_metadata(entry_path, true, false, &app_handle)
+                                .into_iter()
+                                .collect()
+                        }
+                    })
+                    .collect();
+                if event.include_playlists {
+                    playlist_file::match_files(&mut playlist.entries);
+                    playlists.lock().unwrap().push(playlist);
+                }
+                found
+            } else {
+                extract_songs(&path, true, false, &app_handle)
+            };
+            for mut song in found {
                 if event.process_albums {
                     if let Some(album) = process_new_album(&song, &app_handle) {
                         info!("Album: {:?}", album);
@@ -264,6 +531,7 @@ pub async fn scan_paths(
                 Path::new(path),
                 &songs,
                 &albums,
+                event.include_playlists.then_some(&playlists),
                 event.recursive,
                 event.process_albums,
                 &app_handle,
@@ -310,6 +578,7 @@ pub async fn scan_paths(
                     progress: progress,
                     done: progress == 100 && albums.lock().unwrap().clone().len() == 0,
                     error: None,
+                    playlists: vec![],
                 },
             );
         });
@@ -325,6 +594,7 @@ pub async fn scan_paths(
                 progress: 100,
                 done: true,
                 error: None,
+                playlists: vec![],
             },
         );
         let _ = app_handle.emit(
@@ -335,6 +605,7 @@ pub async fn scan_paths(
                 progress: 100,
                 done: albums.lock().unwrap().clone().len() == 0,
                 error: None,
+                playlists: vec![],
             },
         );
     }
@@ -366,6 +637,7 @@ pub async fn scan_paths(
                     progress: progress,
                     done: progress == 100,
                     error: None,
+                    playlists: vec![],
                 },
             );
         });
@@ -378,10 +650,15 @@ pub async fn scan_paths(
                 progress: 100,
                 done: true,
                 error: None,
+                playlists: vec![],
             },
         );
     }
 
+    if event.is_async && !playlists.lock().unwrap().is_empty() {
+        let _ = app_handle.emit("import_playlists", playlists.lock().unwrap().clone());
+    }
+
     // Print how many songs and albums were imported, and how long the import took
     info!(
         "<bold><green>Imported {} songs and {} albums in {:.2} seconds</green></bold>",
@@ -404,6 +681,11 @@ pub async fn scan_paths(
         progress: 100,
         done: true,
         error: None,
+        playlists: if event.is_async {
+            vec![]
+        } else {
+            playlists.lock().unwrap().clone()
+        },
     })
 }
 
@@ -490,6 +772,7 @@ fn process_directory(
     directory_path: &Path,
     songs: &Arc<std::sync::Mutex<Vec<Song>>>,
     albums: &Arc<std::sync::Mutex<HashMap<String, Album>>>,
+    playlists: Option<&Arc<Mutex<Vec<PlaylistFile>>>>,
     recursive: bool,
     process_albums: bool,
     app: &AppHandle,
@@ -505,10 +788,14 @@ fn process_directory(
                     let path = entry.path();
 
                     // info!("{:?}", entry.path());
-                    if path.is_file() {
-                        if let Some(mut song) =
-                            crate::metadata::extract_metadata(&path, true, false, &app)
-                        {
+                    if path.is_file() && playlist_file::is_playlist(&path) {
+                        let playlist = playlists.zip(playlist_file::read(&path));
+                        if let Some((playlists, mut playlist)) = playlist {
+                            playlist_file::match_files(&mut playlist.entries);
+                            playlists.lock().unwrap().push(playlist);
+                        }
+                    } else if path.is_file() {
+                        for mut song in extract_songs(&path, true, false, &app) {
                             if process_albums {
                                 if let Some(album) = process_new_album(&song, app) {
                                     // info!("Album: {:?}", album);
@@ -543,9 +830,15 @@ fn process_directory(
                             subsongs.lock().unwrap().push(song);
                         }
                     } else if path.is_dir() && recursive {
-                        if let Some(sub_results) =
-                            process_directory(&path, songs, albums, true, process_albums, app)
-                        {
+                        if let Some(sub_results) = process_directory(
+                            &path,
+                            songs,
+                            albums,
+                            playlists,
+                            true,
+                            process_albums,
+                            app,
+                        ) {
                             if !sub_results.songs.is_empty() {
                                 songs.lock().unwrap().extend(sub_results.songs);
                             }
@@ -570,6 +863,90 @@ pub fn extract_metadata(
     include_folder_artwork: bool,
     app: &AppHandle,
 ) -> Option<Song> {
+    // A track of an album's file is read along with the rest of the album
+    if let Some((audio, _)) = file_path.to_str().and_then(crate::cue::split_track_path) {
+        let path = file_path.to_string_lossy();
+        return extract_songs(Path::new(audio), is_import, include_folder_artwork, app)
+            .into_iter()
+            .find(|song| song.path == path);
+    }
+    read_song(file_path, is_import, include_folder_artwork, app).map(|(song, _)| song)
+}
+
+/// The songs in a file: one for each track when it's an album with a CUE sheet.
+pub fn extract_songs(
+    file_path: &Path,
+    is_import: bool,
+    include_folder_artwork: bool,
+    app: &AppHandle,
+) -> Vec<Song> {
+    let (song, embedded_sheet) =
+        match read_song(file_path, is_import, include_folder_artwork, app) {
+            Some(read) => read,
+            None => return Vec::new(),
+        };
+    // Albums ripped to one file are lossless, which saves looking for sheets next to the rest
+    if embedded_sheet.is_none() && !song.file_info.lossless {
+        return vec![song];
+    }
+    match crate::cue::find_sheet(file_path, embedded_sheet.as_deref()) {
+        Some(album) => cue_songs(&song, &album),
+        None => vec![song],
+    }
+}
+
+/// A song for each track of an album's file, with the sheet's details over the file's.
+fn cue_songs(song: &Song, album: &crate::cue::AlbumSheet) -> Vec<Song> {
+    let sheet = &album.sheet;
+    let tracks = album.tracks();
+    tracks
+        .iter()
+        .enumerate()
+        .map(|(i, track)| {
+            let path = crate::cue::track_path(&song.path, track.number);
+            let end = tracks
+                .get(i + 1)
+                .map(|next| next.start)
+                .or(song.file_info.duration);
+            let length = end.map_or(0.0, |end| (end - track.start).max(0.0));
+            let mut file_info = song.file_info.clone();
+            file_info.duration = Some(length);
+            Song {
+                id: MD5::hash(path.as_bytes()).to_hex_lowercase(),
+                title: track
+                    .title
+                    .clone()
+                    .unwrap_or_else(|| format!("Track {}", track.number)),
+                artist: track
+                    .performer
+                    .clone()
+                    .or(sheet.performer.clone())
+                    .unwrap_or_else(|| song.artist.clone()),
+                album: sheet.title.clone().unwrap_or_else(|| song.album.clone()),
+                year: sheet.year.unwrap_or(song.year),
+                genre: sheet
+                    .genre
+                    .clone()
+                    .map_or_else(|| song.genre.clone(), |genre| vec![genre]),
+                track_number: track.number as i32,
+                duration: seconds_to_hms(length as u64),
+                file_info,
+                start_pos: Some(track.start),
+                end_pos: end,
+                path,
+                ..song.clone()
+            }
+        })
+        .collect()
+}
+
+/// Reads a file's song, along with the CUE sheet embedded in its tags if there is one.
+fn read_song(
+    file_path: &Path,
+    is_import: bool,
+    include_folder_artwork: bool,
+    app: &AppHandle,
+) -> Option<(Song, Option<String>)> {
     if let Some(extension) = file_path.extension() {
         if let Some(ext_str) = extension.to_str() {
             if ext_str.eq_ignore_ascii_case("mp3")
@@ -577,9 +954,27 @@ pub fn extract_metadata(
                 || ext_str.eq_ignore_ascii_case("wav")
                 || ext_str.eq_ignore_ascii_case("aiff")
                 || ext_str.eq_ignore_ascii_case("ape")
+                || ext_str.eq_ignore_ascii_case("wv")
+                || ext_str.eq_ignore_ascii_case("dsf")
+                || ext_str.eq_ignore_ascii_case("dff")
                 || ext_str.eq_ignore_ascii_case("ogg")
+                || ext_str.eq_ignore_ascii_case("opus")
+                || ext_str.eq_ignore_ascii_case("m4a")
+                || ext_str.eq_ignore_ascii_case("m4b")
+                || ext_str.eq_ignore_ascii_case("mp4")
+                || ext_str.eq_ignore_ascii_case("aac")
             {
-                if let Ok(tagged_file) = read_from_path(&file_path) {
+                let dsd =
+                    if ext_str.eq_ignore_ascii_case("dsf") || ext_str.eq_ignore_ascii_case("dff") {
+                        Some(crate::dsd::read_info(file_path)?)
+                    } else {
+                        None
+                    };
+                let tagged_file = match &dsd {
+                    Some(info) => read_dsd_tags(info.id3.as_deref()),
+                    None => read_from_path(&file_path).ok(),
+                };
+                if let Some(tagged_file) = tagged_file {
                     let id = MD5::hash(file_path.to_str().unwrap().as_bytes()).to_hex_lowercase();
                     let path = file_path.to_string_lossy().into_owned();
                     let file = file_path.file_name()?.to_string_lossy().into_owned();
@@ -599,52 +994,72 @@ pub fn extract_metadata(
                         title = file.to_string();
                     }
                     // info!("bit depth {:?}", tagged_file.properties().bit_depth());
-                    file_info = FileInfo {
-                        duration: Some(tagged_file.properties().duration().as_secs_f64()),
-                        channels: tagged_file.properties().channels(),
-                        bit_depth: tagged_file.properties().bit_depth().or(Some(16)),
-                        sample_rate: tagged_file.properties().sample_rate(),
-                        audio_bitrate: tagged_file.properties().audio_bitrate(),
-                        overall_bitrate: tagged_file.properties().overall_bitrate(),
-                        lossless: vec![FileType::Flac, FileType::Wav]
-                            .iter()
-                            .any(|f| f.eq(&tagged_file.file_type())),
-                        tag_type: if let Some(tag) = tagged_file.primary_tag() {
-                            match tag.tag_type() {
-                                TagType::VorbisComments => Some("vorbis".to_string()),
-                                TagType::Id3v1 => Some("ID3v1".to_string()),
-                                TagType::Id3v2 => Some("ID3v2".to_string()),
-                                TagType::Ape
-                                | TagType::Mp4Ilst
-                                | TagType::RiffInfo
-                                | TagType::AiffText => None,
-                                _ => todo!(),
-                            }
-                        } else {
-                            match tagged_file.file_type() {
-                                FileType::Flac | FileType::Wav | FileType::Vorbis => {
-                                    Some("vorbis".to_string())
+                    let mp4_codec = if tagged_file.file_type() == FileType::Mp4 {
+                        read_mp4_codec(file_path)
+                    } else {
+                        None
+                    };
+                    file_info = if let Some(info) = &dsd {
+                        dsd_file_info(info, &tagged_file)
+                    } else {
+                        FileInfo {
+                            duration: Some(tagged_file.properties().duration().as_secs_f64()),
+                            channels: tagged_file.properties().channels(),
+                            bit_depth: tagged_file.properties().bit_depth().or(Some(16)),
+                            sample_rate: tagged_file.properties().sample_rate(),
+                            audio_bitrate: tagged_file.properties().audio_bitrate(),
+                            overall_bitrate: tagged_file.properties().overall_bitrate(),
+                            lossless: vec![FileType::Flac, FileType::Wav, FileType::Ape]
+                                .iter()
+                                .any(|f| f.eq(&tagged_file.file_type()))
+                                || matches!(mp4_codec, Some("ALAC") | Some("FLAC"))
+                                || (tagged_file.file_type() == FileType::WavPack
+                                    && read_wavpack_lossless(file_path)),
+                            tag_type: if let Some(tag) = tagged_file.primary_tag() {
+                                match tag.tag_type() {
+                                    TagType::VorbisComments => Some("vorbis".to_string()),
+                                    TagType::Id3v1 => Some("ID3v1".to_string()),
+                                    TagType::Id3v2 => Some("ID3v2".to_string()),
+                                    TagType::Mp4Ilst => Some("iTunes".to_string()),
+                                    TagType::Ape
+                                    | TagType::RiffInfo
+                                    | TagType::AiffText => None,
+                                    _ => todo!(),
+                                }
+                            } else {
+                                match tagged_file.file_type() {
+                                    FileType::Flac
+                                    | FileType::Wav
+                                    | FileType::Vorbis
+                                    | FileType::Opus => {
+                                        Some("vorbis".to_string())
+                                    }
+                                    FileType::Mpeg | FileType::Aac => Some("ID3v2".to_string()),
+                                    FileType::Mp4 => Some("iTunes".to_string()),
+                                    FileType::Ape | FileType::WavPack | FileType::Speex => None,
+                                    _ => None,
                                 }
-                                FileType::Mpeg => Some("ID3v2".to_string()),
-                                FileType::Ape | FileType::Opus | FileType::Speex => None,
+                            },
+                            codec: match tagged_file.file_type() {
+                                FileType::Flac => Some("FLAC".to_string()),
+                                FileType::Mpeg => Some("MPEG".to_string()),
+                                FileType::Aiff => Some("AIFF".to_string()),
+                                FileType::Wav => Some("WAV".to_string()),
+                                FileType::Ape => Some("APE".to_string()),
+                                FileType::WavPack => Some("WavPack".to_string()),
+                                FileType::Opus => Some("Opus".to_string()),
+                                FileType::Speex => Some("Speex".to_string()),
+                                FileType::Vorbis => Some("Vorbis".to_string()),
+                                FileType::Aac => Some("AAC".to_string()),
+                                FileType::Mp4 => Some(mp4_codec.unwrap_or("MP4").to_string()),
                                 _ => None,
-                            }
-                        },
-                        codec: match tagged_file.file_type() {
-                            FileType::Flac => Some("FLAC".to_string()),
-                            FileType::Mpeg => Some("MPEG".to_string()),
-                            FileType::Aiff => Some("AIFF".to_string()),
-                            FileType::Wav => Some("WAV".to_string()),
-                            FileType::Ape => Some("APE".to_string()),
-                            FileType::Opus => Some("Opus".to_string()),
-                            FileType::Speex => Some("Speex".to_string()),
-                            FileType::Vorbis => Some("Vorbis".to_string()),
-                            _ => None,
-                        },
+                            },
+                            replay_gain: read_replay_gain(&tagged_file),
+                        }
                     };
 
                     if duration.is_empty() {
-                        duration = seconds_to_hms(tagged_file.properties().duration().as_secs());
+                        duration = seconds_to_hms(file_info.duration.unwrap_or(0.0) as u64);
                     }
 
                     // info!("Tag properties {:?}", file_info);
@@ -712,29 +1127,36 @@ pub fn extract_metadata(
                     let start = SystemTime::now();
                     let since_the_epoch = start.duration_since(UNIX_EPOCH).unwrap().as_millis();
 
-                    return Some(Song {
-                        id,
-                        path,
-                        file,
-                        title,
-                        artist,
-                        album,
-                        year,
-                        genre,
-                        composer,
-                        track_number,
-                        duration,
-                        file_info,
-                        artwork,
-                        // We default the origin country to "" to allow Dexie to return results when using orderBy,
-                        // even if there are zero songs with a non-empty country
-                        origin_country: Some(String::from("")),
-                        date_added: if is_import {
-                            Some(since_the_epoch)
-                        } else {
-                            None
+                    let cue_sheet = crate::cue::embedded_sheet(&tagged_file);
+
+                    return Some((
+                        Song {
+                            id,
+                            path,
+                            file,
+                            title,
+                            artist,
+                            album,
+                            year,
+                            genre,
+                            composer,
+                            track_number,
+                            duration,
+                            file_info,
+                            artwork,
+                            // We default the origin country to "" to allow Dexie to return results when using orderBy,
+                            // even if there are zero songs with a non-empty country
+                            origin_country: Some(String::from("")),
+                            date_added: if is_import {
+                                Some(since_the_epoch)
+                            } else {
+                                None
+                            },
+                            start_pos: None,
+                            end_pos: None,
                         },
-                    });
+                        cue_sheet,
+                    ));
                 }
             }
         }
@@ -742,6 +1164,83 @@ pub fn extract_metadata(
     None
 }
 
+/// Identifies the album a file belongs to (folder and album tag).
+pub fn album_key(path: &Path) -> Option<String> {
+    // The tracks of a file with a CUE sheet are its album
+    if let Some((audio, _)) = path.to_str().and_then(crate::cue::split_track_path) {
+        return Some(audio.to_string());
+    }
+    let tagged_file = read_from_path(path).ok()?;
+    let tag = tagged_file.primary_tag().or(tagged_file.first_tag())?;
+    let album = tag.album().filter(|a| !a.is_empty())?.to_string();
+    let parent = path.parent()?.to_string_lossy();
+    Some(format!("{}/{}", parent, album))
+}
+
+/// Whether two files are from the same album (same album tag, same folder).
+pub fn is_same_album(first: &Path, second: &Path) -> bool {
+    match (album_key(first), album_key(second)) {
+        (Some(a), Some(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Q7.8 fixed point dB, relative to the -23 LUFS of EBU R128, as Opus files store gains.
+fn to_r128_gain(gain: f64) -> String {
+    let q = ((gain - 5.0) * 256.0).round();
+    (q.clamp(i16::MIN as f64, i16::MAX as f64) as i16).to_string()
+}
+
+/// Sets the ReplayGain values in `tag`. Opus files only get the R128 gains, their spec
+/// leaves no room for REPLAYGAIN_* (or peaks).
+pub(crate) fn insert_replay_gain(
+    tag: &mut lofty::tag::Tag,
+    replay_gain: &ReplayGain,
+    file_type: FileType,
+) {
+    if file_type == FileType::Opus {
+        for key in [
+            ItemKey::ReplayGainTrackGain,
+            ItemKey::ReplayGainAlbumGain,
+            ItemKey::ReplayGainTrackPeak,
+            ItemKey::ReplayGainAlbumPeak,
+        ] {
+            tag.remove_key(&key);
+        }
+        let gains = [
+            ("R128_TRACK_GAIN", replay_gain.track_gain),
+            ("R128_ALBUM_GAIN", replay_gain.album_gain),
+        ];
+        for (key, value) in gains {
+            if let Some(value) = value {
+                let key = ItemKey::Unknown(key.to_string());
+                tag.remove_key(&key);
+                tag.insert_unchecked(TagItem::new(key, ItemValue::Text(to_r128_gain(value))));
+            }
+        }
+        return;
+    }
+
+    let gains = [
+        (ItemKey::ReplayGainTrackGain, replay_gain.track_gain),
+        (ItemKey::ReplayGainAlbumGain, replay_gain.album_gain),
+    ];
+    for (key, value) in gains {
+        if let Some(value) = value {
+            tag.insert_text(key, format!("{:.2} dB", value));
+        }
+    }
+    let peaks = [
+        (ItemKey::ReplayGainTrackPeak, replay_gain.track_peak),
+        (ItemKey::ReplayGainAlbumPeak, replay_gain.album_peak),
+    ];
+    for (key, value) in peaks {
+        if let Some(value) = value {
+            tag.insert_text(key, format!("{:.6}", value));
+        }
+    }
+}
+
 fn map_id3v1_to_id3v2_4(key: &str) -> Option<&'static str> {
     match key {
         "title" => Some("TIT2"),
@@ -755,9 +1254,35 @@ fn map_id3v1_to_id3v2_4(key: &str) -> Option<&'static str> {
     }
 }
 
-fn write_metadata_track(v: &WriteMetatadaEvent) -> Result<(), anyhow::Error> {
+pub(crate) fn write_metadata_track(v: &WriteMetatadaEvent) -> Result<(), anyhow::Error> {
     // info!("got event-name with payload {:?}", event.payload());
 
+    // A track from a CUE sheet shares its file with the rest of the album, its tags live
+    // in the sheet
+    if crate::cue::split_track_path(&v.file_path).is_some() {
+        return Err(anyhow::anyhow!(
+            "Tracks from a CUE sheet can't be edited, edit the sheet instead"
+        ));
+    }
+
+    // Only ReplayGain values to set (from the loudness analysis), the file's tags are kept
+    if v.metadata.is_empty() {
+        if let Some(replay_gain) = &v.replay_gain {
+            let mut tag = read_from_path(&v.file_path)?;
+            let tag_file_type = tag.file_type();
+            let mut to_write = match tag.primary_tag() {
+                Some(primary_tag) => primary_tag.clone(),
+                None => lofty::tag::Tag::new(tag.primary_tag_type()),
+            };
+            insert_replay_gain(&mut to_write, replay_gain, tag_file_type);
+            let mut file = File::options().read(true).write(true).open(&v.file_path)?;
+            tag.insert_tag(to_write);
+            tag.save_to(&mut file, WriteOptions::new())?;
+            info!("Wrote ReplayGain tags to {}", v.file_path);
+            return Ok(());
+        }
+    }
+
     // Parse JSON
     // info!("v {:?}", v);
     if v.tag_type.is_some() {
@@ -773,14 +1298,15 @@ fn write_metadata_track(v: &WriteMetatadaEvent) -> Result<(), anyhow::Error> {
                 "ID3v2.2" => tag_type = Some(TagType::Id3v2),
                 "ID3v2.3" => tag_type = Some(TagType::Id3v2),
                 "ID3v2.4" => tag_type = Some(TagType::Id3v2),
+                "iTunes" => tag_type = Some(TagType::Mp4Ilst),
                 _ => info!("Unhandled tag type: {:?}", v.tag_type),
             }
             let tag_type_value = tag_type.unwrap();
-            let probe = Probe::open(&v.file_path).unwrap().guess_file_type()?;
+            let probe = Probe::open(&v.file_path)?.guess_file_type()?;
             // &probe.guess_file_type();
             let file_type = &probe.file_type();
             info!("fileType: {:?}", &file_type);
-            let mut tag = read_from_path(&v.file_path).unwrap();
+            let mut tag = read_from_path(&v.file_path)?;
             let tag_file_type = tag.file_type();
             let mut to_write = lofty::tag::Tag::new(tag_type.unwrap());
 
@@ -844,8 +1370,11 @@ fn write_metadata_track(v: &WriteMetatadaEvent) -> Result<(), anyhow::Error> {
                         } else {
                             let item_value: ItemValue =
                                 ItemValue::Text(String::from(item.value.as_str().unwrap()));
-                            if tag_key.eq_ignore_ascii_case("TRCK") {
+                            // trkn is a pair of numbers in MP4, lofty builds it from these
+                            if tag_key.eq_ignore_ascii_case("TRCK") || tag_key == "trkn" {
                                 item_key = ItemKey::TrackNumber;
+                            } else if tag_key == "disk" {
+                                item_key = ItemKey::DiscNumber;
                             }
                             to_write.insert(TagItem::new(item_key, item_value));
                         }
@@ -877,6 +1406,10 @@ fn write_metadata_track(v: &WriteMetatadaEvent) -> Result<(), anyhow::Error> {
             // Keep picture, overwrite everything else
             let _pictures = to_write.pictures();
 
+            if let Some(replay_gain) = &v.replay_gain {
+                insert_replay_gain(&mut to_write, replay_gain, tag_file_type);
+            }
+
             tag.clear();
             tag.insert_tag(to_write);
             tag.save_to(&mut file, WriteOptions::new())?;
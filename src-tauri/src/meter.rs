@@ -0,0 +1,180 @@
+//! Level meters: peak, RMS and true peak of each channel of what the output plays, after
+//! the volume and DSP.
+//!
+//! True peak is the peak between samples too, found by oversampling 4 times as in
+//! ITU-R BS.1770. The RMS is averaged over 300 ms, as a VU meter would. Peaks are held for
+//! a couple of seconds, and clips are counted until the next track starts.
+
+use serde::Serialize;
+
+/// Levels below this are reported as this, in dBFS
+pub const FLOOR_DB: f32 = -120.0;
+/// How long the RMS is averaged over, in seconds
+const RMS_WINDOW: f32 = 0.3;
+/// How long a peak is held before it falls back, in seconds
+const PEAK_HOLD: f32 = 2.0;
+/// The highest integer formats reach, for 16 bits
+const FULL_SCALE: f32 = 32767.0 / 32768.0;
+/// Samples in a row at full scale that make a clip, as most meters count them
+const CLIP_RUN: usize = 3;
+/// Oversampling for the true peak
+const OVERSAMPLING: usize = 4;
+/// Taps of each phase of the interpolation filter
+const TAPS: usize = 12;
+
+/// Sent as `meter` while the Artist's Toolkit is enabled.
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct MeterLevels {
+    pub channels: Vec<ChannelLevels>,
+}
+
+/// Levels in dBFS since the last frame, except for the held peak and the clips.
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ChannelLevels {
+    pub peak: f32,
+    pub rms: f32,
+    pub true_peak: f32,
+    /// The highest true peak of the last couple of seconds
+    pub peak_hold: f32,
+    /// Since the track started
+    pub clips: u32,
+}
+
+#[derive(Clone)]
+struct ChannelMeter {
+    peak: f32,
+    true_peak: f32,
+    mean_square: f32,
+    hold: f32,
+    held_for: f32,
+    clips: u32,
+    run: usize,
+    /// The last `TAPS` samples, newest first
+    history: [f32; TAPS],
+}
+
+impl ChannelMeter {
+    fn new() -> Self {
+        ChannelMeter {
+            peak: 0.0,
+            true_peak: 0.0,
+            mean_square: 0.0,
+            hold: 0.0,
+            held_for: 0.0,
+            clips: 0,
+            run: 0,
+            history: [0.0; TAPS],
+        }
+    }
+}
+
+pub struct Meter {
+    channels: Vec<ChannelMeter>,
+    /// Each sample's weight in the RMS
+    rms_coefficient: f32,
+    /// The interpolation filter, by phase
+    phases: [[f32; TAPS]; OVERSAMPLING],
+}
+
+impl Meter {
+    pub fn new(sample_rate: u32, channels: usize) -> Self {
+        Meter {
+            channels: vec![ChannelMeter::new(); channels.max(1)],
+            rms_coefficient: 1.0 - (-1.0 / (RMS_WINDOW * sample_rate as f32)).exp(),
+            phases: interpolation_filter(),
+        }
+    }
+
+    /// Starts the clips and held peaks over, for a new track.
+    pub fn reset(&mut self) {
+        for channel in self.channels.iter_mut() {
+            *channel = ChannelMeter::new();
+        }
+    }
+
+    /// Measures interleaved samples.
+    pub fn process(&mut self, samples: &[f32]) {
+        let count = self.channels.len();
+        for frame in samples.chunks_exact(count) {
+            for (channel, &sample) in self.channels.iter_mut().zip(frame) {
+                let level = sample.abs();
+                channel.peak = channel.peak.max(level);
+                channel.mean_square +=
+                    (sample * sample - channel.mean_square) * self.rms_coefficient;
+
+                if level >= FULL_SCALE {
+                    channel.run += 1;
+                    if channel.run == CLIP_RUN {
+                        channel.clips += 1;
+                    }
+                } else {
+                    channel.run = 0;
+                }
+
+                channel.history.copy_within(..TAPS - 1, 1);
+                channel.history[0] = sample;
+                for phase in &self.phases {
+                    let interpolated: f32 =
+                        phase.iter().zip(&channel.history).map(|(h, x)| h * x).sum();
+                    channel.true_peak = channel.true_peak.max(interpolated.abs());
+                }
+            }
+        }
+    }
+
+    /// The levels since the last time, `dt` seconds ago.
+    pub fn levels(&mut self, dt: f32) -> MeterLevels {
+        let channels = self
+            .channels
+            .iter_mut()
+            .map(|channel| {
+                channel.held_for += dt;
+                if channel.true_peak >= channel.hold || channel.held_for > PEAK_HOLD {
+                    channel.hold = channel.true_peak;
+                    channel.held_for = 0.0;
+                }
+                let levels = ChannelLevels {
+                    peak: to_db(channel.peak),
+                    rms: to_db(channel.mean_square.sqrt()),
+                    true_peak: to_db(channel.true_peak),
+                    peak_hold: to_db(channel.hold),
+                    clips: channel.clips,
+                };
+                channel.peak = 0.0;
+                channel.true_peak = 0.0;
+                levels
+            })
+            .collect();
+        MeterLevels { channels }
+    }
+}
+
+pub fn to_db(level: f32) -> f32 {
+    (20.0 * level.log10()).max(FLOOR_DB)
+}
+
+/// A windowed sinc that fills in the 3 samples between each pair, split into the 4 phases
+/// it's applied in. Each phase adds up to 1, so a steady level stays the same.
+fn interpolation_filter() -> [[f32; TAPS]; OVERSAMPLING] {
+    let length = TAPS * OVERSAMPLING;
+    // Phase 0 is the samples themselves, delayed by half the filter
+    let centre = (length / 2) as f32;
+    let mut phases = [[0.0; TAPS]; OVERSAMPLING];
+    for (p, phase) in phases.iter_mut().enumerate() {
+        for (k, tap) in phase.iter_mut().enumerate() {
+            let n = (k * OVERSAMPLING + p) as f32;
+            let x = (n - centre) / OVERSAMPLING as f32;
+            let sinc = match x == 0.0 {
+                true => 1.0,
+                false => (std::f32::consts::PI * x).sin() / (std::f32::consts::PI * x),
+            };
+            let window = 0.5 + 0.5 * (std::f32::consts::PI * (n - centre) / centre).cos();
+            *tap = sinc * window;
+        }
+        let sum: f32 = phase.iter().sum();
+        phase.iter_mut().for_each(|tap| *tap /= sum);
+    }
+    phases
+}
@@ -0,0 +1,146 @@
+//! Opus decoding for symphonia, which can read Ogg Opus files but has no decoder for them.
+//!
+//! The Ogg reader timestamps packets from the start of the pre-skip and trims the end of
+//! the stream, so we drop the pre-skip here and apply the output gain from the header.
+
+use std::sync::Mutex;
+
+use symphonia::core::audio::{AsAudioBufferRef, AudioBuffer, AudioBufferRef, Signal, SignalSpec};
+use symphonia::core::codecs::{
+    CodecDescriptor, CodecParameters, Decoder, DecoderOptions, FinalizeResult, CODEC_TYPE_OPUS,
+};
+use symphonia::core::errors::{decode_error, unsupported_error, Error, Result};
+use symphonia::core::formats::Packet;
+use symphonia::core::support_codec;
+
+/// Opus always decodes at 48kHz.
+const SAMPLE_RATE: u32 = 48_000;
+
+/// The longest packet Opus allows is 120ms.
+const MAX_FRAMES: usize = 5760;
+
+/// The fields of the OpusHead identification header we need (RFC 7845, section 5.1).
+#[derive(Debug, PartialEq)]
+pub struct OpusHead {
+    pub channels: usize,
+    /// Frames to drop from the start of the stream, at 48kHz
+    pub pre_skip: u64,
+    /// Q7.8 dB, to apply to everything we decode
+    pub output_gain: i16,
+    pub mapping_family: u8,
+}
+
+impl OpusHead {
+    pub fn parse(buf: &[u8]) -> Option<OpusHead> {
+        if buf.len() < 19 || &buf[..8] != b"OpusHead" {
+            return None;
+        }
+        Some(OpusHead {
+            channels: buf[9] as usize,
+            pre_skip: u16::from_le_bytes([buf[10], buf[11]]) as u64,
+            output_gain: i16::from_le_bytes([buf[16], buf[17]]),
+            mapping_family: buf[18],
+        })
+    }
+
+    /// The output gain as a factor.
+    pub fn gain(&self) -> f32 {
+        10f32.powf(self.output_gain as f32 / 256.0 / 20.0)
+    }
+}
+
+pub struct OpusDecoder {
+    params: CodecParameters,
+    // libopus' decoder can be sent between threads but not shared, symphonia wants both
+    decoder: Mutex<opus::Decoder>,
+    channels: usize,
+    pre_skip: u64,
+    gain: f32,
+    pcm: Vec<f32>,
+    buf: AudioBuffer<f32>,
+}
+
+impl Decoder for OpusDecoder {
+    fn try_new(params: &CodecParameters, _options: &DecoderOptions) -> Result<Self> {
+        if params.codec != CODEC_TYPE_OPUS {
+            return unsupported_error("opus: invalid codec type");
+        }
+        let head = match params.extra_data.as_deref().and_then(OpusHead::parse) {
+            Some(head) => head,
+            None => return decode_error("opus: missing or invalid OpusHead"),
+        };
+        // Streams with more than two channels are made of several coupled streams,
+        // which needs libopus' multistream decoder
+        let channels = match head.channels {
+            _ if head.mapping_family > 1 => {
+                return unsupported_error("opus: unknown channel mapping")
+            }
+            1 => opus::Channels::Mono,
+            2 => opus::Channels::Stereo,
+            _ => return unsupported_error("opus: multichannel streams are not supported"),
+        };
+        let layout = match params.channels {
+            Some(layout) => layout,
+            None => return decode_error("opus: unknown channel layout"),
+        };
+        let decoder = opus::Decoder::new(SAMPLE_RATE, channels)
+            .map_err(|_| Error::DecodeError("opus: failed to create decoder"))?;
+
+        Ok(OpusDecoder {
+            params: params.clone(),
+            decoder: Mutex::new(decoder),
+            channels: head.channels,
+            pre_skip: head.pre_skip,
+            gain: head.gain(),
+            pcm: vec![0.0; MAX_FRAMES * head.channels],
+            buf: AudioBuffer::new(MAX_FRAMES as u64, SignalSpec::new(SAMPLE_RATE, layout)),
+        })
+    }
+
+    fn supported_codecs() -> &'static [CodecDescriptor] {
+        &[support_codec!(CODEC_TYPE_OPUS, "opus", "Opus")]
+    }
+
+    fn reset(&mut self) {
+        let _ = self.decoder.get_mut().unwrap().reset_state();
+    }
+
+    fn codec_params(&self) -> &CodecParameters {
+        &self.params
+    }
+
+    fn decode(&mut self, packet: &Packet) -> Result<AudioBufferRef<'_>> {
+        self.buf.clear();
+
+        let frames = self
+            .decoder
+            .get_mut()
+            .unwrap()
+            .decode_float(packet.buf(), &mut self.pcm, false)
+            .map_err(|_| Error::DecodeError("opus: invalid packet"))?;
+
+        self.buf.render_reserved(Some(frames));
+        for ch in 0..self.channels {
+            let gain = self.gain;
+            let interleaved = self.pcm[ch..].iter().step_by(self.channels);
+            for (dst, src) in self.buf.chan_mut(ch).iter_mut().zip(interleaved) {
+                *dst = src * gain;
+            }
+        }
+
+        // Timestamps count the pre-skip, which is encoder warm-up rather than audio
+        let pre_skip = self.pre_skip.saturating_sub(packet.ts()) as usize;
+        let start = (packet.trim_start() as usize).max(pre_skip);
+        self.buf.trim(start.min(frames), packet.trim_end() as usize);
+
+        Ok(self.buf.as_audio_buffer_ref())
+    }
+
+    fn finalize(&mut self) -> FinalizeResult {
+        Default::default()
+    }
+
+    fn last_decoded(&self) -> AudioBufferRef<'_> {
+        self.buf.as_audio_buffer_ref()
+    }
+}
@@ -11,20 +11,36 @@ use std::result;
 
 use ::cpal::traits::{DeviceTrait, HostTrait};
 use ::cpal::{default_host, Device};
-use rustfft::{num_complex::Complex, FftPlanner};
+use serde::Serialize;
 use std::sync::Arc;
 
-use symphonia::core::audio::{AudioBufferRef, SignalSpec};
+use symphonia::core::audio::{AudioBuffer, AudioBufferRef, Channels, Layout, SignalSpec};
+use crate::channel_mix::{ChannelMixSettings, ChannelMixer};
+use crate::dither::DitherSettings;
+use crate::file_output::{
+    FileAudioOutput, FileOutputOptions, OutputControls, FILE_OUTPUT_DEVICE,
+};
+use crate::spectrum::SpectrumSettings;
 use webrtc::data_channel::RTCDataChannel;
 
 pub trait AudioOutput {
     fn write(&mut self, decoded: AudioBufferRef<'_>, ramp_up_samples: u64, ramp_down_samples: u64);
     fn flush(&mut self);
-    #[allow(dead_code)]
     fn get_sample_rate(&self) -> u32;
+    fn get_channels(&self) -> usize;
     fn pause(&self);
     fn resume(&self);
     fn update_resampler(&mut self, spec: SignalSpec, max_frames: u64) -> bool;
+    fn set_gain(&mut self, gain: f32);
+    fn set_channel_mix(&mut self, settings: ChannelMixSettings);
+    fn set_dither(&mut self, source_bits: u32, settings: DitherSettings);
+    fn set_spectrum(&mut self, settings: SpectrumSettings);
+    fn set_meters(&mut self, enabled: bool);
+    fn set_playback_rate(&mut self, tempo: f64, semitones: f64);
+    fn get_position(&self) -> f64;
+    fn mark_position(&mut self, sample_offset: u64);
+    fn get_position_handle(&self) -> Arc<std::sync::RwLock<u64>>;
+    fn get_sample_format(&self) -> String;
     fn has_remaining_samples(&self) -> bool;
     fn ramp_down(&mut self, buffer: AudioBufferRef, num_samples: usize);
     fn ramp_up(&mut self, buffer: AudioBufferRef, num_samples: usize);
@@ -32,8 +48,10 @@ pub trait AudioOutput {
 
 #[allow(dead_code)]
 #[allow(clippy::enum_variant_names)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum AudioOutputError {
+    DeviceNotFoundError,
     OpenStreamError,
     PlayStreamError,
     StreamClosedError,
@@ -43,18 +61,25 @@ pub type Result<T> = result::Result<T, AudioOutputError>;
 
 mod cpal {
     use std::sync::mpsc::Receiver;
-    use std::sync::{Arc, RwLock};
-    use std::time::Duration;
-
-    use crate::output::{fft, get_device_by_name, ifft};
-    use crate::resampler::Resampler;
-    use crate::{SampleOffsetEvent, VolumeControlEvent};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, RwLock, TryLockError, Weak};
+    use std::time::{Duration, Instant};
+
+    use crate::channel_mix::ChannelMixSettings;
+    use crate::output::{channels_for_count, get_device_by_name};
+    use crate::output_stage::{equalize_and_set_volume, OutputStage, Processed};
+    use crate::meter::Meter;
+    use crate::spectrum::{frame_bytes, Spectrum, SpectrumSettings, FFT_SIZE, FRAMES_PER_SECOND};
+    use crate::timestretch::TempoMap;
+    use crate::dither::{Dither, DitherSettings};
+    use crate::equalizer::{Equalizer, EqualizerSettings};
+    use crate::{EqualizerControlEvent, SampleOffsetEvent, VolumeControlEvent};
 
     use super::{AudioOutput, AudioOutputError, Result};
 
     use bytes::Bytes;
-    use cpal::{Sample, SupportedBufferSize};
-    use symphonia::core::audio::{AudioBufferRef, Layout, RawSample, SampleBuffer, SignalSpec};
+    use cpal::SupportedBufferSize;
+    use symphonia::core::audio::{AudioBufferRef, RawSample, SignalSpec};
     use symphonia::core::conv::{ConvertibleSample, IntoSample};
     use symphonia::core::units::TimeBase;
 
@@ -77,26 +102,43 @@ mod cpal {
         + std::marker::Send
         + 'static
     {
+        /// Bits per sample of integer formats, which lose precision when converted to
+        const INTEGER_BITS: Option<u32>;
     }
 
-    impl AudioOutputSample for f32 {}
-    impl AudioOutputSample for i16 {}
-    impl AudioOutputSample for u16 {}
+    impl AudioOutputSample for f32 {
+        const INTEGER_BITS: Option<u32> = None;
+    }
+    impl AudioOutputSample for i16 {
+        const INTEGER_BITS: Option<u32> = Some(16);
+    }
+    impl AudioOutputSample for u16 {
+        const INTEGER_BITS: Option<u32> = Some(16);
+    }
 
     impl CpalAudioOutput {
         pub fn try_open(
             device_name: &String,
             spec: SignalSpec,
             volume_control_receiver: Arc<Mutex<Receiver<VolumeControlEvent>>>,
+            equalizer_control_receiver: Arc<Mutex<Receiver<EqualizerControlEvent>>>,
             sample_offset_receiver: Arc<Mutex<Receiver<SampleOffsetEvent>>>,
             playback_state_receiver: Arc<Mutex<Receiver<bool>>>,
             reset_control_receiver: Arc<Mutex<Receiver<bool>>>,
             device_change_receiver: Arc<Mutex<Receiver<String>>>,
             data_channel: Arc<tokio::sync::Mutex<Option<Arc<RTCDataChannel>>>>,
             vol: Option<f64>,
+            equalizer: EqualizerSettings,
+            native_rate_switching: bool,
             app_handle: AppHandle,
         ) -> Result<Arc<Mutex<dyn AudioOutput>>> {
-            let device = get_device_by_name(Some(device_name.clone())).unwrap();
+            let device = match get_device_by_name(Some(device_name.clone())) {
+                Some(device) => device,
+                None => {
+                    error!("no audio output device");
+                    return Err(AudioOutputError::DeviceNotFoundError);
+                }
+            };
 
             info!("Default audio device: {:?}", device.name());
 
@@ -108,20 +150,21 @@ mod cpal {
                 }
             };
 
-            // Only resample when audio device doesn't support file sample rate
-            // so we can't switch the device rate to match.
-            let supports_sample_rate = device
-                .supported_output_configs()
-                .unwrap()
-                .find(|c| {
-                    return c
-                        .try_with_sample_rate(cpal::SampleRate(spec.rate))
-                        .is_some();
-                })
-                .is_some();
+            // Only switch the device rate to match the file when the user asked for it,
+            // otherwise stay at the device rate and resample.
+            let supports_sample_rate = native_rate_switching
+                && device
+                    .supported_output_configs()
+                    .map(|mut configs| {
+                        configs.any(|c| {
+                            c.try_with_sample_rate(cpal::SampleRate(spec.rate))
+                                .is_some()
+                        })
+                    })
+                    .unwrap_or(false);
 
             info!(
-                "output: supports sample rate ({}) ? {}",
+                "output: switching to sample rate ({}) ? {}",
                 spec.rate, supports_sample_rate
             );
 
@@ -131,16 +174,9 @@ mod cpal {
                 config.sample_rate().0
             };
 
-            let device_spec = SignalSpec::new_with_layout(
-                rate,
-                match spec.channels.count() {
-                    1 => Layout::Mono,
-                    2 => Layout::Stereo,
-                    3 => Layout::TwoPointOne,
-                    5 => Layout::FivePointOne,
-                    _ => Layout::Stereo,
-                },
-            );
+            // Open with the channels the device has, the track is mixed to fit
+            let device_spec =
+                SignalSpec::new(rate, channels_for_count(config.channels() as usize));
 
             let duration = match config.buffer_size() {
                 SupportedBufferSize::Range { min: _, max } => {
@@ -156,20 +192,15 @@ mod cpal {
                     duration,
                     &device,
                     volume_control_receiver,
+                    equalizer_control_receiver,
                     sample_offset_receiver,
                     playback_state_receiver,
                     reset_control_receiver,
                     device_change_receiver,
                     data_channel,
                     |packet, volume| ((packet as f64) * volume) as f32,
-                    |data| {
-                        let fft_result = fft(&data);
-
-                        let time_domain_signal = ifft(&fft_result);
-
-                        Bytes::from(time_domain_signal)
-                    },
                     vol,
+                    equalizer,
                     app_handle,
                 ),
                 cpal::SampleFormat::I16 => CpalAudioOutputImpl::<i16>::try_open(
@@ -177,21 +208,15 @@ mod cpal {
                     duration,
                     &device,
                     volume_control_receiver,
+                    equalizer_control_receiver,
                     sample_offset_receiver,
                     playback_state_receiver,
                     reset_control_receiver,
                     device_change_receiver,
                     data_channel,
                     |packet, volume| ((packet as f64) * volume) as i16,
-                    |data| {
-                        let mut byte_array = Vec::with_capacity(data.len());
-
-                        for d in &mut data.iter() {
-                            byte_array.push(*d as u8);
-                        }
-                        Bytes::from(byte_array)
-                    },
                     vol,
+                    equalizer,
                     app_handle,
                 ),
                 cpal::SampleFormat::U16 => CpalAudioOutputImpl::<u16>::try_open(
@@ -199,21 +224,15 @@ mod cpal {
                     duration,
                     &device,
                     volume_control_receiver,
+                    equalizer_control_receiver,
                     sample_offset_receiver,
                     playback_state_receiver,
                     reset_control_receiver,
                     device_change_receiver,
                     data_channel,
                     |packet, volume| ((packet as f64) * volume) as u16,
-                    |data| {
-                        let mut byte_array = Vec::with_capacity(data.len());
-
-                        for d in &mut data.iter() {
-                            byte_array.push(*d as u8);
-                        }
-                        Bytes::from(byte_array)
-                    },
                     vol,
+                    equalizer,
                     app_handle,
                 ),
                 _ => CpalAudioOutputImpl::<f32>::try_open(
@@ -221,20 +240,15 @@ mod cpal {
                     duration,
                     &device,
                     volume_control_receiver,
+                    equalizer_control_receiver,
                     sample_offset_receiver,
                     playback_state_receiver,
                     reset_control_receiver,
                     device_change_receiver,
                     data_channel,
                     |packet, volume| ((packet as f64) * volume) as f32,
-                    |data| {
-                        let fft_result = fft(&data);
-
-                        let time_domain_signal = ifft(&fft_result);
-
-                        Bytes::from(time_domain_signal)
-                    },
                     vol,
+                    equalizer,
                     app_handle,
                 ),
             }
@@ -246,31 +260,49 @@ mod cpal {
         T: AudioOutputSample + Send + Sync,
     {
         ring_buf: SpscRb<T>,
-        ring_buf_producer: rb::Producer<T>,
-        sample_buf: SampleBuffer<T>,
         stream: cpal::Stream,
-        resampler: Option<Resampler<T>>,
+        stage: OutputStage,
+        sink: RingBufferSink<T>,
         sample_rate: u32,
+        channels: usize,
+        dither_state: Arc<RwLock<DitherSettings>>,
+        spectrum_state: Arc<RwLock<SpectrumSettings>>,
+        meters_enabled: Arc<AtomicBool>,
+        position: Arc<RwLock<u64>>,
         name: String,
     }
 
+    /// Takes what comes out of the output stage to the device's sample format, and into the
+    /// ring buffer the callback plays from.
+    struct RingBufferSink<T: AudioOutputSample> {
+        producer: rb::Producer<T>,
+        dither: Dither,
+        /// The output has fewer bits than the track, so even untouched samples are rounded
+        dither_needed: bool,
+        dither_buf: Vec<f32>,
+        output_buf: Vec<T>,
+        tempo_map: Arc<std::sync::Mutex<TempoMap>>,
+    }
+
     impl<T: AudioOutputSample + Send + Sync> CpalAudioOutputImpl<T> {
         pub fn try_open(
             spec: SignalSpec,
             duration: symphonia::core::units::Duration,
             device: &cpal::Device,
             volume_control_receiver: Arc<Mutex<Receiver<VolumeControlEvent>>>,
+            equalizer_control_receiver: Arc<Mutex<Receiver<EqualizerControlEvent>>>,
             sample_offset_receiver: Arc<Mutex<Receiver<SampleOffsetEvent>>>,
             playback_state_receiver: Arc<Mutex<Receiver<bool>>>,
             reset_control_receiver: Arc<Mutex<Receiver<bool>>>,
             device_change_receiver: Arc<Mutex<Receiver<String>>>,
             data_channel: Arc<tokio::sync::Mutex<Option<Arc<RTCDataChannel>>>>,
             volume_change: fn(T, f64) -> T,
-            get_viz_bytes: fn(Vec<T>) -> Bytes,
             vol: Option<f64>,
+            equalizer: EqualizerSettings,
             app_handle: AppHandle,
         ) -> Result<Arc<Mutex<dyn AudioOutput>>> {
             let num_channels = spec.channels.count();
+            let name = device.name().unwrap_or(String::from("Unknown"));
             // Output audio stream config.
             let config = if cfg!(not(target_os = "windows")) {
                 cpal::StreamConfig {
@@ -282,7 +314,10 @@ mod cpal {
                 // Use the default config for Windows.
                 device
                     .default_output_config()
-                    .expect("Failed to get the default output config.")
+                    .map_err(|err| {
+                        error!("audio output default config error: {}", err);
+                        AudioOutputError::OpenStreamError
+                    })?
                     .config()
             };
 
@@ -298,155 +333,237 @@ mod cpal {
             let (ring_buf_producer, ring_buf_consumer) = (ring_buf.producer(), ring_buf.consumer());
             info!("Ring buffer capacity: {:?}", ring_buf.capacity());
             // States
-            let volume_state = Arc::new(RwLock::new(vol.unwrap()));
-            let frame_idx_state = Arc::new(RwLock::new(0));
-            let elapsed_time_state = Arc::new(RwLock::new(0));
+            let volume_state = Arc::new(RwLock::new(vol.unwrap_or(1.0)));
+            let frame_idx_state = Arc::new(RwLock::new(0u64));
+            let position = frame_idx_state.clone();
+            let tempo_map = Arc::new(std::sync::Mutex::new(TempoMap::default()));
+            let tempo_map_state = tempo_map.clone();
+            // Samples played but not yet counted in the position
+            let mut unaccounted = 0;
+            let elapsed_time_state = Arc::new(RwLock::new(0u64));
             let playback_state = Arc::new(RwLock::new(true));
-            let device_state = Arc::new(RwLock::new(
-                device.name().unwrap_or(String::from("Unknown")),
-            ));
-            let device_name_state = Arc::new(RwLock::new(
-                device.name().unwrap_or(String::from("Unknown")),
-            ));
-            let dc = Arc::new(data_channel);
+            let device_state = Arc::new(RwLock::new(name.clone()));
+            let device_name_state = Arc::new(RwLock::new(name.clone()));
+            // What's played is analyzed on a thread of its own, for as long as the output
+            // holds on to the settings
+            let spectrum_state = Arc::new(RwLock::new(SpectrumSettings::default()));
+            let meters_enabled = Arc::new(AtomicBool::new(false));
+            let meter_reset = Arc::new(AtomicBool::new(false));
+            let analysis_buf = SpscRb::new(FFT_SIZE * config.channels as usize * 4);
+            let (analysis_producer, analysis_consumer) =
+                (analysis_buf.producer(), analysis_buf.consumer());
+            spawn_analyzer(
+                Arc::downgrade(&spectrum_state),
+                meters_enabled.clone(),
+                meter_reset.clone(),
+                analysis_consumer,
+                config.sample_rate.0,
+                config.channels as usize,
+                data_channel,
+                app_handle.clone(),
+            );
+            let mut analysis_samples: Vec<f32> = Vec::new();
+            let analysis_channels = config.channels as usize;
+
+            let mut equalizer =
+                Equalizer::new(config.sample_rate.0, config.channels as usize, equalizer);
+            let mut equalizer_buf: Vec<f32> = Vec::new();
+            // The volume requantizes too, so dither it the same way as the decoded samples
+            let dither_state = Arc::new(RwLock::new(DitherSettings::default()));
+            let callback_dither_state = dither_state.clone();
+            let mut dither = Dither::new(T::INTEGER_BITS, config.channels as usize);
+
+            // Fills `data` the way the device should play it
+            let mut render = move |data: &mut [T]| {
+                // If the device changed, ignore callback
+                if let Ok(device_change) = device_change_receiver.try_lock() {
+                    if let Ok(result) = device_change.try_recv() {
+                        info!("Got device change: {:?}", result);
+                        let mut dvc_state = device_state.write().unwrap();
+                        *dvc_state = result;
+                    }
+                }
 
-            let rt = tokio::runtime::Runtime::new().unwrap();
-            let mut viz_data = Vec::with_capacity(1024);
+                if let Ok(dvc_state) = device_state.try_read() {
+                    if *dvc_state != *device_name_state.try_read().unwrap() {
+                        data.iter_mut().for_each(|s| *s = T::MID);
+                        info!("Ignoring this device");
+                        return;
+                    }
+                }
 
-            let stream_result = device.build_output_stream(
-                &config,
-                move |data: &mut [T], _cb: &cpal::OutputCallbackInfo| {
-                    // If the device changed, ignore callback
-                    if let Ok(device_change) = device_change_receiver.try_lock() {
-                        if let Ok(result) = device_change.try_recv() {
-                            info!("Got device change: {:?}", result);
-                            let mut dvc_state = device_state.write().unwrap();
-                            *dvc_state = result;
+                // info!("playing back {:?}", data.len());
+                // If file changed, reset
+                let reset = reset_control_receiver.try_lock();
+                if let Ok(reset_lock) = reset {
+                    if let Ok(rst) = reset_lock.try_recv() {
+                        if rst {
+                            info!("Got rst: {:?}", rst);
+                            meter_reset.store(true, Ordering::Relaxed);
+                            let mut frame_idx = frame_idx_state.write().unwrap();
+                            *frame_idx = 0;
+                            unaccounted = 0;
+                            let mut elapsed_time = elapsed_time_state.write().unwrap();
+                            *elapsed_time = 0;
+                            let _ = app_handle.emit("timestamp", Some(0f64));
                         }
                     }
+                }
 
-                    if let Ok(dvc_state) = device_state.try_read() {
-                        if *dvc_state != *device_name_state.try_read().unwrap() {
-                            data.iter_mut().for_each(|s| *s = T::MID);
-                            info!("Ignoring this device");
-                            return;
-                        }
+                // Get volume
+                let volume = volume_control_receiver.try_lock();
+                if let Ok(volume_lock) = volume {
+                    if let Ok(vol) = volume_lock.try_recv() {
+                        info!("Got volume: {:?}", vol);
+                        let mut current_volume = volume_state.write().unwrap();
+                        *current_volume = vol.volume.unwrap();
                     }
+                }
 
-                    // info!("playing back {:?}", data.len());
-                    // If file changed, reset
-                    let reset = reset_control_receiver.try_lock();
-                    if let Ok(reset_lock) = reset {
-                        if let Ok(rst) = reset_lock.try_recv() {
-                            if rst {
-                                info!("Got rst: {:?}", rst);
-                                let mut frame_idx = frame_idx_state.write().unwrap();
-                                *frame_idx = 0;
-                                let mut elapsed_time = elapsed_time_state.write().unwrap();
-                                *elapsed_time = 0;
-                                let _ = app_handle.emit("timestamp", Some(0f64));
-                            }
-                        }
+                let current_volume = { *volume_state.read().unwrap() };
+
+                // Get equalizer settings
+                if let Ok(equalizer_lock) = equalizer_control_receiver.try_lock() {
+                    if let Ok(eq) = equalizer_lock.try_recv() {
+                        info!("Got equalizer: {:?}", eq);
+                        equalizer.update(eq.settings);
                     }
+                }
+                // info!("Current volume: {:?}", current_volume);
 
-                    // Get volume
-                    let volume = volume_control_receiver.try_lock();
-                    if let Ok(volume_lock) = volume {
-                        if let Ok(vol) = volume_lock.try_recv() {
-                            info!("Got volume: {:?}", vol);
-                            let mut current_volume = volume_state.write().unwrap();
-                            *current_volume = vol.volume.unwrap();
-                        }
+                let playing = playback_state_receiver.try_lock();
+                if let Ok(play_lock) = playing {
+                    if let Ok(pl) = play_lock.try_recv() {
+                        let mut current_playing = playback_state.write().unwrap();
+                        *current_playing = pl;
                     }
+                }
 
-                    let current_volume = { *volume_state.read().unwrap() };
-                    // info!("Current volume: {:?}", current_volume);
+                // update duration if seconds changed
+                if let Ok(pl_state) = playback_state.try_read() {
+                    if *pl_state {
+                        // Write out as many samples as possible from the ring buffer to the audio
+                        // output.
+                        let written = ring_buf_consumer.read(data).unwrap_or(0);
+
+                        let sample_offset = sample_offset_receiver.try_lock();
+                        if let Ok(offset_lock) = sample_offset {
+                            if let Ok(offset) = offset_lock.try_recv() {
+                                info!("Got sample offset: {:?}", offset);
+                                let sample_offset = offset.sample_offset.unwrap();
+                                let mut current_sample_offset =
+                                    frame_idx_state.write().unwrap();
+                                *current_sample_offset = sample_offset;
+
+                                // Report the exact position we seeked to
+                                let seeked_time = time_base.calc_time(sample_offset);
+                                let _ = app_handle.emit(
+                                    "timestamp",
+                                    Some(seeked_time.seconds as f64 + seeked_time.frac),
+                                );
+                                let mut elapsed_time = elapsed_time_state.write().unwrap();
+                                *elapsed_time = seeked_time.seconds;
+                            }
+                        }
 
-                    let playing = playback_state_receiver.try_lock();
-                    if let Ok(play_lock) = playing {
-                        if let Ok(pl) = play_lock.try_recv() {
-                            let mut current_playing = playback_state.write().unwrap();
-                            *current_playing = pl;
+                        if let Ok(dither_settings) = callback_dither_state.try_read() {
+                            dither.set(*dither_settings);
                         }
-                    }
 
-                    // update duration if seconds changed
-                    if let Ok(pl_state) = playback_state.try_read() {
-                        if *pl_state {
-                            // Write out as many samples as possible from the ring buffer to the audio
-                            // output.
-                            let written = ring_buf_consumer.read(data).unwrap_or(0);
-
-                            let sample_offset = sample_offset_receiver.try_lock();
-                            if let Ok(offset_lock) = sample_offset {
-                                if let Ok(offset) = offset_lock.try_recv() {
-                                    info!("Got sample offset: {:?}", offset);
-                                    let mut current_sample_offset =
-                                        frame_idx_state.write().unwrap();
-                                    *current_sample_offset = offset.sample_offset.unwrap();
-                                }
+                        if equalizer.is_enabled()
+                            || (dither.is_active() && current_volume != 1.0)
+                        {
+                            // Apply the equalizer and volume in float, and round only once
+                            equalizer_buf.clear();
+                            equalizer_buf
+                                .extend(data[..written].iter().map(|d| (*d).into_sample()));
+                            equalize_and_set_volume(
+                                &mut equalizer,
+                                current_volume,
+                                &mut equalizer_buf,
+                            );
+                            dither.process(&mut equalizer_buf);
+                            for (d, s) in data[..written].iter_mut().zip(&equalizer_buf) {
+                                *d = (*s).into_sample();
                             }
-
-                            let mut i = 0;
-                            for d in &mut *data {
+                        } else {
+                            for d in &mut data[..written] {
                                 *d = volume_change(*d, current_volume);
-                                i += 1;
                             }
+                        }
 
-                            let length = data.len();
+                        // Dropped if the analyzer falls behind, it only wants the latest. Only
+                        // whole frames go in, or every frame after would be channel-shifted.
+                        let whole_frames = written - written % analysis_channels;
+                        if analysis_buf.slots_free() >= whole_frames {
+                            analysis_samples.clear();
+                            analysis_samples.extend(
+                                data[..whole_frames].iter().map(|d| (*d).into_sample()),
+                            );
+                            let _ = analysis_producer.write(&analysis_samples);
+                        }
 
-                            let mut should_send = false;
-                            for d in &mut *data {
-                                if viz_data.len() < length {
-                                    viz_data.push(*d);
-                                } else {
-                                    should_send = true;
+                        // new offset
+                        let (new_sample_offset, jumped) = {
+                            // Count the source samples played, which differ from the
+                            // samples written if the playback rate isn't 1. The decoder
+                            // holds the map while it writes; rather than wait for it,
+                            // these samples are counted with the next callback's.
+                            unaccounted += written;
+                            let mut sample_offset = frame_idx_state.write().unwrap();
+                            let tempo_map = match tempo_map_state.try_lock() {
+                                Ok(tempo_map) => Some(tempo_map),
+                                Err(TryLockError::Poisoned(poisoned)) => {
+                                    Some(poisoned.into_inner())
                                 }
-                            }
-
-                            // new offset
-                            let new_sample_offset = {
-                                let mut sample_offset = frame_idx_state.write().unwrap();
-                                *sample_offset += i;
-                                *sample_offset
+                                Err(TryLockError::WouldBlock) => None,
                             };
-                            // new duration
-                            let next_duration =
-                                time_base.calc_time(new_sample_offset as u64).seconds;
-                            // info!("Next duration: {:?}", next_duration);
+                            let jumped = match tempo_map {
+                                Some(mut tempo_map) => {
+                                    let samples = std::mem::take(&mut unaccounted);
+                                    tempo_map.advance(samples, &mut sample_offset)
+                                }
+                                None => false,
+                            };
+                            (*sample_offset, jumped)
+                        };
+                        if jumped {
+                            // Looped back, report the exact position
+                            let time = time_base.calc_time(new_sample_offset);
+                            let _ = app_handle
+                                .emit("timestamp", Some(time.seconds as f64 + time.frac));
+                            let mut elapsed_time = elapsed_time_state.write().unwrap();
+                            *elapsed_time = time.seconds;
+                        }
+                        // new duration
+                        let next_duration =
+                            time_base.calc_time(new_sample_offset as u64).seconds;
+                        // info!("Next duration: {:?}", next_duration);
 
-                            let prev_duration = { *elapsed_time_state.read().unwrap() };
+                        let prev_duration = { *elapsed_time_state.read().unwrap() };
 
-                            if prev_duration != next_duration {
-                                let new_duration = Duration::from_secs(next_duration);
+                        if prev_duration != next_duration {
+                            let new_duration = Duration::from_secs(next_duration);
 
-                                let _ =
-                                    app_handle.emit("timestamp", Some(new_duration.as_secs_f64()));
+                            let _ =
+                                app_handle.emit("timestamp", Some(new_duration.as_secs_f64()));
 
-                                let mut duration = elapsed_time_state.write().unwrap();
-                                *duration = new_duration.as_secs();
-                            }
-                            let viz = viz_data.clone();
-                            // Every x samples - send viz data to frontend
-                            if should_send {
-                                viz_data.clear();
-                                if let Ok(dc_guard) = dc.try_lock() {
-                                    if let Some(dc1) = dc_guard.as_ref().cloned() {
-                                        rt.spawn(async move {
-                                            let _ = dc1.send(&get_viz_bytes(viz)).await;
-                                        });
-                                    }
-                                }
-                            }
-                            // Mute any remaining samples.
-                            data[written..].iter_mut().for_each(|s| *s = T::MID);
-                        } else {
-                            data.iter_mut().for_each(|s| *s = T::MID);
+                            let mut duration = elapsed_time_state.write().unwrap();
+                            *duration = new_duration.as_secs();
                         }
+                        // Mute any remaining samples.
+                        data[written..].iter_mut().for_each(|s| *s = T::MID);
                     } else {
                         data.iter_mut().for_each(|s| *s = T::MID);
                     }
-                },
+                } else {
+                    data.iter_mut().for_each(|s| *s = T::MID);
+                }
+            };
+
+            let stream_result = device.build_output_stream(
+                &config,
+                move |data: &mut [T], _cb: &cpal::OutputCallbackInfo| render(data),
                 move |err| error!("audio output error: {}", err),
                 None,
             );
@@ -466,20 +583,150 @@ mod cpal {
                 return Err(AudioOutputError::PlayStreamError);
             }
 
-            let sample_buf = SampleBuffer::<T>::new(duration, spec);
+            // The stage mixes everything to the stream's channel layout
+            let output_spec = SignalSpec::new(
+                config.sample_rate.0,
+                channels_for_count(config.channels as usize),
+            );
 
             Ok(Arc::new(Mutex::new(CpalAudioOutputImpl {
                 ring_buf,
-                ring_buf_producer,
-                sample_buf,
                 stream,
-                resampler: None,
+                stage: OutputStage::new(output_spec, duration),
+                sink: RingBufferSink {
+                    producer: ring_buf_producer,
+                    dither: Dither::new(T::INTEGER_BITS, config.channels as usize),
+                    dither_needed: false,
+                    dither_buf: Vec::new(),
+                    output_buf: Vec::new(),
+                    tempo_map,
+                },
                 sample_rate: config.sample_rate.0,
-                name: device.name().unwrap_or(String::from("Unknown")),
+                channels: config.channels as usize,
+                dither_state,
+                spectrum_state,
+                meters_enabled,
+                position,
+                name,
             })))
         }
     }
 
+    /// Sends the spectrum of what's played to the visualiser at a fixed frame rate, and its
+    /// levels as `meter` if they're wanted, until the output that holds `settings` is gone.
+    fn spawn_analyzer(
+        settings: Weak<RwLock<SpectrumSettings>>,
+        meters_enabled: Arc<AtomicBool>,
+        meter_reset: Arc<AtomicBool>,
+        samples: rb::Consumer<f32>,
+        sample_rate: u32,
+        channels: usize,
+        data_channel: Arc<Mutex<Option<Arc<RTCDataChannel>>>>,
+        app_handle: AppHandle,
+    ) {
+        std::thread::spawn(move || {
+            let rt = match tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+            {
+                Ok(rt) => rt,
+                Err(err) => {
+                    error!("spectrum analyzer: {}", err);
+                    return;
+                }
+            };
+            let interval = Duration::from_secs(1) / FRAMES_PER_SECOND;
+            let mut spectrum = Spectrum::new(sample_rate, channels, SpectrumSettings::default());
+            let mut meter = Meter::new(sample_rate, channels);
+            let mut buf = vec![0f32; FFT_SIZE * channels];
+            let mut last_frame = Instant::now();
+            loop {
+                std::thread::sleep(interval.saturating_sub(last_frame.elapsed()));
+                let Some(settings) = settings.upgrade() else {
+                    break;
+                };
+                spectrum.set_settings(*settings.read().unwrap());
+                drop(settings);
+
+                if meter_reset.swap(false, Ordering::Relaxed) {
+                    meter.reset();
+                }
+                let meters = meters_enabled.load(Ordering::Relaxed);
+
+                // Nothing new while paused, the last frame stays up
+                let mut received = false;
+                while let Ok(read) = samples.read(&mut buf) {
+                    spectrum.push(&buf[..read]);
+                    if meters {
+                        meter.process(&buf[..read]);
+                    }
+                    received = true;
+                }
+                if !received {
+                    continue;
+                }
+                let dt = last_frame.elapsed().as_secs_f32();
+                last_frame = Instant::now();
+
+                if meters {
+                    let _ = app_handle.emit("meter", meter.levels(dt));
+                }
+
+                let Some(dc) = data_channel.try_lock().ok().and_then(|dc| dc.clone()) else {
+                    continue;
+                };
+                let frame = Bytes::from(frame_bytes(spectrum.analyze(dt)));
+                let _ = rt.block_on(dc.send(&frame));
+            }
+        });
+    }
+
+    impl<T: AudioOutputSample> RingBufferSink<T> {
+        /// Writes all of `processed` to the ring buffer, waiting for room.
+        fn write(&mut self, processed: Processed<'_>) {
+            // Untouched samples land on the output's steps already, unless it has fewer bits
+            let requantized = self.dither_needed || processed.requantized;
+            // Everything up to here is in float, convert once for the ring buffer
+            let mut samples = to_output_format(
+                &mut self.dither,
+                &mut self.dither_buf,
+                &mut self.output_buf,
+                processed.samples,
+                requantized,
+            );
+            self.tempo_map
+                .lock()
+                .unwrap()
+                .push(samples.len(), processed.tempo);
+
+            while let Some(written) = self.producer.write_blocking(samples) {
+                samples = &samples[written..];
+            }
+        }
+    }
+
+    /// Converts interleaved samples to the output's sample format, into `buf`. Integer
+    /// formats are dithered first when the samples are `requantized`, that is changed in
+    /// any way from the source's own steps.
+    fn to_output_format<'a, T: AudioOutputSample>(
+        dither: &mut Dither,
+        dither_buf: &mut Vec<f32>,
+        buf: &'a mut Vec<T>,
+        samples: &[f32],
+        requantized: bool,
+    ) -> &'a [T] {
+        buf.clear();
+        if requantized && dither.is_active() {
+            dither_buf.clear();
+            dither_buf.extend_from_slice(samples);
+            dither.process(dither_buf);
+            buf.extend(dither_buf.iter().map(|s| (*s).into_sample()));
+        } else {
+            buf.extend(samples.iter().map(|s| (*s).into_sample()));
+        }
+        buf
+    }
+
     impl<T: AudioOutputSample + Send + Sync> Drop for CpalAudioOutputImpl<T> {
         fn drop(&mut self) {
             info!("Audio output dropped: {}", self.name);
@@ -499,71 +746,30 @@ mod cpal {
                 return;
             }
 
-            // Print buffer size
+            if let Some(processed) = self.stage.process(decoded, ramp_up_samples, ramp_down_samples)
             {
-                // info!("decoded samples: {}", decoded.frames());
-                // // Current Buffer size
-                // info!("buffer samples: {}", self.sample_buf.samples().len());
-                // info!("ring buffer size: {}", self.ring_buf.count());
-            }
-
-            let mut samples = if let Some(resampler) = &mut self.resampler {
-                // Resampling is required. The resampler will return interleaved samples in the
-                // correct sample format.
-                match resampler.resample(decoded) {
-                    Some(resampled) => resampled,
-                    None => return,
-                }
-            } else {
-                // Resampling is not required. Interleave the sample for cpal using a sample buffer.
-                if ramp_up_samples > 0 {
-                    info!("Ramping up first {:?}", ramp_up_samples);
-                    self.ramp_up(decoded, ramp_up_samples as usize);
-                    self.sample_buf.samples()
-                } else if ramp_down_samples > 0 {
-                    info!("Ramping down last {:?}", ramp_down_samples);
-                    self.ramp_down(decoded, ramp_down_samples as usize);
-                    self.sample_buf.samples()
-                } else {
-                    self.sample_buf.copy_interleaved_ref(decoded);
-                    self.sample_buf.samples()
-                }
-            };
-
-            // Write all samples to the ring buffer.
-            while let Some(written) = self.ring_buf_producer.write_blocking(samples) {
-                samples = &samples[written..];
-                // Print written
-                // info!("written: {}", written);
+                self.sink.write(processed);
             }
         }
 
         fn flush(&mut self) {
-            // If there is a resampler, then it may need to be flushed
-            // depending on the number of samples it has.
-            if let Some(resampler) = &mut self.resampler {
-                while let Some(remaining_samples) = resampler.flush() {
-                    info!("Flushed samples {:?}", remaining_samples.len());
-                }
-            }
-
             // Flush is best-effort, ignore the returned result.
-
-            self.sample_buf.clear();
+            self.stage.flush();
             self.ring_buf.clear();
+            self.sink.tempo_map.lock().unwrap().clear();
 
             // Check what's left now
-            info!(
-                "Sample buf empty: {}, Ring buf empty:{} ",
-                self.sample_buf.is_empty(),
-                self.ring_buf.is_empty()
-            );
+            info!("Ring buf empty: {}", self.ring_buf.is_empty());
         }
 
         fn get_sample_rate(&self) -> u32 {
             return self.sample_rate;
         }
 
+        fn get_channels(&self) -> usize {
+            return self.channels;
+        }
+
         fn pause(&self) {
             let pause_result = self.stream.pause();
             info!("cpal: Stream pause result: {:?}", pause_result);
@@ -578,16 +784,60 @@ mod cpal {
             // If we have a default audio device (we always should, but just in case)
             // we check if the track spec differs from the output device
             // if it does - resample the decoded audio using Symphonia.
+            let sink = &mut self.sink;
+            self.stage
+                .update_resampler(spec, max_frames, |drained| sink.write(drained))
+        }
 
-            if self.sample_rate != spec.rate {
-                info!("resampling {} Hz to {} Hz", spec.rate, self.sample_rate);
-                self.resampler
-                    .replace(Resampler::new(spec, self.sample_rate as usize, max_frames));
-                return true;
-            } else {
-                self.resampler.take();
-                return false;
+        fn set_gain(&mut self, gain: f32) {
+            self.stage.set_gain(gain);
+        }
+
+        fn set_channel_mix(&mut self, settings: ChannelMixSettings) {
+            self.stage.set_channel_mix(settings);
+        }
+
+        /// Dithers integer output whenever samples are requantized: when it has fewer bits
+        /// than the track, or the gain, resampling, DSP or volume changed them.
+        fn set_dither(&mut self, source_bits: u32, settings: DitherSettings) {
+            self.sink.dither_needed = T::INTEGER_BITS.map_or(false, |bits| bits < source_bits);
+            if settings != *self.dither_state.read().unwrap() {
+                info!("output: dither {:?} ({} bit source)", settings, source_bits);
             }
+            self.sink.dither.set(settings);
+            *self.dither_state.write().unwrap() = settings;
+        }
+
+        fn set_spectrum(&mut self, settings: SpectrumSettings) {
+            *self.spectrum_state.write().unwrap() = settings;
+        }
+
+        /// Measures the levels of what's played, for the Artist's Toolkit.
+        fn set_meters(&mut self, enabled: bool) {
+            self.meters_enabled.store(enabled, Ordering::Relaxed);
+        }
+
+        fn set_playback_rate(&mut self, tempo: f64, semitones: f64) {
+            self.stage.set_playback_rate(tempo, semitones);
+        }
+
+        /// The samples written from now on start at `sample_offset` in the track.
+        fn mark_position(&mut self, sample_offset: u64) {
+            self.sink.tempo_map.lock().unwrap().mark_position(sample_offset);
+        }
+
+        fn get_position_handle(&self) -> Arc<RwLock<u64>> {
+            self.position.clone()
+        }
+
+        fn get_sample_format(&self) -> String {
+            std::any::type_name::<T>().to_string()
+        }
+
+        /// Position in the track (source time) of what is playing now, in seconds.
+        fn get_position(&self) -> f64 {
+            let samples = *self.position.read().unwrap();
+            samples as f64 / (self.sample_rate as f64 * self.channels as f64)
         }
 
         /// Checks if there are any samples left in the buffer that have not been played yet.
@@ -596,29 +846,11 @@ mod cpal {
         }
 
         fn ramp_down(&mut self, buffer: AudioBufferRef, num_samples: usize) {
-            self.sample_buf.copy_interleaved_ref(buffer);
-            let ramp_len = num_samples.min(self.sample_buf.len());
-
-            for (i, sample) in self.sample_buf.samples_mut()[..ramp_len]
-                .iter_mut()
-                .enumerate()
-            {
-                let factor = 1.0 - (i as f32 / ramp_len as f32);
-                sample.mul_amp(factor.to_sample());
-            }
+            self.stage.ramp_down(buffer, num_samples);
         }
 
         fn ramp_up(&mut self, buffer: AudioBufferRef, num_samples: usize) {
-            self.sample_buf.copy_interleaved_ref(buffer);
-            let ramp_len = num_samples.min(self.sample_buf.len());
-
-            for (i, sample) in self.sample_buf.samples_mut()[..ramp_len]
-                .iter_mut()
-                .enumerate()
-            {
-                let factor = i as f32 / ramp_len as f32;
-                sample.mul_amp(factor.to_sample());
-            }
+            self.stage.ramp_up(buffer, num_samples);
         }
     }
 }
@@ -629,6 +861,9 @@ pub fn try_open(
     volume_control_receiver: Arc<
         tokio::sync::Mutex<std::sync::mpsc::Receiver<crate::VolumeControlEvent>>,
     >,
+    equalizer_control_receiver: Arc<
+        tokio::sync::Mutex<std::sync::mpsc::Receiver<crate::EqualizerControlEvent>>,
+    >,
     sample_offset_receiver: Arc<
         tokio::sync::Mutex<std::sync::mpsc::Receiver<crate::SampleOffsetEvent>>,
     >,
@@ -637,18 +872,42 @@ pub fn try_open(
     device_change_receiver: Arc<tokio::sync::Mutex<std::sync::mpsc::Receiver<String>>>,
     data_channel: Arc<tokio::sync::Mutex<Option<Arc<RTCDataChannel>>>>,
     vol: Option<f64>,
+    equalizer: crate::equalizer::EqualizerSettings,
+    native_rate_switching: bool,
+    file_output: FileOutputOptions,
     app_handle: tauri::AppHandle,
 ) -> Result<Arc<tokio::sync::Mutex<dyn AudioOutput>>> {
+    // Not a sound card, so it takes the track's rate and channels as they are
+    if device_name == FILE_OUTPUT_DEVICE {
+        return FileAudioOutput::try_open(
+            file_output,
+            spec,
+            vol,
+            equalizer,
+            OutputControls {
+                volume_control_receiver,
+                equalizer_control_receiver,
+                sample_offset_receiver,
+                playback_state_receiver,
+                reset_control_receiver,
+                device_change_receiver,
+                app_handle,
+            },
+        );
+    }
     cpal::CpalAudioOutput::try_open(
         device_name,
         spec,
         volume_control_receiver,
+        equalizer_control_receiver,
         sample_offset_receiver,
         playback_state_receiver,
         reset_control_receiver,
         device_change_receiver,
         data_channel,
         vol,
+        equalizer,
+        native_rate_switching,
         app_handle,
     )
 }
@@ -661,90 +920,22 @@ pub fn get_device_by_name(name: Option<String>) -> Option<Device> {
     let name = name.unwrap();
     return host
         .devices()
-        .unwrap()
-        .find(|device| device.name().unwrap() == name)
+        .ok()
+        .and_then(|mut devices| devices.find(|device| device.name().map_or(false, |n| n == name)))
         .or(host.default_output_device());
 }
 
-fn fft(input: &[f32]) -> Vec<Complex<f32>> {
-    let len = input.len();
-    let mut planner = FftPlanner::new();
-    let fft = planner.plan_fft_forward(len);
-
-    // Apply Hanning window
-    // let windowed_input: Vec<f32> = input
-    //     .iter()
-    //     .enumerate()
-    //     .map(|(i, &x)| x * hamming_window(i, len))
-    //     .collect();
-
-    // Convert input into complex numbers
-    let mut complex_input: Vec<Complex<f32>> =
-        input.iter().map(|&x| Complex::new(x, 0.0)).collect();
-
-    // Perform FFT
-    fft.process(&mut complex_input);
-
-    complex_input
-}
-
-fn ifft(input: &[Complex<f32>]) -> Vec<u8> {
-    let len = input.len();
-    let mut planner = FftPlanner::new();
-    let ifft = planner.plan_fft_inverse(len);
-
-    let mut output: Vec<Complex<f32>> = input.to_vec();
-
-    // Perform inverse FFT
-    ifft.process(&mut output);
-
-    // Extract real parts and scale
-    let mut time_domain_signal = output.iter().map(|&freq| freq.re).collect::<Vec<f32>>();
-
-    // Remove any residual imaginary part due to precision issues
-    for val in time_domain_signal.iter_mut() {
-        if val.abs() < 1e-6 {
-            *val = 0.0;
-        }
+/// Builds the usual channel mask for `count` channels: the first `count` positions,
+/// except 7.1 which has its surrounds at the sides.
+pub(crate) fn channels_for_count(count: usize) -> Channels {
+    if count == 8 {
+        return Layout::FivePointOne.into_channels() | Channels::SIDE_LEFT | Channels::SIDE_RIGHT;
     }
-
-    let interpolated_signal: Vec<f32> = time_domain_signal
-        .windows(2)
-        .map(|pair| {
-            let (x0, x1) = (pair[0], pair[1]);
-            smoothing(x0, x1, 0.2f32)
-        })
-        .collect();
-
-    let mut interleaved_bytes: Vec<u8> = Vec::new();
-
-    // Iterate through each complex number in the FFT result
-    for i in 0..interpolated_signal.len() {
-        // Every 2nd sample, sum the last two together and divide by two
-        if i % 2 == 0 && i + 1 < interpolated_signal.len() {
-            let freq1 = interpolated_signal[i];
-            let freq2 = interpolated_signal[i + 1];
-
-            // Calculate magnitude of the complex number
-            // let magnitude1 = (freq1.re.powi(2) + freq1.im.powi(2)).sqrt();
-            // let magnitude2 = (freq2.re.powi(2) + freq2.im.powi(2)).sqrt();
-            let summed = (((freq1 - freq2) * 0.8 / 2.0) + 128.0) as u8;
-            // info!("L: {}, R: {}, summed: {}", freq1, freq2, summed);
-
-            // Split the f32 into its individual bytes
-            // let bytes = summed.to_ne_bytes();
-            interleaved_bytes.push(summed);
-
-            // Interleave the bytes
-            // for &byte in bytes.iter() {
-            //     interleaved_bytes.push(byte);
-            // }
-        }
-    }
-
-    interleaved_bytes
+    Channels::from_bits_truncate(((1u64 << count) - 1) as u32)
 }
 
-fn smoothing(x0: f32, x1: f32, factor: f32) -> f32 {
-    x1 + ((x0 - x1) * factor)
+/// Maps a decoded buffer onto other output channels by speaker position, without the
+/// user's channel settings.
+pub fn remap_channels(decoded: &AudioBufferRef<'_>, out_channels: Channels) -> AudioBuffer<f32> {
+    ChannelMixer::new(out_channels, ChannelMixSettings::default()).mix(decoded)
 }
@@ -0,0 +1,225 @@
+//! The processing every output applies to decoded audio: the channel mix, the track gain,
+//! resampling (or the fades when there's none) and the playback rate, then the equalizer
+//! and volume as it's played. The sound card and the file output both run it, so a
+//! recording holds exactly what would have been heard.
+
+use log::info;
+use symphonia::core::audio::{AsAudioBufferRef, AudioBufferRef, SampleBuffer, Signal, SignalSpec};
+
+use crate::channel_mix::{ChannelMixSettings, ChannelMixer};
+use crate::equalizer::Equalizer;
+use crate::output::channels_for_count;
+use crate::resampler::Resampler;
+use crate::timestretch::TimeStretch;
+
+/// Decoded samples on their way to an output.
+pub struct Processed<'a> {
+    /// Interleaved, at the output's rate and channels
+    pub samples: &'a [f32],
+    /// Changed in any way from the decoded samples, so off the source's own steps
+    pub requantized: bool,
+    /// The playback rate they were stretched to
+    pub tempo: f64,
+}
+
+pub struct OutputStage {
+    sample_buf: SampleBuffer<f32>,
+    resampler: Option<Resampler<f32>>,
+    sample_rate: u32,
+    channels: usize,
+    gain: f32,
+    mixer: ChannelMixer,
+    time_stretch: TimeStretch,
+}
+
+impl OutputStage {
+    /// For an output of `spec`, taking packets of up to `max_frames` without growing.
+    pub fn new(spec: SignalSpec, max_frames: u64) -> Self {
+        let channels = spec.channels.count();
+        let spec = SignalSpec::new(spec.rate, channels_for_count(channels));
+        OutputStage {
+            sample_buf: SampleBuffer::new(max_frames, spec),
+            resampler: None,
+            sample_rate: spec.rate,
+            channels,
+            gain: 1.0,
+            mixer: ChannelMixer::new(spec.channels, ChannelMixSettings::default()),
+            time_stretch: TimeStretch::new(spec.rate, channels),
+        }
+    }
+
+    /// Takes a decoded packet to the output's rate and channels. None while the resampler
+    /// is still filling up.
+    pub fn process(
+        &mut self,
+        decoded: AudioBufferRef<'_>,
+        ramp_up_samples: u64,
+        ramp_down_samples: u64,
+    ) -> Option<Processed<'_>> {
+        let tempo = self.time_stretch.tempo();
+
+        // The output stays open across tracks, so mix the track's channels
+        // onto the output's channels if they differ, or the user wants them mixed.
+        // Then apply the track gain (ReplayGain), which comes before the user volume.
+        let processed;
+        let mixed = !self.mixer.is_passthrough(decoded.spec().channels) || self.gain != 1.0;
+        let requantized = mixed
+            || self.resampler.is_some()
+            || self.time_stretch.is_active()
+            || ramp_up_samples > 0
+            || ramp_down_samples > 0;
+        let decoded = if mixed {
+            let mut buffer = self.mixer.mix(&decoded);
+            if self.gain != 1.0 {
+                for ch in 0..self.channels {
+                    buffer.chan_mut(ch).iter_mut().for_each(|s| *s *= self.gain);
+                }
+            }
+            processed = buffer;
+            processed.as_audio_buffer_ref()
+        } else {
+            decoded
+        };
+
+        let sample_buf = &mut self.sample_buf;
+        let mut samples = if let Some(resampler) = &mut self.resampler {
+            // Resampling is required. The resampler will return interleaved samples.
+            resampler.resample(decoded)?
+        } else if ramp_up_samples > 0 {
+            info!("Ramping up first {:?}", ramp_up_samples);
+            ramp(sample_buf, decoded, ramp_up_samples as usize, true);
+            sample_buf.samples()
+        } else if ramp_down_samples > 0 {
+            info!("Ramping down last {:?}", ramp_down_samples);
+            ramp(sample_buf, decoded, ramp_down_samples as usize, false);
+            sample_buf.samples()
+        } else {
+            interleave(sample_buf, decoded);
+            sample_buf.samples()
+        };
+
+        // Change the speed and pitch here, so the output's callback doesn't have to
+        if self.time_stretch.is_active() {
+            samples = self.time_stretch.process(samples);
+        }
+        Some(Processed {
+            samples,
+            requantized,
+            tempo,
+        })
+    }
+
+    /// Resamples tracks of `spec` if their rate isn't the output's, and returns whether
+    /// they are. What a replaced resampler was still holding goes to `write` first, so the
+    /// end of the previous track isn't lost when the next one needs a different resampler.
+    pub fn update_resampler(
+        &mut self,
+        spec: SignalSpec,
+        max_frames: u64,
+        write: impl FnOnce(Processed<'_>),
+    ) -> bool {
+        // Channels are remapped before resampling
+        let spec = SignalSpec::new(spec.rate, channels_for_count(self.channels));
+
+        if self.sample_rate != spec.rate {
+            if let Some(resampler) = &self.resampler {
+                if resampler.accepts(&spec, max_frames) {
+                    info!("resampling {} Hz to {} Hz (continued)", spec.rate, self.sample_rate);
+                    return true;
+                }
+            }
+            info!("resampling {} Hz to {} Hz", spec.rate, self.sample_rate);
+            self.drain_resampler(write);
+            self.resampler
+                .replace(Resampler::new(spec, self.sample_rate as usize, max_frames));
+            true
+        } else {
+            self.drain_resampler(write);
+            self.resampler.take();
+            false
+        }
+    }
+
+    fn drain_resampler(&mut self, write: impl FnOnce(Processed<'_>)) {
+        let tempo = self.time_stretch.tempo();
+        if let Some(resampler) = &mut self.resampler {
+            if let Some(mut samples) = resampler.flush() {
+                if self.time_stretch.is_active() {
+                    samples = self.time_stretch.process(samples);
+                }
+                write(Processed {
+                    samples,
+                    requantized: true,
+                    tempo,
+                });
+            }
+        }
+    }
+
+    /// Drops whatever is held back, when the output is flushed.
+    pub fn flush(&mut self) {
+        // If there is a resampler, then it may need to be flushed
+        // depending on the number of samples it has.
+        if let Some(resampler) = &mut self.resampler {
+            while let Some(remaining_samples) = resampler.flush() {
+                info!("Flushed samples {:?}", remaining_samples.len());
+            }
+        }
+        self.sample_buf.clear();
+        self.time_stretch.reset();
+    }
+
+    pub fn set_gain(&mut self, gain: f32) {
+        if gain != self.gain {
+            info!("output: track gain {:.3}", gain);
+        }
+        self.gain = gain;
+    }
+
+    pub fn set_channel_mix(&mut self, settings: ChannelMixSettings) {
+        self.mixer.set_settings(settings);
+    }
+
+    pub fn set_playback_rate(&mut self, tempo: f64, semitones: f64) {
+        if self.time_stretch.set_rate(tempo, semitones) {
+            info!("output: playback rate {:.2}x, {:+} semitones", tempo, semitones);
+        }
+    }
+
+    pub fn ramp_down(&mut self, buffer: AudioBufferRef, num_samples: usize) {
+        ramp(&mut self.sample_buf, buffer, num_samples, false);
+    }
+
+    pub fn ramp_up(&mut self, buffer: AudioBufferRef, num_samples: usize) {
+        ramp(&mut self.sample_buf, buffer, num_samples, true);
+    }
+}
+
+/// Interleaves `buffer` into `sample_buf`, growing it for packets larger than any before.
+fn interleave(sample_buf: &mut SampleBuffer<f32>, buffer: AudioBufferRef<'_>) {
+    if sample_buf.capacity() < buffer.capacity() * buffer.spec().channels.count() {
+        *sample_buf = SampleBuffer::new(buffer.capacity() as u64, *buffer.spec());
+    }
+    sample_buf.copy_interleaved_ref(buffer);
+}
+
+/// Interleaves `buffer` into `sample_buf`, fading its first `num_samples` in or out.
+fn ramp(sample_buf: &mut SampleBuffer<f32>, buffer: AudioBufferRef, num_samples: usize, up: bool) {
+    interleave(sample_buf, buffer);
+    let ramp_len = num_samples.min(sample_buf.len());
+    for (i, sample) in sample_buf.samples_mut()[..ramp_len].iter_mut().enumerate() {
+        let factor = i as f32 / ramp_len as f32;
+        *sample *= if up { factor } else { 1.0 - factor };
+    }
+}
+
+/// Applies the equalizer, then the user volume, to interleaved samples. Outputs do this
+/// as the samples are played rather than in `OutputStage`, so changes are heard at once.
+pub fn equalize_and_set_volume(equalizer: &mut Equalizer, volume: f64, samples: &mut [f32]) {
+    equalizer.process(samples);
+    if volume != 1.0 {
+        for s in samples {
+            *s = (*s as f64 * volume) as f32;
+        }
+    }
+}
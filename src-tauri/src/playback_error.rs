@@ -0,0 +1,68 @@
+//! Failures that stop a track from playing, sent to the frontend as `playback_error`.
+
+use std::fmt;
+use std::io;
+
+use serde::Serialize;
+
+use crate::output::AudioOutputError;
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "kind", content = "reason", rename_all = "kebab-case")]
+pub enum PlaybackError {
+    FileNotFound(String),
+    Io(String),
+    /// Unknown format or codec
+    Unsupported(String),
+    /// The stream couldn't be read past a point
+    Corrupt(String),
+    Output(AudioOutputError),
+}
+
+impl fmt::Display for PlaybackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlaybackError::FileNotFound(reason) => write!(f, "file not found: {}", reason),
+            PlaybackError::Io(reason) => write!(f, "i/o error: {}", reason),
+            PlaybackError::Unsupported(reason) => write!(f, "unsupported: {}", reason),
+            PlaybackError::Corrupt(reason) => write!(f, "corrupt stream: {}", reason),
+            PlaybackError::Output(err) => write!(f, "audio output: {:?}", err),
+        }
+    }
+}
+
+impl From<io::Error> for PlaybackError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::NotFound => PlaybackError::FileNotFound(err.to_string()),
+            _ => PlaybackError::Io(err.to_string()),
+        }
+    }
+}
+
+impl From<symphonia::core::errors::Error> for PlaybackError {
+    fn from(err: symphonia::core::errors::Error) -> Self {
+        use symphonia::core::errors::Error;
+        match err {
+            Error::IoError(err) => PlaybackError::from(err),
+            Error::Unsupported(reason) => PlaybackError::Unsupported(reason.to_string()),
+            Error::DecodeError(reason) => PlaybackError::Corrupt(reason.to_string()),
+            err => PlaybackError::Corrupt(err.to_string()),
+        }
+    }
+}
+
+impl From<AudioOutputError> for PlaybackError {
+    fn from(err: AudioOutputError) -> Self {
+        PlaybackError::Output(err)
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PlaybackErrorEvent {
+    pub path: Option<String>,
+    pub error: PlaybackError,
+    /// The track played instead, when skipping on errors
+    pub skipped_to: Option<String>,
+}
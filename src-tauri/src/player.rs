@@ -9,7 +9,7 @@ use std::time::Instant;
 use std::collections::HashMap;
 use std::fs::File;
 use std::marker::PhantomData;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::AtomicU32;
 use std::sync::Arc;
 
@@ -17,16 +17,18 @@ use atomic_wait::wake_all;
 use cpal::traits::{DeviceTrait, HostTrait};
 use log::{error, info, warn};
 use serde::{Deserialize, Serialize};
-use symphonia::core::audio::{Layout, SampleBuffer, SignalSpec};
-use symphonia::core::codecs::{DecoderOptions, CODEC_TYPE_NULL};
+use symphonia::core::audio::{
+    AsAudioBufferRef, AudioBuffer, AudioBufferRef, Layout, SampleBuffer, Signal, SignalSpec,
+};
+use symphonia::core::codecs::{Decoder, DecoderOptions, CODEC_TYPE_NULL};
+use symphonia::core::errors::end_of_stream_error;
 use symphonia::core::errors::Error::ResetRequired;
-use symphonia::core::formats::{FormatOptions, SeekTo, Track};
-use symphonia::core::io::MediaSourceStream;
+use symphonia::core::formats::{FormatOptions, FormatReader, Packet, SeekTo, Track};
+use symphonia::core::io::{MediaSource, MediaSourceStream};
 use symphonia::core::meta::MetadataOptions;
 use symphonia::core::probe::Hint;
 use symphonia::core::units::Time;
-use symphonia::default::get_probe;
-use tauri::{AppHandle, Emitter, State};
+use tauri::{AppHandle, Emitter, Manager, State};
 use tokio::sync::Mutex;
 use tokio::time::Duration;
 
@@ -43,10 +45,23 @@ use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
 use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
 use webrtc::peer_connection::RTCPeerConnection;
 
+use crate::channel_mix::ChannelMixSettings;
+use crate::crossfade::{Crossfade, CrossfadeCurve};
+use crate::dither::DitherSettings;
+use crate::equalizer::EqualizerSettings;
+use crate::file_output::{FileOutputOptions, FILE_OUTPUT_DEVICE};
+use crate::looping::{LoopAction, RegionLoop};
+use crate::metadata::{read_replay_gain_from_path, ReplayGainMode};
 use crate::output::{self, get_device_by_name, AudioOutput};
+use crate::playback_error::{PlaybackError, PlaybackErrorEvent};
+use crate::player_state::{LoopRegionState, PlaybackStatus, PlayerState, PlayerStateHandle};
+use crate::queue::PlayQueue;
+use crate::radio::is_stream_url;
+use crate::spectrum::SpectrumSettings;
 use crate::store::load_settings;
+use crate::waveform_cache::SAMPLES_PER_PEAK;
 use crate::{
-    dsp, GetWaveformRequest, GetWaveformResponse, SampleOffsetEvent, StreamFileRequest,
+    dsp, EqualizerControlEvent, GetWaveformRequest, SampleOffsetEvent, StreamFileRequest,
     VolumeControlEvent,
 };
 
@@ -55,6 +70,31 @@ pub struct LoopRegionRequest {
     enabled: Option<bool>,
     start_pos: Option<f64>,
     end_pos: Option<f64>,
+    crossfade: Option<f64>, // ms, at the loop seam
+    count: Option<u32>,     // passes before carrying on, 0 or None to loop forever
+}
+
+impl LoopRegionRequest {
+    /// The region to loop, or None to stop looping. An enabled region without its bounds
+    /// is an error, and ignored.
+    fn region(&self) -> Result<Option<(f64, f64)>, &'static str> {
+        match (self.enabled, self.start_pos, self.end_pos) {
+            (Some(true), Some(start), Some(end)) => Ok(Some((start, end))),
+            (Some(true), _, _) => Err("loop region without its bounds"),
+            _ => Ok(None),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PlaybackRateRequest {
+    rate: Option<f64>,  // 0.5 to 2.0
+    pitch: Option<f64>, // semitones, -12 to 12
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MetersRequest {
+    enabled: bool,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -68,6 +108,8 @@ pub enum PlayerControlEvent {
     StreamFile(StreamFileRequest), // path, seekpos
     LoopRegion(LoopRegionRequest),
     ChangeAudioDevice(ChangeAudioDeviceRequest),
+    PlaybackRate(PlaybackRateRequest),
+    Meters(MetersRequest),
 }
 
 #[tauri::command]
@@ -82,6 +124,32 @@ pub fn loop_region(
         .send(PlayerControlEvent::LoopRegion(event));
 }
 
+#[tauri::command]
+pub fn playback_rate(
+    event: PlaybackRateRequest,
+    state: State<AudioStreamer>,
+    _app_handle: tauri::AppHandle,
+) {
+    info!("Playback rate {:?}", event);
+    let _ = state
+        .player_control_sender
+        .send(PlayerControlEvent::PlaybackRate(event));
+}
+
+/// Turns the level meters on or off while playing, when the Artist's Toolkit is.
+#[tauri::command]
+pub fn meters(event: MetersRequest, state: State<AudioStreamer>) {
+    info!("Meters {:?}", event);
+    let _ = state
+        .player_control_sender
+        .send(PlayerControlEvent::Meters(event));
+}
+
+#[tauri::command]
+pub fn get_player_state(state: State<AudioStreamer>) -> PlayerState {
+    state.player_state.snapshot()
+}
+
 #[tauri::command]
 pub fn change_audio_device(
     event: ChangeAudioDeviceRequest,
@@ -105,50 +173,57 @@ pub struct AudioStreamer<'a> {
     pub peer_connection: Arc<Mutex<Option<Arc<RTCPeerConnection>>>>,
     pub data_channel: Arc<Mutex<Option<Arc<RTCDataChannel>>>>,
     pub cancel_tokens: Arc<Mutex<HashMap<String, CancellationToken>>>,
+    /// Taken from synchronous commands, so not the async mutex of the others
+    pub loudness_cancel_token: Arc<std::sync::Mutex<Option<CancellationToken>>>,
     phantom: PhantomData<&'a RTCPeerConnection>,
     phantom2: PhantomData<&'a RTCDataChannel>,
     pub player_control_receiver: Arc<Mutex<Receiver<PlayerControlEvent>>>,
     pub player_control_sender: Sender<PlayerControlEvent>,
-    pub next_track_receiver: Arc<Mutex<Receiver<StreamFileRequest>>>,
-    pub next_track_sender: Sender<StreamFileRequest>,
+    pub queue: Arc<std::sync::Mutex<PlayQueue>>,
     pub decoding_active: Arc<AtomicU32>,
     pub volume_control_receiver: Arc<Mutex<Receiver<VolumeControlEvent>>>,
     pub volume_control_sender: Sender<VolumeControlEvent>,
+    pub equalizer_control_receiver: Arc<Mutex<Receiver<EqualizerControlEvent>>>,
+    pub equalizer_control_sender: Sender<EqualizerControlEvent>,
+    pub player_state: PlayerStateHandle,
 }
 
 impl<'a> AudioStreamer<'a> {
     pub fn create() -> Result<AudioStreamer<'a>, Box<dyn std::error::Error + Send + Sync>> {
         let (sender_vol, receiver_vol) = std::sync::mpsc::channel();
+        let (sender_eq, receiver_eq) = std::sync::mpsc::channel();
 
         // set up message passing
         let (sender_tx, receiver_rx): (Sender<PlayerControlEvent>, Receiver<PlayerControlEvent>) =
             std::sync::mpsc::channel();
 
-        let (sender_next, receiver_next): (Sender<StreamFileRequest>, Receiver<StreamFileRequest>) =
-            std::sync::mpsc::channel();
-
         Ok(AudioStreamer {
             peer_connection: Arc::new(Mutex::new(None)),
             data_channel: Arc::new(Mutex::new(None)),
             cancel_tokens: Arc::new(Mutex::new(HashMap::new())),
+            loudness_cancel_token: Arc::new(std::sync::Mutex::new(None)),
             phantom: PhantomData,
             phantom2: PhantomData,
             player_control_receiver: Arc::new(Mutex::new(receiver_rx)),
             player_control_sender: sender_tx,
-            next_track_receiver: Arc::new(Mutex::new(receiver_next)),
-            next_track_sender: sender_next,
+            queue: Arc::new(std::sync::Mutex::new(PlayQueue::default())),
             decoding_active: Arc::new(AtomicU32::new(ACTIVE)),
             volume_control_receiver: Arc::new(Mutex::new(receiver_vol)),
             volume_control_sender: sender_vol,
+            equalizer_control_receiver: Arc::new(Mutex::new(receiver_eq)),
+            equalizer_control_sender: sender_eq,
+            player_state: PlayerStateHandle::default(),
         })
     }
 
     pub fn init(&self, app_handle: AppHandle) -> () {
         let receiver = self.player_control_receiver.clone();
-        let next_track_receiver = self.next_track_receiver.clone();
+        let queue = self.queue.clone();
         let decoding_active = self.decoding_active.clone();
         let volume_control_receiver = self.volume_control_receiver.clone();
+        let equalizer_control_receiver = self.equalizer_control_receiver.clone();
         let data_channel = self.data_channel.clone();
+        let player_state = self.player_state.clone();
 
         std::thread::spawn(move || {
             // AUDIO THREAD!
@@ -157,9 +232,11 @@ impl<'a> AudioStreamer<'a> {
             start_audio(
                 &decoding_active,
                 &volume_control_receiver,
+                &equalizer_control_receiver,
                 &receiver,
-                &next_track_receiver,
+                queue,
                 data_channel,
+                player_state,
                 &app_handle,
             );
         });
@@ -339,9 +416,11 @@ impl<'a> AudioStreamer<'a> {
 pub fn start_audio(
     decoding_active: &Arc<AtomicU32>,
     volume_control_receiver: &Arc<Mutex<Receiver<VolumeControlEvent>>>,
+    equalizer_control_receiver: &Arc<Mutex<Receiver<EqualizerControlEvent>>>,
     player_control_receiver: &Arc<Mutex<Receiver<PlayerControlEvent>>>,
-    next_track_receiver: &Arc<Mutex<Receiver<StreamFileRequest>>>,
+    queue: Arc<std::sync::Mutex<PlayQueue>>,
     data_channel: Arc<Mutex<Option<Arc<RTCDataChannel>>>>,
+    player_state: PlayerStateHandle,
     app_handle: &AppHandle,
 ) {
     let decoding_active = decoding_active.clone();
@@ -353,33 +432,41 @@ pub fn start_audio(
 
     decode_loop(
         vol_receiver,
+        equalizer_control_receiver.clone(),
         player_control_receiver,
-        next_track_receiver,
+        queue,
         decoding_active,
         data_channel,
+        player_state,
         app_handle,
     );
 }
 
 fn decode_loop(
     volume_control_receiver: Arc<Mutex<Receiver<VolumeControlEvent>>>,
+    equalizer_control_receiver: Arc<Mutex<Receiver<EqualizerControlEvent>>>,
     player_control_receiver: &Arc<Mutex<Receiver<PlayerControlEvent>>>,
-    next_track_receiver: &Arc<Mutex<Receiver<StreamFileRequest>>>,
+    queue: Arc<std::sync::Mutex<PlayQueue>>,
     decoding_active: Arc<AtomicU32>,
     data_channel: Arc<Mutex<Option<Arc<RTCDataChannel>>>>,
+    player_state: PlayerStateHandle,
     app_handle: &AppHandle,
 ) {
     // These will be reset when changing tracks
     let mut path_str: Option<String> = None;
     let mut path_str_clone: Option<String>;
     let mut seek = None;
+    let mut resume_at: Option<f64> = None; // restart here, without changing the loop region
     let mut end_pos = None; // for loop region
+    let mut loop_crossfade = 0.0;
+    let mut loop_count = None;
+    let mut tempo = 1.0;
+    let mut semitones = 0.0;
     let mut volume = None;
     let mut audio_device_name = None;
     let mut previous_audio_device_name: String = String::new();
 
     let mut previous_sample_rate = 44100;
-    let mut previous_channels = 2;
 
     let (playback_state_sender, playback_state_receiver) = std::sync::mpsc::channel();
     let (reset_control_sender, reset_control_receiver) = std::sync::mpsc::channel();
@@ -398,6 +485,7 @@ fn decode_loop(
 
     let mut is_transition = false; // This is set to speed up decoding during transition (last 5s)
     let mut is_reset = true; // Whether the playback has been 'reset' (i.e double click on new track, next btn)
+    let mut failed_tracks = 0; // in a row, to stop skipping when nothing in the queue plays
 
     // Loop here!
     loop {
@@ -413,110 +501,129 @@ fn decode_loop(
                 match result {
                     PlayerControlEvent::StreamFile(request) => {
                         info!("audio: got file request! {:?}", request);
-                        path_str.replace(request.path.unwrap());
-                        seek.replace(request.seek.unwrap());
-                        volume.replace(request.volume.unwrap());
+                        let Some(path) = request.path else {
+                            warn!("audio: file request without a path, ignored");
+                            continue;
+                        };
+                        path_str.replace(path);
+                        seek.replace(request.seek.unwrap_or(0.0));
+                        if let Some(request_volume) = request.volume {
+                            volume.replace(request_volume);
+                        }
                         audio_device_name = request.output_device;
                     }
                     PlayerControlEvent::LoopRegion(request) => {
                         info!("audio: loop region! {:?}", request);
-                        path_str.replace(path_str_clone.unwrap());
-                        seek.replace(request.start_pos.unwrap());
-                        end_pos.replace(request.end_pos.unwrap());
-                        cancel_token.cancel();
+                        match (path_str_clone.clone(), request.region()) {
+                            (Some(path), Ok(Some((start, end)))) => {
+                                path_str.replace(path);
+                                seek.replace(start);
+                                end_pos.replace(end);
+                                loop_crossfade = request.crossfade.unwrap_or(0.0);
+                                loop_count = request.count;
+                                cancel_token.cancel();
+                            }
+                            (_, Err(err)) => warn!("audio: {}, ignored", err),
+                            _ => warn!("audio: loop region with nothing loaded, ignored"),
+                        }
                     }
                     PlayerControlEvent::ChangeAudioDevice(request) => {
                         info!("audio: change audio device! {:?}", request);
                         audio_device_name = request.audio_device;
                         if path_str_clone.is_some() && path_str.is_some() {
-                            path_str.replace(path_str_clone.clone().unwrap());
+                            path_str = path_str_clone.clone();
                         }
                         cancel_token.cancel();
                         is_reset = true;
                     }
+                    PlayerControlEvent::PlaybackRate(request) => {
+                        info!("audio: playback rate! {:?}", request);
+                        tempo = request.rate.unwrap_or(tempo);
+                        semitones = request.pitch.unwrap_or(semitones);
+                    }
+                    // The next track reads the setting when it opens
+                    PlayerControlEvent::Meters(_) => {}
                 }
             }
         } else if let Some(ref p) = path_str.clone() {
-            let path = Path::new(p.as_str());
-
-            // Create a hint to help the format registry guess what format reader is appropriate.
-            let mut hint = Hint::new();
-            let source = Box::new(File::open(path).unwrap());
-            info!("source {:?}", source);
-
-            // Provide the file extension as a hint.
-            info!("extension: {:?}", path.extension());
-            if let Some(extension) = path.extension() {
-                if let Some(extension_str) = extension.to_str() {
-                    hint.with_extension(extension_str);
-                }
-            }
-
-            // Create the media source stream using the boxed media source from above.
-            let mss = MediaSourceStream::new(source, Default::default());
-
-            // Use the default options for format readers other than for gapless playback.
-            let format_opts = FormatOptions {
-                enable_gapless: true,
-                ..Default::default()
-            };
-
-            // Use the default options for metadata readers.
-            let metadata_opts: MetadataOptions = MetadataOptions {
-                limit_metadata_bytes: symphonia::core::meta::Limit::Maximum(50),
-                limit_visual_bytes: symphonia::core::meta::Limit::Maximum(0),
-            };
-
-            // Get the value of the track option, if provided.
-            info!("probing {:?}", hint);
-            info!("opts {:?}", format_opts);
-            info!("meta {:?}", metadata_opts);
-
-            let probe_result = get_probe().format(&hint, mss, &format_opts, &metadata_opts);
-            info!("probe format {:?}", probe_result.is_ok());
-
-            if probe_result.is_err() {
-                path_str = None;
-                continue;
-            }
-
-            info!("Resetting path_str");
             path_str = None;
+            // A track from a CUE sheet plays part of its album's file
+            let bounds = crate::cue::track_bounds(p);
+            let path = bounds
+                .as_ref()
+                .map_or(Path::new(p.as_str()), |bounds| bounds.file.as_path());
+            // Live streams can't seek, so can't loop a region either
+            let is_live = is_stream_url(p);
+            if is_live {
+                end_pos = None;
+            }
 
-            let mut reader = probe_result.unwrap().format;
-
-            let track = reader.default_track().unwrap().clone();
+            let (mut reader, mut decoder, track, spec) =
+                match open_for_playback(path, &player_state, app_handle) {
+                    Ok(opened) => opened,
+                    Err(err) => {
+                        failed_tracks += 1;
+                        path_str = report_playback_error(
+                            p,
+                            err,
+                            failed_tracks,
+                            !is_transition,
+                            &queue,
+                            &player_state,
+                            app_handle,
+                        );
+                        seek = None;
+                        end_pos = None;
+                        continue;
+                    }
+                };
 
-            if let Some(frames) = track.codec_params.n_frames {
+            // Positions in the UI are from the start of the track, not of the file
+            let start = bounds.as_ref().map_or(0.0, |bounds| bounds.start);
+            let start_ts = (start * spec.rate as f64) as u64;
+            let end_ts = bounds
+                .as_ref()
+                .and_then(|bounds| bounds.end)
+                .map(|end| (end * spec.rate as f64) as u64)
+                .or(track.codec_params.n_frames);
+            let n_frames = end_ts.map(|end| end.saturating_sub(start_ts));
+
+            if let Some(frames) = n_frames {
                 let _ = app_handle.emit("file-samples", frames);
             }
 
             let mut track_id = track.id;
 
             // If seeking, seek the reader to the time or timestamp specified and get the timestamp of the
-            // seeked position. All packets with a timestamp < the seeked position will not be played.
-            //
-            // Note: This is a half-baked approach to seeking! After seeking the reader, packets should be
-            // decoded and *samples* discarded up-to the exact *sample* indicated by required_ts. The
-            // current approach will discard excess samples if seeking to a sample within a packet.
-            let seek_ts = if let Some(sk) = seek {
+            // seeked position. Packets before the seeked position are decoded (to prime the decoder)
+            // but not played, and the packet containing the seeked position is trimmed so that
+            // playback starts at the exact sample indicated by required_ts.
+            // A track from a CUE sheet always needs a seek, to its start
+            let seek_to_time = resume_at
+                .take()
+                .or(seek)
+                .or(bounds.as_ref().map(|_| 0.0))
+                .filter(|_| !is_live);
+            let mut seek_ts = if let Some(sk) = seek_to_time {
                 let seek_to = SeekTo::Time {
-                    time: Time::from(sk),
+                    time: Time::from(sk + start),
                     track_id: Some(track_id),
                 };
 
-                // Attempt the seek. If the seek fails, ignore the error and return a seek timestamp of 0 so
-                // that no samples are trimmed.
+                // Attempt the seek. If the seek fails, decode from the start and discard everything
+                // before the position, which is slow but lands in the right place; a CUE track
+                // would otherwise play its album from the top.
                 match reader.seek(symphonia::core::formats::SeekMode::Accurate, seek_to) {
                     Ok(seeked_to) => seeked_to.required_ts,
                     Err(ResetRequired) => {
-                        track_id = first_supported_track(reader.tracks()).unwrap().id;
+                        if let Some(track) = first_supported_track(reader.tracks()) {
+                            track_id = track.id;
+                        }
                         0
                     }
                     Err(err) => {
-                        // Don't give-up on a seek error.
-                        warn!("seek error: {}", err);
-                        0
+                        warn!("seek error: {}, decoding up to the position instead", err);
+                        ((sk + start) * spec.rate as f64) as u64
                     }
                 }
             } else {
@@ -526,16 +633,6 @@ fn decode_loop(
 
             info!("codec params: {:?}", &track.codec_params);
 
-            // Create a decoder for the track.
-            let mut decoder = symphonia::default::get_codecs()
-                .make(&track.codec_params, &DecoderOptions { verify: false })
-                .unwrap();
-
-            let spec = SignalSpec {
-                rate: decoder.codec_params().sample_rate.unwrap(),
-                channels: decoder.codec_params().channels.unwrap(),
-            };
-
             let mut should_reset_audio = false;
             let mut new_duration = 1152;
 
@@ -547,17 +644,89 @@ fn decode_loop(
 
             // Check if audio device changed
             let mut follow_system_output = false;
+            let mut native_rate_switching = false;
+            let mut crossfade_enabled = false;
+            let mut crossfade_duration = 0f64;
+            let mut crossfade_curve = CrossfadeCurve::default();
+            let mut replay_gain_mode = ReplayGainMode::default();
+            let mut replay_gain_preamp = 0f64;
+            let mut replay_gain_prevent_clipping = true;
+            let mut equalizer = EqualizerSettings::default();
+            let mut file_output_path = None;
+            let mut file_output_realtime = true;
+            let mut channel_mix = ChannelMixSettings::default();
+            let mut spectrum = SpectrumSettings::default();
+            let mut meters = false;
+            let mut dither = DitherSettings {
+                enabled: true,
+                noise_shaping: false,
+            };
             if let Ok(settings) = load_settings(app_handle) {
                 audio_device_name = settings.output_device;
                 follow_system_output = settings.follow_system_output;
+                native_rate_switching = settings.native_rate_switching;
+                crossfade_enabled = settings.crossfade_enabled;
+                crossfade_duration = settings.crossfade_duration;
+                crossfade_curve = settings.crossfade_curve;
+                replay_gain_mode = settings.replay_gain_mode;
+                replay_gain_preamp = settings.replay_gain_preamp;
+                replay_gain_prevent_clipping = settings.replay_gain_prevent_clipping;
+                equalizer = settings.equalizer;
+                file_output_path = settings.file_output_path;
+                file_output_realtime = settings.file_output_realtime;
+                channel_mix = settings.channel_mix;
+                spectrum = settings.spectrum;
+                meters = settings.is_artists_toolkit_enabled;
+                dither = DitherSettings {
+                    enabled: settings.dither,
+                    noise_shaping: settings.noise_shaping,
+                };
             }
-            let output_device = output::get_device_by_name(if follow_system_output {
+            let replay_gain_for = |path: &Path| -> f32 {
+                read_replay_gain_from_path(path)
+                    .map(|rg| {
+                        rg.linear_gain(
+                            replay_gain_mode,
+                            replay_gain_preamp,
+                            replay_gain_prevent_clipping,
+                        )
+                    })
+                    .unwrap_or(1.0)
+            };
+            let track_gain = replay_gain_for(path);
+            // The file output isn't a cpal device, there's nothing to look up
+            let use_file_output = !follow_system_output
+                && audio_device_name.as_deref() == Some(FILE_OUTPUT_DEVICE);
+            let output_device = if use_file_output {
                 None
             } else {
-                audio_device_name.clone()
-            });
+                output::get_device_by_name(if follow_system_output {
+                    None
+                } else {
+                    audio_device_name.clone()
+                })
+            };
 
-            let device_name = output_device.clone().unwrap().name().unwrap();
+            let device_name = match output_device
+                .as_ref()
+                .and_then(|d| d.name().ok())
+                .or_else(|| use_file_output.then(|| String::from(FILE_OUTPUT_DEVICE)))
+            {
+                Some(name) => name,
+                None => {
+                    failed_tracks += 1;
+                    report_playback_error(
+                        p,
+                        PlaybackError::Output(output::AudioOutputError::DeviceNotFoundError),
+                        failed_tracks,
+                        false,
+                        &queue,
+                        &player_state,
+                        app_handle,
+                    );
+                    continue;
+                }
+            };
             // If we have a default audio device (we always should, but just in case)
             // we check if the track spec differs from the output device
             // if it does - resample the decoded audio using Symphonia.
@@ -597,41 +766,106 @@ fn decode_loop(
                         "failed to get audio output device config: {}",
                         supported_output_configs.err().unwrap()
                     );
-                    device = get_device_by_name(None).unwrap();
+                    if let Some(default_device) = get_device_by_name(None) {
+                        device = default_device;
+                    }
                 }
-                // If sample rate or channels changed - reinit the audio device with the new spec
-                // (if this sample rate isn't supported, it will be resampled)
-                should_reset_audio = previous_audio_device_name != device.name().unwrap()
-                    || supports_sample_rate && spec.rate != previous_sample_rate
-                    || spec.channels.count() != previous_channels;
+                // If the sample rate changed and the user wants the device to follow the file -
+                // reinit the audio device with the new spec. Otherwise the stream stays open and
+                // the new track is resampled and its channels remapped, keeping it gapless.
+                should_reset_audio = device
+                    .name()
+                    .map_or(true, |name| name != previous_audio_device_name)
+                    || native_rate_switching
+                        && supports_sample_rate
+                        && spec.rate != previous_sample_rate;
+            } else {
+                should_reset_audio = device_name != previous_audio_device_name;
             }
 
             previous_sample_rate = spec.rate;
-            previous_channels = spec.channels.count();
             previous_audio_device_name = device_name.clone();
 
             if audio_output.is_none() || should_reset_audio {
                 info!("player: Resetting audio device");
+
+                // Let the previous track finish playing before closing its stream
+                if is_transition {
+                    if let Some(Ok(ref ao)) = audio_output {
+                        while ao
+                            .try_lock()
+                            .map(|guard| guard.has_remaining_samples())
+                            .unwrap_or(false)
+                        {
+                            thread::sleep(Duration::from_millis(10));
+                        }
+                    }
+                }
+
                 // Try to open the audio output.
 
                 audio_output = Some(output::try_open(
                     &previous_audio_device_name,
                     spec,
                     volume_control_receiver.clone(),
+                    equalizer_control_receiver.clone(),
                     sample_offset_receiver.clone(),
                     playback_state.clone(),
                     reset_control.clone(),
                     device_change.clone(),
                     data_channel.clone(),
                     volume.clone(),
+                    equalizer,
+                    native_rate_switching,
+                    FileOutputOptions {
+                        path: file_output_path
+                            .map(PathBuf::from)
+                            .unwrap_or_else(|| default_file_output_path(app_handle)),
+                        realtime: file_output_realtime,
+                    },
                     app_handle.clone(),
                 ));
             } else {
                 info!("player: Re-using existing audio output");
             }
 
+            let output_error = match &audio_output {
+                Some(Err(err)) => Some(err.clone()),
+                _ => None,
+            };
+            if let Some(err) = output_error {
+                // Try opening it again next time
+                audio_output = None;
+                failed_tracks += 1;
+                report_playback_error(
+                    p,
+                    PlaybackError::Output(err),
+                    failed_tracks,
+                    false,
+                    &queue,
+                    &player_state,
+                    app_handle,
+                );
+                continue;
+            }
+
             let mut last_sent_time;
 
+            // The output counts interleaved samples at its own sample rate and channel count,
+            // which may differ from the file's if we're resampling or remapping channels.
+            let (output_sample_rate, output_channels) = match audio_output {
+                Some(Ok(ref ao)) => ao
+                    .try_lock()
+                    .map(|guard| {
+                        player_state.set_output_position(guard.get_position_handle());
+                        player_state
+                            .update(|state| state.output_format = Some(guard.get_sample_format()));
+                        (guard.get_sample_rate(), guard.get_channels())
+                    })
+                    .unwrap_or((spec.rate, spec.channels.count())),
+                _ => (spec.rate, spec.channels.count()),
+            };
+
             if !is_transition {
                 let clone_device_name = device_name.clone();
                 let clone_device_name2 = device_name.clone();
@@ -639,17 +873,63 @@ fn decode_loop(
                 let _ = device_change_sender.send(clone_device_name);
                 let _ = app_handle.emit("audio_device_changed", clone_device_name2);
                 let _ = sender_sample_offset.send(SampleOffsetEvent {
-                    sample_offset: Some(
-                        seek_ts * track.codec_params.channels.unwrap().count() as u64,
-                    ),
+                    sample_offset: Some(to_output_sample_offset(
+                        seek_ts.saturating_sub(start_ts),
+                        &spec,
+                        output_sample_rate,
+                        output_channels,
+                    )),
                 });
             }
 
-            let end_pos_frame_idx = if end_pos.is_some() {
-                (end_pos.unwrap() * track.codec_params.sample_rate.unwrap() as f64) as u64
+            // Loop the region in place, seeking back to the start when we reach the end
+            let mut region_loop = end_pos.map(|end| {
+                RegionLoop::new(
+                    ((seek.unwrap_or(0.0) + start) * spec.rate as f64) as u64,
+                    ((end + start) * spec.rate as f64) as u64,
+                    (loop_crossfade.clamp(0.0, 500.0) / 1000.0 * spec.rate as f64) as usize,
+                    loop_count,
+                )
+            });
+
+            // The track fields only change once the track is heard, which is later
+            // if it's queued after the current one
+            let set_track_state = |state: &mut PlayerState| {
+                state.path = Some(p.clone());
+                state.live = is_live;
+                if !is_live {
+                    state.stream_title = None;
+                }
+                state.duration = n_frames.map(|frames| frames as f64 / spec.rate as f64);
+                state.sample_rate = Some(spec.rate);
+            };
+            player_state.update_and_emit(app_handle, |state| {
+                if !is_transition {
+                    set_track_state(state);
+                }
+                state.status = PlaybackStatus::Playing;
+                state.volume = volume;
+                state.device = Some(device_name.clone());
+                state.output_sample_rate = Some(output_sample_rate);
+                state.output_channels = Some(output_channels);
+                state.loop_region = end_pos.map(|end| LoopRegionState {
+                    start_pos: seek.unwrap_or(0.0),
+                    end_pos: end,
+                    crossfade: loop_crossfade,
+                    count: loop_count,
+                });
+            });
+
+            // Crossfade into the next track, unless we're looping a region
+            let crossfade_frames =
+                (crossfade_duration.clamp(1.0, 12.0) * spec.rate as f64) as u64;
+            let crossfade_start_ts = if crossfade_enabled && end_pos.is_none() {
+                end_ts.map(|frames| frames.saturating_sub(crossfade_frames))
             } else {
-                0
+                None
             };
+            let mut crossfade: Option<Crossfade> = None;
+            let mut crossfade_checked = false;
 
             let receiver = player_control_receiver.try_lock().unwrap();
 
@@ -662,6 +942,16 @@ fn decode_loop(
                         // Resampling stuff
                         guard.resume();
                         guard.update_resampler(spec, new_duration);
+                        guard.set_gain(track_gain);
+                        guard.set_channel_mix(channel_mix);
+                        guard.set_spectrum(spectrum);
+                        guard.set_meters(meters);
+                        // Same as FileInfo.bit_depth, lossy formats decode to float
+                        guard.set_dither(
+                            track.codec_params.bits_per_sample.unwrap_or(32),
+                            dither,
+                        );
+                        guard.set_playback_rate(tempo, semitones);
 
                         // Until all samples have been flushed - don't start decoding
                         // Keep checking until all samples have been played (buffer is empty)
@@ -684,36 +974,73 @@ fn decode_loop(
                                             "audio: source changed during decoding! {:?}",
                                             request
                                         );
-                                        path_str.replace(request.path.unwrap());
-                                        seek.replace(request.seek.unwrap());
-                                        end_pos = None;
-                                        volume.replace(request.volume.unwrap());
-                                        cancel_token.cancel();
-                                        guard.flush();
-                                        is_reset = true;
+                                        match request.path {
+                                            Some(path) => {
+                                                path_str.replace(path);
+                                                seek.replace(request.seek.unwrap_or(0.0));
+                                                end_pos = None;
+                                                if let Some(request_volume) = request.volume {
+                                                    volume.replace(request_volume);
+                                                }
+                                                cancel_token.cancel();
+                                                guard.flush();
+                                                is_reset = true;
+                                            }
+                                            None => warn!("audio: file request without a path, ignored"),
+                                        }
                                     }
                                     PlayerControlEvent::LoopRegion(request) => {
                                         info!("audio: loop region! {:?}", request);
-                                        if request.enabled.unwrap() {
-                                            seek.replace(request.start_pos.unwrap());
-                                            end_pos.replace(request.end_pos.unwrap());
-                                        } else {
-                                            end_pos = None;
+                                        match request.region() {
+                                            Ok(region) => {
+                                                if let Some((start, end)) = region {
+                                                    seek.replace(start);
+                                                    end_pos.replace(end);
+                                                    loop_crossfade = request.crossfade.unwrap_or(0.0);
+                                                    loop_count = request.count;
+                                                } else {
+                                                    end_pos = None;
+                                                }
+                                                path_str = path_str_clone.clone();
+                                                cancel_token.cancel();
+                                                guard.flush();
+                                                is_reset = true;
+                                            }
+                                            Err(err) => warn!("audio: {}, ignored", err),
                                         }
-                                        path_str.replace(path_str_clone.clone().unwrap());
-                                        cancel_token.cancel();
-                                        guard.flush();
-                                        is_reset = true;
                                     }
                                     PlayerControlEvent::ChangeAudioDevice(request) => {
                                         info!("audio: change audio device! {:?}", request);
                                         audio_device_name = request.audio_device;
-                                        path_str.replace(path_str_clone.clone().unwrap());
+                                        path_str = path_str_clone.clone();
                                         cancel_token.cancel();
                                         guard.flush();
                                         guard.pause();
                                         is_reset = true;
                                     }
+                                    PlayerControlEvent::Meters(request) => {
+                                        info!("audio: meters! {:?}", request);
+                                        guard.set_meters(request.enabled);
+                                    }
+                                    PlayerControlEvent::PlaybackRate(request) => {
+                                        info!("audio: playback rate! {:?}", request);
+                                        tempo = request.rate.unwrap_or(tempo);
+                                        semitones = request.pitch.unwrap_or(semitones);
+                                        if is_transition {
+                                            // What's playing, and so the position, is still
+                                            // the previous track's end. Stretch this one from
+                                            // here instead, after that end has played out.
+                                            guard.set_playback_rate(tempo, semitones);
+                                        } else {
+                                            // The ring buffer holds a few seconds at the old
+                                            // rate, so carry on from what's playing now instead.
+                                            resume_at.replace(guard.get_position());
+                                            path_str = path_str_clone.clone();
+                                            cancel_token.cancel();
+                                            guard.flush();
+                                            is_reset = true;
+                                        }
+                                    }
                                 }
                             }
 
@@ -725,6 +1052,9 @@ fn decode_loop(
                                 guard.pause();
                                 let _ = playback_state_sender.send(false);
                                 let _ = app_handle.emit("paused", {});
+                                player_state.update_and_emit(app_handle, |state| {
+                                    state.status = PlaybackStatus::Paused
+                                });
                             }
 
                             // waits while the value is PAUSED (0)
@@ -739,31 +1069,45 @@ fn decode_loop(
                                                 "audio: source changed during decoding! {:?}",
                                                 request
                                             );
-                                            path_str.replace(request.path.unwrap());
-                                            seek.replace(request.seek.unwrap());
-                                            end_pos = None;
-                                            volume.replace(request.volume.unwrap());
-                                            cancel_token.cancel();
-                                            guard.flush();
-                                            is_reset = true;
+                                            match request.path {
+                                                Some(path) => {
+                                                    path_str.replace(path);
+                                                    seek.replace(request.seek.unwrap_or(0.0));
+                                                    end_pos = None;
+                                                    if let Some(request_volume) = request.volume {
+                                                        volume.replace(request_volume);
+                                                    }
+                                                    cancel_token.cancel();
+                                                    guard.flush();
+                                                    is_reset = true;
+                                                }
+                                                None => warn!("audio: file request without a path, ignored"),
+                                            }
                                         }
                                         PlayerControlEvent::LoopRegion(request) => {
                                             info!("audio: loop region! {:?}", request);
-                                            if request.enabled.unwrap() {
-                                                seek.replace(request.start_pos.unwrap());
-                                                end_pos.replace(request.end_pos.unwrap());
-                                            } else {
-                                                end_pos = None;
+                                            match request.region() {
+                                                Ok(region) => {
+                                                    if let Some((start, end)) = region {
+                                                        seek.replace(start);
+                                                        end_pos.replace(end);
+                                                        loop_crossfade = request.crossfade.unwrap_or(0.0);
+                                                        loop_count = request.count;
+                                                    } else {
+                                                        end_pos = None;
+                                                    }
+                                                    path_str = path_str_clone.clone();
+                                                    cancel_token.cancel();
+                                                    guard.flush();
+                                                    is_reset = true;
+                                                }
+                                                Err(err) => warn!("audio: {}, ignored", err),
                                             }
-                                            path_str.replace(path_str_clone.clone().unwrap());
-                                            cancel_token.cancel();
-                                            guard.flush();
-                                            is_reset = true;
                                         }
                                         PlayerControlEvent::ChangeAudioDevice(request) => {
                                             info!("audio: change audio device! {:?}", request);
                                             audio_device_name = request.audio_device;
-                                            path_str.replace(path_str_clone.clone().unwrap());
+                                            path_str = path_str_clone.clone();
                                             cancel_token.cancel();
                                             guard.flush();
                                             guard.pause();
@@ -777,9 +1121,30 @@ fn decode_loop(
                                                 wake_all(decoding_active.as_ref());
                                             }
                                         }
+                                        PlayerControlEvent::Meters(request) => {
+                                            info!("audio: meters! {:?}", request);
+                                            guard.set_meters(request.enabled);
+                                        }
+                                        PlayerControlEvent::PlaybackRate(request) => {
+                                            info!("audio: playback rate! {:?}", request);
+                                            tempo = request.rate.unwrap_or(tempo);
+                                            semitones = request.pitch.unwrap_or(semitones);
+                                            if is_transition {
+                                                guard.set_playback_rate(tempo, semitones);
+                                            } else {
+                                                resume_at.replace(guard.get_position());
+                                                path_str = path_str_clone.clone();
+                                                cancel_token.cancel();
+                                                guard.flush();
+                                                is_reset = true;
+                                            }
+                                        }
                                     }
                                 }
                                 guard.resume();
+                                player_state.update_and_emit(app_handle, |state| {
+                                    state.status = PlaybackStatus::Playing
+                                });
                             }
 
                             let _ = playback_state_sender.send(true);
@@ -799,35 +1164,11 @@ fn decode_loop(
                                 continue;
                             }
 
-                            // Loop region mode: If this packet is past the loop region,
-                            // seek the reader back to the start point
-                            if end_pos.is_some() && packet.ts > end_pos_frame_idx {
-                                let seek_to = SeekTo::Time {
-                                    time: Time::from(seek.unwrap()),
-                                    track_id: Some(track_id),
-                                };
-                                match reader
-                                    .seek(symphonia::core::formats::SeekMode::Accurate, seek_to)
-                                {
-                                    Ok(seeked_to) => seeked_to.required_ts,
-                                    Err(ResetRequired) => {
-                                        // Don't give-up on a seek error.
-                                        warn!("reset required:");
-                                        0
-                                    }
-                                    Err(err) => {
-                                        // Don't give-up on a seek error.
-                                        warn!("seek error: {}", err);
-                                        0
-                                    }
-                                };
-                                is_transition = true; // To delay sending sample offset by 5s
-                            }
-
                             // Decode the packet into audio samples.
                             match decoder.decode(&packet) {
                                 Ok(mut _decoded) => {
                                     last_sent_time = Instant::now();
+                                    failed_tracks = 0;
 
                                     /*
                                     The transition is 5 seconds long, since that is the size of the buffer.
@@ -839,15 +1180,6 @@ fn decode_loop(
                                         transition_time = last_sent_time;
                                     } else if is_transition && started_transition {
                                         if transition_time.elapsed().as_secs() >= 5 {
-                                            if end_pos.is_some() {
-                                                let _ =
-                                                    sender_sample_offset.send(SampleOffsetEvent {
-                                                        sample_offset: Some(
-                                                            seek_ts * previous_channels as u64,
-                                                        ),
-                                                    });
-                                            }
-
                                             if let Some(song) = crate::metadata::extract_metadata(
                                                 &Path::new(&p.clone().as_str()),
                                                 false,
@@ -855,18 +1187,19 @@ fn decode_loop(
                                                 &app_handle,
                                             ) {
                                                 let _ = app_handle.emit("song_change", Some(song));
+                                                player_state
+                                                    .update_and_emit(app_handle, &set_track_state);
 
                                                 let _ = reset_control_sender.send(true);
                                                 let _ =
                                                     sender_sample_offset.send(SampleOffsetEvent {
                                                         sample_offset: Some(
-                                                            seek_ts
-                                                                * track
-                                                                    .codec_params
-                                                                    .channels
-                                                                    .unwrap()
-                                                                    .count()
-                                                                    as u64,
+                                                            to_output_sample_offset(
+                                                                seek_ts.saturating_sub(start_ts),
+                                                                &spec,
+                                                                output_sample_rate,
+                                                                output_channels,
+                                                            ),
                                                         ),
                                                     });
                                             } else {
@@ -877,28 +1210,89 @@ fn decode_loop(
                                         }
                                     }
 
+                                    // Start decoding the next track once we reach the crossfade
+                                    if let Some(start_ts) = crossfade_start_ts {
+                                        if !crossfade_checked && packet.ts() >= start_ts {
+                                            crossfade_checked = true;
+                                            let next_path = queue.lock().ok().and_then(|queue| {
+                                                queue.peek_next().map(String::from)
+                                            });
+                                            crossfade = start_crossfade(
+                                                p,
+                                                next_path,
+                                                &spec,
+                                                start_ts,
+                                                crossfade_frames,
+                                                crossfade_curve,
+                                            );
+                                            if let Some(ref mut fade) = crossfade {
+                                                let incoming_gain =
+                                                    replay_gain_for(Path::new(&fade.path));
+                                                fade.set_relative_gain(incoming_gain / track_gain);
+                                            }
+                                        }
+                                    }
+
                                     /*
                                     Write packet to audio ring buffer here
                                     Because the audio playback uses the ringbuffer, we are effectively
                                     "slowing down" decoding to allow the audio stream to read from the
                                     buffer as it's playing.
                                      */
+                                    let mut loop_action = LoopAction::Continue;
+                                    let packet_end_ts = packet.ts() + _decoded.frames() as u64;
+                                    // Frames past the end of a track from a CUE sheet
+                                    let past_end = match end_ts {
+                                        Some(end) if bounds.is_some() => {
+                                            packet_end_ts.saturating_sub(end) as usize
+                                        }
+                                        _ => 0,
+                                    };
                                     if !cancel_token.is_cancelled() {
-                                        // Write the decoded audio samples to the audio output if the presentation timestamp
-                                        // for the packet is >= the seeked position (0 if not seeking).
-                                        if packet.ts() >= seek_ts {
-                                            let mut ramp_up_smpls = 0;
-                                            let mut ramp_down_smpls = 0;
-                                            // Avoid clicks by ramping down and up quickly
-                                            if let Some(frames) = track.codec_params.n_frames {
-                                                if packet.ts >= frames - packet.dur {
-                                                    ramp_down_smpls = packet.dur;
-                                                } else if packet.ts < packet.dur {
-                                                    ramp_up_smpls = packet.dur;
-                                                }
+                                        loop_action = write_packet(
+                                            &mut *guard,
+                                            _decoded,
+                                            &packet,
+                                            seek_ts,
+                                            past_end,
+                                            track.codec_params.n_frames,
+                                            &mut crossfade,
+                                            &mut region_loop,
+                                        );
+                                    }
+
+                                    match loop_action {
+                                        LoopAction::Wrap => {
+                                            if let Some(ref mut region) = region_loop {
+                                                seek_ts = wrap_region(
+                                                    reader.as_mut(),
+                                                    decoder.as_mut(),
+                                                    region,
+                                                    track_id,
+                                                );
                                             }
-                                            guard.write(_decoded, ramp_up_smpls, ramp_down_smpls);
+                                            // The output jumps back when it gets to this point
+                                            guard.mark_position(to_output_sample_offset(
+                                                seek_ts.saturating_sub(start_ts),
+                                                &spec,
+                                                output_sample_rate,
+                                                output_channels,
+                                            ));
+                                        }
+                                        LoopAction::Finished => {
+                                            region_loop = None;
+                                            end_pos = None;
+                                            let _ = app_handle.emit("loop_finished", {});
+                                            player_state.update_and_emit(app_handle, |state| {
+                                                state.loop_region = None
+                                            });
                                         }
+                                        LoopAction::Continue => {}
+                                    }
+
+                                    // The next track of a CUE sheet carries on from here
+                                    if past_end > 0 && region_loop.is_none() {
+                                        break end_of_stream_error();
                                     }
 
                                     continue;
@@ -917,22 +1311,34 @@ fn decode_loop(
                                     && err.to_string() == "end of stream" =>
                             {
                                 info!("End of stream!!");
-                                let mut next_track = None;
-                                while let Ok(value) =
-                                    next_track_receiver.try_lock().unwrap().try_recv()
-                                {
-                                    info!("received {:?}", value);
-                                    next_track.replace(value);
-                                }
-                                if let Some(request) = next_track {
-                                    if let Some(path) = request.path.clone() {
+                                let next_track = match queue.lock() {
+                                    Ok(mut queue) => {
+                                        let next_track = queue.advance(true);
+                                        crate::queue::emit_queue(&queue, &player_state, app_handle);
+                                        next_track
+                                    }
+                                    Err(_) => None,
+                                };
+                                match next_track {
+                                    Some(path) => {
                                         is_transition = true;
-                                        info!("player: next track received! {:?}", request);
+                                        info!("player: next track from the queue! {:?}", path);
+                                        seek.replace(0.0);
+                                        // Carry on from where the crossfade got to
+                                        if let Some(fade) = crossfade.take() {
+                                            if fade.path == path {
+                                                // Half a frame, so that the seek lands on
+                                                // exactly this frame
+                                                seek.replace(
+                                                    (fade.consumed_frames() as f64 + 0.5)
+                                                        / spec.rate as f64,
+                                                );
+                                            }
+                                        }
                                         path_str.replace(path);
-                                        seek.replace(request.seek.unwrap());
-                                        volume.replace(request.volume.unwrap());
                                         is_reset = false;
-                                    } else {
+                                    }
+                                    None => {
                                         info!("player: nothing else in the queue");
 
                                         // Keep checking until all samples have been played (buffer is empty)
@@ -943,13 +1349,36 @@ fn decode_loop(
                                         info!("Buffer is now empty. Pausing stream...");
                                         guard.pause();
                                         let _ = app_handle.emit("stopped", Some(0.0f64));
+                                        player_state.update_and_emit(app_handle, |state| {
+                                            state.status = PlaybackStatus::Stopped
+                                        });
                                     }
                                 }
                                 // Do not treat "end of stream" as a fatal error. It's the currently only way a
                                 // format reader can indicate the media is complete.
                                 Ok(())
                             }
-                            _ => result,
+                            Err(err) => {
+                                failed_tracks += 1;
+                                if let Some(path) = report_playback_error(
+                                    p,
+                                    err.into(),
+                                    failed_tracks,
+                                    false,
+                                    &queue,
+                                    &player_state,
+                                    app_handle,
+                                ) {
+                                    // What was decoded before the error still plays first
+                                    is_transition = true;
+                                    seek.replace(0.0);
+                                    end_pos = None;
+                                    path_str.replace(path);
+                                    is_reset = false;
+                                }
+                                Ok(())
+                            }
+                            Ok(()) => Ok(()),
                         };
                     }
                 }
@@ -960,23 +1389,308 @@ fn decode_loop(
     // do_verification(decoder.finalize())
 }
 
+/// Opens a track for the decode loop, with the signal spec the decoder will produce.
+fn open_for_playback(
+    path: &Path,
+    player_state: &PlayerStateHandle,
+    app_handle: &AppHandle,
+) -> Result<(Box<dyn FormatReader>, Box<dyn Decoder>, Track, SignalSpec), PlaybackError> {
+    let (reader, decoder, track_id) = match path.to_str().filter(|p| is_stream_url(p)) {
+        Some(url) => crate::radio::open_track(url, player_state, app_handle)?,
+        None => open_track(path)?,
+    };
+    let track = reader
+        .tracks()
+        .iter()
+        .find(|track| track.id == track_id)
+        .cloned()
+        .ok_or_else(|| PlaybackError::Unsupported(String::from("no default track")))?;
+    let spec = match (
+        decoder.codec_params().sample_rate,
+        decoder.codec_params().channels,
+    ) {
+        (Some(rate), Some(channels)) => SignalSpec { rate, channels },
+        _ => {
+            return Err(PlaybackError::Unsupported(String::from(
+                "unknown sample rate or channel layout",
+            )))
+        }
+    };
+    Ok((reader, decoder, track, spec))
+}
+
+/// Where the file output writes when the user hasn't picked a file.
+fn default_file_output_path(app_handle: &AppHandle) -> PathBuf {
+    app_handle
+        .path()
+        .audio_dir()
+        .unwrap_or_else(|_| std::env::temp_dir())
+        .join("Musicat output.wav")
+}
+
+/// Reports a track that couldn't be played. If the user skips past errors, moves the queue
+/// on and returns the track to play instead (announcing it right away if `announce`),
+/// otherwise playback stops.
+fn report_playback_error(
+    path: &str,
+    error: PlaybackError,
+    failed_tracks: usize,
+    announce: bool,
+    queue: &Arc<std::sync::Mutex<PlayQueue>>,
+    player_state: &PlayerStateHandle,
+    app_handle: &AppHandle,
+) -> Option<String> {
+    error!("player: couldn't play {}: {}", path, error);
+
+    // The next track won't fare any better with a broken output
+    let skip = !matches!(error, PlaybackError::Output(_))
+        && load_settings(app_handle).map_or(true, |settings| settings.skip_on_playback_error);
+    let skipped_to = match queue.lock() {
+        // Give up once every track in the queue has failed
+        Ok(mut queue) if skip && failed_tracks < queue.len() => {
+            let next = queue.advance(false);
+            crate::queue::emit_queue(&queue, player_state, app_handle);
+            next
+        }
+        _ => None,
+    };
+
+    let _ = app_handle.emit(
+        "playback_error",
+        PlaybackErrorEvent {
+            path: Some(path.to_string()),
+            error,
+            skipped_to: skipped_to.clone(),
+        },
+    );
+
+    match skipped_to {
+        Some(ref next) if announce => {
+            if let Some(song) =
+                crate::metadata::extract_metadata(Path::new(next), false, false, app_handle)
+            {
+                let _ = app_handle.emit("song_change", Some(song));
+            }
+        }
+        Some(_) => {}
+        None => {
+            let _ = app_handle.emit("stopped", Some(0.0f64));
+            player_state
+                .update_and_emit(app_handle, |state| state.status = PlaybackStatus::Stopped);
+        }
+    }
+    skipped_to
+}
+
+/// Probes the file and creates a decoder for its default track.
+pub fn open_track(
+    path: &Path,
+) -> Result<(Box<dyn FormatReader>, Box<dyn Decoder>, u32), symphonia::core::errors::Error> {
+    let mut hint = Hint::new();
+    if let Some(extension_str) = path.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(extension_str);
+    }
+    open_source(Box::new(File::open(path)?), &hint)
+}
+
+/// Probes a source and creates a decoder for its default track.
+pub fn open_source(
+    source: Box<dyn MediaSource>,
+    hint: &Hint,
+) -> Result<(Box<dyn FormatReader>, Box<dyn Decoder>, u32), symphonia::core::errors::Error> {
+    let mss = MediaSourceStream::new(source, Default::default());
+    let format_opts = FormatOptions {
+        enable_gapless: true,
+        ..Default::default()
+    };
+    let metadata_opts: MetadataOptions = MetadataOptions {
+        limit_metadata_bytes: symphonia::core::meta::Limit::Maximum(50),
+        limit_visual_bytes: symphonia::core::meta::Limit::Maximum(0),
+    };
+    let reader = crate::registry::get_probe()
+        .format(hint, mss, &format_opts, &metadata_opts)?
+        .format;
+    // MP4 files can list a chapter or cover track first, play the first audio one
+    let track = first_supported_track(reader.tracks())
+        .ok_or(symphonia::core::errors::Error::Unsupported("no supported track"))?;
+    let track_id = track.id;
+    let decoder = crate::registry::get_codecs()
+        .make(&track.codec_params, &DecoderOptions { verify: false })?;
+    Ok((reader, decoder, track_id))
+}
+
+/// Opens the next track for a crossfade. Returns None if there is nothing queued, or if the
+/// transition should stay gapless instead (same album, or a different sample rate).
+fn start_crossfade(
+    current_path: &str,
+    next_path: Option<String>,
+    spec: &SignalSpec,
+    start_ts: u64,
+    length: u64,
+    curve: CrossfadeCurve,
+) -> Option<Crossfade> {
+    let next_path = next_path?;
+
+    if crate::metadata::is_same_album(Path::new(current_path), Path::new(&next_path)) {
+        info!("crossfade: same album, keeping transition gapless");
+        return None;
+    }
+
+    if crate::cue::split_track_path(&next_path).is_some() {
+        info!("crossfade: next track starts partway into its file, keeping transition gapless");
+        return None;
+    }
+
+    let (reader, decoder, track_id) = match open_track(Path::new(&next_path)) {
+        Ok(opened) => opened,
+        Err(err) => {
+            warn!("crossfade: couldn't open next track: {}", err);
+            return None;
+        }
+    };
+
+    if decoder.codec_params().sample_rate != Some(spec.rate) {
+        info!("crossfade: sample rates differ, keeping transition gapless");
+        return None;
+    }
+
+    Some(Crossfade::new(
+        next_path, reader, decoder, track_id, start_ts, length, curve, *spec,
+    ))
+}
+
+/// Converts a timestamp in source frames to an interleaved sample offset at the
+/// output sample rate and channel count, which is what the output callback counts in.
+pub(crate) fn to_output_sample_offset(
+    ts: u64,
+    spec: &SignalSpec,
+    output_sample_rate: u32,
+    output_channels: usize,
+) -> u64 {
+    let frames = ts as u128 * output_sample_rate as u128 / spec.rate as u128;
+    frames as u64 * output_channels as u64
+}
+
+/// Writes a decoded packet to the output from the seeked position on, without the frames
+/// past the end of a CUE track, mixed into the next track's start or cut at the end of
+/// the loop region. Returns what the loop region needs done next.
+pub(crate) fn write_packet(
+    output: &mut dyn AudioOutput,
+    decoded: AudioBufferRef<'_>,
+    packet: &Packet,
+    seek_ts: u64,
+    past_end: usize,
+    n_frames: Option<u64>,
+    crossfade: &mut Option<Crossfade>,
+    region_loop: &mut Option<RegionLoop>,
+) -> LoopAction {
+    // Write the decoded audio samples to the audio output if the packet
+    // ends after the seeked position (0 if not seeking).
+    if packet.ts() + decoded.frames() as u64 <= seek_ts {
+        return LoopAction::Continue;
+    }
+    let mut ramp_up_smpls = 0;
+    let mut ramp_down_smpls = 0;
+    // Avoid clicks by ramping down and up quickly
+    if let Some(frames) = n_frames {
+        if packet.ts >= frames - packet.dur {
+            ramp_down_smpls = packet.dur;
+        } else if packet.ts < packet.dur {
+            ramp_up_smpls = packet.dur;
+        }
+    }
+    let skip = seek_ts.saturating_sub(packet.ts());
+    if let Some(ref mut fade) = crossfade {
+        // Only the outgoing track's own frames fade out,
+        // not the next CUE track's in the same file
+        let mixed = if skip > 0 || past_end > 0 {
+            let trimmed = trim(&decoded, skip as usize, past_end);
+            fade.mix(&trimmed.as_audio_buffer_ref(), packet.ts() + skip)
+        } else {
+            fade.mix(&decoded, packet.ts())
+        };
+        output.write(mixed.as_audio_buffer_ref(), 0, 0);
+    } else if let Some(ref mut region) = region_loop {
+        let (looped, action) = region.process(&decoded, packet.ts(), skip as usize);
+        output.write(looped.as_audio_buffer_ref(), 0, 0);
+        return action;
+    } else if skip > 0 || past_end > 0 {
+        // The seeked position is inside this packet, discard
+        // the frames before it, and any after the track.
+        let trimmed = trim(&decoded, skip as usize, past_end);
+        output.write(trimmed.as_audio_buffer_ref(), ramp_up_smpls, ramp_down_smpls);
+    } else {
+        output.write(decoded, ramp_up_smpls, ramp_down_smpls);
+    }
+    LoopAction::Continue
+}
+
+/// Seeks back to the start of the loop region for its next pass.
+/// Returns the timestamp to play from.
+pub(crate) fn wrap_region(
+    reader: &mut dyn FormatReader,
+    decoder: &mut dyn Decoder,
+    region: &mut RegionLoop,
+    track_id: u32,
+) -> u64 {
+    let seek_to = SeekTo::TimeStamp {
+        ts: region.start_ts(),
+        track_id,
+    };
+    let seek_ts = match reader.seek(symphonia::core::formats::SeekMode::Accurate, seek_to) {
+        Ok(seeked_to) => seeked_to.required_ts,
+        Err(err) => {
+            // Like the first seek, decode from the top and discard everything before the
+            // start; playing from the top would start a CUE track's pass at its album's start.
+            warn!("seek error: {}, decoding up to the loop start instead", err);
+            let rewind = SeekTo::TimeStamp { ts: 0, track_id };
+            if let Err(err) = reader.seek(symphonia::core::formats::SeekMode::Coarse, rewind) {
+                warn!("rewind error: {}", err);
+            }
+            region.start_ts()
+        }
+    };
+    // The decoder state belongs to the packets before the seek
+    decoder.reset();
+    region.wrap();
+    seek_ts
+}
+
+/// Copies the decoded buffer, dropping the first `start` and the last `end` frames.
+pub(crate) fn trim(decoded: &AudioBufferRef<'_>, start: usize, end: usize) -> AudioBuffer<f32> {
+    let mut trimmed = decoded.make_equivalent::<f32>();
+    decoded.convert(&mut trimmed);
+    let start = start.min(trimmed.frames());
+    trimmed.trim(start, end.min(trimmed.frames() - start));
+    trimmed
+}
+
 fn first_supported_track(tracks: &[Track]) -> Option<&Track> {
     tracks
         .iter()
         .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
 }
 
+/// Decodes a file for the RMS level of every `SAMPLES_PER_PEAK` samples. While it does,
+/// `on_progress` gets the peaks so far, padded with silence to the whole track.
 pub fn get_peaks(
     event: GetWaveformRequest,
-    app_handle: &AppHandle,
     cancel_token: CancellationToken,
+    mut on_progress: impl FnMut(Vec<f32>),
 ) -> Result<Vec<f32>, symphonia::core::errors::Error> {
     let binding = event.path.unwrap();
-    let path = Path::new(binding.as_str());
+    if is_stream_url(&binding) {
+        return Err(symphonia::core::errors::Error::Unsupported("live stream"));
+    }
+    // A track from a CUE sheet is part of its album's file
+    let bounds = crate::cue::track_bounds(&binding);
+    let path = bounds
+        .as_ref()
+        .map_or(Path::new(binding.as_str()), |bounds| bounds.file.as_path());
 
     // Create a hint to help the format registry guess what format reader is appropriate.
     let mut hint = Hint::new();
-    let source = Box::new(File::open(path).unwrap());
+    let source = Box::new(File::open(path)?);
     info!("source {:?}", source);
 
     // Provide the file extension as a hint.
@@ -1007,7 +1721,8 @@ pub fn get_peaks(
     info!("opts {:?}", format_opts);
     info!("meta {:?}", metadata_opts);
 
-    let probe_result = get_probe().format(&hint, mss, &format_opts, &metadata_opts);
+    let probe_result =
+        crate::registry::get_probe().format(&hint, mss, &format_opts, &metadata_opts);
     info!("probe format {:?}", probe_result.is_ok());
 
     if probe_result.is_err() {
@@ -1015,23 +1730,44 @@ pub fn get_peaks(
     }
     let mut reader = probe_result.unwrap().format;
 
-    let track = reader.default_track().unwrap().clone();
+    let track = first_supported_track(reader.tracks())
+        .ok_or(symphonia::core::errors::Error::Unsupported("no supported track"))?
+        .clone();
 
     let track_id = track.id;
 
     info!("codec params: {:?}", &track.codec_params);
 
     // Create a decoder for the track.
-    let mut decoder = symphonia::default::get_codecs()
-        .make(&track.codec_params, &DecoderOptions { verify: false })
-        .unwrap();
+    let mut decoder = crate::registry::get_codecs()
+        .make(&track.codec_params, &DecoderOptions { verify: false })?;
 
     let new_spec = SignalSpec::new_with_layout(44100, Layout::Stereo);
 
+    // A track from a CUE sheet runs from its start to where the next one starts
+    let rate = track.codec_params.sample_rate.unwrap_or(44100) as f64;
+    let mut start_ts = 0;
+    let mut end_ts = None;
+    if let Some(bounds) = bounds {
+        let seek_to = SeekTo::Time {
+            time: Time::from(bounds.start),
+            track_id: Some(track_id),
+        };
+        start_ts = reader
+            .seek(symphonia::core::formats::SeekMode::Coarse, seek_to)?
+            .actual_ts;
+        end_ts = bounds.end.map(|end| (end * rate) as u64);
+    }
+
+    // Raw AAC (ADTS) streams don't say how long they are
+    let frames = end_ts
+        .or(track.codec_params.n_frames)
+        .unwrap_or(0)
+        .saturating_sub(start_ts);
     let expected_peaks_size =
-        (track.codec_params.n_frames.unwrap() * new_spec.channels.count() as u64 / 4000) as usize;
+        (frames * new_spec.channels.count() as u64 / SAMPLES_PER_PEAK as u64) as usize;
 
-    let mut window: Vec<f32> = Vec::with_capacity(4000);
+    let mut window: Vec<f32> = Vec::with_capacity(SAMPLES_PER_PEAK);
     let mut peaks: Vec<f32> = Vec::new();
 
     let mut total_count = 0;
@@ -1047,6 +1783,9 @@ pub fn get_peaks(
         if packet.track_id() != track_id {
             continue;
         }
+        if end_ts.map_or(false, |end| packet.ts() >= end) {
+            break end_of_stream_error();
+        }
         // Decode the packet into audio samples.
         match decoder.decode(&packet) {
             Ok(_decoded) => {
@@ -1061,7 +1800,7 @@ pub fn get_peaks(
                 // any required conversions.
                 sample_buf.copy_interleaved_ref(_decoded);
                 sample_buf.samples().iter().for_each(|f| {
-                    if window.len() < 4000 {
+                    if window.len() < SAMPLES_PER_PEAK {
                         window.push(*f);
                     } else {
                         peaks.push(dsp::calculate_rms(&window));
@@ -1075,7 +1814,7 @@ pub fn get_peaks(
                     let len = expected_peaks_size.saturating_sub(peaks.len());
                     // info!("expected peaks size: {}, len: {}, n_adds: {}", expected_peaks_size, peaks.len(), n_adds);
                     let cln = [peaks.clone().as_slice(), vec![0f32; len].as_slice()].concat();
-                    let _ = app_handle.emit("waveform", GetWaveformResponse { data: Some(cln) });
+                    on_progress(cln);
                 }
 
                 // Get waveform here
@@ -1096,9 +1835,8 @@ pub fn get_peaks(
         {
             info!("End of stream!!");
             info!(
-                "Number of frames: {} (actual), {} (expected)",
-                n_frames,
-                track.codec_params.n_frames.unwrap()
+                "Number of frames: {} (actual), {:?} (expected)",
+                n_frames, track.codec_params.n_frames
             );
             // Do not treat "end of stream" as a fatal error. It's the currently only way a
             // format reader can indicate the media is complete.
@@ -1125,7 +1863,7 @@ pub fn get_devices(_app_handle: tauri::AppHandle) -> Option<AudioDevices> {
     // Get default host.
     let host = cpal::default_host();
 
-    let cpal_devices: Vec<AudioDevice> = host
+    let mut cpal_devices: Vec<AudioDevice> = host
         .output_devices()
         .unwrap()
         .map(|device| AudioDevice {
@@ -1133,6 +1871,11 @@ pub fn get_devices(_app_handle: tauri::AppHandle) -> Option<AudioDevices> {
         })
         .collect();
 
+    // Not a real device, but it's picked the same way
+    cpal_devices.push(AudioDevice {
+        name: String::from(FILE_OUTPUT_DEVICE),
+    });
+
     let cpal_default = host.default_output_device();
     
     let default = if cpal_default.is_none() { None } else { Some(AudioDevice {
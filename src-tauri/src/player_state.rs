@@ -0,0 +1,97 @@
+use std::sync::{Arc, RwLock};
+
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Emitter};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum PlaybackStatus {
+    Stopped,
+    Playing,
+    Paused,
+}
+
+impl Default for PlaybackStatus {
+    fn default() -> Self {
+        PlaybackStatus::Stopped
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct LoopRegionState {
+    pub start_pos: f64, // seconds
+    pub end_pos: f64,   // seconds
+    pub crossfade: f64, // ms
+    pub count: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayerState {
+    pub path: Option<String>,
+    /// Position in the track, in frames at the track's sample rate
+    pub position_samples: u64,
+    pub position: f64,         // seconds
+    pub duration: Option<f64>, // seconds
+    pub status: PlaybackStatus,
+    pub volume: Option<f64>, // 0 to 1
+    pub device: Option<String>,
+    pub sample_rate: Option<u32>, // of the track
+    pub output_sample_rate: Option<u32>,
+    pub output_channels: Option<usize>,
+    pub output_format: Option<String>,
+    pub loop_region: Option<LoopRegionState>,
+    pub next_track: Option<String>,
+    /// An internet radio stream, which can't seek
+    pub live: bool,
+    /// What the stream says is playing
+    pub stream_title: Option<String>,
+}
+
+/// The player state shared between the decode thread and commands.
+#[derive(Clone, Default)]
+pub struct PlayerStateHandle {
+    state: Arc<RwLock<PlayerState>>,
+    /// The output's position counter (interleaved samples at the output rate)
+    output_position: Arc<RwLock<Option<Arc<RwLock<u64>>>>>,
+}
+
+impl PlayerStateHandle {
+    pub fn update<F: FnOnce(&mut PlayerState)>(&self, f: F) {
+        if let Ok(mut state) = self.state.write() {
+            f(&mut state);
+        }
+    }
+
+    /// Updates the state, then sends it to the frontend.
+    pub fn update_and_emit<F: FnOnce(&mut PlayerState)>(&self, app_handle: &AppHandle, f: F) {
+        self.update(f);
+        let _ = app_handle.emit("player_state", self.snapshot());
+    }
+
+    pub fn set_output_position(&self, position: Arc<RwLock<u64>>) {
+        if let Ok(mut output_position) = self.output_position.write() {
+            output_position.replace(position);
+        }
+    }
+
+    /// The current state, with the position of what is playing right now.
+    pub fn snapshot(&self) -> PlayerState {
+        let mut state = self.state.read().map(|s| s.clone()).unwrap_or_default();
+
+        let samples = self
+            .output_position
+            .read()
+            .ok()
+            .and_then(|p| p.as_ref().and_then(|p| p.read().ok().map(|p| *p)));
+        if let (Some(samples), Some(rate), Some(channels)) =
+            (samples, state.output_sample_rate, state.output_channels)
+        {
+            state.position = samples as f64 / (rate as f64 * channels as f64);
+            state.position_samples =
+                (state.position * state.sample_rate.unwrap_or(rate) as f64).round() as u64;
+        }
+        state
+    }
+}
@@ -0,0 +1,417 @@
+//! Playlist files: M3U/M3U8, PLS and XSPF.
+//!
+//! Entries are read with whatever the file says about them besides the path, so they can
+//! still be found in the library by artist and title after the files have moved.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use chksum_md5::MD5;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::xml::{elements, escape, first_text};
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PlaylistEntry {
+    /// A file path, or the URL of a stream
+    pub path: String,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub duration: Option<f64>, // s
+    /// The library song the entry was matched to
+    #[serde(default)]
+    pub song_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PlaylistFile {
+    pub path: String,
+    pub title: String,
+    pub entries: Vec<PlaylistEntry>,
+}
+
+/// A song of the library, to match entries to.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LibrarySong {
+    id: String,
+    path: String,
+    artist: String,
+    title: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ReadPlaylistEvent {
+    path: String,
+    /// Entries are only matched by path without it
+    library: Option<Vec<LibrarySong>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WritePlaylistEvent {
+    path: String,
+    title: Option<String>,
+    entries: Vec<PlaylistEntry>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    M3u,
+    Pls,
+    Xspf,
+}
+
+impl Format {
+    fn from_path(path: &Path) -> Option<Format> {
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+        match ext.as_str() {
+            "m3u" | "m3u8" => Some(Format::M3u),
+            "pls" => Some(Format::Pls),
+            "xspf" => Some(Format::Xspf),
+            _ => None,
+        }
+    }
+}
+
+pub fn is_playlist(path: &Path) -> bool {
+    Format::from_path(path).is_some()
+}
+
+/// Reads a playlist file, with its entries' paths made absolute.
+pub fn read(path: &Path) -> Option<PlaylistFile> {
+    let format = Format::from_path(path)?;
+    let text = crate::cue::read_text(path)?;
+    let text = text.trim_start_matches('\u{feff}');
+    let base = path.parent()?;
+    let (title, entries) = match format {
+        Format::M3u => parse_m3u(text, base),
+        Format::Pls => (None, parse_pls(text, base)),
+        Format::Xspf => parse_xspf(text, base),
+    };
+    Some(PlaylistFile {
+        path: path.to_string_lossy().to_string(),
+        title: title.unwrap_or_else(|| {
+            path.file_stem()
+                .map_or(String::new(), |s| s.to_string_lossy().to_string())
+        }),
+        entries,
+    })
+}
+
+/// Writes a playlist file in the format of its extension. Entries in the playlist's folder
+/// are written relative to it, so the folder can be moved along with its playlist.
+pub fn write(path: &Path, title: Option<&str>, entries: &[PlaylistEntry]) -> Result<(), String> {
+    let format = Format::from_path(path).ok_or("Unsupported playlist format")?;
+    let base = path.parent().ok_or("Invalid playlist path")?;
+    let text = match format {
+        Format::M3u => write_m3u(title, entries, base),
+        Format::Pls => write_pls(entries, base),
+        Format::Xspf => write_xspf(title, entries, base),
+    };
+    fs::write(path, text).map_err(|e| e.to_string())
+}
+
+/// Matches entries to library songs by path, or by artist and title for files that have
+/// moved since the playlist was made.
+pub fn match_entries(entries: &mut [PlaylistEntry], library: &[LibrarySong]) {
+    let by_path: HashMap<&str, &str> = library
+        .iter()
+        .map(|song| (song.path.as_str(), song.id.as_str()))
+        .collect();
+    let by_name: HashMap<(String, String), &str> = library
+        .iter()
+        .map(|song| {
+            let key = (song.artist.to_lowercase(), song.title.to_lowercase());
+            (key, song.id.as_str())
+        })
+        .collect();
+    for entry in entries.iter_mut() {
+        let find_by_name = || {
+            let artist = entry.artist.as_ref()?.to_lowercase();
+            let title = entry.title.as_ref()?.to_lowercase();
+            by_name.get(&(artist, title)).copied()
+        };
+        entry.song_id = by_path
+            .get(entry.path.as_str())
+            .copied()
+            .or_else(find_by_name)
+            .map(String::from);
+    }
+}
+
+/// Matches entries to the songs their files import as, for playlists found while scanning.
+pub fn match_files(entries: &mut [PlaylistEntry]) {
+    for entry in entries.iter_mut() {
+        let file = crate::cue::split_track_path(&entry.path).map_or(entry.path.as_str(), |t| t.0);
+        if Path::new(file).is_file() {
+            entry.song_id = Some(MD5::hash(entry.path.as_bytes()).to_hex_lowercase());
+        }
+    }
+}
+
+fn non_empty(value: &str) -> Option<String> {
+    let value = value.trim();
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+/// Players write "Artist - Title" where there's a single name for an entry.
+fn split_name(name: &str) -> (Option<String>, Option<String>) {
+    match name.split_once(" - ") {
+        Some((artist, title)) => (non_empty(artist), non_empty(title)),
+        None => (None, non_empty(name)),
+    }
+}
+
+fn display_name(entry: &PlaylistEntry) -> String {
+    match (&entry.artist, &entry.title) {
+        (Some(artist), Some(title)) => format!("{} - {}", artist, title),
+        (None, Some(title)) => title.clone(),
+        _ => Path::new(&entry.path)
+            .file_stem()
+            .map_or(String::new(), |s| s.to_string_lossy().to_string()),
+    }
+}
+
+/// Makes `location` absolute, against the playlist's folder if it's relative. URLs of
+/// anything other than files are left as they are.
+fn resolve(location: &str, base: &Path) -> Option<String> {
+    let location = location.trim();
+    // A single letter is a Windows drive, not a scheme
+    if let Some(url) = Url::parse(location)
+        .ok()
+        .filter(|url| url.scheme().len() > 1)
+    {
+        return match url.scheme() {
+            "file" => url.to_file_path().ok().map(|path| normalize(&path)),
+            _ => Some(url.to_string()),
+        };
+    }
+    let location = if cfg!(windows) {
+        location.to_string()
+    } else {
+        location.replace('\\', "/")
+    };
+    non_empty(&location).map(|location| normalize(&base.join(location)))
+}
+
+/// XSPF locations are URIs, relative ones to the playlist's own.
+fn resolve_uri(location: &str, base: &Path) -> Option<String> {
+    let url = Url::from_directory_path(base)
+        .ok()?
+        .join(location.trim())
+        .ok()?;
+    match url.scheme() {
+        "file" => url.to_file_path().ok().map(|path| normalize(&path)),
+        _ => Some(url.to_string()),
+    }
+}
+
+/// Drops the `.` and `..` of a path, so it's the same string as the library's.
+fn normalize(path: &Path) -> String {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            _ => normalized.push(component),
+        }
+    }
+    normalized.to_string_lossy().to_string()
+}
+
+fn relative<'a>(path: &'a str, base: &Path) -> &'a str {
+    Path::new(path)
+        .strip_prefix(base)
+        .ok()
+        .and_then(|relative| relative.to_str())
+        .unwrap_or(path)
+}
+
+pub fn parse_m3u(text: &str, base: &Path) -> (Option<String>, Vec<PlaylistEntry>) {
+    let mut title = None;
+    let mut entries = Vec::new();
+    // The #EXTINF of the entry on the next line
+    let mut info: Option<PlaylistEntry> = None;
+    for line in text.lines() {
+        let line = line.trim();
+        if let Some(extinf) = line.strip_prefix("#EXTINF:") {
+            let (duration, name) = extinf.split_once(',').unwrap_or((extinf, ""));
+            // IPTV lists put attributes after the duration
+            let duration = duration
+                .split_whitespace()
+                .next()
+                .and_then(|d| d.parse::<f64>().ok())
+                .filter(|d| *d >= 0.0);
+            let (artist, title) = split_name(name);
+            info = Some(PlaylistEntry {
+                title,
+                artist,
+                duration,
+                ..Default::default()
+            });
+        } else if let Some(name) = line.strip_prefix("#PLAYLIST:") {
+            title = non_empty(name);
+        } else if line.is_empty() || line.starts_with('#') {
+            continue;
+        } else if let Some(path) = resolve(line, base) {
+            entries.push(PlaylistEntry {
+                path,
+                ..info.take().unwrap_or_default()
+            });
+        }
+    }
+    (title, entries)
+}
+
+pub fn parse_pls(text: &str, base: &Path) -> Vec<PlaylistEntry> {
+    // Entries are numbered, and not always listed in order
+    let mut entries: BTreeMap<u32, PlaylistEntry> = BTreeMap::new();
+    for line in text.lines() {
+        let Some((key, value)) = line.trim().split_once('=') else {
+            continue;
+        };
+        let key = key.trim().to_ascii_lowercase();
+        let split = key.find(|c: char| c.is_ascii_digit()).unwrap_or(key.len());
+        let (field, number) = key.split_at(split);
+        let Ok(number) = number.parse() else {
+            continue;
+        };
+        let entry = entries.entry(number).or_default();
+        match field {
+            "file" => entry.path = resolve(value, base).unwrap_or_default(),
+            "title" => (entry.artist, entry.title) = split_name(value),
+            "length" => entry.duration = value.trim().parse().ok().filter(|d| *d >= 0.0),
+            _ => {}
+        }
+    }
+    entries
+        .into_values()
+        .filter(|entry| !entry.path.is_empty())
+        .collect()
+}
+
+pub fn parse_xspf(text: &str, base: &Path) -> (Option<String>, Vec<PlaylistEntry>) {
+    let (head, track_list) = match text.find("<trackList") {
+        Some(start) => text.split_at(start),
+        None => (text, ""),
+    };
+    let title = first_text(head, "title");
+    let entries = elements(track_list, "track")
+        .filter_map(|track| {
+            let track = track.content;
+            // Of several locations, the first that can be played
+            let path = elements(track, "location").find_map(|l| resolve_uri(&l.text(), base))?;
+            let field = |name| first_text(track, name);
+            Some(PlaylistEntry {
+                path,
+                title: field("title"),
+                artist: field("creator"),
+                duration: field("duration")
+                    .and_then(|ms| ms.parse::<f64>().ok())
+                    .map(|ms| ms / 1000.0),
+                song_id: None,
+            })
+        })
+        .collect();
+    (title, entries)
+}
+
+fn write_m3u(title: Option<&str>, entries: &[PlaylistEntry], base: &Path) -> String {
+    let mut text = String::from("#EXTM3U\n");
+    if let Some(title) = title {
+        text += &format!("#PLAYLIST:{}\n", title);
+    }
+    for entry in entries {
+        let duration = entry.duration.map_or(-1, |d| d.round() as i64);
+        text += &format!("#EXTINF:{},{}\n", duration, display_name(entry));
+        text += &format!("{}\n", relative(&entry.path, base));
+    }
+    text
+}
+
+fn write_pls(entries: &[PlaylistEntry], base: &Path) -> String {
+    let mut text = String::from("[playlist]\n");
+    for (i, entry) in entries.iter().enumerate() {
+        let n = i + 1;
+        text += &format!("File{}={}\n", n, relative(&entry.path, base));
+        text += &format!("Title{}={}\n", n, display_name(entry));
+        text += &format!(
+            "Length{}={}\n",
+            n,
+            entry.duration.map_or(-1, |d| d.round() as i64)
+        );
+    }
+    text += &format!("NumberOfEntries={}\nVersion=2\n", entries.len());
+    text
+}
+
+fn write_xspf(title: Option<&str>, entries: &[PlaylistEntry], base: &Path) -> String {
+    let base_url = Url::from_directory_path(base).ok();
+    let location = |path: &str| {
+        let url = Url::from_file_path(path).ok();
+        let relative = match (&base_url, &url) {
+            (Some(base_url), Some(url)) if Path::new(path).starts_with(base) => {
+                base_url.make_relative(url)
+            }
+            _ => None,
+        };
+        relative
+            .or_else(|| url.map(String::from))
+            .unwrap_or_else(|| path.to_string())
+    };
+
+    let mut text = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n",
+    );
+    if let Some(title) = title {
+        text += &format!("  <title>{}</title>\n", escape(title));
+    }
+    text += "  <trackList>\n";
+    for entry in entries {
+        text += "    <track>\n";
+        text += &format!(
+            "      <location>{}</location>\n",
+            escape(&location(&entry.path))
+        );
+        if let Some(title) = &entry.title {
+            text += &format!("      <title>{}</title>\n", escape(title));
+        }
+        if let Some(artist) = &entry.artist {
+            text += &format!("      <creator>{}</creator>\n", escape(artist));
+        }
+        if let Some(duration) = entry.duration {
+            text += &format!(
+                "      <duration>{}</duration>\n",
+                (duration * 1000.0).round() as u64
+            );
+        }
+        text += "    </track>\n";
+    }
+    text += "  </trackList>\n</playlist>\n";
+    text
+}
+
+#[tauri::command]
+pub async fn read_playlist(event: ReadPlaylistEvent) -> Option<PlaylistFile> {
+    let mut playlist = read(Path::new(&event.path))?;
+    match event.library {
+        Some(library) => match_entries(&mut playlist.entries, &library),
+        None => match_files(&mut playlist.entries),
+    }
+    Some(playlist)
+}
+
+#[tauri::command]
+pub async fn write_playlist(event: WritePlaylistEvent) -> Result<(), String> {
+    write(
+        Path::new(&event.path),
+        event.title.as_deref(),
+        &event.entries,
+    )
+}
@@ -0,0 +1,496 @@
+//! Podcasts: RSS and Atom feeds, and their episodes.
+//!
+//! Subscriptions are kept in `podcasts.json` in the app's config folder, along with where
+//! each episode was downloaded to and how far into it the listener got. Episodes are
+//! downloaded into the download location, in a folder for each podcast, and imported like
+//! any other song.
+
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use chksum_md5::MD5;
+use log::info;
+use reqwest::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Emitter, Manager};
+use url::Url;
+
+use crate::metadata::{extract_metadata, Song};
+use crate::store::load_settings;
+use crate::xml::{elements, first_text, Element};
+
+/// Held while `podcasts.json` is read, changed and written back
+static PODCASTS_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Podcast {
+    /// The feed's URL, which identifies the podcast
+    pub url: String,
+    pub title: String,
+    pub author: Option<String>,
+    pub description: Option<String>,
+    pub image: Option<String>,
+    /// Validators from the last time the feed was fetched
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// Newest first, as feeds list them
+    pub episodes: Vec<Episode>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Episode {
+    pub guid: String,
+    pub title: String,
+    /// Where the audio is, from the enclosure
+    pub url: String,
+    pub mime_type: Option<String>,
+    /// Bytes, if the feed says
+    pub length: Option<u64>,
+    /// As the feed wrote it
+    pub published: Option<String>,
+    /// Seconds
+    pub duration: Option<f64>,
+    /// Where it was downloaded to
+    pub path: Option<String>,
+    /// Seconds in, to resume from
+    pub position: f64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SubscribePodcastEvent {
+    url: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadEpisodeEvent {
+    feed_url: String,
+    guid: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct EpisodePositionEvent {
+    path: String,
+    #[serde(default)]
+    position: f64,
+}
+
+/// Sent as `episode-download-progress` while an episode downloads.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+struct EpisodeDownloadProgress {
+    guid: String,
+    progress: f64,
+}
+
+/// Parses an RSS or Atom feed fetched from `url`. Items without audio are left out.
+pub fn parse_feed(xml: &str, url: &str) -> Option<Podcast> {
+    let base = Url::parse(url).ok();
+    let resolve = |href: String| match base.as_ref().and_then(|base| base.join(&href).ok()) {
+        Some(resolved) => resolved.to_string(),
+        None => href,
+    };
+
+    // Everything before the first item describes the feed itself
+    let (items, tag) = match xml.contains("<item") {
+        true => ("item", "<item"),
+        false => ("entry", "<entry"),
+    };
+    let head = &xml[..xml.find(tag).unwrap_or(xml.len())];
+    if !head.contains("<rss") && !head.contains("<feed") && !head.contains("<channel") {
+        return None;
+    }
+
+    let episodes = elements(xml, items)
+        .filter_map(|item| parse_episode(item, &resolve))
+        .collect();
+    Some(Podcast {
+        url: url.to_string(),
+        title: first_text(head, "title").unwrap_or_else(|| url.to_string()),
+        author: first_text(head, "itunes:author")
+            .or_else(|| elements(head, "author").find_map(|a| first_text(a.content, "name")))
+            .or_else(|| first_text(head, "author")),
+        description: first_text(head, "description")
+            .or_else(|| first_text(head, "subtitle"))
+            .or_else(|| first_text(head, "itunes:summary")),
+        image: elements(head, "itunes:image")
+            .find_map(|image| image.attribute("href"))
+            .or_else(|| elements(head, "image").find_map(|image| first_text(image.content, "url")))
+            .or_else(|| first_text(head, "logo"))
+            .map(resolve),
+        etag: None,
+        last_modified: None,
+        episodes,
+    })
+}
+
+fn parse_episode(item: Element, resolve: &impl Fn(String) -> String) -> Option<Episode> {
+    // RSS has an enclosure, Atom a link to one
+    let (url, mime_type, length) = elements(item.content, "enclosure")
+        .find_map(|e| {
+            Some((
+                e.attribute("url")?,
+                e.attribute("type"),
+                e.attribute("length"),
+            ))
+        })
+        .or_else(|| {
+            elements(item.content, "link")
+                .filter(|link| link.attribute("rel").as_deref() == Some("enclosure"))
+                .find_map(|l| {
+                    Some((
+                        l.attribute("href")?,
+                        l.attribute("type"),
+                        l.attribute("length"),
+                    ))
+                })
+        })?;
+    let url = resolve(url);
+    Some(Episode {
+        guid: first_text(item.content, "guid")
+            .or_else(|| first_text(item.content, "id"))
+            .unwrap_or_else(|| url.clone()),
+        title: first_text(item.content, "title").unwrap_or_else(|| file_name(&url)),
+        mime_type,
+        // Feeds that don't know put 0
+        length: length
+            .and_then(|length| length.trim().parse().ok())
+            .filter(|length| *length > 0),
+        published: first_text(item.content, "pubDate")
+            .or_else(|| first_text(item.content, "published"))
+            .or_else(|| first_text(item.content, "updated")),
+        duration: first_text(item.content, "itunes:duration").and_then(|d| parse_duration(&d)),
+        url,
+        path: None,
+        position: 0.0,
+    })
+}
+
+/// `itunes:duration` is in seconds, or `mm:ss` or `hh:mm:ss`.
+pub fn parse_duration(duration: &str) -> Option<f64> {
+    duration
+        .trim()
+        .split(':')
+        .try_fold(None, |total: Option<f64>, part| {
+            let part: f64 = part.trim().parse().ok()?;
+            Some(Some(total.unwrap_or(0.0) * 60.0 + part))
+        })?
+}
+
+/// The name of the file at `url`, without its query.
+fn file_name(url: &str) -> String {
+    let path = Url::parse(url)
+        .map(|url| url.path().to_string())
+        .unwrap_or_else(|_| url.to_string());
+    let name = path.rsplit('/').next().unwrap_or("");
+    urlencoding::decode(name)
+        .map(|name| name.into_owned())
+        .unwrap_or_else(|_| name.to_string())
+}
+
+/// Keeps a podcast's title or an episode's file name out of other folders.
+fn sanitize(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let name = name.trim().trim_matches('.').trim();
+    match name.is_empty() {
+        true => "Untitled".to_string(),
+        false => name.to_string(),
+    }
+}
+
+/// Where an episode of `podcast` is downloaded to in `folder`. Feeds often give every
+/// enclosure the same file name, so the name ends with a hash of the episode's guid.
+pub fn episode_path(folder: &Path, podcast: &Podcast, episode: &Episode) -> PathBuf {
+    let name = file_name(&episode.url);
+    let (stem, extension) = match Path::new(&name).extension().and_then(|e| e.to_str()) {
+        Some(extension) => (
+            name[..name.len() - extension.len() - 1].to_string(),
+            extension.to_string(),
+        ),
+        None => (episode.title.clone(), extension(episode).to_string()),
+    };
+    let guid = MD5::hash(episode.guid.as_bytes()).to_hex_lowercase();
+    let name = format!("{} [{}].{}", stem, &guid[..8], extension);
+    folder.join(sanitize(&podcast.title)).join(sanitize(&name))
+}
+
+fn extension(episode: &Episode) -> &'static str {
+    match episode.mime_type.as_deref() {
+        Some("audio/mp4" | "audio/x-m4a" | "audio/m4a") => "m4a",
+        Some("audio/ogg" | "application/ogg") => "ogg",
+        Some("audio/opus") => "opus",
+        Some("audio/aac") => "aac",
+        Some("audio/flac") => "flac",
+        Some("audio/wav" | "audio/x-wav") => "wav",
+        _ => "mp3",
+    }
+}
+
+/// Fetches the podcast's feed again, unless it hasn't changed since last time. Episodes
+/// keep their downloads and positions, and downloaded ones stay after they leave the feed.
+/// Returns whether there was anything new.
+pub async fn refresh(podcast: &mut Podcast, client: &Client) -> Result<bool, String> {
+    let mut request = client.get(&podcast.url);
+    if let Some(etag) = &podcast.etag {
+        request = request.header(IF_NONE_MATCH, etag);
+    }
+    if let Some(last_modified) = &podcast.last_modified {
+        request = request.header(IF_MODIFIED_SINCE, last_modified);
+    }
+    let response = request.send().await.map_err(|e| e.to_string())?;
+    if response.status() == StatusCode::NOT_MODIFIED {
+        return Ok(false);
+    }
+    let response = response.error_for_status().map_err(|e| e.to_string())?;
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from)
+    };
+    let (etag, last_modified) = (header(ETAG), header(LAST_MODIFIED));
+    let xml = response.text().await.map_err(|e| e.to_string())?;
+    let mut feed = parse_feed(&xml, &podcast.url)
+        .ok_or_else(|| format!("{} isn't a podcast feed", podcast.url))?;
+
+    for episode in feed.episodes.iter_mut() {
+        if let Some(old) = podcast.episodes.iter().find(|old| old.guid == episode.guid) {
+            episode.path = old.path.clone();
+            episode.position = old.position;
+        }
+    }
+    let gone = podcast.episodes.drain(..).filter(|old| {
+        old.path.is_some() && !feed.episodes.iter().any(|episode| episode.guid == old.guid)
+    });
+    feed.episodes.extend(gone.collect::<Vec<_>>());
+    feed.etag = etag;
+    feed.last_modified = last_modified;
+    *podcast = feed;
+    Ok(true)
+}
+
+fn podcasts_path(app: &AppHandle) -> Result<PathBuf, String> {
+    let config_dir = app.path().app_config_dir().map_err(|e| e.to_string())?;
+    Ok(config_dir.join("podcasts.json"))
+}
+
+/// The saved podcasts, none if there's no file yet. A file that can't be read is an
+/// error, saving over it would lose every subscription.
+pub fn load_podcasts(path: &Path) -> Result<Vec<Podcast>, String> {
+    let data = match fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(format!("Couldn't read {:?}: {}", path, err)),
+    };
+    serde_json::from_str(&data).map_err(|err| format!("Couldn't read {:?}: {}", path, err))
+}
+
+pub fn save_podcasts(path: &Path, podcasts: &[Podcast]) -> Result<(), String> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    }
+    let data = serde_json::to_string_pretty(podcasts).map_err(|e| e.to_string())?;
+    fs::write(path, data).map_err(|e| e.to_string())
+}
+
+/// Changes the saved podcasts with `f`. Never held across an await, so what's fetched
+/// is merged in afterwards.
+fn update_podcasts<T>(
+    app: &AppHandle,
+    f: impl FnOnce(&mut Vec<Podcast>) -> Result<T, String>,
+) -> Result<T, String> {
+    let path = podcasts_path(app)?;
+    let _lock = PODCASTS_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+    let mut podcasts = load_podcasts(&path)?;
+    let result = f(&mut podcasts)?;
+    save_podcasts(&path, &podcasts)?;
+    Ok(result)
+}
+
+/// Fetches `podcast` again and saves what's new, keeping anything that changed meanwhile.
+async fn refresh_and_save(
+    mut podcast: Podcast,
+    client: &Client,
+    app: &AppHandle,
+) -> Result<Podcast, String> {
+    if !refresh(&mut podcast, client).await? {
+        return Ok(podcast);
+    }
+    update_podcasts(app, |podcasts| {
+        match podcasts.iter_mut().find(|saved| saved.url == podcast.url) {
+            Some(saved) => {
+                for episode in podcast.episodes.iter_mut() {
+                    if let Some(old) = saved.episodes.iter().find(|old| old.guid == episode.guid) {
+                        episode.path = old.path.clone();
+                        episode.position = old.position;
+                    }
+                }
+                *saved = podcast.clone();
+            }
+            None => podcasts.push(podcast.clone()),
+        }
+        Ok(podcast)
+    })
+}
+
+#[tauri::command]
+pub async fn subscribe_podcast(
+    event: SubscribePodcastEvent,
+    app_handle: AppHandle,
+) -> Result<Podcast, String> {
+    let url = event.url.trim().to_string();
+    let existing = load_podcasts(&podcasts_path(&app_handle)?)?
+        .into_iter()
+        .find(|podcast| podcast.url == url);
+    if let Some(podcast) = existing {
+        return Ok(podcast);
+    }
+    info!("podcast: subscribing to {}", url);
+    let podcast = Podcast {
+        url,
+        ..Default::default()
+    };
+    refresh_and_save(podcast, &crate::http_client(), &app_handle).await
+}
+
+#[tauri::command]
+pub fn unsubscribe_podcast(
+    event: SubscribePodcastEvent,
+    app_handle: AppHandle,
+) -> Result<(), String> {
+    update_podcasts(&app_handle, |podcasts| {
+        podcasts.retain(|podcast| podcast.url != event.url);
+        Ok(())
+    })
+}
+
+#[tauri::command]
+pub fn get_podcasts(app_handle: AppHandle) -> Result<Vec<Podcast>, String> {
+    load_podcasts(&podcasts_path(&app_handle)?)
+}
+
+/// Fetches every feed that's changed. A feed that can't be fetched is left as it was.
+#[tauri::command]
+pub async fn refresh_podcasts(app_handle: AppHandle) -> Result<Vec<Podcast>, String> {
+    let client = crate::http_client();
+    let podcasts = get_podcasts(app_handle.clone())?;
+    let mut refreshed = Vec::with_capacity(podcasts.len());
+    for podcast in podcasts {
+        let url = podcast.url.clone();
+        match refresh_and_save(podcast.clone(), &client, &app_handle).await {
+            Ok(podcast) => refreshed.push(podcast),
+            Err(err) => {
+                info!("podcast: couldn't refresh {}: {}", url, err);
+                refreshed.push(podcast);
+            }
+        }
+    }
+    Ok(refreshed)
+}
+
+/// Downloads an episode into the download location and imports it. Progress is sent as
+/// `episode-download-progress`.
+#[tauri::command]
+pub async fn download_episode(
+    event: DownloadEpisodeEvent,
+    app_handle: AppHandle,
+) -> Result<Song, String> {
+    let (podcast, episode) = update_podcasts(&app_handle, |podcasts| {
+        let podcast = podcasts
+            .iter()
+            .find(|podcast| podcast.url == event.feed_url)
+            .ok_or("Not subscribed to this podcast")?;
+        let episode = podcast
+            .episodes
+            .iter()
+            .find(|episode| episode.guid == event.guid)
+            .ok_or("No such episode")?;
+        Ok((podcast.clone(), episode.clone()))
+    })?;
+
+    let folder = match load_settings(&app_handle)
+        .ok()
+        .and_then(|settings| settings.download_location)
+    {
+        Some(location) => PathBuf::from(location),
+        None => app_handle
+            .path()
+            .download_dir()
+            .map_err(|e| e.to_string())?,
+    };
+    // Downloaded before, maybe under another name
+    let path = match episode.path.as_deref().map(PathBuf::from) {
+        Some(path) if path.exists() => path,
+        _ => episode_path(&folder, &podcast, &episode),
+    };
+    if !path.exists() {
+        info!("podcast: downloading {} to {:?}", episode.url, path);
+        crate::download(&episode.url, &path, |progress| {
+            let _ = app_handle.emit(
+                "episode-download-progress",
+                EpisodeDownloadProgress {
+                    guid: episode.guid.clone(),
+                    progress,
+                },
+            );
+        })
+        .await?;
+    }
+
+    let song = extract_metadata(&path, true, false, &app_handle)
+        .ok_or_else(|| format!("Couldn't read {:?}", path))?;
+    update_podcasts(&app_handle, |podcasts| {
+        let episode = podcasts
+            .iter_mut()
+            .filter(|podcast| podcast.url == event.feed_url)
+            .flat_map(|podcast| podcast.episodes.iter_mut())
+            .find(|episode| episode.guid == event.guid);
+        if let Some(episode) = episode {
+            episode.path = Some(path.to_string_lossy().into_owned());
+        }
+        Ok(())
+    })?;
+    Ok(song)
+}
+
+fn find_episode<'a>(podcasts: &'a mut [Podcast], path: &str) -> Option<&'a mut Episode> {
+    podcasts
+        .iter_mut()
+        .flat_map(|podcast| podcast.episodes.iter_mut())
+        .find(|episode| episode.path.as_deref() == Some(path))
+}
+
+/// Remembers how far into a downloaded episode the listener got. Anything that isn't an
+/// episode is ignored.
+#[tauri::command]
+pub fn save_episode_position(
+    event: EpisodePositionEvent,
+    app_handle: AppHandle,
+) -> Result<(), String> {
+    update_podcasts(&app_handle, |podcasts| {
+        if let Some(episode) = find_episode(podcasts, &event.path) {
+            episode.position = event.position.max(0.0);
+        }
+        Ok(())
+    })
+}
+
+/// Where to resume a downloaded episode from, or None if the song isn't an episode.
+#[tauri::command]
+pub fn get_episode_position(event: EpisodePositionEvent, app_handle: AppHandle) -> Option<f64> {
+    let mut podcasts = load_podcasts(&podcasts_path(&app_handle).ok()?).ok()?;
+    find_episode(&mut podcasts, &event.path).map(|episode| episode.position)
+}
@@ -0,0 +1,511 @@
+//! The play queue, owned by the backend so gapless transitions and the end of the
+//! queue don't depend on the webview being responsive.
+
+use std::cmp::Ordering;
+use std::path::Path;
+
+use log::info;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Emitter, State};
+
+use crate::player::{AudioStreamer, PlayerControlEvent};
+use crate::player_state::PlayerStateHandle;
+use crate::waveform_cache;
+use crate::StreamFileRequest;
+
+/// Number of tracks remembered for going back
+const MAX_HISTORY: usize = 500;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RepeatMode {
+    Off,
+    One,
+    All,
+}
+
+impl Default for RepeatMode {
+    fn default() -> Self {
+        RepeatMode::Off
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct QueueState {
+    pub tracks: Vec<String>,
+    /// Play order, as indices into `tracks`
+    pub order: Vec<usize>,
+    /// Index of the current track in `tracks`
+    pub current: Option<usize>,
+    pub next: Option<String>,
+    pub shuffle: bool,
+    pub repeat: RepeatMode,
+}
+
+#[derive(Default)]
+pub struct PlayQueue {
+    tracks: Vec<String>,
+    /// Play order, as indices into `tracks`. In list order unless shuffled
+    order: Vec<usize>,
+    /// Position of the current track in `order`, None before the first one
+    position: Option<usize>,
+    /// The current track was removed, `position` is the track before it
+    detached: bool,
+    /// Tracks played before the current one, most recent last
+    history: Vec<usize>,
+    shuffle: bool,
+    repeat: RepeatMode,
+}
+
+impl PlayQueue {
+    /// Replaces the queue, with `current` (an index into `tracks`) as the current track.
+    pub fn set(&mut self, tracks: Vec<String>, current: Option<usize>) {
+        self.tracks = tracks;
+        self.history.clear();
+        self.detached = false;
+        self.reorder(current.filter(|i| *i < self.tracks.len()));
+    }
+
+    /// Inserts tracks before `index`, or at the end. When shuffling they're played at
+    /// random points after the current track.
+    pub fn insert(&mut self, index: Option<usize>, tracks: Vec<String>) {
+        let index = index.map_or(self.tracks.len(), |i| i.min(self.tracks.len()));
+        let count = tracks.len();
+        self.tracks.splice(index..index, tracks);
+        self.remap(|i| Some(if i >= index { i + count } else { i }));
+
+        let anchor = self.anchor();
+        let mut rng = rand::thread_rng();
+        for new in index..index + count {
+            let at = if self.shuffle {
+                rng.gen_range(self.position.map_or(0, |p| p + 1)..=self.order.len())
+            } else {
+                new
+            };
+            self.order.insert(at, new);
+        }
+        if let Some(anchor) = anchor {
+            self.position = self.order.iter().position(|i| *i == anchor);
+        }
+    }
+
+    pub fn remove(&mut self, index: usize) -> bool {
+        if index >= self.tracks.len() {
+            return false;
+        }
+        self.tracks.remove(index);
+        self.remap(|i| match i.cmp(&index) {
+            Ordering::Less => Some(i),
+            Ordering::Equal => None,
+            Ordering::Greater => Some(i - 1),
+        });
+        true
+    }
+
+    pub fn move_track(&mut self, from: usize, to: usize) -> bool {
+        if from >= self.tracks.len() || to >= self.tracks.len() {
+            return false;
+        }
+        let track = self.tracks.remove(from);
+        self.tracks.insert(to, track);
+        self.remap(|i| {
+            Some(if i == from {
+                to
+            } else if from < to && i > from && i <= to {
+                i - 1
+            } else if to < from && i >= to && i < from {
+                i + 1
+            } else {
+                i
+            })
+        });
+        true
+    }
+
+    /// Moves a track within the play order, for reordering a shuffled queue as it's shown.
+    pub fn move_in_order(&mut self, from: usize, to: usize) -> bool {
+        if from >= self.order.len() || to >= self.order.len() {
+            return false;
+        }
+        let anchor = self.anchor();
+        let index = self.order.remove(from);
+        self.order.insert(to, index);
+        if let Some(anchor) = anchor {
+            self.position = self.order.iter().position(|i| *i == anchor);
+        }
+        true
+    }
+
+    pub fn clear(&mut self) {
+        self.tracks.clear();
+        self.order.clear();
+        self.position = None;
+        self.detached = false;
+        self.history.clear();
+    }
+
+    pub fn set_shuffle(&mut self, shuffle: bool) {
+        if shuffle != self.shuffle {
+            self.shuffle = shuffle;
+            self.reorder(self.anchor());
+        }
+    }
+
+    pub fn set_repeat(&mut self, repeat: RepeatMode) {
+        self.repeat = repeat;
+    }
+
+    pub fn is_shuffled(&self) -> bool {
+        self.shuffle
+    }
+
+    pub fn len(&self) -> usize {
+        self.tracks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tracks.is_empty()
+    }
+
+    pub fn current_index(&self) -> Option<usize> {
+        if self.detached {
+            None
+        } else {
+            self.anchor()
+        }
+    }
+
+    pub fn current(&self) -> Option<&str> {
+        self.current_index().map(|i| self.tracks[i].as_str())
+    }
+
+    /// The track that plays when the current one ends.
+    pub fn peek_next(&self) -> Option<&str> {
+        self.next_position(true)
+            .map(|p| self.tracks[self.order[p]].as_str())
+    }
+
+    /// The current track and the ones after it in play order, up to `count` of them.
+    pub fn upcoming(&self, count: usize) -> Vec<String> {
+        let start = match self.position {
+            Some(position) if self.detached => position + 1,
+            Some(position) => position,
+            None => 0,
+        };
+        self.order
+            .iter()
+            .skip(start)
+            .take(count)
+            .map(|i| self.tracks[*i].clone())
+            .collect()
+    }
+
+    /// Moves on to the next track. `auto` is set when the current track ended by itself,
+    /// which plays it again in repeat-one mode; skipping always moves on.
+    pub fn advance(&mut self, auto: bool) -> Option<String> {
+        let next = self.next_position(auto)?;
+        self.go_to(next);
+        self.current().map(String::from)
+    }
+
+    /// Makes the track at `index` the current one, as when it's picked to play. The
+    /// play order stays as it is.
+    pub fn select(&mut self, index: usize) -> bool {
+        match self.order.iter().position(|i| *i == index) {
+            Some(position) => {
+                self.go_to(position);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Goes back to the track played before the current one.
+    pub fn previous(&mut self) -> Option<String> {
+        let position = match self.history.pop() {
+            Some(index) => self.order.iter().position(|i| *i == index),
+            None => match self.position {
+                Some(p) if self.detached => Some(p),
+                Some(p) if p > 0 => Some(p - 1),
+                Some(_) if self.repeat == RepeatMode::All => self.order.len().checked_sub(1),
+                _ => None,
+            },
+        }?;
+        self.position = Some(position);
+        self.detached = false;
+        self.current().map(String::from)
+    }
+
+    pub fn state(&self) -> QueueState {
+        QueueState {
+            tracks: self.tracks.clone(),
+            order: self.order.clone(),
+            current: self.current_index(),
+            next: self.peek_next().map(String::from),
+            shuffle: self.shuffle,
+            repeat: self.repeat,
+        }
+    }
+
+    /// Moves to `position` in the play order, remembering the track it leaves.
+    fn go_to(&mut self, position: usize) {
+        if let Some(current) = self.current_index() {
+            if Some(position) != self.position {
+                self.history.push(current);
+                if self.history.len() > MAX_HISTORY {
+                    self.history.remove(0);
+                }
+            }
+        }
+        self.position = Some(position);
+        self.detached = false;
+    }
+
+    /// Repeat-all carries on from the start of the same order, shuffled or not.
+    fn next_position(&self, auto: bool) -> Option<usize> {
+        if self.is_empty() {
+            return None;
+        }
+        let next = match self.position {
+            None => 0,
+            Some(position) if auto && self.repeat == RepeatMode::One && !self.detached => position,
+            Some(position) => position + 1,
+        };
+        if next < self.order.len() {
+            Some(next)
+        } else if self.repeat == RepeatMode::All {
+            Some(0)
+        } else {
+            None
+        }
+    }
+
+    /// The track at `position`, even if it's the one before a removed current track.
+    fn anchor(&self) -> Option<usize> {
+        self.position.map(|p| self.order[p])
+    }
+
+    /// Rebuilds the play order. When shuffling, `current` goes first.
+    fn reorder(&mut self, current: Option<usize>) {
+        self.order = (0..self.tracks.len()).collect();
+        if self.shuffle {
+            self.order.shuffle(&mut rand::thread_rng());
+            if let Some(current) = current {
+                if let Some(position) = self.order.iter().position(|i| *i == current) {
+                    self.order.swap(0, position);
+                }
+            }
+        }
+        self.position = current.and_then(|c| self.order.iter().position(|i| *i == c));
+    }
+
+    /// Applies new track indices (None for a removed track) to the order and history.
+    fn remap<F: Fn(usize) -> Option<usize>>(&mut self, map: F) {
+        let anchor = self.anchor().and_then(&map);
+        // If the current track was removed, the one after it is next
+        let mut kept_before = 0usize;
+        let mut order = Vec::with_capacity(self.order.len());
+        for (position, index) in self.order.iter().enumerate() {
+            if let Some(index) = map(*index) {
+                order.push(index);
+                if self.position.map_or(false, |p| position < p) {
+                    kept_before += 1;
+                }
+            }
+        }
+        if !self.shuffle {
+            order.sort_unstable();
+        }
+        match anchor {
+            Some(anchor) => self.position = order.iter().position(|i| *i == anchor),
+            None if self.position.is_some() => {
+                self.position = kept_before.checked_sub(1);
+                self.detached = true;
+            }
+            None => {}
+        }
+        self.order = order;
+        self.history = self.history.iter().filter_map(|i| map(*i)).collect();
+    }
+}
+
+/// Sends the queue to the frontend, and the next track to the player state. The
+/// waveforms of the tracks coming up are computed in the meantime.
+pub fn emit_queue(queue: &PlayQueue, player_state: &PlayerStateHandle, app_handle: &AppHandle) {
+    let state = queue.state();
+    player_state.update_and_emit(app_handle, |player_state| {
+        player_state.next_track = state.next.clone()
+    });
+    let _ = app_handle.emit("queue_changed", state);
+    waveform_cache::precompute(app_handle, queue.upcoming(waveform_cache::PRECOMPUTE_AHEAD));
+}
+
+fn update_queue<R, F: FnOnce(&mut PlayQueue) -> R>(
+    state: &AudioStreamer,
+    app_handle: &AppHandle,
+    f: F,
+) -> Option<R> {
+    let mut queue = state.queue.lock().ok()?;
+    let result = f(&mut queue);
+    emit_queue(&queue, &state.player_state, app_handle);
+    Some(result)
+}
+
+/// Starts playing a track from the queue, and tells the frontend about it.
+fn play_track(state: &AudioStreamer, app_handle: &AppHandle, path: String) {
+    info!("queue: playing {}", path);
+    let volume = state.player_state.snapshot().volume.unwrap_or(1.0);
+    let _ = state
+        .player_control_sender
+        .send(PlayerControlEvent::StreamFile(StreamFileRequest {
+            path: Some(path.clone()),
+            seek: Some(0.0),
+            file_info: None,
+            volume: Some(volume),
+            output_device: None,
+        }));
+    state.resume();
+
+    if let Some(song) =
+        crate::metadata::extract_metadata(Path::new(&path), false, false, app_handle)
+    {
+        let _ = app_handle.emit("song_change", Some(song));
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct QueueSetRequest {
+    paths: Vec<String>,
+    index: Option<usize>, // current track
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct QueueInsertRequest {
+    paths: Vec<String>,
+    index: Option<usize>, // None to append
+}
+
+/// Positions as the queue is shown: in play order when shuffling, in list order otherwise.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct QueueMoveRequest {
+    from: usize,
+    to: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct QueueSelectRequest {
+    index: usize, // into the tracks
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct QueueRemoveRequest {
+    index: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct QueueShuffleRequest {
+    enabled: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct QueueRepeatRequest {
+    mode: RepeatMode,
+}
+
+#[tauri::command]
+pub fn get_queue(state: State<AudioStreamer>) -> Option<QueueState> {
+    state.queue.lock().ok().map(|queue| queue.state())
+}
+
+#[tauri::command]
+pub fn queue_set(event: QueueSetRequest, state: State<AudioStreamer>, app_handle: AppHandle) {
+    info!(
+        "Queue set {} tracks, current {:?}",
+        event.paths.len(),
+        event.index
+    );
+    update_queue(&state, &app_handle, |queue| {
+        queue.set(event.paths, event.index)
+    });
+}
+
+#[tauri::command]
+pub fn queue_insert(event: QueueInsertRequest, state: State<AudioStreamer>, app_handle: AppHandle) {
+    info!(
+        "Queue insert {} tracks at {:?}",
+        event.paths.len(),
+        event.index
+    );
+    update_queue(&state, &app_handle, |queue| {
+        queue.insert(event.index, event.paths)
+    });
+}
+
+#[tauri::command]
+pub fn queue_move(event: QueueMoveRequest, state: State<AudioStreamer>, app_handle: AppHandle) {
+    info!("Queue move {:?}", event);
+    update_queue(&state, &app_handle, |queue| match queue.is_shuffled() {
+        true => queue.move_in_order(event.from, event.to),
+        false => queue.move_track(event.from, event.to),
+    });
+}
+
+/// The frontend picked a track of the queue to play, it carries on from there.
+#[tauri::command]
+pub fn queue_select(event: QueueSelectRequest, state: State<AudioStreamer>, app_handle: AppHandle) {
+    info!("Queue select {:?}", event);
+    update_queue(&state, &app_handle, |queue| queue.select(event.index));
+}
+
+#[tauri::command]
+pub fn queue_remove(event: QueueRemoveRequest, state: State<AudioStreamer>, app_handle: AppHandle) {
+    info!("Queue remove {:?}", event);
+    update_queue(&state, &app_handle, |queue| queue.remove(event.index));
+}
+
+#[tauri::command]
+pub fn queue_clear(state: State<AudioStreamer>, app_handle: AppHandle) {
+    info!("Queue clear");
+    update_queue(&state, &app_handle, |queue| queue.clear());
+}
+
+#[tauri::command]
+pub fn queue_shuffle(
+    event: QueueShuffleRequest,
+    state: State<AudioStreamer>,
+    app_handle: AppHandle,
+) {
+    info!("Queue shuffle {:?}", event);
+    update_queue(&state, &app_handle, |queue| {
+        queue.set_shuffle(event.enabled)
+    });
+}
+
+#[tauri::command]
+pub fn queue_repeat(event: QueueRepeatRequest, state: State<AudioStreamer>, app_handle: AppHandle) {
+    info!("Queue repeat {:?}", event);
+    update_queue(&state, &app_handle, |queue| queue.set_repeat(event.mode));
+}
+
+/// Skips to the next track, returning its path (None at the end of the queue).
+#[tauri::command]
+pub fn queue_next_track(state: State<AudioStreamer>, app_handle: AppHandle) -> Option<String> {
+    let next = update_queue(&state, &app_handle, |queue| queue.advance(false)).flatten();
+    if let Some(path) = next.clone() {
+        play_track(&state, &app_handle, path);
+    }
+    next
+}
+
+/// Goes back to the previous track, returning its path.
+#[tauri::command]
+pub fn queue_previous_track(state: State<AudioStreamer>, app_handle: AppHandle) -> Option<String> {
+    let previous = update_queue(&state, &app_handle, |queue| queue.previous()).flatten();
+    if let Some(path) = previous.clone() {
+        play_track(&state, &app_handle, path);
+    }
+    previous
+}
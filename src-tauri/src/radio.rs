@@ -0,0 +1,345 @@
+//! Internet radio: HTTP and Icecast/Shoutcast streams.
+//!
+//! A stream is fetched on a thread of its own and handed to the decoder through a channel,
+//! so a slow connection stalls playback instead of ending it. Servers asked for ICY
+//! metadata send it between every `icy-metaint` bytes of audio; it's taken out here and
+//! the StreamTitle reported whenever it changes.
+
+use std::io::{self, Read, Seek, SeekFrom};
+use std::thread;
+
+use bytes::Bytes;
+use futures_util::StreamExt;
+use log::{info, warn};
+use reqwest::{Client, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use symphonia::core::codecs::Decoder;
+use symphonia::core::formats::FormatReader;
+use symphonia::core::io::MediaSource;
+use symphonia::core::probe::Hint;
+use tauri::{AppHandle, Emitter};
+use tokio::sync::mpsc;
+use tokio::time::{timeout, Duration};
+
+use crate::player_state::PlayerStateHandle;
+
+/// Chunks read ahead of the decoder
+const BUFFERED_CHUNKS: usize = 256;
+/// A stream that sends nothing for this long has dropped, and one that doesn't answer
+/// for this long never started
+const STALL_TIMEOUT: Duration = Duration::from_secs(10);
+/// Tries at reconnecting after a stream drops, each a second longer after the last
+const RECONNECT_ATTEMPTS: u64 = 5;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RadioStation {
+    pub name: String,
+    pub url: String,
+}
+
+/// Sent as `stream_metadata` when the station or what's playing on it changes.
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamMetadata {
+    pub url: String,
+    /// icy-name
+    pub station: Option<String>,
+    /// StreamTitle, usually "Artist - Title"
+    pub title: Option<String>,
+}
+
+pub fn is_stream_url(path: &str) -> bool {
+    let scheme = path.get(..8).unwrap_or(path).to_ascii_lowercase();
+    scheme.starts_with("http://") || scheme.starts_with("https://")
+}
+
+enum IcyState {
+    Audio(usize),
+    Length,
+    Metadata(usize),
+}
+
+/// Splits the audio of a stream from the metadata blocks sent between it.
+pub struct IcyParser {
+    metaint: usize,
+    state: IcyState,
+    metadata: Vec<u8>,
+}
+
+impl IcyParser {
+    pub fn new(metaint: usize) -> Self {
+        IcyParser {
+            metaint,
+            state: IcyState::Audio(metaint),
+            metadata: Vec::new(),
+        }
+    }
+
+    /// Adds the audio in `chunk` to `audio`. Returns the StreamTitle of the last metadata
+    /// block that ended in it, if there was one.
+    pub fn feed(&mut self, mut chunk: &[u8], audio: &mut Vec<u8>) -> Option<String> {
+        let mut title = None;
+        while !chunk.is_empty() {
+            match self.state {
+                IcyState::Audio(remaining) => {
+                    let n = remaining.min(chunk.len());
+                    audio.extend_from_slice(&chunk[..n]);
+                    chunk = &chunk[n..];
+                    self.state = match remaining - n {
+                        0 => IcyState::Length,
+                        remaining => IcyState::Audio(remaining),
+                    };
+                }
+                IcyState::Length => {
+                    // In blocks of 16 bytes, mostly 0 for no change
+                    let length = chunk[0] as usize * 16;
+                    chunk = &chunk[1..];
+                    self.metadata.clear();
+                    self.state = match length {
+                        0 => IcyState::Audio(self.metaint),
+                        length => IcyState::Metadata(length),
+                    };
+                }
+                IcyState::Metadata(remaining) => {
+                    let n = remaining.min(chunk.len());
+                    self.metadata.extend_from_slice(&chunk[..n]);
+                    chunk = &chunk[n..];
+                    self.state = match remaining - n {
+                        0 => {
+                            title = stream_title(&self.metadata).or(title);
+                            IcyState::Audio(self.metaint)
+                        }
+                        remaining => IcyState::Metadata(remaining),
+                    };
+                }
+            }
+        }
+        title
+    }
+}
+
+/// The StreamTitle of a metadata block, `StreamTitle='...';StreamUrl='...';` padded with
+/// zeros. Stations that don't send UTF-8 usually send Latin-1.
+fn stream_title(metadata: &[u8]) -> Option<String> {
+    let text = String::from_utf8(metadata.to_vec())
+        .unwrap_or_else(|err| err.into_bytes().iter().map(|&b| b as char).collect());
+    let text = text.trim_end_matches('\0');
+    let start = text.find("StreamTitle='")? + "StreamTitle='".len();
+    let rest = &text[start..];
+    let end = rest.find("';").unwrap_or(rest.len());
+    Some(rest[..end].trim_end_matches(['\'', ';']).trim().to_string())
+}
+
+/// A live stream, as a source the decoder can read from but not seek in.
+pub struct HttpStream {
+    chunks: mpsc::Receiver<Bytes>,
+    chunk: Bytes,
+    content_type: Option<String>,
+}
+
+impl HttpStream {
+    /// Connects to `url`, then keeps reading it in the background, reconnecting if it
+    /// drops. `on_metadata` is called from the background thread.
+    pub fn open<F>(url: &str, on_metadata: F) -> io::Result<HttpStream>
+    where
+        F: Fn(StreamMetadata) + Send + 'static,
+    {
+        let (sender, chunks) = mpsc::channel(BUFFERED_CHUNKS);
+        let (connected_sender, connected) = std::sync::mpsc::channel();
+        let url = url.to_string();
+        thread::spawn(move || {
+            match tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+            {
+                Ok(rt) => rt.block_on(fetch(url, sender, connected_sender, on_metadata)),
+                Err(err) => {
+                    let _ = connected_sender.send(Err(err));
+                }
+            }
+        });
+        let content_type = connected
+            .recv()
+            .map_err(|_| io::Error::other("stream thread stopped"))??;
+        Ok(HttpStream {
+            chunks,
+            chunk: Bytes::new(),
+            content_type,
+        })
+    }
+
+    /// Streams have no file extension to go by, only their content type.
+    pub fn hint(&self) -> Hint {
+        let mut hint = Hint::new();
+        if let Some(content_type) = &self.content_type {
+            let mime = content_type.split(';').next().unwrap_or("").trim();
+            hint.mime_type(mime);
+            let extension = match mime {
+                "audio/mpeg" | "audio/mp3" => Some("mp3"),
+                "audio/aac" | "audio/aacp" => Some("aac"),
+                "audio/flac" => Some("flac"),
+                "audio/ogg" | "application/ogg" => Some("ogg"),
+                _ => None,
+            };
+            if let Some(extension) = extension {
+                hint.with_extension(extension);
+            }
+        }
+        hint
+    }
+}
+
+impl Read for HttpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.chunk.is_empty() {
+            match self.chunks.blocking_recv() {
+                Some(chunk) => self.chunk = chunk,
+                // Gone for good, after trying to reconnect
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len());
+        buf[..n].copy_from_slice(&self.chunk.split_to(n));
+        Ok(n)
+    }
+}
+
+impl Seek for HttpStream {
+    fn seek(&mut self, _pos: SeekFrom) -> io::Result<u64> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "live streams can't seek",
+        ))
+    }
+}
+
+impl MediaSource for HttpStream {
+    fn is_seekable(&self) -> bool {
+        false
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// Connects to a stream. Giving up after `STALL_TIMEOUT` matters, as the player waits for
+/// the first connection and can't be paused or stopped meanwhile.
+async fn connect(client: &Client, url: &str) -> io::Result<Response> {
+    let request = client.get(url).header("Icy-MetaData", "1").send();
+    timeout(STALL_TIMEOUT, request)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "the station didn't answer"))?
+        .and_then(|response| response.error_for_status())
+        .map_err(|err| {
+            let kind = match err.status() {
+                Some(StatusCode::NOT_FOUND) => io::ErrorKind::NotFound,
+                _ => io::ErrorKind::Other,
+            };
+            io::Error::new(kind, err)
+        })
+}
+
+/// Reads the stream into `sender` until the player stops reading it. The first
+/// connection's result, with the stream's content type, goes to `connected`.
+async fn fetch<F: Fn(StreamMetadata)>(
+    url: String,
+    sender: mpsc::Sender<Bytes>,
+    connected: std::sync::mpsc::Sender<io::Result<Option<String>>>,
+    on_metadata: F,
+) {
+    let client = crate::http_client();
+    let mut connected = Some(connected);
+    let mut metadata = StreamMetadata {
+        url: url.clone(),
+        ..Default::default()
+    };
+    let mut attempt = 0;
+    loop {
+        match connect(&client, &url).await {
+            Ok(response) => {
+                let header = |name: &str| {
+                    response
+                        .headers()
+                        .get(name)
+                        .and_then(|value| value.to_str().ok())
+                        .map(String::from)
+                };
+                if let Some(connected) = connected.take() {
+                    let _ = connected.send(Ok(header("content-type")));
+                }
+                let station = header("icy-name");
+                if station != metadata.station {
+                    metadata.station = station;
+                    on_metadata(metadata.clone());
+                }
+                let mut icy = header("icy-metaint")
+                    .and_then(|metaint| metaint.trim().parse().ok())
+                    .filter(|metaint| *metaint > 0)
+                    .map(IcyParser::new);
+
+                // Anything with a length isn't live, and ends when it's all been read
+                let length = response.content_length();
+                let mut received = 0;
+                let mut body = response.bytes_stream();
+                while let Ok(Some(Ok(chunk))) = timeout(STALL_TIMEOUT, body.next()).await {
+                    attempt = 0;
+                    received += chunk.len() as u64;
+                    let audio = match icy.as_mut() {
+                        Some(icy) => {
+                            let mut audio = Vec::with_capacity(chunk.len());
+                            if let Some(title) = icy.feed(&chunk, &mut audio) {
+                                let title = Some(title).filter(|title| !title.is_empty());
+                                if title != metadata.title {
+                                    metadata.title = title;
+                                    on_metadata(metadata.clone());
+                                }
+                            }
+                            Bytes::from(audio)
+                        }
+                        None => chunk,
+                    };
+                    if sender.send(audio).await.is_err() {
+                        // The player has moved on
+                        return;
+                    }
+                }
+                if length == Some(received) {
+                    return;
+                }
+                info!("radio: {} dropped", url);
+            }
+            Err(err) => {
+                if let Some(connected) = connected.take() {
+                    let _ = connected.send(Err(err));
+                    return;
+                }
+                warn!("radio: couldn't reconnect to {}: {}", url, err);
+            }
+        }
+        attempt += 1;
+        if attempt > RECONNECT_ATTEMPTS || sender.is_closed() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_secs(attempt)).await;
+    }
+}
+
+/// Connects to a stream and creates a decoder for it. What's playing is sent as
+/// `stream_metadata`, and kept in the player state.
+pub fn open_track(
+    url: &str,
+    player_state: &PlayerStateHandle,
+    app_handle: &AppHandle,
+) -> Result<(Box<dyn FormatReader>, Box<dyn Decoder>, u32), symphonia::core::errors::Error> {
+    let player_state = player_state.clone();
+    let app_handle = app_handle.clone();
+    let stream = HttpStream::open(url, move |metadata| {
+        player_state.update_and_emit(&app_handle, |state| {
+            state.stream_title = metadata.title.clone()
+        });
+        let _ = app_handle.emit("stream_metadata", metadata);
+    })?;
+    let hint = stream.hint();
+    crate::player::open_source(Box::new(stream), &hint)
+}
@@ -0,0 +1,31 @@
+//! The formats and codecs the player can decode: symphonia's own, plus the ones it doesn't
+//! ship.
+
+use symphonia::core::codecs::CodecRegistry;
+use symphonia::core::probe::Probe;
+
+use crate::ape::{ApeDecoder, ApeReader};
+use crate::dsd::{DsdDecoder, DsdReader};
+use crate::opus::OpusDecoder;
+use crate::wavpack::{WavPackDecoder, WavPackReader};
+
+/// Symphonia's enabled codecs and ours. Cheap enough to build for each track.
+pub fn get_codecs() -> CodecRegistry {
+    let mut registry = CodecRegistry::new();
+    symphonia::default::register_enabled_codecs(&mut registry);
+    registry.register_all::<OpusDecoder>();
+    registry.register_all::<ApeDecoder>();
+    registry.register_all::<WavPackDecoder>();
+    registry.register_all::<DsdDecoder>();
+    registry
+}
+
+/// Symphonia's enabled formats and ours, likewise.
+pub fn get_probe() -> Probe {
+    let mut probe = Probe::default();
+    symphonia::default::register_enabled_formats(&mut probe);
+    probe.register_all::<ApeReader>();
+    probe.register_all::<WavPackReader>();
+    probe.register_all::<DsdReader>();
+    probe
+}
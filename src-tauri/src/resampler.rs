@@ -17,6 +17,10 @@ pub struct Resampler<T> {
     output: Vec<Vec<f32>>,
     interleaved: Vec<T>,
     duration: usize,
+    input_rate: u32,
+    output_rate: u32,
+    frames_in: u64,
+    frames_out: u64,
 }
 
 impl<T> Resampler<T>
@@ -42,6 +46,8 @@ where
             channel.drain(0..self.duration);
         }
 
+        self.frames_out += output[0].len() as u64;
+
         // Interleave the planar samples from Rubato.
         let num_channels = output.len();
 
@@ -102,9 +108,19 @@ where
             output,
             duration,
             interleaved: Default::default(),
+            input_rate: spec.rate,
+            output_rate: to_sample_rate as u32,
+            frames_in: 0,
+            frames_out: 0,
         }
     }
 
+    /// Whether this resampler can take over input with the given sample rate and packet size
+    /// without being re-created (and losing the samples it holds).
+    pub fn accepts(&self, spec: &SignalSpec, max_frames: u64) -> bool {
+        self.input_rate == spec.rate && self.duration >= max_frames as usize
+    }
+
     /// Resamples a planar/non-interleaved input.
     ///
     /// Returns the resampled samples in an interleaved format.
@@ -117,24 +133,46 @@ where
             return None;
         }
 
+        self.frames_in += self.duration as u64;
         Some(self.resample_inner())
     }
 
     /// Resample any remaining samples in the resample buffer.
+    ///
+    /// The partial chunk is padded with silence and pushed through together with the
+    /// resampler's delay, and the output is cut at the frame that corresponds to the
+    /// end of the real input. Even with no partial chunk, the delay still holds output.
     pub fn flush(&mut self) -> Option<&[T]> {
         let len = self.input[0].len();
 
         info!("Flushing resampler, len: {}", len);
-        if len == 0 {
-            return None;
-        }
 
-        for channel in self.input.iter_mut() {
-            channel.drain(0..len);
-            // channel.resize(len + (self.duration - partial_len), f32::MID);
+        self.frames_in += len as u64;
+        let expected_frames_out =
+            self.frames_in * self.output_rate as u64 / self.input_rate as u64;
+
+        let num_channels = self.input.len();
+        let mut flushed: Vec<T> = Vec::new();
+
+        // The delay of the resampler is less than two chunks
+        for _ in 0..3 {
+            if self.frames_out >= expected_frames_out {
+                break;
+            }
+            for channel in self.input.iter_mut() {
+                channel.resize(self.duration, 0.0);
+            }
+            let frames_out_before = self.frames_out;
+            let resampled = self.resample_inner();
+            let wanted = (expected_frames_out - frames_out_before) as usize * num_channels;
+            flushed.extend_from_slice(&resampled[..wanted.min(resampled.len())]);
         }
 
-        None
+        if flushed.is_empty() {
+            return None;
+        }
+        self.interleaved = flushed;
+        Some(&self.interleaved)
     }
 }
 
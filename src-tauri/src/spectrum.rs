@@ -0,0 +1,238 @@
+//! Spectrum analyzer for the visualiser.
+//!
+//! What the output plays is copied into a ring buffer, and a thread of its own takes it
+//! from there at a fixed frame rate. Each frame is the last `FFT_SIZE` frames of audio,
+//! Hann-windowed, with the bins grouped into bands spaced evenly in pitch. Band levels are
+//! in dB, smoothed so bars rise quickly and fall slowly, and sent as one byte each.
+
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+use serde::{Deserialize, Serialize};
+
+/// Samples per channel in each analysis, about 46 ms at 44.1 kHz
+pub const FFT_SIZE: usize = 2048;
+/// Frames sent to the visualiser each second
+pub const FRAMES_PER_SECOND: u32 = 60;
+/// The lowest and highest frequencies shown
+const MIN_FREQUENCY: f32 = 20.0;
+const MAX_FREQUENCY: f32 = 20000.0;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum BandScale {
+    /// `bands` bands spaced evenly in pitch
+    #[default]
+    Log,
+    /// The standard third-octave bands (ISO 266), however many fit
+    ThirdOctave,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct SpectrumSettings {
+    pub bands: usize,
+    pub scale: BandScale,
+    /// The level shown as an empty band, full scale being 0 dB
+    pub min_db: f32,
+    /// How long bands take to rise and fall to a new level, in milliseconds
+    pub attack: f32,
+    pub decay: f32,
+    /// A spectrum for each channel, rather than one for all of them
+    pub per_channel: bool,
+}
+
+impl Default for SpectrumSettings {
+    fn default() -> Self {
+        SpectrumSettings {
+            bands: 64,
+            scale: BandScale::Log,
+            min_db: -80.0,
+            attack: 10.0,
+            decay: 300.0,
+            per_channel: false,
+        }
+    }
+}
+
+/// The frequencies a band covers, in Hz.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Band {
+    pub low: f32,
+    pub centre: f32,
+    pub high: f32,
+}
+
+/// The bands for `settings` between 20 Hz and 20 kHz, or Nyquist if that's lower.
+pub fn bands(settings: &SpectrumSettings, sample_rate: u32) -> Vec<Band> {
+    let max = MAX_FREQUENCY.min(sample_rate as f32 / 2.0);
+    match settings.scale {
+        BandScale::Log => {
+            let count = settings.bands.max(1);
+            let ratio = (max / MIN_FREQUENCY).powf(1.0 / count as f32);
+            (0..count)
+                .map(|i| {
+                    let low = MIN_FREQUENCY * ratio.powi(i as i32);
+                    Band {
+                        low,
+                        centre: low * ratio.sqrt(),
+                        high: low * ratio,
+                    }
+                })
+                .collect()
+        }
+        // 20 Hz to 20 kHz, centred on 1 kHz and each a third of an octave wide
+        BandScale::ThirdOctave => (-17..=13)
+            .map(|i| {
+                let centre = 1000.0 * 2f32.powf(i as f32 / 3.0);
+                Band {
+                    low: centre / 2f32.powf(1.0 / 6.0),
+                    centre,
+                    high: centre * 2f32.powf(1.0 / 6.0),
+                }
+            })
+            .filter(|band| band.low < max)
+            .collect(),
+    }
+}
+
+pub struct Spectrum {
+    settings: SpectrumSettings,
+    sample_rate: u32,
+    channels: usize,
+    fft: Arc<dyn Fft<f32>>,
+    window: Vec<f32>,
+    /// Sum of the window, which a full scale sine's bin adds up to twice over
+    window_gain: f32,
+    bands: Vec<Band>,
+    /// The last `FFT_SIZE` samples of each channel, oldest first
+    history: Vec<Vec<f32>>,
+    buffer: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+    magnitudes: Vec<f32>,
+    /// Smoothed band levels from 0 to 1, for each channel shown
+    levels: Vec<Vec<f32>>,
+}
+
+impl Spectrum {
+    pub fn new(sample_rate: u32, channels: usize, settings: SpectrumSettings) -> Self {
+        let fft = FftPlanner::new().plan_fft_forward(FFT_SIZE);
+        let scratch = vec![Complex::default(); fft.get_inplace_scratch_len()];
+        // Periodic Hann, so overlapping frames sum flat
+        let window: Vec<f32> = (0..FFT_SIZE)
+            .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / FFT_SIZE as f32).cos())
+            .collect();
+        let mut spectrum = Spectrum {
+            settings,
+            sample_rate,
+            channels: channels.max(1),
+            fft,
+            window_gain: window.iter().sum(),
+            window,
+            bands: Vec::new(),
+            history: vec![vec![0.0; FFT_SIZE]; channels.max(1)],
+            buffer: vec![Complex::default(); FFT_SIZE],
+            scratch,
+            magnitudes: vec![0.0; FFT_SIZE / 2 + 1],
+            levels: Vec::new(),
+        };
+        spectrum.set_settings(settings);
+        spectrum
+    }
+
+    pub fn set_settings(&mut self, settings: SpectrumSettings) {
+        if settings != self.settings || self.levels.is_empty() {
+            self.settings = settings;
+            self.bands = bands(&settings, self.sample_rate);
+            let rows = if settings.per_channel {
+                self.channels
+            } else {
+                1
+            };
+            self.levels = vec![vec![0.0; self.bands.len()]; rows];
+        }
+    }
+
+    /// Adds interleaved samples to what's analyzed, keeping only the latest.
+    pub fn push(&mut self, samples: &[f32]) {
+        let frames = samples.len() / self.channels;
+        let keep = FFT_SIZE.saturating_sub(frames);
+        let skip = frames.saturating_sub(FFT_SIZE);
+        for (channel, history) in self.history.iter_mut().enumerate() {
+            history.copy_within(FFT_SIZE - keep.., 0);
+            let new = samples
+                .chunks_exact(self.channels)
+                .skip(skip)
+                .map(|frame| frame[channel]);
+            for (slot, sample) in history[keep..].iter_mut().zip(new) {
+                *slot = sample;
+            }
+        }
+    }
+
+    /// Analyzes the latest samples, `dt` seconds after the last time. Returns the level of
+    /// each band from 0 (`min_db` or lower) to 1 (full scale), for each channel if they're
+    /// shown apart.
+    pub fn analyze(&mut self, dt: f32) -> &[Vec<f32>] {
+        let coefficient = |ms: f32| match ms > 0.0 {
+            true => (-dt * 1000.0 / ms).exp(),
+            false => 0.0,
+        };
+        let attack = coefficient(self.settings.attack);
+        let decay = coefficient(self.settings.decay);
+        let min_db = self.settings.min_db.min(-1.0);
+
+        for row in 0..self.levels.len() {
+            // One spectrum for all channels is of their average
+            for (i, slot) in self.buffer.iter_mut().enumerate() {
+                let sample = match self.settings.per_channel {
+                    true => self.history[row][i],
+                    false => self.history.iter().map(|h| h[i]).sum::<f32>() / self.channels as f32,
+                };
+                *slot = Complex::new(sample * self.window[i], 0.0);
+            }
+            self.fft
+                .process_with_scratch(&mut self.buffer, &mut self.scratch);
+            for (magnitude, bin) in self.magnitudes.iter_mut().zip(&self.buffer) {
+                *magnitude = bin.norm() * 2.0 / self.window_gain;
+            }
+
+            let bin_width = self.sample_rate as f32 / FFT_SIZE as f32;
+            for (band, level) in self.bands.iter().zip(self.levels[row].iter_mut()) {
+                let first = (band.low / bin_width).ceil() as usize;
+                let last = ((band.high / bin_width).ceil() as usize).min(self.magnitudes.len());
+                let magnitude = match first < last {
+                    true => self.magnitudes[first..last]
+                        .iter()
+                        .cloned()
+                        .fold(0.0, f32::max),
+                    // Low bands can be narrower than a bin, read between the nearest two
+                    false => {
+                        let position = band.centre / bin_width;
+                        let i = (position.floor() as usize).min(self.magnitudes.len() - 2);
+                        let t = position - i as f32;
+                        self.magnitudes[i] * (1.0 - t) + self.magnitudes[i + 1] * t
+                    }
+                };
+                let db = 20.0 * magnitude.max(1e-10).log10();
+                let target = ((db - min_db) / -min_db).clamp(0.0, 1.0);
+                let coefficient = if target > *level { attack } else { decay };
+                *level = target + (*level - target) * coefficient;
+            }
+        }
+        &self.levels
+    }
+}
+
+/// A frame for the visualiser: how many rows of bands there are, then each band of each
+/// row from 0 to 255.
+pub fn frame_bytes(levels: &[Vec<f32>]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(1 + levels.iter().map(Vec::len).sum::<usize>());
+    bytes.push(levels.len() as u8);
+    for row in levels {
+        bytes.extend(row.iter().map(|level| (level * 255.0).round() as u8));
+    }
+    bytes
+}
@@ -3,6 +3,13 @@ use std::fs;
 use serde::{Deserialize, Serialize};
 use tauri::{AppHandle, Manager};
 
+use crate::channel_mix::ChannelMixSettings;
+use crate::crossfade::CrossfadeCurve;
+use crate::equalizer::EqualizerSettings;
+use crate::metadata::ReplayGainMode;
+use crate::radio::RadioStation;
+use crate::spectrum::SpectrumSettings;
+
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
@@ -18,7 +25,53 @@ pub struct UserSettings {
     pub download_location: Option<String>,
     pub theme: String,
     pub output_device: Option<String>,
-    pub follow_system_output: bool
+    pub follow_system_output: bool,
+    #[serde(default)]
+    pub native_rate_switching: bool,
+    #[serde(default)]
+    pub crossfade_enabled: bool,
+    #[serde(default = "default_crossfade_duration")]
+    pub crossfade_duration: f64, // seconds
+    #[serde(default)]
+    pub crossfade_curve: CrossfadeCurve,
+    #[serde(default)]
+    pub replay_gain_mode: ReplayGainMode,
+    #[serde(default)]
+    pub replay_gain_preamp: f64, // dB
+    #[serde(default = "default_true")]
+    pub replay_gain_prevent_clipping: bool,
+    #[serde(default)]
+    pub equalizer: EqualizerSettings,
+    #[serde(default = "default_true")]
+    pub skip_on_playback_error: bool,
+    #[serde(default)]
+    pub file_output_path: Option<String>,
+    #[serde(default = "default_true")]
+    pub file_output_realtime: bool,
+    #[serde(default = "default_true")]
+    pub dither: bool,
+    #[serde(default)]
+    pub noise_shaping: bool,
+    #[serde(default)]
+    pub channel_mix: ChannelMixSettings,
+    #[serde(default)]
+    pub radio_stations: Vec<RadioStation>,
+    #[serde(default)]
+    pub spectrum: SpectrumSettings,
+    #[serde(default = "default_waveform_cache_size")]
+    pub waveform_cache_size: u64, // MB
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_crossfade_duration() -> f64 {
+    6.0
+}
+
+fn default_waveform_cache_size() -> u64 {
+    crate::waveform_cache::DEFAULT_MAX_SIZE
 }
 
 #[derive(Serialize, Deserialize, Debug)]
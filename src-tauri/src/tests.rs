@@ -40,3 +40,1092 @@ fn write_track_number() {
         }
     }
 }
+
+#[test]
+fn loudness_of_full_scale_sine() {
+    // BS.1770: a 0 dBFS 997 Hz sine in both channels of a stereo signal reads 0 LUFS
+    let rate = 48000;
+    let sine: Vec<f32> = (0..rate * 10)
+        .map(|i| (2.0 * std::f64::consts::PI * 997.0 * i as f64 / rate as f64).sin() as f32)
+        .collect();
+
+    let mut meter = crate::loudness::LoudnessMeter::new(rate as u32, 2);
+    meter.process(&[&sine, &sine]);
+
+    let loudness = meter.integrated_loudness().unwrap();
+    assert!(loudness.abs() < 0.1, "measured {} LUFS", loudness);
+}
+
+#[test]
+fn queue_skips_removed_track_and_repeats() {
+    use crate::queue::{PlayQueue, RepeatMode};
+
+    let mut queue = PlayQueue::default();
+    let tracks = ["a", "b", "c", "d"].iter().map(|t| t.to_string()).collect();
+    queue.set(tracks, Some(1));
+
+    // Removing the current track keeps the one after it up next
+    queue.remove(1);
+    assert_eq!(queue.current(), None);
+    assert_eq!(queue.peek_next(), Some("c"));
+    assert_eq!(queue.advance(true).as_deref(), Some("c"));
+
+    queue.set_repeat(RepeatMode::One);
+    assert_eq!(queue.peek_next(), Some("c"));
+    assert_eq!(queue.advance(false).as_deref(), Some("d"));
+
+    queue.set_repeat(RepeatMode::All);
+    assert_eq!(queue.advance(false).as_deref(), Some("a"));
+    assert_eq!(queue.previous().as_deref(), Some("d"));
+}
+
+#[test]
+fn seek_trims_to_the_exact_frame() {
+    use crate::player::{to_output_sample_offset, trim};
+    use symphonia::core::audio::{AudioBuffer, Channels, Signal, SignalSpec};
+
+    let spec = SignalSpec::new(44100, Channels::FRONT_LEFT | Channels::FRONT_RIGHT);
+    let mut packet = AudioBuffer::<f32>::new(1152, spec);
+    packet.render_reserved(Some(1152));
+    for (i, sample) in packet.chan_mut(0).iter_mut().enumerate() {
+        *sample = i as f32;
+    }
+
+    // Seeking to frame 1000 of a packet at 576, that ends 100 frames past the track's end
+    let trimmed = trim(&packet.as_audio_buffer_ref(), 1000 - 576, 100);
+    assert_eq!(trimmed.frames(), 1152 - 424 - 100);
+    assert_eq!(trimmed.chan(0)[0], 424.0);
+    assert_eq!(trimmed.chan(0)[trimmed.frames() - 1], 1051.0);
+    // A seek past the packet leaves nothing of it
+    assert_eq!(trim(&packet.as_audio_buffer_ref(), 2000, 100).frames(), 0);
+
+    // A second at 44.1 kHz is as many stereo samples as a second at 48 kHz
+    assert_eq!(to_output_sample_offset(44100, &spec, 48000, 2), 96000);
+    assert_eq!(to_output_sample_offset(1000, &spec, 44100, 2), 2000);
+    // Rounded down to whole frames, and without overflowing on long files
+    assert_eq!(to_output_sample_offset(1, &spec, 48000, 2), 2);
+    let ten_hours = 10 * 3600 * 44100;
+    assert_eq!(
+        to_output_sample_offset(ten_hours, &spec, 192000, 8),
+        10 * 3600 * 192000 * 8
+    );
+}
+
+#[test]
+fn file_output_captures_seeked_gapless_and_looped_playback() {
+    use crate::equalizer::EqualizerSettings;
+    use crate::file_output::{create_unused, FileAudioOutput, WavWriter};
+    use crate::looping::{LoopAction, RegionLoop};
+    use crate::player::{open_source, wrap_region, write_packet};
+    use std::io::Cursor;
+    use symphonia::core::audio::{Channels, SignalSpec};
+    use symphonia::core::formats::{SeekMode, SeekTo};
+    use symphonia::core::probe::Hint;
+
+    // One second of stereo where every sample tells where it came from
+    let track = |sign: f32| {
+        let samples: Vec<f32> = (0..44100 * 2).map(|i| sign * i as f32 / 131072.0).collect();
+        let mut writer = WavWriter::new(Cursor::new(Vec::new()), 44100, 2).unwrap();
+        writer.write_samples(&samples).unwrap();
+        let wav = writer.finish().unwrap().into_inner();
+        // The header describes what was written
+        assert_eq!(&wav[..4], b"RIFF");
+        assert_eq!(
+            u32::from_le_bytes([wav[40], wav[41], wav[42], wav[43]]),
+            44100 * 8
+        );
+        assert_eq!(wav.len(), 44 + 44100 * 8);
+        (wav, samples)
+    };
+    let (a, a_samples) = track(1.0);
+    let (b, b_samples) = track(-1.0);
+
+    let spec = SignalSpec::new(44100, Channels::FRONT_LEFT | Channels::FRONT_RIGHT);
+    let mut output = FileAudioOutput::new(
+        Cursor::new(Vec::new()),
+        spec,
+        false,
+        1.0,
+        EqualizerSettings::default(),
+        None,
+    )
+    .unwrap();
+    // Plays a track from `seek_ts` through decode_loop's packet handling
+    let mut play = |wav: &[u8], seek_ts: u64, mut region_loop: Option<RegionLoop>| {
+        let mut hint = Hint::new();
+        hint.with_extension("wav");
+        let (mut reader, mut decoder, track_id) =
+            open_source(Box::new(Cursor::new(wav.to_vec())), &hint).unwrap();
+        let n_frames = reader.tracks()[0].codec_params.n_frames;
+        let seek_to = SeekTo::TimeStamp {
+            ts: seek_ts,
+            track_id,
+        };
+        let mut seek_ts = reader
+            .seek(SeekMode::Accurate, seek_to)
+            .unwrap()
+            .required_ts;
+        while let Ok(packet) = reader.next_packet() {
+            let decoded = decoder.decode(&packet).unwrap();
+            let action = write_packet(
+                &mut output,
+                decoded,
+                &packet,
+                seek_ts,
+                0,
+                n_frames,
+                &mut None,
+                &mut region_loop,
+            );
+            match action {
+                LoopAction::Wrap => {
+                    let region = region_loop.as_mut().unwrap();
+                    seek_ts = wrap_region(reader.as_mut(), decoder.as_mut(), region, track_id);
+                }
+                LoopAction::Finished => region_loop = None,
+                LoopAction::Continue => {}
+            }
+        }
+    };
+    play(&a, 10000, None);
+    play(&b, 0, None);
+    play(&a, 11025, Some(RegionLoop::new(11025, 20000, 0, Some(2))));
+
+    let wav = output.finish().unwrap().into_inner();
+    let captured: Vec<f32> = wav[44..]
+        .chunks(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect();
+    let looped = (20000 - 11025) * 2;
+    assert_eq!(
+        captured.len(),
+        (44100 - 10000) * 2 + 44100 * 2 + looped + (44100 - 11025) * 2
+    );
+
+    // Starts on the exact frame seeked to
+    assert_eq!(captured[..4], a_samples[20000..20004]);
+    // The next track follows without a gap
+    let b_start = (44100 - 10000) * 2;
+    assert_eq!(captured[b_start + 44100], b_samples[44100]);
+    // Then the region plays twice, back from its start at the seam, and carries on
+    let loop_start = b_start + 44100 * 2;
+    assert_eq!(
+        captured[loop_start..loop_start + looped],
+        a_samples[22050..40000]
+    );
+    assert_eq!(
+        captured[loop_start + looped..][..60000],
+        a_samples[22050..82050]
+    );
+
+    // Files already there are kept
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("musicat.wav");
+    std::fs::write(&path, b"").unwrap();
+    let (_, unused) = create_unused(&path).unwrap();
+    assert_eq!(unused, dir.path().join("musicat (2).wav"));
+}
+
+#[test]
+fn channel_mix_downmixes_without_clipping() {
+    use crate::channel_mix::{mix_matrix, ChannelMixSettings};
+    use symphonia::core::audio::{Channels, Layout};
+
+    let stereo = Layout::Stereo.into_channels();
+    let settings = ChannelMixSettings::default();
+
+    // 5.1 to stereo folds the centre and surrounds into the sides and drops the LFE
+    let matrix = mix_matrix(Layout::FivePointOne.into_channels(), stereo, &settings);
+    assert_eq!(matrix.len(), 2);
+    for row in &matrix {
+        assert!((row.iter().sum::<f32>() - 1.0).abs() < 1e-6);
+        assert_eq!(row[3], 0.0);
+    }
+
+    // Mono plays on both sides, swapped and balanced like anything else
+    let settings = ChannelMixSettings {
+        mono: false,
+        swap: true,
+        balance: 0.5,
+    };
+    assert_eq!(
+        mix_matrix(Channels::FRONT_LEFT, stereo, &settings),
+        vec![vec![0.5], vec![1.0]]
+    );
+    assert_eq!(
+        mix_matrix(stereo, stereo, &settings),
+        vec![vec![0.0, 0.5], vec![1.0, 0.0]]
+    );
+}
+
+#[test]
+fn opus_head_reads_pre_skip_and_gain() {
+    use crate::opus::OpusHead;
+
+    let mut head = b"OpusHead".to_vec();
+    head.push(1); // version
+    head.push(2); // channels
+    head.extend_from_slice(&312u16.to_le_bytes());
+    head.extend_from_slice(&48000u32.to_le_bytes());
+    head.extend_from_slice(&(-6i16 * 256).to_le_bytes());
+    head.push(0); // mapping family
+
+    let parsed = OpusHead::parse(&head).unwrap();
+    assert_eq!(parsed.channels, 2);
+    assert_eq!(parsed.pre_skip, 312);
+    assert_eq!(parsed.mapping_family, 0);
+    assert!((parsed.gain() - 0.501).abs() < 1e-3);
+
+    assert_eq!(OpusHead::parse(&head[..18]), None);
+}
+
+#[test]
+fn opus_decodes_from_the_pre_skip_to_the_last_granule() {
+    use crate::player::open_source;
+    use std::io::Cursor;
+    use symphonia::core::probe::Hint;
+
+    // An Ogg page, with its CRC (polynomial 0x04c11db7, unreflected)
+    let page = |flags: u8, granule: u64, sequence: u32, packets: &[&[u8]]| {
+        let mut page = b"OggS\x00".to_vec();
+        page.push(flags);
+        page.extend_from_slice(&granule.to_le_bytes());
+        page.extend_from_slice(&1u32.to_le_bytes()); // serial
+        page.extend_from_slice(&sequence.to_le_bytes());
+        page.extend_from_slice(&[0; 4]);
+        page.push(packets.len() as u8);
+        page.extend(packets.iter().map(|p| p.len() as u8));
+        packets.iter().for_each(|p| page.extend_from_slice(p));
+        let mut crc = 0u32;
+        for byte in &page {
+            crc ^= (*byte as u32) << 24;
+            for _ in 0..8 {
+                crc = if crc & 0x8000_0000 != 0 {
+                    (crc << 1) ^ 0x04c1_1db7
+                } else {
+                    crc << 1
+                };
+            }
+        }
+        page[22..26].copy_from_slice(&crc.to_le_bytes());
+        page
+    };
+
+    let mut head = b"OpusHead".to_vec();
+    head.push(1); // version
+    head.push(1); // channels
+    head.extend_from_slice(&1000u16.to_le_bytes());
+    head.extend_from_slice(&48000u32.to_le_bytes());
+    head.extend_from_slice(&0i16.to_le_bytes());
+    head.push(0); // mapping family
+    let tags = b"OpusTags\x00\x00\x00\x00\x00\x00\x00\x00";
+    // 20ms CELT frames with no data, which libopus conceals as 960 frames of silence
+    let frame: &[u8] = &[0xf8];
+    let mut ogg = page(0x02, 0, 0, &[&head]);
+    ogg.extend(page(0x00, 0, 1, &[tags]));
+    ogg.extend(page(0x00, 4800, 2, &[frame; 5]));
+    // The last granule ends the stream 600 frames into its last packet
+    ogg.extend(page(0x04, 9000, 3, &[frame; 5]));
+
+    let mut hint = Hint::new();
+    hint.with_extension("opus");
+    let (mut reader, mut decoder, _) = open_source(Box::new(Cursor::new(ogg)), &hint).unwrap();
+    let mut frames = Vec::new();
+    while let Ok(packet) = reader.next_packet() {
+        frames.push(decoder.decode(&packet).unwrap().frames());
+    }
+    // The pre-skip is longer than a packet, so it comes off the first two
+    assert_eq!(frames.len(), 10);
+    assert_eq!(frames[..3], [0, 1920 - 1000, 960]);
+    assert_eq!(frames[9], 960 - 600);
+    assert_eq!(frames.iter().sum::<usize>(), 9000 - 1000);
+}
+
+#[test]
+fn ape_header_reads_descriptor_and_header() {
+    use crate::ape::ApeHeader;
+
+    let mut buf = b"MAC ".to_vec();
+    buf.extend_from_slice(&3990u16.to_le_bytes());
+    buf.extend_from_slice(&0u16.to_le_bytes()); // padding
+    for len in [52u32, 24, 8, 44, 1000, 0, 0] {
+        // descriptor, header, seek table, wav header, audio, audio high, wav tail
+        buf.extend_from_slice(&len.to_le_bytes());
+    }
+    buf.extend_from_slice(&[0; 16]); // MD5
+    buf.extend_from_slice(&2000u16.to_le_bytes());
+    buf.extend_from_slice(&0u16.to_le_bytes()); // format flags
+    buf.extend_from_slice(&73728u32.to_le_bytes());
+    buf.extend_from_slice(&1000u32.to_le_bytes());
+    buf.extend_from_slice(&2u32.to_le_bytes());
+    buf.extend_from_slice(&16u16.to_le_bytes());
+    buf.extend_from_slice(&2u16.to_le_bytes());
+    buf.extend_from_slice(&44100u32.to_le_bytes());
+
+    let header = ApeHeader::parse(&buf).unwrap();
+    assert_eq!(header.version, 3990);
+    assert_eq!(header.compression, 2000);
+    assert_eq!(header.channels, 2);
+    assert_eq!(header.sample_rate, 44100);
+    assert_eq!(header.first_frame, 128);
+    assert_eq!(header.total_blocks(), 74728);
+
+    assert_eq!(ApeHeader::parse(&buf[..60]), None);
+}
+
+#[test]
+fn ape_decodes_bit_exact() {
+    use crate::player::open_source;
+    use std::io::Cursor;
+    use symphonia::core::audio::{AudioBufferRef, Signal};
+    use symphonia::core::probe::Hint;
+
+    // 16-bit stereo at extra high compression: two frames of 600 blocks, the second
+    // starting partway into a word, and a final frame of 250 flagged as silence
+    let ape = include_bytes!("../tests/fixtures/stereo.ape");
+    // What tests/fixtures/make_ape.py encoded it from: two sawtooth mixes with full scale spikes
+    let pcm = |i: i32| {
+        if i >= 1200 {
+            return [0, 0];
+        }
+        let mut left = (i * i * 31 + i * 1237) % 40000 - 20000;
+        let mut right = (i * 4567) % 30011 - 15005 + left.div_euclid(4);
+        if i % 89 == 0 {
+            left = 32767;
+        }
+        if i % 97 == 0 {
+            right = -32768;
+        }
+        if i >= 600 {
+            [right, left.div_euclid(2)]
+        } else {
+            [left, right]
+        }
+    };
+
+    let mut hint = Hint::new();
+    hint.with_extension("ape");
+    let (mut reader, mut decoder, _) =
+        open_source(Box::new(Cursor::new(ape.to_vec())), &hint).unwrap();
+    assert_eq!(reader.tracks()[0].codec_params.n_frames, Some(1450));
+    let mut decoded = Vec::new();
+    while let Ok(packet) = reader.next_packet() {
+        let AudioBufferRef::S32(buf) = decoder.decode(&packet).unwrap() else {
+            panic!("ape: expected integer samples");
+        };
+        for (left, right) in buf.chan(0).iter().zip(buf.chan(1)) {
+            decoded.push([left >> 16, right >> 16]);
+        }
+    }
+    assert_eq!(decoded, (0..1450).map(pcm).collect::<Vec<_>>());
+}
+
+#[test]
+fn wavpack_log_tables_round_trip() {
+    use crate::wavpack::{restore_weight, Tables};
+
+    let tables = Tables::new();
+    for value in [1, 100, 1000, 65535, 1 << 20] {
+        let restored = tables.exp2s(tables.log2(value as u32));
+        assert!(
+            (restored - value).abs() <= value / 64 + 1,
+            "{} came back as {}",
+            value,
+            restored
+        );
+    }
+    assert_eq!(tables.exp2s(-tables.log2(1000)), -1000);
+
+    assert_eq!(restore_weight(127), 1024);
+    assert_eq!(restore_weight(-128i8 as u8), -1024);
+}
+
+#[test]
+fn wavpack_decodes_bit_exact() {
+    use crate::player::open_source;
+    use std::io::Cursor;
+    use symphonia::core::audio::{AudioBufferRef, Signal};
+    use symphonia::core::probe::Hint;
+
+    // Two blocks of 16-bit joint stereo with cross-channel decorrelation, the first
+    // starting from nothing and the second from stored weights, samples and medians
+    let wv = include_bytes!("../tests/fixtures/joint_stereo.wv");
+    // What tests/fixtures/make_wv.py encoded it from: silence, a wobbly ramp and a full scale jump
+    let pcm = |i: i32| {
+        if i < 6 {
+            return [0, 0];
+        }
+        let left = (i * i * 37) % 4001 - 2000;
+        let right = (i * 613) % 3001 - 1500 + left.div_euclid(3);
+        match i {
+            40 => [30000, right],
+            41 => [left, -32768],
+            _ => [left, right],
+        }
+    };
+
+    let mut hint = Hint::new();
+    hint.with_extension("wv");
+    let (mut reader, mut decoder, _) =
+        open_source(Box::new(Cursor::new(wv.to_vec())), &hint).unwrap();
+    assert_eq!(reader.tracks()[0].codec_params.n_frames, Some(64));
+    let mut decoded = Vec::new();
+    while let Ok(packet) = reader.next_packet() {
+        let AudioBufferRef::S32(buf) = decoder.decode(&packet).unwrap() else {
+            panic!("wavpack: expected integer samples");
+        };
+        for (left, right) in buf.chan(0).iter().zip(buf.chan(1)) {
+            decoded.push([left >> 16, right >> 16]);
+        }
+    }
+    assert_eq!(decoded, (0..64).map(pcm).collect::<Vec<_>>());
+}
+
+#[test]
+fn dsf_decodes_to_pcm() {
+    use crate::dsd::{DsdDecoder, DsdReader};
+    use std::io::Cursor;
+    use symphonia::core::audio::{AudioBufferRef, Signal};
+    use symphonia::core::codecs::Decoder;
+    use symphonia::core::formats::FormatReader;
+    use symphonia::core::io::MediaSourceStream;
+
+    // DSD64 stereo, a block of all ones on the left and all zeros on the right
+    let mut file = b"DSD ".to_vec();
+    for value in [28u64, 28 + 52 + 12 + 8192, 0] {
+        file.extend_from_slice(&value.to_le_bytes());
+    }
+    file.extend_from_slice(b"fmt ");
+    file.extend_from_slice(&52u64.to_le_bytes());
+    for value in [1u32, 0, 2, 2, 2_822_400, 1] {
+        // version, format, channel type, channels, rate, bits per sample
+        file.extend_from_slice(&value.to_le_bytes());
+    }
+    file.extend_from_slice(&(4096u64 * 8).to_le_bytes());
+    file.extend_from_slice(&4096u32.to_le_bytes());
+    file.extend_from_slice(&[0; 4]);
+    file.extend_from_slice(b"data");
+    file.extend_from_slice(&(12u64 + 8192).to_le_bytes());
+    file.extend_from_slice(&[0xff; 4096]);
+    file.extend_from_slice(&[0; 4096]);
+
+    let source = MediaSourceStream::new(Box::new(Cursor::new(file)), Default::default());
+    let mut reader = DsdReader::try_new(source, &Default::default()).unwrap();
+    let params = reader.tracks()[0].codec_params.clone();
+    assert_eq!(params.sample_rate, Some(88200));
+    assert_eq!(params.n_frames, Some(1024));
+    assert_eq!(params.channels.unwrap().count(), 2);
+
+    let mut decoder = DsdDecoder::try_new(&params, &Default::default()).unwrap();
+    let packet = reader.next_packet().unwrap();
+    let decoded = match decoder.decode(&packet).unwrap() {
+        AudioBufferRef::F32(buf) => buf,
+        _ => panic!("expected f32 samples"),
+    };
+    assert_eq!(decoded.frames(), 1024);
+    assert!((decoded.chan(0)[1023] - 1.0).abs() < 1e-3);
+    assert!((decoded.chan(1)[1023] + 1.0).abs() < 1e-3);
+    assert!(reader.next_packet().is_err());
+
+    // The ID3v2.4 tag at the end of the file, with only a title
+    let mut id3 = b"ID3\x04\x00\x00\x00\x00\x00\x12".to_vec();
+    id3.extend_from_slice(b"TIT2\x00\x00\x00\x08\x00\x00\x03So What");
+    let tagged_file = crate::metadata::read_dsd_tags(Some(&id3)).unwrap();
+    let tag = tagged_file.primary_tag().unwrap();
+    assert_eq!(tag.title().as_deref(), Some("So What"));
+    assert!(crate::metadata::read_dsd_tags(None).unwrap().tags().is_empty());
+}
+
+#[test]
+fn cue_sheet_splits_album_into_tracks() {
+    use crate::cue::{parse, split_track_path, track_path};
+
+    let sheet = parse(
+        "\u{feff}REM GENRE Jazz\r\n\
+         REM DATE 1959\r\n\
+         PERFORMER \"Miles Davis\"\r\n\
+         TITLE \"Kind of Blue\"\r\n\
+         FILE \"Kind of Blue.wav\" WAVE\r\n\
+         \x20 TRACK 01 AUDIO\r\n\
+         \x20   TITLE \"So What\"\r\n\
+         \x20   INDEX 01 00:00:00\r\n\
+         \x20 TRACK 02 AUDIO\r\n\
+         \x20   TITLE \"Freddie Freeloader\"\r\n\
+         \x20   INDEX 00 09:20:50\r\n\
+         \x20   INDEX 01 09:22:30\r\n",
+    )
+    .unwrap();
+    assert_eq!(sheet.title.as_deref(), Some("Kind of Blue"));
+    assert_eq!(sheet.performer.as_deref(), Some("Miles Davis"));
+    assert_eq!(sheet.genre.as_deref(), Some("Jazz"));
+    assert_eq!(sheet.year, Some(1959));
+    assert_eq!(sheet.files[0].name, "Kind of Blue.wav");
+
+    let tracks = &sheet.files[0].tracks;
+    assert_eq!(tracks.len(), 2);
+    assert_eq!(tracks[1].number, 2);
+    assert_eq!(tracks[1].title.as_deref(), Some("Freddie Freeloader"));
+    assert!((tracks[1].start - 562.4).abs() < 1e-9);
+
+    let path = track_path("/music/Kind of Blue.flac", 2);
+    assert_eq!(split_track_path(&path), Some(("/music/Kind of Blue.flac", 2)));
+    assert_eq!(split_track_path("/music/Track #1.flac"), None);
+}
+
+#[test]
+fn playlist_files_resolve_relative_entries() {
+    use crate::playlist_file::{parse_m3u, parse_pls, parse_xspf};
+    use std::path::Path;
+
+    let base = Path::new("/music/playlists");
+    let (title, entries) = parse_m3u(
+        "#EXTM3U\n\
+         #PLAYLIST:Road trip\n\
+         #EXTINF:215,Miles Davis - So What\n\
+         ../Kind of Blue/01 So What.flac\n\
+         \n\
+         /music/Other/02 Blue in Green.mp3\n",
+        base,
+    );
+    assert_eq!(title.as_deref(), Some("Road trip"));
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].path, "/music/Kind of Blue/01 So What.flac");
+    assert_eq!(entries[0].artist.as_deref(), Some("Miles Davis"));
+    assert_eq!(entries[0].title.as_deref(), Some("So What"));
+    assert_eq!(entries[0].duration, Some(215.0));
+    assert_eq!(entries[1].title, None);
+
+    let entries = parse_pls(
+        "[playlist]\nFile2=b.mp3\nFile1=a.mp3\nLength1=-1\nNumberOfEntries=2\n",
+        base,
+    );
+    assert_eq!(entries[0].path, "/music/playlists/a.mp3");
+    assert_eq!(entries[0].duration, None);
+    assert_eq!(entries[1].path, "/music/playlists/b.mp3");
+
+    let (title, entries) = parse_xspf(
+        "<?xml version=\"1.0\"?>\n\
+         <playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n\
+         <title>Tom &amp; Jerry</title>\n\
+         <trackList><track>\n\
+         <location>../Kind%20of%20Blue/01%20So%20What.flac</location>\n\
+         <creator>Miles Davis</creator><duration>562400</duration>\n\
+         </track></trackList></playlist>",
+        base,
+    );
+    assert_eq!(title.as_deref(), Some("Tom & Jerry"));
+    assert_eq!(entries[0].path, "/music/Kind of Blue/01 So What.flac");
+    assert_eq!(entries[0].artist.as_deref(), Some("Miles Davis"));
+    assert_eq!(entries[0].duration, Some(562.4));
+}
+
+#[test]
+fn radio_stream_strips_icy_metadata() {
+    use crate::radio::HttpStream;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    // A station that sends metadata every 32 bytes, then ends
+    let audio: Vec<u8> = (0..64).collect();
+    let title = b"StreamTitle='Miles Davis - So What';";
+    let blocks = title.len().div_ceil(16);
+    let mut body = audio[..32].to_vec();
+    body.push(blocks as u8);
+    body.extend_from_slice(title);
+    body.resize(body.len() + blocks * 16 - title.len(), 0);
+    body.extend_from_slice(&audio[32..]);
+    body.push(0);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/stream", listener.local_addr().unwrap());
+    std::thread::spawn(move || {
+        let (mut socket, _) = listener.accept().unwrap();
+        let mut request = [0; 1024];
+        let _ = socket.read(&mut request);
+        let headers = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: audio/mpeg\r\nicy-name: Test FM\r\n\
+             icy-metaint: 32\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        );
+        socket.write_all(headers.as_bytes()).unwrap();
+        socket.write_all(&body).unwrap();
+    });
+
+    let (sender, metadata) = mpsc::channel();
+    let mut stream = HttpStream::open(&url, move |m| sender.send(m).unwrap()).unwrap();
+    let mut read = Vec::new();
+    stream.read_to_end(&mut read).unwrap();
+    assert_eq!(read, audio);
+
+    let updates: Vec<_> = metadata.try_iter().collect();
+    assert_eq!(updates[0].station.as_deref(), Some("Test FM"));
+    assert_eq!(
+        updates.last().unwrap().title.as_deref(),
+        Some("Miles Davis - So What")
+    );
+}
+
+#[test]
+fn podcast_feed_refreshes_and_downloads_from_local_server() {
+    use crate::podcast::{episode_path, load_podcasts, refresh, Episode, Podcast};
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let host = format!("http://{}", listener.local_addr().unwrap());
+    let feed = format!(
+        r#"<?xml version="1.0"?>
+        <rss version="2.0" xmlns:itunes="http://www.itunes.com/dtds/podcast-1.0.dtd">
+          <channel>
+            <title>Jazz &amp; Talk</title>
+            <itunes:author>Musicat</itunes:author>
+            <itunes:image href="/cover.jpg"/>
+            <item>
+              <title><![CDATA[Episode 2: Kind of Blue]]></title>
+              <guid isPermaLink="false">ep-2</guid>
+              <pubDate>Tue, 02 Jan 2024 10:00:00 GMT</pubDate>
+              <itunes:duration>1:02:03</itunes:duration>
+              <enclosure url="{host}/episodes/2.mp3" type="audio/mpeg" length="6"/>
+            </item>
+            <item>
+              <title>Show notes only</title>
+              <guid>notes</guid>
+            </item>
+          </channel>
+        </rss>"#
+    );
+    let episode = b"ID3abc".to_vec();
+    std::thread::spawn(move || {
+        for socket in listener.incoming() {
+            let mut socket = socket.unwrap();
+            let mut request = [0; 2048];
+            let n = socket.read(&mut request).unwrap();
+            let request = String::from_utf8_lossy(&request[..n]).to_lowercase();
+            let (status, body) = if request.starts_with("get /feed") {
+                match request.contains("if-none-match: \"v1\"") {
+                    true => ("304 Not Modified", vec![]),
+                    false => ("200 OK", feed.clone().into_bytes()),
+                }
+            } else {
+                ("200 OK", episode.clone())
+            };
+            let headers = format!(
+                "HTTP/1.1 {}\r\nETag: \"v1\"\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                status,
+                body.len()
+            );
+            socket.write_all(headers.as_bytes()).unwrap();
+            socket.write_all(&body).unwrap();
+        }
+    });
+
+    let dir = tempfile::tempdir().unwrap();
+    tokio::runtime::Runtime::new().unwrap().block_on(async {
+        let client = reqwest::Client::new();
+        let mut podcast = Podcast {
+            url: format!("{}/feed.xml", host),
+            ..Default::default()
+        };
+        assert!(refresh(&mut podcast, &client).await.unwrap());
+        assert_eq!(podcast.title, "Jazz & Talk");
+        assert_eq!(podcast.author.as_deref(), Some("Musicat"));
+        assert_eq!(podcast.image, Some(format!("{}/cover.jpg", host)));
+        assert_eq!(podcast.etag.as_deref(), Some("\"v1\""));
+        assert_eq!(podcast.episodes.len(), 1);
+        let episode = &podcast.episodes[0];
+        assert_eq!(episode.guid, "ep-2");
+        assert_eq!(episode.title, "Episode 2: Kind of Blue");
+        assert_eq!(episode.duration, Some(3723.0));
+        assert_eq!(episode.length, Some(6));
+
+        // Positions survive a refresh, and an unchanged feed isn't parsed again
+        podcast.episodes[0].position = 42.0;
+        assert!(!refresh(&mut podcast, &client).await.unwrap());
+        podcast.etag = None;
+        assert!(refresh(&mut podcast, &client).await.unwrap());
+        assert_eq!(podcast.episodes[0].position, 42.0);
+
+        let path = episode_path(dir.path(), &podcast, &podcast.episodes[0]);
+        assert_eq!(path, dir.path().join("Jazz & Talk").join("2 [e662d94a].mp3"));
+        // Another episode whose enclosure has the same name doesn't overwrite it
+        let other = Episode {
+            guid: String::from("ep-1"),
+            url: format!("{}/episodes/2.mp3?id=1", host),
+            ..Default::default()
+        };
+        assert_ne!(episode_path(dir.path(), &podcast, &other), path);
+        crate::download(&podcast.episodes[0].url, &path, |_| {})
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"ID3abc");
+    });
+
+    // No file is no podcasts, but a broken one mustn't be saved over
+    let podcasts = dir.path().join("podcasts.json");
+    assert_eq!(load_podcasts(&podcasts), Ok(vec![]));
+    std::fs::write(&podcasts, "[{\"url\": ").unwrap();
+    assert!(load_podcasts(&podcasts).is_err());
+}
+
+#[test]
+fn spectrum_analyzer_finds_tone_in_its_band() {
+    use crate::spectrum::{bands, frame_bytes, BandScale, Spectrum, SpectrumSettings, FFT_SIZE};
+    use std::f32::consts::PI;
+
+    let third_octave = SpectrumSettings {
+        scale: BandScale::ThirdOctave,
+        ..Default::default()
+    };
+    assert_eq!(bands(&third_octave, 44100).len(), 31);
+    assert_eq!(bands(&SpectrumSettings::default(), 44100).len(), 64);
+
+    // A full scale 1 kHz tone on the left, silence on the right
+    let settings = SpectrumSettings {
+        per_channel: true,
+        ..Default::default()
+    };
+    let mut spectrum = Spectrum::new(44100, 2, settings);
+    let samples: Vec<f32> = (0..FFT_SIZE)
+        .flat_map(|i| [(2.0 * PI * 1000.0 * i as f32 / 44100.0).sin(), 0.0])
+        .collect();
+    spectrum.push(&samples);
+    let tone = bands(&settings, 44100)
+        .iter()
+        .position(|band| band.low <= 1000.0 && band.high > 1000.0)
+        .unwrap();
+
+    // Bars rise within a frame or two, and fall slowly
+    spectrum.analyze(1.0 / 60.0);
+    let levels = spectrum.analyze(1.0 / 60.0).to_vec();
+    assert!(levels[0][tone] > 0.9, "{}", levels[0][tone]);
+    assert!(levels[0][0] < 0.5 && levels[0][63] < 0.5);
+    assert!(levels[1].iter().all(|level| *level == 0.0));
+
+    spectrum.push(&vec![0.0; FFT_SIZE * 2]);
+    let falling = spectrum.analyze(1.0 / 60.0)[0][tone];
+    assert!(falling > 0.8 && falling < levels[0][tone]);
+
+    let bytes = frame_bytes(&levels);
+    assert_eq!(bytes.len(), 1 + 2 * 64);
+    assert_eq!(bytes[0], 2);
+}
+
+#[test]
+fn level_meter_measures_true_peak_and_clips() {
+    use crate::meter::Meter;
+    use std::f32::consts::PI;
+
+    // A sine at a quarter of the sample rate, sampled either side of its peaks, for long
+    // enough that the RMS settles
+    let mut meter = Meter::new(48000, 2);
+    let samples: Vec<f32> = (0..48000 * 3)
+        .flat_map(|i| {
+            let sample = (PI / 2.0 * (i % 4) as f32 + PI / 4.0).sin();
+            [sample, sample * 0.5]
+        })
+        .collect();
+    meter.process(&samples);
+    let levels = meter.levels(1.0);
+    let (left, right) = (&levels.channels[0], &levels.channels[1]);
+    assert!((left.peak + 3.01).abs() < 0.05, "{}", left.peak);
+    assert!(left.true_peak.abs() < 0.2, "{}", left.true_peak);
+    assert!((left.rms + 3.01).abs() < 0.05, "{}", left.rms);
+    assert!((right.true_peak + 6.02).abs() < 0.2, "{}", right.true_peak);
+    assert_eq!(left.peak_hold, left.true_peak);
+    assert_eq!(left.clips, 0);
+
+    // Two runs at full scale on the left, and one sample that isn't a clip
+    let mut clipped = vec![0.0f32; 64];
+    clipped[10..16].fill(1.0);
+    clipped[30..36].fill(-1.0);
+    clipped[50] = 1.0;
+    let clipped: Vec<f32> = clipped.iter().flat_map(|&sample| [sample, 0.0]).collect();
+    meter.process(&clipped);
+    let levels = meter.levels(0.1);
+    assert_eq!(levels.channels[0].clips, 2);
+    assert_eq!(levels.channels[1].clips, 0);
+    assert!(levels.channels[0].peak_hold >= levels.channels[0].true_peak);
+
+    meter.reset();
+    assert_eq!(meter.levels(0.1).channels[0].clips, 0);
+}
+
+#[test]
+fn waveform_cache_keeps_peaks_until_the_file_changes() {
+    use crate::waveform_cache::{downsample, Waveform, WaveformCache};
+    use std::fs;
+    use std::time::Duration;
+
+    let dir = tempfile::tempdir().unwrap();
+    let audio: Vec<_> = ["a.flac", "b.flac", "c.flac"]
+        .iter()
+        .map(|name| {
+            let path = dir.path().join(name);
+            fs::write(&path, [0u8; 64]).unwrap();
+            path.to_str().unwrap().to_string()
+        })
+        .collect();
+
+    // Coarser levels average the power of the peaks they merge
+    let peaks: Vec<f32> = (0..100).map(|i| i as f32 / 100.0).collect();
+    let waveform = Waveform::from_peaks(&peaks);
+    assert_eq!(waveform.peaks(1), peaks);
+    assert_eq!(waveform.peaks(4).len(), 25);
+    let merged = ((0.0f32 + 0.0001 + 0.0004 + 0.0009) / 4.0).sqrt();
+    assert!((waveform.peaks(4)[0] - merged).abs() < 1e-6);
+    let eighths = waveform.peaks(8);
+    assert_eq!(eighths.len(), 13);
+    for (cached, exact) in eighths.iter().zip(downsample(&peaks, 8)) {
+        assert!((cached - exact).abs() < 1e-5);
+    }
+    assert_eq!(waveform.peaks(16).len(), 7);
+
+    let cache = WaveformCache::new(dir.path().join("cache"), 1024 * 1024);
+    assert!(cache.get(&audio[0]).is_none());
+    cache.put(&audio[0], &waveform).unwrap();
+    assert_eq!(cache.get(&audio[0]), Some(waveform.clone()));
+
+    // A file that changed is decoded again
+    fs::write(&audio[0], [0u8; 65]).unwrap();
+    assert!(cache.get(&audio[0]).is_none());
+    cache.put(&audio[0], &waveform).unwrap();
+    cache.remove(&audio[0]);
+    assert!(cache.get(&audio[0]).is_none());
+
+    // Room for two entries, the least recently used goes
+    let cache = WaveformCache::new(dir.path().join("small"), 1200);
+    for path in &audio[..2] {
+        cache.put(path, &waveform).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    assert!(cache.get(&audio[0]).is_some());
+    std::thread::sleep(Duration::from_millis(20));
+    cache.put(&audio[2], &waveform).unwrap();
+    assert!(cache.get(&audio[0]).is_some());
+    assert!(cache.get(&audio[1]).is_none());
+    assert!(cache.get(&audio[2]).is_some());
+}
+
+#[test]
+fn resampler_flushes_its_delay_after_whole_chunks() {
+    use crate::resampler::Resampler;
+    use symphonia::core::audio::{AsAudioBufferRef, AudioBuffer, Channels, Signal, SignalSpec};
+
+    let spec = SignalSpec::new(44100, Channels::FRONT_LEFT | Channels::FRONT_RIGHT);
+    let mut resampler = Resampler::<f32>::new(spec, 48000, 1024);
+    let mut buffer = AudioBuffer::<f32>::new(1024, spec);
+    buffer.render_reserved(Some(1024));
+    for ch in 0..2 {
+        buffer.chan_mut(ch).fill(0.5);
+    }
+
+    // Whole chunks only, so nothing is left waiting but the resampler's delay
+    let mut frames_out = 0;
+    for _ in 0..10 {
+        frames_out += resampler
+            .resample(buffer.as_audio_buffer_ref())
+            .map_or(0, |s| s.len())
+            / 2;
+    }
+    frames_out += resampler.flush().map_or(0, |s| s.len()) / 2;
+    assert_eq!(frames_out, 10 * 1024 * 48000 / 44100);
+    assert!(resampler.flush().is_none());
+}
+
+#[test]
+fn opus_replay_gain_is_written_as_r128() {
+    use crate::metadata::{insert_replay_gain, ReplayGain};
+    use lofty::file::FileType;
+
+    let replay_gain = ReplayGain {
+        track_gain: Some(-3.5),
+        track_peak: Some(0.9),
+        album_gain: Some(0.0),
+        album_peak: Some(0.95),
+    };
+    let mut tag = lofty::tag::Tag::new(TagType::VorbisComments);
+    tag.insert_text(ItemKey::ReplayGainTrackGain, String::from("1.00 dB"));
+    insert_replay_gain(&mut tag, &replay_gain, FileType::Opus);
+
+    assert!(tag.get_string(&ItemKey::ReplayGainTrackGain).is_none());
+    assert!(tag.get_string(&ItemKey::ReplayGainTrackPeak).is_none());
+    // -3.5 dB above -18 LUFS is -8.5 dB above -23 LUFS, in 1/256 dB
+    let r128 = |key: &str| tag.get_string(&ItemKey::Unknown(key.to_string())).map(String::from);
+    assert_eq!(r128("R128_TRACK_GAIN").as_deref(), Some("-2176"));
+    assert_eq!(r128("R128_ALBUM_GAIN").as_deref(), Some("-1280"));
+}
+
+#[test]
+fn shuffled_queue_goes_back_through_what_was_played() {
+    use crate::queue::PlayQueue;
+
+    let mut queue = PlayQueue::default();
+    let tracks = (0..20).map(|t| t.to_string()).collect();
+    queue.set_shuffle(true);
+    queue.set(tracks, Some(0));
+    assert_eq!(queue.current(), Some("0"));
+    let second = queue.advance(false).unwrap();
+
+    // Picking a track keeps the order, and previous retraces what was played
+    let order = queue.state().order;
+    assert!(queue.select(7));
+    assert_eq!(queue.current(), Some("7"));
+    assert_eq!(queue.state().order, order);
+    assert_eq!(queue.previous(), Some(second));
+    assert_eq!(queue.previous().as_deref(), Some("0"));
+
+    // Reordering as shown moves the last track up next
+    assert!(queue.move_in_order(order.len() - 1, 1));
+    assert_eq!(queue.current(), Some("0"));
+    assert_eq!(queue.peek_next(), Some(order[order.len() - 1].to_string().as_str()));
+}
+
+#[test]
+fn region_loop_wraps_with_a_seam_and_stops_after_its_passes() {
+    use crate::looping::{LoopAction, RegionLoop};
+    use symphonia::core::audio::{AsAudioBufferRef, AudioBuffer, Channels, Signal, SignalSpec};
+
+    // Plays a mono ramp, where each sample is its frame number, in packets of 100 frames
+    // like the decode loop does: a wrap seeks back to the packet holding the start. Stops
+    // after 10 wraps, for loops without an end.
+    fn play(region: &mut RegionLoop, start_ts: u64, len: u64) -> (Vec<f32>, u32) {
+        let spec = SignalSpec::new(44100, Channels::FRONT_LEFT);
+        let (mut output, mut wraps) = (Vec::new(), 0);
+        let mut ts = 0;
+        let mut seek_ts: u64 = 0;
+        while ts < len && wraps < 10 {
+            let mut packet = AudioBuffer::<f32>::new(100, spec);
+            packet.render_reserved(Some(100));
+            for (i, sample) in packet.chan_mut(0).iter_mut().enumerate() {
+                *sample = (ts + i as u64) as f32;
+            }
+            let skip = seek_ts.saturating_sub(ts) as usize;
+            let (looped, action) = region.process(&packet.as_audio_buffer_ref(), ts, skip);
+            output.extend_from_slice(looped.chan(0));
+            match action {
+                LoopAction::Wrap => {
+                    region.wrap();
+                    wraps += 1;
+                    seek_ts = start_ts;
+                    ts = start_ts / 100 * 100;
+                }
+                LoopAction::Continue | LoopAction::Finished => ts += 100,
+            }
+        }
+        (output, wraps)
+    }
+    let ramp = |from: u64, to: u64| (from..to).map(|f| f as f32).collect::<Vec<_>>();
+
+    // Without a seam, the output goes back to the start exactly at the end, from the middle
+    // of a packet, and carries on past the end on the last pass
+    let mut region = RegionLoop::new(250, 620, 0, Some(3));
+    let (output, wraps) = play(&mut region, 250, 1000);
+    assert_eq!(wraps, 2);
+    let expected = [ramp(0, 620), ramp(250, 620), ramp(250, 1000)].concat();
+    assert_eq!(output, expected);
+
+    // With a seam, the 20 frames before the end are held back and faded into the first 20
+    // after the start
+    let mut region = RegionLoop::new(250, 620, 20, Some(2));
+    let (output, wraps) = play(&mut region, 250, 1000);
+    assert_eq!(wraps, 1);
+    assert_eq!(output.len(), 600 + 20 + (1000 - 270));
+    assert_eq!(output[..600], ramp(0, 600)[..]);
+    for i in 0..20 {
+        let in_gain = (i as f32 + 0.5) / 20.0;
+        let mixed = (250 + i) as f32 * in_gain + (600 + i) as f32 * (1.0 - in_gain);
+        assert!((output[600 + i] - mixed).abs() < 1e-3);
+    }
+    assert_eq!(output[620..], ramp(270, 1000)[..]);
+
+    // A loop without a count keeps wrapping
+    let mut region = RegionLoop::new(250, 620, 0, None);
+    assert_eq!(play(&mut region, 250, 1000).1, 10);
+}
+
+#[test]
+fn region_loop_decodes_up_to_its_start_when_the_seek_back_fails() {
+    use crate::file_output::WavWriter;
+    use crate::looping::{LoopAction, RegionLoop};
+    use crate::player::{open_source, trim, wrap_region};
+    use std::io::Cursor;
+    use symphonia::core::audio::Signal;
+    use symphonia::core::errors::{seek_error, unsupported_error, Result, SeekErrorKind};
+    use symphonia::core::formats::{
+        Cue, FormatOptions, FormatReader, Packet, SeekMode, SeekTo, SeekedTo, Track,
+    };
+    use symphonia::core::io::MediaSourceStream;
+    use symphonia::core::meta::Metadata;
+    use symphonia::core::probe::Hint;
+
+    // A reader that can only go back to the top, like one without a seek index
+    struct RewindOnly(Box<dyn FormatReader>);
+
+    impl FormatReader for RewindOnly {
+        fn try_new(_: MediaSourceStream, _: &FormatOptions) -> Result<Self> {
+            unsupported_error("rewind only")
+        }
+        fn cues(&self) -> &[Cue] {
+            self.0.cues()
+        }
+        fn metadata(&mut self) -> Metadata<'_> {
+            self.0.metadata()
+        }
+        fn seek(&mut self, mode: SeekMode, to: SeekTo) -> Result<SeekedTo> {
+            match to {
+                SeekTo::TimeStamp { ts: 0, .. } => self.0.seek(mode, to),
+                _ => seek_error(SeekErrorKind::Unseekable),
+            }
+        }
+        fn tracks(&self) -> &[Track] {
+            self.0.tracks()
+        }
+        fn next_packet(&mut self) -> Result<Packet> {
+            self.0.next_packet()
+        }
+        fn into_inner(self: Box<Self>) -> MediaSourceStream {
+            self.0.into_inner()
+        }
+    }
+
+    // A mono ramp where each sample is its frame number
+    let samples: Vec<f32> = (0..30000).map(|i| i as f32 / 32768.0).collect();
+    let mut writer = WavWriter::new(Cursor::new(Vec::new()), 44100, 1).unwrap();
+    writer.write_samples(&samples).unwrap();
+    let wav = writer.finish().unwrap().into_inner();
+    let mut hint = Hint::new();
+    hint.with_extension("wav");
+    let (reader, mut decoder, track_id) = open_source(Box::new(Cursor::new(wav)), &hint).unwrap();
+    let mut reader = RewindOnly(reader);
+
+    // The first seek already failed, so the first pass is decoded up to the start too
+    let mut region_loop = Some(RegionLoop::new(11025, 20000, 0, Some(2)));
+    let mut seek_ts = 11025;
+    let mut played = Vec::new();
+    while let Ok(packet) = reader.next_packet() {
+        let decoded = decoder.decode(&packet).unwrap();
+        if packet.ts() + decoded.frames() as u64 <= seek_ts {
+            continue;
+        }
+        let skip = seek_ts.saturating_sub(packet.ts()) as usize;
+        let Some(region) = region_loop.as_mut() else {
+            let rest = trim(&decoded, skip, 0);
+            played.extend(rest.chan(0).iter().map(|s| (s * 32768.0).round() as u32));
+            continue;
+        };
+        let (looped, action) = region.process(&decoded, packet.ts(), skip);
+        played.extend(looped.chan(0).iter().map(|s| (s * 32768.0).round() as u32));
+        match action {
+            LoopAction::Wrap => {
+                seek_ts = wrap_region(&mut reader, decoder.as_mut(), region, track_id);
+            }
+            LoopAction::Finished => region_loop = None,
+            LoopAction::Continue => {}
+        }
+    }
+
+    // Both passes start at the region's start, not the top of the file
+    let expected: Vec<u32> = (11025..20000).chain(11025..30000).collect();
+    assert_eq!(played, expected);
+}
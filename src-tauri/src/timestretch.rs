@@ -0,0 +1,293 @@
+//! Playback speed and pitch
+//!
+//! Tempo is changed with WSOLA (waveform similarity overlap-add), which keeps the pitch.
+//! Pitch is then shifted by resampling the stretched audio, after stretching it by the
+//! same amount so the tempo stays put.
+
+use std::collections::VecDeque;
+
+/// Length of the overlap between segments
+const OVERLAP_MS: usize = 20;
+/// How far either side of the nominal position to look for the best match
+const SEARCH_MS: usize = 8;
+
+pub const MIN_TEMPO: f64 = 0.5;
+pub const MAX_TEMPO: f64 = 2.0;
+pub const MAX_SEMITONES: f64 = 12.0;
+
+pub struct TimeStretch {
+    channels: usize,
+    overlap: usize,
+    search: usize,
+    tempo: f64,
+    pitch: f64,
+    /// Rising half of a Hann window, `overlap` frames long
+    window: Vec<f32>,
+    /// Input frames not consumed yet (interleaved)
+    input: Vec<f32>,
+    /// Nominal start of the next segment in `input`, in frames
+    next_pos: f64,
+    /// Start of the last segment that was used
+    prev_pos: Option<usize>,
+    /// Falling half of the last segment, added to the next one
+    tail: Vec<f32>,
+    /// Stretched frames waiting to be pitch shifted
+    stretched: Vec<f32>,
+    resample_pos: f64,
+    output: Vec<f32>,
+}
+
+impl TimeStretch {
+    pub fn new(rate: u32, channels: usize) -> Self {
+        let overlap = (rate as usize * OVERLAP_MS / 1000).max(1);
+        let window = (0..overlap)
+            .map(|i| {
+                let x = (std::f64::consts::PI * (i as f64 + 0.5) / (2 * overlap) as f64).sin();
+                (x * x) as f32
+            })
+            .collect();
+        TimeStretch {
+            channels,
+            overlap,
+            search: rate as usize * SEARCH_MS / 1000,
+            tempo: 1.0,
+            pitch: 1.0,
+            window,
+            input: Vec::new(),
+            next_pos: 0.0,
+            prev_pos: None,
+            tail: vec![0.0; overlap * channels],
+            stretched: Vec::new(),
+            resample_pos: 0.0,
+            output: Vec::new(),
+        }
+    }
+
+    /// `tempo` is the speed (1.0 is normal), `semitones` the transposition.
+    /// Returns whether anything changed.
+    pub fn set_rate(&mut self, tempo: f64, semitones: f64) -> bool {
+        let tempo = tempo.clamp(MIN_TEMPO, MAX_TEMPO);
+        let pitch = 2f64.powf(semitones.clamp(-MAX_SEMITONES, MAX_SEMITONES) / 12.0);
+        if tempo == self.tempo && pitch == self.pitch {
+            return false;
+        }
+        self.tempo = tempo;
+        self.pitch = pitch;
+        self.reset();
+        true
+    }
+
+    pub fn tempo(&self) -> f64 {
+        self.tempo
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.tempo != 1.0 || self.pitch != 1.0
+    }
+
+    pub fn reset(&mut self) {
+        self.input.clear();
+        self.next_pos = 0.0;
+        self.prev_pos = None;
+        self.tail.iter_mut().for_each(|s| *s = 0.0);
+        self.stretched.clear();
+        self.resample_pos = 0.0;
+        self.output.clear();
+    }
+
+    /// Stretches interleaved samples. The output is shorter or longer than the input
+    /// depending on the tempo, and lags it by a few tens of milliseconds.
+    pub fn process(&mut self, input: &[f32]) -> &[f32] {
+        self.input.extend_from_slice(input);
+        self.output.clear();
+
+        let ch = self.channels;
+        let overlap = self.overlap;
+        let frames = self.input.len() / ch;
+        // Input frames to advance for every `overlap` output frames
+        let hop = overlap as f64 * self.tempo / self.pitch;
+
+        loop {
+            let nominal = self.next_pos.round() as usize;
+            if nominal + self.search + 2 * overlap > frames {
+                break;
+            }
+
+            match self.prev_pos {
+                None => {
+                    // Nothing to overlap with yet
+                    self.stretched
+                        .extend_from_slice(&self.input[nominal * ch..(nominal + overlap) * ch]);
+                    self.fill_tail(nominal);
+                    self.prev_pos = Some(nominal);
+                }
+                Some(prev) => {
+                    let start = self.best_match(prev + overlap, nominal);
+                    for i in 0..overlap {
+                        for c in 0..ch {
+                            let sample = self.input[(start + i) * ch + c];
+                            self.stretched
+                                .push(self.tail[i * ch + c] + sample * self.window[i]);
+                        }
+                    }
+                    self.fill_tail(start);
+                    self.prev_pos = Some(start);
+                }
+            }
+            self.next_pos += hop;
+        }
+
+        // Drop input that won't be looked at again
+        let keep_from = match self.prev_pos {
+            Some(prev) => (prev + overlap)
+                .min((self.next_pos.floor() as usize).saturating_sub(self.search)),
+            None => (self.next_pos.floor() as usize).saturating_sub(self.search),
+        }
+        .min(frames);
+        if keep_from > 0 {
+            self.input.drain(..keep_from * ch);
+            self.next_pos -= keep_from as f64;
+            self.prev_pos = self.prev_pos.map(|p| p - keep_from);
+        }
+
+        self.shift_pitch();
+        &self.output
+    }
+
+    /// Keeps the second half of the segment starting at `start`, faded out.
+    fn fill_tail(&mut self, start: usize) {
+        let ch = self.channels;
+        let overlap = self.overlap;
+        for i in 0..overlap {
+            let gain = self.window[overlap - 1 - i];
+            for c in 0..ch {
+                self.tail[i * ch + c] = self.input[(start + overlap + i) * ch + c] * gain;
+            }
+        }
+    }
+
+    /// Finds the segment start near `nominal` that best continues the waveform at `target`.
+    fn best_match(&self, target: usize, nominal: usize) -> usize {
+        let ch = self.channels;
+        let mono = |frame: usize| -> f32 { self.input[frame * ch..(frame + 1) * ch].iter().sum() };
+
+        let lo = nominal.saturating_sub(self.search);
+        let hi = nominal + self.search;
+        let mut best = nominal;
+        let mut best_score = f32::MIN;
+        for start in lo..=hi {
+            let mut correlation = 0.0;
+            let mut energy = 1e-9;
+            // Every other frame is plenty to find the peak
+            for i in (0..self.overlap).step_by(2) {
+                let candidate = mono(start + i);
+                correlation += candidate * mono(target + i);
+                energy += candidate * candidate;
+            }
+            let score = correlation / energy.sqrt();
+            if score > best_score {
+                best_score = score;
+                best = start;
+            }
+        }
+        best
+    }
+
+    /// Resamples the stretched frames by the pitch factor (linear interpolation).
+    fn shift_pitch(&mut self) {
+        let ch = self.channels;
+        if self.pitch == 1.0 {
+            self.output.append(&mut self.stretched);
+            return;
+        }
+
+        let frames = self.stretched.len() / ch;
+        while self.resample_pos + 1.0 < frames as f64 {
+            let index = self.resample_pos.floor() as usize;
+            let frac = (self.resample_pos - index as f64) as f32;
+            for c in 0..ch {
+                let a = self.stretched[index * ch + c];
+                let b = self.stretched[(index + 1) * ch + c];
+                self.output.push(a + (b - a) * frac);
+            }
+            self.resample_pos += self.pitch;
+        }
+
+        let consumed = (self.resample_pos.floor() as usize).min(frames);
+        self.stretched.drain(..consumed * ch);
+        self.resample_pos -= consumed as f64;
+    }
+}
+
+/// Keeps track of how much source audio each stretched sample in the ring buffer stands
+/// for, so the output can report the position in the track rather than time played.
+/// It also carries position changes (loop seams) to the point where they're heard.
+#[derive(Default)]
+pub struct TempoMap {
+    segments: VecDeque<Segment>,
+    remainder: f64,
+}
+
+enum Segment {
+    /// Samples, and source samples per sample
+    Samples(usize, f64),
+    /// Jump to this position
+    Position(u64),
+}
+
+impl TempoMap {
+    pub fn push(&mut self, samples: usize, tempo: f64) {
+        if samples == 0 {
+            return;
+        }
+        match self.segments.back_mut() {
+            Some(Segment::Samples(count, t)) if *t == tempo => *count += samples,
+            _ => self.segments.push_back(Segment::Samples(samples, tempo)),
+        }
+    }
+
+    /// The samples written after this start at `position`.
+    pub fn mark_position(&mut self, position: u64) {
+        self.segments.push_back(Segment::Position(position));
+    }
+
+    /// Moves `position` on by the source samples that `samples` played samples correspond to.
+    /// Returns true if the position jumped.
+    pub fn advance(&mut self, samples: usize, position: &mut u64) -> bool {
+        let mut remaining = samples;
+        let mut source = self.remainder;
+        let mut jumped = false;
+        loop {
+            match self.segments.front_mut() {
+                Some(Segment::Position(new_position)) => {
+                    *position = *new_position;
+                    source = 0.0;
+                    jumped = true;
+                    self.segments.pop_front();
+                }
+                Some(Segment::Samples(count, tempo)) if remaining > 0 => {
+                    let n = remaining.min(*count);
+                    source += n as f64 * *tempo;
+                    *count -= n;
+                    remaining -= n;
+                    if *count == 0 {
+                        self.segments.pop_front();
+                    }
+                }
+                _ => break,
+            }
+        }
+        // Not stretched
+        source += remaining as f64;
+
+        let whole = source.floor();
+        self.remainder = source - whole;
+        *position += whole as u64;
+        jumped
+    }
+
+    pub fn clear(&mut self) {
+        self.segments.clear();
+        self.remainder = 0.0;
+    }
+}
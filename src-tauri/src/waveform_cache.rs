@@ -0,0 +1,265 @@
+//! Waveforms already computed, kept in the app's cache directory so a track is only
+//! decoded for its peaks once.
+//!
+//! Each file's peaks are stored in a file named after the hash of its path, along with
+//! the audio file's modification time and size when they were computed; if either has
+//! changed since, the entry is stale. Coarser levels are stored too, for zoomed-out views.
+//! Entries are touched when read, and the least recently used are removed when the cache
+//! grows past its limit.
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+use chksum_md5::MD5;
+use filetime::FileTime;
+use log::{info, warn};
+use tauri::{AppHandle, Manager};
+use tokio_util::sync::CancellationToken;
+
+use crate::radio::is_stream_url;
+use crate::store::load_settings;
+use crate::GetWaveformRequest;
+
+/// Interleaved samples in each of the finest peaks
+pub const SAMPLES_PER_PEAK: usize = 4000;
+/// How many of the finest peaks each stored level merges into one
+pub const RESOLUTIONS: [usize; 3] = [1, 4, 16];
+/// How many tracks of the queue are computed ahead of time, the current one included
+pub const PRECOMPUTE_AHEAD: usize = 5;
+/// In megabytes, when the settings don't say
+pub const DEFAULT_MAX_SIZE: u64 = 256;
+
+const MAGIC: &[u8; 4] = b"MWAV";
+const VERSION: u32 = 1;
+
+/// Stops the tracks being computed for the queue when the queue changes.
+static PRECOMPUTE_TOKEN: Mutex<Option<CancellationToken>> = Mutex::new(None);
+
+/// A track's peaks, at each of the `RESOLUTIONS`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Waveform {
+    levels: Vec<(usize, Vec<f32>)>,
+}
+
+impl Waveform {
+    pub fn from_peaks(peaks: &[f32]) -> Self {
+        let levels = RESOLUTIONS
+            .iter()
+            .map(|&factor| (factor, downsample(peaks, factor)))
+            .collect();
+        Waveform { levels }
+    }
+
+    /// The peaks with `resolution` of the finest ones merged into each, from the closest
+    /// level stored.
+    pub fn peaks(&self, resolution: usize) -> Vec<f32> {
+        let resolution = resolution.max(1);
+        self.levels
+            .iter()
+            .rev()
+            .find(|(factor, _)| resolution % factor == 0)
+            .map_or_else(Vec::new, |(factor, peaks)| {
+                downsample(peaks, resolution / factor)
+            })
+    }
+}
+
+/// Merges every `factor` peaks into one. They're RMS levels, so they're averaged as powers.
+pub fn downsample(peaks: &[f32], factor: usize) -> Vec<f32> {
+    if factor <= 1 {
+        return peaks.to_vec();
+    }
+    peaks
+        .chunks(factor)
+        .map(|chunk| (chunk.iter().map(|p| p * p).sum::<f32>() / chunk.len() as f32).sqrt())
+        .collect()
+}
+
+/// The modification time, in nanoseconds, and size of the audio file behind `path`.
+fn stamp(path: &str) -> Option<(u64, u64)> {
+    // A track from a CUE sheet changes with its album's file
+    let file = crate::cue::split_track_path(path).map_or(path, |(file, _)| file);
+    let metadata = fs::metadata(file).ok()?;
+    let modified = metadata
+        .modified()
+        .ok()?
+        .duration_since(UNIX_EPOCH)
+        .ok()?
+        .as_nanos() as u64;
+    Some((modified, metadata.len()))
+}
+
+pub struct WaveformCache {
+    dir: PathBuf,
+    /// In bytes
+    max_size: u64,
+}
+
+impl WaveformCache {
+    pub fn new(dir: PathBuf, max_size: u64) -> Self {
+        WaveformCache { dir, max_size }
+    }
+
+    /// The cache in the app's cache directory, as big as the settings allow.
+    pub fn open(app: &AppHandle) -> Option<Self> {
+        let dir = app.path().app_cache_dir().ok()?.join("waveforms");
+        let max_size = load_settings(app).map_or(DEFAULT_MAX_SIZE, |s| s.waveform_cache_size);
+        Some(WaveformCache::new(dir, max_size * 1024 * 1024))
+    }
+
+    fn entry_path(&self, path: &str) -> PathBuf {
+        let key = MD5::hash(path.as_bytes()).to_hex_lowercase();
+        self.dir.join(format!("{}.bin", key))
+    }
+
+    /// The peaks of `path`, if they were computed since it last changed.
+    pub fn get(&self, path: &str) -> Option<Waveform> {
+        let entry = self.entry_path(path);
+        let (stored_stamp, waveform) = read_entry(&entry).ok()?;
+        if Some(stored_stamp) != stamp(path) {
+            let _ = fs::remove_file(&entry);
+            return None;
+        }
+        // Recently used entries are the last to be evicted
+        let _ = filetime::set_file_mtime(&entry, FileTime::now());
+        Some(waveform)
+    }
+
+    pub fn put(&self, path: &str, waveform: &Waveform) -> io::Result<()> {
+        let stamp = stamp(path)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no file to stamp"))?;
+        fs::create_dir_all(&self.dir)?;
+        // Written aside and moved into place, so a reader never sees half an entry
+        let mut file = tempfile::NamedTempFile::new_in(&self.dir)?;
+        write_entry(&mut BufWriter::new(file.as_file_mut()), stamp, waveform)?;
+        file.persist(self.entry_path(path))?;
+        self.evict();
+        Ok(())
+    }
+
+    /// Forgets the peaks of `path`, for when its audio changes.
+    pub fn remove(&self, path: &str) {
+        let _ = fs::remove_file(self.entry_path(path));
+    }
+
+    /// Removes the least recently used entries until the cache fits in its limit.
+    fn evict(&self) {
+        let Ok(read_dir) = fs::read_dir(&self.dir) else {
+            return;
+        };
+        let mut entries: Vec<(FileTime, u64, PathBuf)> = read_dir
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension().map_or(false, |e| e == "bin"))
+            .filter_map(|entry| {
+                let metadata = entry.metadata().ok()?;
+                let used = FileTime::from_last_modification_time(&metadata);
+                Some((used, metadata.len(), entry.path()))
+            })
+            .collect();
+        let mut total: u64 = entries.iter().map(|(_, size, _)| size).sum();
+        entries.sort_by_key(|(used, _, _)| *used);
+        for (_, size, path) in entries {
+            if total <= self.max_size {
+                break;
+            }
+            if fs::remove_file(&path).is_ok() {
+                total -= size;
+            }
+        }
+    }
+}
+
+fn write_entry(writer: &mut impl Write, stamp: (u64, u64), waveform: &Waveform) -> io::Result<()> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&VERSION.to_le_bytes())?;
+    writer.write_all(&stamp.0.to_le_bytes())?;
+    writer.write_all(&stamp.1.to_le_bytes())?;
+    writer.write_all(&(waveform.levels.len() as u32).to_le_bytes())?;
+    for (factor, peaks) in &waveform.levels {
+        writer.write_all(&(*factor as u32).to_le_bytes())?;
+        writer.write_all(&(peaks.len() as u32).to_le_bytes())?;
+        for peak in peaks {
+            writer.write_all(&peak.to_le_bytes())?;
+        }
+    }
+    writer.flush()
+}
+
+fn read_entry(path: &Path) -> io::Result<((u64, u64), Waveform)> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC || read_u32(&mut reader)? != VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a waveform"));
+    }
+    let stamp = (read_u64(&mut reader)?, read_u64(&mut reader)?);
+    let count = read_u32(&mut reader)?;
+    let mut levels = Vec::new();
+    for _ in 0..count {
+        let factor = read_u32(&mut reader)? as usize;
+        let len = read_u32(&mut reader)? as usize;
+        let mut bytes = vec![0; len * 4];
+        reader.read_exact(&mut bytes)?;
+        let peaks = bytes
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+        levels.push((factor, peaks));
+    }
+    Ok((stamp, Waveform { levels }))
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Computes the peaks of the tracks coming up in the queue that aren't cached yet, one at
+/// a time in the background. Whatever was being computed for the queue before stops.
+pub fn precompute(app_handle: &AppHandle, paths: Vec<String>) {
+    let token = CancellationToken::new();
+    if let Some(previous) = PRECOMPUTE_TOKEN
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .replace(token.clone())
+    {
+        previous.cancel();
+    }
+    let app_handle = app_handle.clone();
+    std::thread::spawn(move || {
+        let Some(cache) = WaveformCache::open(&app_handle) else {
+            return;
+        };
+        for path in paths {
+            if token.is_cancelled() {
+                break;
+            }
+            if is_stream_url(&path) || cache.get(&path).is_some() {
+                continue;
+            }
+            info!("waveform: precomputing {}", path);
+            let event = GetWaveformRequest {
+                path: Some(path.clone()),
+                resolution: None,
+            };
+            match crate::player::get_peaks(event, token.clone(), |_| {}) {
+                Ok(peaks) => {
+                    if let Err(err) = cache.put(&path, &Waveform::from_peaks(&peaks)) {
+                        warn!("waveform: couldn't cache {}: {}", path, err);
+                    }
+                }
+                Err(err) => info!("waveform: skipped {}: {}", path, err),
+            }
+        }
+    });
+}
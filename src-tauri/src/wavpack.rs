@@ -0,0 +1,1293 @@
+//! WavPack (`.wv`) reading and decoding for symphonia, which supports neither.
+//!
+//! Lossless and hybrid (lossy) streams decode here, integer or float, but not the `.wvc`
+//! correction files that make hybrid lossless again, nor DSD. Every block carries its own
+//! decorrelation and entropy state, so a packet (the blocks holding each channel of the
+//! same samples) decodes on its own and seeking only has to find the right one.
+
+use std::io::{Seek, SeekFrom};
+
+use symphonia::core::audio::{
+    AsAudioBufferRef, AudioBuffer, AudioBufferRef, Channels, Signal, SignalSpec,
+};
+use symphonia::core::codecs::{
+    decl_codec_type, CodecDescriptor, CodecParameters, CodecType, Decoder, DecoderOptions,
+    FinalizeResult,
+};
+use symphonia::core::errors::{decode_error, seek_error, unsupported_error, Result, SeekErrorKind};
+use symphonia::core::formats::{
+    Cue, FormatOptions, FormatReader, Packet, SeekMode, SeekTo, SeekedTo, Track,
+};
+use symphonia::core::io::{MediaSource, MediaSourceStream, ReadBytes};
+use symphonia::core::meta::{Metadata, MetadataLog};
+use symphonia::core::probe::{Descriptor, Instantiate, QueryDescriptor};
+use symphonia::core::sample::SampleFormat;
+use symphonia::core::units::TimeBase;
+use symphonia::core::{support_codec, support_format};
+
+pub const CODEC_TYPE_WAVPACK: CodecType = decl_codec_type(b"wv");
+
+const HEADER_LEN: usize = 32;
+
+// Block header flags
+const BYTES_STORED: u32 = 0x3;
+const MONO_FLAG: u32 = 0x4;
+const HYBRID_FLAG: u32 = 0x8;
+const JOINT_STEREO: u32 = 0x10;
+const FLOAT_DATA: u32 = 0x80;
+const INT32_DATA: u32 = 0x100;
+const HYBRID_BITRATE: u32 = 0x200;
+const HYBRID_BALANCE: u32 = 0x400;
+const INITIAL_BLOCK: u32 = 0x800;
+const FINAL_BLOCK: u32 = 0x1000;
+const SHIFT_LSB: u32 = 13;
+const SRATE_LSB: u32 = 23;
+const FALSE_STEREO: u32 = 0x4000_0000;
+const DSD_FLAG: u32 = 0x8000_0000;
+const MONO_DATA: u32 = MONO_FLAG | FALSE_STEREO;
+
+// Metadata sub-block ids
+const ID_UNIQUE: u8 = 0x3f;
+const ID_ODD_SIZE: u8 = 0x40;
+const ID_LARGE: u8 = 0x80;
+const ID_DECORR_TERMS: u8 = 0x2;
+const ID_DECORR_WEIGHTS: u8 = 0x3;
+const ID_DECORR_SAMPLES: u8 = 0x4;
+const ID_ENTROPY_VARS: u8 = 0x5;
+const ID_HYBRID_PROFILE: u8 = 0x6;
+const ID_FLOAT_INFO: u8 = 0x8;
+const ID_INT32_INFO: u8 = 0x9;
+const ID_WV_BITSTREAM: u8 = 0xa;
+const ID_WVX_BITSTREAM: u8 = 0xc;
+const ID_CHANNEL_INFO: u8 = 0xd;
+const ID_SAMPLE_RATE: u8 = 0x27;
+
+// Float info flags
+const FLOAT_SHIFT_ONES: u8 = 0x1;
+const FLOAT_SHIFT_SAME: u8 = 0x2;
+const FLOAT_SHIFT_SENT: u8 = 0x4;
+const FLOAT_ZEROS_SENT: u8 = 0x8;
+const FLOAT_NEG_ZEROS: u8 = 0x10;
+
+/// The rates the header's 4-bit index refers to; 15 means it's in a sub-block.
+const SAMPLE_RATES: [u32; 15] = [
+    6000, 8000, 9600, 11025, 12000, 16000, 22050, 24000, 32000, 44100, 48000, 64000, 88200, 96000,
+    192000,
+];
+
+const MAX_TERMS: usize = 16;
+const MAX_TERM: usize = 8;
+const LIMIT_ONES: u32 = 16;
+const SLS: u32 = 8;
+const SLO: u32 = 1 << (SLS - 1);
+
+#[derive(Clone, Copy, Debug)]
+struct BlockHeader {
+    /// Bytes after the id and this size
+    size: u32,
+    version: u16,
+    index: u64,
+    total_samples: Option<u64>,
+    samples: u32,
+    flags: u32,
+}
+
+impl BlockHeader {
+    fn parse(buf: &[u8]) -> Option<BlockHeader> {
+        if buf.len() < HEADER_LEN || &buf[..4] != b"wvpk" {
+            return None;
+        }
+        let u32_at = |i: usize| u32::from_le_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+        let size = u32_at(4);
+        let version = u16::from_le_bytes([buf[8], buf[9]]);
+        if !(0x402..=0x410).contains(&version) || size < 24 || size > 1 << 24 {
+            return None;
+        }
+        let total_samples = match u32_at(12) {
+            u32::MAX => None,
+            total => Some(total as u64 + ((buf[11] as u64) << 32) - buf[11] as u64),
+        };
+        Some(BlockHeader {
+            size,
+            version,
+            index: u32_at(16) as u64 + ((buf[10] as u64) << 32),
+            total_samples,
+            samples: u32_at(20),
+            flags: u32_at(24),
+        })
+    }
+
+    /// Channels the block decodes to, a false stereo block stores one of them.
+    fn channels(&self) -> usize {
+        if self.flags & MONO_FLAG != 0 {
+            1
+        } else {
+            2
+        }
+    }
+
+    fn mono_data(&self) -> bool {
+        self.flags & MONO_DATA != 0
+    }
+}
+
+/// The metadata sub-blocks of a block, as (id, data).
+fn sub_blocks(block: &[u8]) -> impl Iterator<Item = (u8, &[u8])> + '_ {
+    let mut pos = HEADER_LEN;
+    std::iter::from_fn(move || {
+        let id = *block.get(pos)?;
+        let (len, start) = if id & ID_LARGE != 0 {
+            let len = block.get(pos + 1..pos + 4)?;
+            let len = len[0] as usize | (len[1] as usize) << 8 | (len[2] as usize) << 16;
+            (len * 2, pos + 4)
+        } else {
+            (*block.get(pos + 1)? as usize * 2, pos + 2)
+        };
+        let data = block.get(start..start + len)?;
+        pos = start + len;
+        let data = if id & ID_ODD_SIZE != 0 && len > 0 {
+            &data[..len - 1]
+        } else {
+            data
+        };
+        Some((id & ID_UNIQUE, data))
+    })
+}
+
+/// WavPack's fixed-point log and exp tables, 8 bits of fraction each.
+pub struct Tables {
+    exp2: [u8; 256],
+    log2: [u8; 256],
+}
+
+impl Tables {
+    pub fn new() -> Self {
+        let mut tables = Tables {
+            exp2: [0; 256],
+            log2: [0; 256],
+        };
+        for i in 0..256 {
+            let x = i as f64 / 256.0;
+            tables.exp2[i] = ((256.0 * x.exp2()).round() as u32 & 0xff) as u8;
+            tables.log2[i] = (256.0 * (1.0 + x).log2()).round() as u8;
+        }
+        tables
+    }
+
+    /// Signed 8.8 log value to linear.
+    pub fn exp2s(&self, log: i32) -> i32 {
+        if log < 0 {
+            return -self.exp2s(-log);
+        }
+        let value = self.exp2[(log & 0xff) as usize] as u32 | 0x100;
+        let log = log >> 8;
+        if log <= 9 {
+            (value >> (9 - log)) as i32
+        } else {
+            (value << ((log - 9) & 0x1f)) as i32
+        }
+    }
+
+    /// Linear to 8.8 log, the inverse of `exp2s`.
+    pub fn log2(&self, value: u32) -> i32 {
+        let value = value.wrapping_add(value >> 9);
+        let bits = 32 - value.leading_zeros();
+        let fraction = if bits < 9 {
+            value << (9 - bits)
+        } else {
+            value >> (bits - 9)
+        };
+        (bits << 8) as i32 + self.log2[(fraction & 0xff) as usize] as i32
+    }
+}
+
+/// Decorrelation weights are stored in 8 bits, scaled from the +/-1024 they work in.
+pub fn restore_weight(weight: u8) -> i32 {
+    let weight = weight as i8 as i32 * 8;
+    if weight > 0 {
+        weight + ((weight + 64) >> 7)
+    } else {
+        weight
+    }
+}
+
+/// Reads a bitstream from the least significant bit of each byte up. Reading past the end
+/// gives zeros and marks the stream as overrun.
+struct Bits<'a> {
+    data: &'a [u8],
+    pos: usize,
+    overrun: bool,
+}
+
+impl<'a> Bits<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Bits {
+            data,
+            pos: 0,
+            overrun: false,
+        }
+    }
+
+    fn bit(&mut self) -> u32 {
+        match self.data.get(self.pos >> 3) {
+            Some(byte) => {
+                let bit = (byte >> (self.pos & 7)) as u32 & 1;
+                self.pos += 1;
+                bit
+            }
+            None => {
+                self.overrun = true;
+                0
+            }
+        }
+    }
+
+    fn bits(&mut self, count: u32) -> u32 {
+        (0..count).fold(0, |value, i| value | self.bit() << i)
+    }
+
+    /// A count sent as its length in ones, then the bits below its top one.
+    fn count(&mut self) -> Option<u32> {
+        let mut bits = 0;
+        while bits < 33 && self.bit() == 1 {
+            bits += 1;
+        }
+        match bits {
+            33 => None,
+            0 | 1 => Some(bits),
+            _ => Some(self.bits(bits - 1) | 1 << (bits - 1)),
+        }
+    }
+
+    /// A value from 0 to `max`, taking one bit less for the lower ones when `max + 1`
+    /// isn't a power of two.
+    fn code(&mut self, max: u32) -> u32 {
+        if max < 2 {
+            return if max == 1 { self.bit() } else { 0 };
+        }
+        let bits = 32 - max.leading_zeros();
+        let extras = ((1u64 << bits) - max as u64 - 1) as u32;
+        let code = self.bits(bits - 1);
+        if code >= extras {
+            (code << 1) - extras + self.bit()
+        } else {
+            code
+        }
+    }
+}
+
+const MEDIAN_DIVS: [u32; 3] = [128, 64, 32];
+
+#[derive(Clone, Copy, Default)]
+struct Entropy {
+    /// The running medians the residuals are coded against, with 4 bits of fraction
+    median: [u32; 3],
+    slow_level: u32,
+    error_limit: u32,
+    bitrate_acc: u32,
+    bitrate_delta: u32,
+}
+
+impl Entropy {
+    fn median(&self, i: usize) -> u32 {
+        (self.median[i] >> 4) + 1
+    }
+
+    fn inc_median(&mut self, i: usize) {
+        let div = MEDIAN_DIVS[i];
+        self.median[i] = self.median[i].wrapping_add((self.median[i].wrapping_add(div) / div) * 5);
+    }
+
+    fn dec_median(&mut self, i: usize) {
+        let div = MEDIAN_DIVS[i];
+        self.median[i] =
+            self.median[i].wrapping_sub((self.median[i].wrapping_add(div - 2) / div) * 2);
+    }
+
+    fn slow_down(&mut self) {
+        self.slow_level = self
+            .slow_level
+            .wrapping_sub(self.slow_level.wrapping_add(SLO) >> SLS);
+    }
+
+    fn slow_log(&self) -> i32 {
+        (self.slow_level.wrapping_add(SLO) >> SLS) as i32
+    }
+}
+
+/// Decodes the residuals, which are coded against running medians with runs of zeros
+/// sent as counts.
+#[derive(Default)]
+struct Words {
+    c: [Entropy; 2],
+    holding_zero: bool,
+    holding_one: u32,
+    zeros_acc: u32,
+}
+
+impl Words {
+    fn word(&mut self, bits: &mut Bits, chan: usize, flags: u32, tables: &Tables) -> Option<i32> {
+        if self.c[0].median[0] < 2
+            && self.c[1].median[0] < 2
+            && !self.holding_zero
+            && self.holding_one == 0
+        {
+            if self.zeros_acc > 0 {
+                self.zeros_acc -= 1;
+                if self.zeros_acc > 0 {
+                    self.c[chan].slow_down();
+                    return Some(0);
+                }
+            } else {
+                self.zeros_acc = bits.count()?;
+                if self.zeros_acc > 0 {
+                    self.c[chan].slow_down();
+                    self.c[0].median = [0; 3];
+                    self.c[1].median = [0; 3];
+                    return Some(0);
+                }
+            }
+        }
+
+        let ones = if self.holding_zero {
+            self.holding_zero = false;
+            0
+        } else {
+            let mut ones = 0;
+            while ones < LIMIT_ONES + 1 && bits.bit() == 1 {
+                ones += 1;
+            }
+            if ones == LIMIT_ONES + 1 {
+                return None;
+            }
+            if ones == LIMIT_ONES {
+                ones = bits.count()?.wrapping_add(LIMIT_ONES);
+            }
+            let held = self.holding_one;
+            self.holding_one = ones & 1;
+            self.holding_zero = ones & 1 == 0;
+            (ones >> 1) + held
+        };
+
+        if flags & HYBRID_FLAG != 0 && chan == 0 {
+            self.update_error_limit(flags, tables);
+        }
+
+        let c = &mut self.c[chan];
+        let (mut low, mut high);
+        if ones == 0 {
+            low = 0;
+            high = c.median(0) - 1;
+            c.dec_median(0);
+        } else {
+            low = c.median(0);
+            c.inc_median(0);
+            if ones == 1 {
+                high = low.wrapping_add(c.median(1) - 1);
+                c.dec_median(1);
+            } else {
+                low = low.wrapping_add(c.median(1));
+                c.inc_median(1);
+                if ones == 2 {
+                    high = low.wrapping_add(c.median(2) - 1);
+                    c.dec_median(2);
+                } else {
+                    low = low.wrapping_add((ones - 2).wrapping_mul(c.median(2)));
+                    high = low.wrapping_add(c.median(2) - 1);
+                    c.inc_median(2);
+                }
+            }
+        }
+
+        let mid = if flags & HYBRID_FLAG == 0 {
+            low.wrapping_add(bits.code(high.wrapping_sub(low)))
+        } else {
+            // Lossy: only narrow the range down to the error limit
+            low &= 0x7fff_ffff;
+            high = (high & 0x7fff_ffff).max(low);
+            let mut mid = (high + low + 1) >> 1;
+            if c.error_limit == 0 {
+                mid = bits.code(high - low) + low;
+            } else {
+                while high - low > c.error_limit {
+                    if bits.bit() == 1 {
+                        low = mid;
+                    } else {
+                        high = mid - 1;
+                    }
+                    mid = (high + low + 1) >> 1;
+                }
+            }
+            mid
+        };
+
+        let negative = bits.bit() == 1;
+        if flags & HYBRID_BITRATE != 0 {
+            c.slow_down();
+            c.slow_level = c.slow_level.wrapping_add(tables.log2(mid) as u32);
+        }
+        Some(if negative { !(mid as i32) } else { mid as i32 })
+    }
+
+    fn update_error_limit(&mut self, flags: u32, tables: &Tables) {
+        let channels = if flags & MONO_DATA != 0 { 1 } else { 2 };
+        let mut bitrates = [0i32; 2];
+        for (c, bitrate) in self.c.iter_mut().zip(bitrates.iter_mut()).take(channels) {
+            c.bitrate_acc = c.bitrate_acc.wrapping_add(c.bitrate_delta);
+            *bitrate = (c.bitrate_acc >> 16) as i32;
+        }
+
+        if flags & HYBRID_BITRATE == 0 {
+            for (c, bitrate) in self.c.iter_mut().zip(bitrates).take(channels) {
+                c.error_limit = tables.exp2s(bitrate) as u32;
+            }
+            return;
+        }
+
+        let logs = [self.c[0].slow_log(), self.c[1].slow_log()];
+        if channels == 2 && flags & HYBRID_BALANCE != 0 {
+            let balance = (logs[1] - logs[0] + bitrates[1] + 1) >> 1;
+            bitrates = if balance > bitrates[0] {
+                [0, bitrates[0] * 2]
+            } else if -balance > bitrates[0] {
+                [bitrates[0] * 2, 0]
+            } else {
+                [bitrates[0] - balance, bitrates[0] + balance]
+            };
+        }
+        for i in 0..channels {
+            self.c[i].error_limit = if logs[i] - bitrates[i] > -0x100 {
+                tables.exp2s(logs[i] - bitrates[i] + 0x100) as u32
+            } else {
+                0
+            };
+        }
+    }
+}
+
+/// One decorrelation pass, undoing a prediction from earlier samples (terms 1 to 8 and
+/// 17, 18) or, for stereo, from the other channel (terms -1 to -3).
+#[derive(Clone, Copy, Default)]
+struct Pass {
+    term: i32,
+    delta: i32,
+    weight: [i32; 2],
+    samples: [[i32; MAX_TERM]; 2],
+}
+
+fn apply_weight(weight: i32, sample: i32) -> i32 {
+    ((weight as i64 * sample as i64 + 512) >> 10) as i32
+}
+
+fn update_weight(weight: &mut i32, delta: i32, source: i32, result: i32) {
+    if source != 0 && result != 0 {
+        if (source ^ result) < 0 {
+            *weight -= delta;
+        } else {
+            *weight += delta;
+        }
+    }
+}
+
+fn update_weight_clip(weight: &mut i32, delta: i32, source: i32, result: i32) {
+    if source != 0 && result != 0 {
+        if (source ^ result) < 0 {
+            *weight = (*weight - delta).max(-1024);
+        } else {
+            *weight = (*weight + delta).min(1024);
+        }
+    }
+}
+
+fn decorr_channel<'a>(
+    term: i32,
+    delta: i32,
+    weight: &mut i32,
+    history: &mut [i32; MAX_TERM],
+    samples: impl Iterator<Item = &'a mut i32>,
+) {
+    match term {
+        17 | 18 => {
+            for sample in samples {
+                let predicted = if term == 17 {
+                    history[0].wrapping_mul(2).wrapping_sub(history[1])
+                } else {
+                    history[0].wrapping_add(history[0].wrapping_sub(history[1]) >> 1)
+                };
+                history[1] = history[0];
+                history[0] = apply_weight(*weight, predicted).wrapping_add(*sample);
+                update_weight(weight, delta, predicted, *sample);
+                *sample = history[0];
+            }
+        }
+        _ => {
+            let mut m = 0;
+            let mut k = term as usize & (MAX_TERM - 1);
+            for sample in samples {
+                let predicted = history[m];
+                history[k] = apply_weight(*weight, predicted).wrapping_add(*sample);
+                update_weight(weight, delta, predicted, *sample);
+                *sample = history[k];
+                m = (m + 1) & (MAX_TERM - 1);
+                k = (k + 1) & (MAX_TERM - 1);
+            }
+        }
+    }
+}
+
+fn decorr_mono(pass: &mut Pass, buf: &mut [i32]) {
+    let (term, delta) = (pass.term, pass.delta);
+    let history = &mut pass.samples[0];
+    decorr_channel(term, delta, &mut pass.weight[0], history, buf.iter_mut());
+}
+
+fn decorr_stereo(pass: &mut Pass, buf: &mut [i32]) {
+    let Pass {
+        term,
+        delta,
+        weight,
+        samples,
+    } = pass;
+    let (delta, [weight_a, weight_b], [a, b]) = (*delta, weight, samples);
+    match *term {
+        -1 => {
+            for frame in buf.chunks_exact_mut(2) {
+                let left = frame[0].wrapping_add(apply_weight(*weight_a, a[0]));
+                update_weight_clip(weight_a, delta, a[0], frame[0]);
+                frame[0] = left;
+                a[0] = frame[1].wrapping_add(apply_weight(*weight_b, left));
+                update_weight_clip(weight_b, delta, left, frame[1]);
+                frame[1] = a[0];
+            }
+        }
+        -2 => {
+            for frame in buf.chunks_exact_mut(2) {
+                let right = frame[1].wrapping_add(apply_weight(*weight_b, b[0]));
+                update_weight_clip(weight_b, delta, b[0], frame[1]);
+                frame[1] = right;
+                b[0] = frame[0].wrapping_add(apply_weight(*weight_a, right));
+                update_weight_clip(weight_a, delta, right, frame[0]);
+                frame[0] = b[0];
+            }
+        }
+        -3 => {
+            for frame in buf.chunks_exact_mut(2) {
+                let left = frame[0].wrapping_add(apply_weight(*weight_a, a[0]));
+                update_weight_clip(weight_a, delta, a[0], frame[0]);
+                let right = frame[1].wrapping_add(apply_weight(*weight_b, b[0]));
+                update_weight_clip(weight_b, delta, b[0], frame[1]);
+                b[0] = left;
+                a[0] = right;
+                frame[0] = left;
+                frame[1] = right;
+            }
+        }
+        term => {
+            decorr_channel(term, delta, weight_a, a, buf.iter_mut().step_by(2));
+            decorr_channel(term, delta, weight_b, b, buf.iter_mut().skip(1).step_by(2));
+        }
+    }
+}
+
+/// Undoes the integer tricks (shifted, 32-bit and hybrid-clipped samples), leaving each
+/// sample right-aligned in the block's bytes per sample.
+fn fixup_integers(values: &mut [i32], flags: u32, int32: [u8; 4], wvx: Option<&mut Bits>) {
+    let lossy = flags & HYBRID_FLAG != 0;
+    let mut shift = (flags >> SHIFT_LSB) & 0x1f;
+
+    if flags & INT32_DATA != 0 {
+        let [sent_bits, mut zeros, mut ones, mut dups] = int32.map(|v| (v & 0x1f) as u32);
+        let restore = |v: i32, zeros: u32, ones: u32, dups: u32| {
+            if zeros > 0 {
+                ((v as u32) << zeros) as i32
+            } else if ones > 0 {
+                (((v.wrapping_add(1) as u32) << ones) as i32).wrapping_sub(1)
+            } else if dups > 0 {
+                (((v.wrapping_add(v & 1) as u32) << dups) as i32).wrapping_sub(v & 1)
+            } else {
+                v
+            }
+        };
+        if let Some(wvx) = wvx {
+            for v in values.iter_mut() {
+                let low = wvx.bits(sent_bits);
+                *v = restore(((*v as u32) << sent_bits | low) as i32, zeros, ones, dups);
+            }
+        } else if sent_bits == 0 && zeros + ones + dups > 0 {
+            while lossy && flags & BYTES_STORED == 3 && shift < 8 {
+                if zeros > 0 {
+                    zeros -= 1;
+                } else if ones > 0 {
+                    ones -= 1;
+                } else if dups > 0 {
+                    dups -= 1;
+                } else {
+                    break;
+                }
+                shift += 1;
+            }
+            for v in values.iter_mut() {
+                *v = restore(*v, zeros, ones, dups);
+            }
+        } else {
+            shift += zeros + sent_bits + ones + dups;
+        }
+    }
+
+    let shift = shift & 0x1f;
+    if lossy {
+        let unused = 32 - 8 * ((flags & BYTES_STORED) + 1);
+        let min = i32::MIN >> unused >> shift;
+        let max = i32::MAX >> unused >> shift;
+        for v in values.iter_mut() {
+            *v = (*v).clamp(min, max) << shift;
+        }
+    } else if shift > 0 {
+        for v in values.iter_mut() {
+            *v <<= shift;
+        }
+    }
+}
+
+/// Rebuilds float samples (as their bits) from the integers they were coded as, with the
+/// bits that didn't fit coming from the extra `wvx` stream when there is one.
+fn float_values(values: &mut [i32], info: [u8; 4], mut wvx: Option<&mut Bits>) {
+    let [float_flags, float_shift, max_exp, norm_exp] = info;
+    let scale = 2f32.powi(127 - norm_exp as i32);
+
+    for v in values.iter_mut() {
+        let mut exp = max_exp as u32;
+        let (mut negative, mut mantissa) = (false, 0u32);
+        let mut value = *v;
+
+        if value == 0 {
+            if let Some(wvx) = wvx.as_deref_mut() {
+                if float_flags & FLOAT_ZEROS_SENT != 0 {
+                    if wvx.bit() == 1 {
+                        mantissa = wvx.bits(23);
+                        exp = if max_exp >= 25 { wvx.bits(8) } else { 0 };
+                        negative = wvx.bit() == 1;
+                    } else {
+                        exp = 0;
+                        if float_flags & FLOAT_NEG_ZEROS != 0 {
+                            negative = wvx.bit() == 1;
+                        }
+                    }
+                } else {
+                    exp = 0;
+                }
+            } else {
+                exp = 0;
+            }
+        } else {
+            value = ((value as u32) << (float_shift & 0x1f)) as i32;
+            if value < 0 {
+                value = value.wrapping_neg();
+                negative = true;
+            }
+            let mut value = value as u32;
+
+            if wvx.is_some() && value == 0x100_0000 {
+                // Infinity or NaN
+                let wvx = wvx.as_deref_mut().unwrap();
+                if wvx.bit() == 1 {
+                    mantissa = wvx.bits(23);
+                }
+                exp = 255;
+            } else if wvx.is_none() && value >= 0x100_0000 {
+                while value & 0xf00_0000 != 0 {
+                    value >>= 1;
+                    exp += 1;
+                }
+                mantissa = value;
+            } else {
+                let mut shifted = 0;
+                if exp > 0 {
+                    while value & 0x80_0000 == 0 {
+                        exp -= 1;
+                        if exp == 0 {
+                            break;
+                        }
+                        shifted += 1;
+                        value <<= 1;
+                    }
+                }
+                let shifted = shifted & 0x1f;
+                if shifted > 0 {
+                    let fill = (1u32 << shifted) - 1;
+                    if float_flags & FLOAT_SHIFT_ONES != 0 {
+                        value |= fill;
+                    } else if let Some(wvx) = wvx.as_deref_mut() {
+                        if float_flags & FLOAT_SHIFT_SAME != 0 && wvx.bit() == 1 {
+                            value |= fill;
+                        } else if float_flags & FLOAT_SHIFT_SENT != 0 {
+                            value |= wvx.bits(shifted) & fill;
+                        }
+                    }
+                }
+                mantissa = value;
+            }
+        }
+
+        let bits = (negative as u32) << 31 | (exp & 0xff) << 23 | mantissa & 0x7f_ffff;
+        *v = if norm_exp == 127 {
+            bits as i32
+        } else {
+            (f32::from_bits(bits) * scale).to_bits() as i32
+        };
+    }
+}
+
+/// Decodes one block into `out`, interleaved when it has two channels. Integers are
+/// right-aligned in the block's bytes per sample, floats are their bits.
+fn decode_block(block: &[u8], tables: &Tables, out: &mut Vec<i32>) -> Result<BlockHeader> {
+    let header = match BlockHeader::parse(block) {
+        Some(header) => header,
+        None => return decode_error("wavpack: invalid block header"),
+    };
+    let flags = header.flags;
+    if flags & DSD_FLAG != 0 {
+        return unsupported_error("wavpack: DSD is not supported");
+    }
+    let mono = header.mono_data();
+    let data_channels = if mono { 1 } else { 2 };
+
+    let mut passes: Vec<Pass> = Vec::new();
+    let mut words = Words::default();
+    let mut int32 = [0u8; 4];
+    let mut float_info = None;
+    let mut wv = None;
+    let mut wvx = None;
+
+    for (id, data) in sub_blocks(block) {
+        match id {
+            ID_DECORR_TERMS => {
+                if data.len() > MAX_TERMS {
+                    return decode_error("wavpack: too many decorrelation terms");
+                }
+                passes = data
+                    .iter()
+                    .rev()
+                    .map(|b| Pass {
+                        term: (b & 0x1f) as i32 - 5,
+                        delta: ((b >> 5) & 0x7) as i32,
+                        ..Default::default()
+                    })
+                    .collect();
+                let invalid = |term: i32| {
+                    term == 0
+                        || term < -3
+                        || (term > MAX_TERM as i32 && term < 17)
+                        || term > 18
+                        || (mono && term < 0)
+                };
+                if passes.iter().any(|pass| invalid(pass.term)) {
+                    return decode_error("wavpack: invalid decorrelation term");
+                }
+            }
+            ID_DECORR_WEIGHTS => {
+                if data.len() / data_channels > passes.len() {
+                    return decode_error("wavpack: too many decorrelation weights");
+                }
+                // Weights are stored from the last pass back
+                for (pass, weights) in passes
+                    .iter_mut()
+                    .rev()
+                    .zip(data.chunks_exact(data_channels))
+                {
+                    for (weight, stored) in pass.weight.iter_mut().zip(weights) {
+                        *weight = restore_weight(*stored);
+                    }
+                }
+            }
+            ID_DECORR_SAMPLES => {
+                let mut values = data
+                    .chunks_exact(2)
+                    .map(|b| tables.exp2s(i16::from_le_bytes([b[0], b[1]]) as i32));
+                if header.version == 0x402 && flags & HYBRID_FLAG != 0 {
+                    // Noise shaping errors, which only matter with a correction file
+                    values.nth(data_channels - 1);
+                }
+                for pass in passes.iter_mut().rev() {
+                    if values.len() == 0 {
+                        break;
+                    }
+                    let history = if pass.term > MAX_TERM as i32 {
+                        2
+                    } else if pass.term < 0 {
+                        1
+                    } else {
+                        pass.term as usize
+                    };
+                    let channels = if pass.term < 0 { 2 } else { data_channels };
+                    if values.len() < history * channels {
+                        return decode_error("wavpack: truncated decorrelation samples");
+                    }
+                    if pass.term > MAX_TERM as i32 {
+                        // Both samples of one channel, then the other's
+                        for samples in pass.samples.iter_mut().take(channels) {
+                            samples[0] = values.next().unwrap();
+                            samples[1] = values.next().unwrap();
+                        }
+                    } else {
+                        for m in 0..history {
+                            for samples in pass.samples.iter_mut().take(channels) {
+                                samples[m] = values.next().unwrap();
+                            }
+                        }
+                    }
+                }
+                if values.len() > 0 {
+                    return decode_error("wavpack: too many decorrelation samples");
+                }
+            }
+            ID_ENTROPY_VARS => {
+                if data.len() != 6 * data_channels {
+                    return decode_error("wavpack: invalid entropy variables");
+                }
+                for (c, medians) in words.c.iter_mut().zip(data.chunks_exact(6)) {
+                    for (median, b) in c.median.iter_mut().zip(medians.chunks_exact(2)) {
+                        *median = tables.exp2s(u16::from_le_bytes([b[0], b[1]]) as i32) as u32;
+                    }
+                }
+            }
+            ID_HYBRID_PROFILE => {
+                let mut values = data
+                    .chunks_exact(2)
+                    .map(|b| u16::from_le_bytes([b[0], b[1]]));
+                if flags & HYBRID_BITRATE != 0 {
+                    for c in words.c.iter_mut().take(data_channels) {
+                        c.slow_level = tables.exp2s(values.next().unwrap_or(0) as i32) as u32;
+                    }
+                }
+                for c in words.c.iter_mut().take(data_channels) {
+                    c.bitrate_acc = (values.next().unwrap_or(0) as u32) << 16;
+                }
+                for c in words.c.iter_mut().take(data_channels) {
+                    c.bitrate_delta = values
+                        .next()
+                        .map_or(0, |v| tables.exp2s(v as i16 as i32) as u32);
+                }
+            }
+            ID_FLOAT_INFO if data.len() == 4 => {
+                float_info = Some([data[0], data[1], data[2], data[3]]);
+            }
+            ID_INT32_INFO if data.len() == 4 => {
+                int32 = [data[0], data[1], data[2], data[3]];
+            }
+            ID_WV_BITSTREAM => wv = Some(data),
+            ID_WVX_BITSTREAM if data.len() > 4 => wvx = Some(Bits::new(&data[4..])),
+            _ => {}
+        }
+    }
+
+    let len = header.samples as usize * data_channels;
+    out.clear();
+    out.resize(len, 0);
+
+    // A block without a bitstream has nothing but zeros to decorrelate
+    if let Some(wv) = wv {
+        let mut bits = Bits::new(wv);
+        for (i, sample) in out.iter_mut().enumerate() {
+            *sample = match words.word(&mut bits, i % data_channels, flags, tables) {
+                Some(word) => word,
+                None => return decode_error("wavpack: invalid residual"),
+            };
+        }
+        if bits.overrun {
+            return decode_error("wavpack: truncated bitstream");
+        }
+    }
+
+    for pass in passes.iter_mut() {
+        if mono {
+            decorr_mono(pass, out);
+        } else {
+            decorr_stereo(pass, out);
+        }
+    }
+    if !mono && flags & JOINT_STEREO != 0 {
+        for frame in out.chunks_exact_mut(2) {
+            frame[1] = frame[1].wrapping_sub(frame[0] >> 1);
+            frame[0] = frame[0].wrapping_add(frame[1]);
+        }
+    }
+
+    match float_info {
+        Some(info) if flags & FLOAT_DATA != 0 => float_values(out, info, wvx.as_mut()),
+        _ => fixup_integers(out, flags, int32, wvx.as_mut()),
+    }
+
+    if flags & FALSE_STEREO != 0 {
+        out.resize(len * 2, 0);
+        for i in (0..len).rev() {
+            out[i * 2] = out[i];
+            out[i * 2 + 1] = out[i];
+        }
+    }
+
+    Ok(header)
+}
+
+/// Up to four little-endian bytes as a number.
+fn le_bits(bytes: &[u8]) -> u32 {
+    bytes
+        .iter()
+        .take(4)
+        .enumerate()
+        .fold(0, |value, (i, b)| value | (*b as u32) << (8 * i))
+}
+
+pub struct WavPackReader {
+    reader: MediaSourceStream,
+    tracks: Vec<Track>,
+    cues: Vec<Cue>,
+    metadata: MetadataLog,
+    /// Sample index of the first block, which timestamps count from
+    start: u64,
+    /// Timestamp and byte position of the packets seen so far, to seek back to
+    index: Vec<(u64, u64)>,
+}
+
+impl WavPackReader {
+    /// Finds the next block header, skipping anything else (like the tags at the end).
+    fn next_header(&mut self) -> Result<(u64, [u8; HEADER_LEN], BlockHeader)> {
+        let mut buf = [0u8; HEADER_LEN];
+        self.reader.read_buf_exact(&mut buf)?;
+        loop {
+            if let Some(header) = BlockHeader::parse(&buf) {
+                return Ok((self.reader.pos() - HEADER_LEN as u64, buf, header));
+            }
+            buf.copy_within(1.., 0);
+            buf[HEADER_LEN - 1] = self.reader.read_byte()?;
+        }
+    }
+
+    /// Reads the blocks of the next packet, from an initial block to a final one.
+    fn read_frame(&mut self) -> Result<(u64, BlockHeader, Vec<u8>)> {
+        let mut data = Vec::new();
+        let mut first: Option<(u64, BlockHeader)> = None;
+        loop {
+            let (pos, buf, header) = self.next_header()?;
+            let rest = (header.size - 24) as u64;
+            if header.flags & INITIAL_BLOCK != 0 {
+                data.clear();
+                first = Some((pos, header));
+            }
+            // Blocks without samples only hold metadata, like the original file's header
+            if header.samples == 0 || first.is_none() {
+                self.reader.ignore_bytes(rest)?;
+                continue;
+            }
+            let len = data.len();
+            data.extend_from_slice(&buf);
+            data.resize(len + HEADER_LEN + rest as usize, 0);
+            self.reader.read_buf_exact(&mut data[len + HEADER_LEN..])?;
+            if header.flags & FINAL_BLOCK != 0 {
+                let (pos, header) = first.unwrap();
+                return Ok((pos, header, data));
+            }
+        }
+    }
+}
+
+impl QueryDescriptor for WavPackReader {
+    fn query() -> &'static [Descriptor] {
+        &[support_format!(
+            "wavpack",
+            "WavPack",
+            &["wv"],
+            &["audio/x-wavpack", "audio/wavpack"],
+            &[b"wvpk"]
+        )]
+    }
+
+    fn score(_context: &[u8]) -> u8 {
+        255
+    }
+}
+
+impl FormatReader for WavPackReader {
+    fn try_new(source: MediaSourceStream, _options: &FormatOptions) -> Result<Self> {
+        let mut reader = WavPackReader {
+            reader: source,
+            tracks: Vec::new(),
+            cues: Vec::new(),
+            metadata: Default::default(),
+            start: 0,
+            index: Vec::new(),
+        };
+
+        // The first packet says what the stream is
+        let (pos, first, data) = reader.read_frame()?;
+        if first.flags & DSD_FLAG != 0 {
+            return unsupported_error("wavpack: DSD is not supported");
+        }
+        let mut rate = SAMPLE_RATES
+            .get((first.flags >> SRATE_LSB & 0xf) as usize)
+            .copied();
+        let mut count = 0;
+        let mut mask = None;
+        let mut offset = 0;
+        while let Some(header) = BlockHeader::parse(&data[offset..]) {
+            let block = &data[offset..offset + 8 + header.size as usize];
+            count += header.channels();
+            for (id, data) in sub_blocks(block) {
+                match id {
+                    ID_SAMPLE_RATE if data.len() == 3 || data.len() == 4 => {
+                        rate = Some(le_bits(data) & 0x7fff_ffff);
+                    }
+                    // The channel count, then the WAVEFORMATEXTENSIBLE mask
+                    ID_CHANNEL_INFO if !data.is_empty() && data.len() < 6 => {
+                        mask = Some((data[0] as usize, le_bits(&data[1..])));
+                    }
+                    // Since WavPack 5, with room for more channels and streams
+                    ID_CHANNEL_INFO if data.len() == 6 || data.len() == 7 => {
+                        let count = data[0] as usize | (data[2] as usize & 0xf) << 8;
+                        mask = Some((count + 1, le_bits(&data[3..])));
+                    }
+                    _ => {}
+                }
+            }
+            offset += 8 + header.size as usize;
+        }
+        if count > 32 {
+            return unsupported_error("wavpack: too many channels");
+        }
+        let channels = match mask.and_then(|(n, bits)| Channels::from_bits(bits).map(|c| (n, c))) {
+            Some((n, channels)) if n == count && channels.count() == count => channels,
+            _ => Channels::from_bits_truncate(((1u64 << count) - 1) as u32),
+        };
+        let rate = rate.unwrap_or(44100);
+
+        let float = first.flags & FLOAT_DATA != 0;
+        let bits = if float {
+            32
+        } else {
+            8 * ((first.flags & BYTES_STORED) + 1) - ((first.flags >> SHIFT_LSB) & 0x1f)
+        };
+
+        let mut params = CodecParameters::new();
+        params
+            .for_codec(CODEC_TYPE_WAVPACK)
+            .with_sample_rate(rate)
+            .with_time_base(TimeBase::new(1, rate))
+            .with_bits_per_sample(bits)
+            .with_sample_format(if float {
+                SampleFormat::F32
+            } else {
+                SampleFormat::S32
+            })
+            .with_channels(channels)
+            .with_max_frames_per_packet(first.samples as u64);
+        if let Some(total) = first.total_samples {
+            params.with_n_frames(total);
+        }
+
+        reader.tracks.push(Track::new(0, params));
+        reader.start = first.index;
+        reader.index.push((0, pos));
+        reader.reader.seek(SeekFrom::Start(pos))?;
+        Ok(reader)
+    }
+
+    fn next_packet(&mut self) -> Result<Packet> {
+        let (pos, header, data) = self.read_frame()?;
+        let ts = header.index.saturating_sub(self.start);
+        if self.index.last().map_or(true, |(last, _)| ts > *last) {
+            self.index.push((ts, pos));
+        }
+        Ok(Packet::new_from_boxed_slice(
+            0,
+            ts,
+            header.samples as u64,
+            data.into_boxed_slice(),
+        ))
+    }
+
+    fn metadata(&mut self) -> Metadata<'_> {
+        self.metadata.metadata()
+    }
+
+    fn cues(&self) -> &[Cue] {
+        &self.cues
+    }
+
+    fn tracks(&self) -> &[Track] {
+        &self.tracks
+    }
+
+    fn seek(&mut self, _mode: SeekMode, to: SeekTo) -> Result<SeekedTo> {
+        if !self.reader.is_seekable() {
+            return seek_error(SeekErrorKind::Unseekable);
+        }
+        let params = &self.tracks[0].codec_params;
+        let ts = match to {
+            SeekTo::TimeStamp { ts, .. } => ts,
+            SeekTo::Time { time, .. } => {
+                TimeBase::new(1, params.sample_rate.unwrap()).calc_timestamp(time)
+            }
+        };
+        if params.n_frames.map_or(false, |n| ts > n) {
+            return seek_error(SeekErrorKind::OutOfRange);
+        }
+
+        // Blocks aren't indexed, walk their headers from the closest packet we know of
+        let known = self.index.partition_point(|(start, _)| *start <= ts);
+        let (_, pos) = self.index[known.saturating_sub(1)];
+        self.reader.seek(SeekFrom::Start(pos))?;
+        loop {
+            let (pos, _, header) = self.next_header()?;
+            let start = header.index.saturating_sub(self.start);
+            if header.flags & INITIAL_BLOCK != 0 && header.samples > 0 {
+                if ts < start + header.samples as u64 {
+                    self.reader.seek(SeekFrom::Start(pos))?;
+                    return Ok(SeekedTo {
+                        track_id: 0,
+                        actual_ts: start,
+                        required_ts: ts,
+                    });
+                }
+                if self.index.last().map_or(true, |(last, _)| start > *last) {
+                    self.index.push((start, pos));
+                }
+            }
+            self.reader.ignore_bytes((header.size - 24) as u64)?;
+        }
+    }
+
+    fn into_inner(self: Box<Self>) -> MediaSourceStream {
+        self.reader
+    }
+}
+
+enum Buffer {
+    Int(AudioBuffer<i32>),
+    Float(AudioBuffer<f32>),
+}
+
+pub struct WavPackDecoder {
+    params: CodecParameters,
+    tables: Tables,
+    spec: SignalSpec,
+    block: Vec<i32>,
+    buf: Buffer,
+}
+
+impl WavPackDecoder {
+    fn make_buffer(&self, frames: u64) -> Buffer {
+        match self.buf {
+            Buffer::Int(_) => Buffer::Int(AudioBuffer::new(frames, self.spec)),
+            Buffer::Float(_) => Buffer::Float(AudioBuffer::new(frames, self.spec)),
+        }
+    }
+}
+
+impl Decoder for WavPackDecoder {
+    fn try_new(params: &CodecParameters, _options: &DecoderOptions) -> Result<Self> {
+        if params.codec != CODEC_TYPE_WAVPACK {
+            return unsupported_error("wavpack: invalid codec type");
+        }
+        let spec = match (params.sample_rate, params.channels) {
+            (Some(rate), Some(channels)) => SignalSpec::new(rate, channels),
+            _ => return decode_error("wavpack: unknown sample rate or channels"),
+        };
+        let frames = params.max_frames_per_packet.unwrap_or(0);
+        let buf = match params.sample_format {
+            Some(SampleFormat::F32) => Buffer::Float(AudioBuffer::new(frames, spec)),
+            _ => Buffer::Int(AudioBuffer::new(frames, spec)),
+        };
+
+        Ok(WavPackDecoder {
+            params: params.clone(),
+            tables: Tables::new(),
+            spec,
+            block: Vec::new(),
+            buf,
+        })
+    }
+
+    fn supported_codecs() -> &'static [CodecDescriptor] {
+        &[support_codec!(CODEC_TYPE_WAVPACK, "wavpack", "WavPack")]
+    }
+
+    fn reset(&mut self) {}
+
+    fn codec_params(&self) -> &CodecParameters {
+        &self.params
+    }
+
+    fn decode(&mut self, packet: &Packet) -> Result<AudioBufferRef<'_>> {
+        let data = packet.buf();
+        let frames = packet.dur() as usize;
+        let capacity = match &self.buf {
+            Buffer::Int(buf) => buf.capacity(),
+            Buffer::Float(buf) => buf.capacity(),
+        };
+        if capacity < frames {
+            self.buf = self.make_buffer(frames as u64);
+        }
+        match &mut self.buf {
+            Buffer::Int(buf) => {
+                buf.clear();
+                buf.render_silence(Some(frames));
+            }
+            Buffer::Float(buf) => {
+                buf.clear();
+                buf.render_silence(Some(frames));
+            }
+        }
+
+        let channels = self.spec.channels.count();
+        let (mut offset, mut channel) = (0, 0);
+        while offset + HEADER_LEN <= data.len() && channel < channels {
+            let size = u32::from_le_bytes([
+                data[offset + 4],
+                data[offset + 5],
+                data[offset + 6],
+                data[offset + 7],
+            ]) as usize;
+            let block = match data.get(offset..offset + 8 + size) {
+                Some(block) => block,
+                None => return decode_error("wavpack: truncated block"),
+            };
+            let header = decode_block(block, &self.tables, &mut self.block)?;
+            if header.samples as usize != frames {
+                return decode_error("wavpack: blocks of different lengths");
+            }
+
+            let stride = header.channels();
+            for c in 0..stride.min(channels - channel) {
+                let samples = self.block[c..].iter().step_by(stride);
+                match &mut self.buf {
+                    Buffer::Int(buf) => {
+                        let shift = 32 - 8 * ((header.flags & BYTES_STORED) + 1);
+                        for (dst, src) in buf.chan_mut(channel + c).iter_mut().zip(samples) {
+                            *dst = src << shift;
+                        }
+                    }
+                    Buffer::Float(buf) => {
+                        for (dst, src) in buf.chan_mut(channel + c).iter_mut().zip(samples) {
+                            *dst = f32::from_bits(*src as u32);
+                        }
+                    }
+                }
+            }
+            channel += stride;
+            offset += 8 + size;
+        }
+
+        Ok(self.last_decoded())
+    }
+
+    fn finalize(&mut self) -> FinalizeResult {
+        Default::default()
+    }
+
+    fn last_decoded(&self) -> AudioBufferRef<'_> {
+        match &self.buf {
+            Buffer::Int(buf) => buf.as_audio_buffer_ref(),
+            Buffer::Float(buf) => buf.as_audio_buffer_ref(),
+        }
+    }
+}
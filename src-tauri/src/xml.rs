@@ -0,0 +1,120 @@
+//! Just enough XML for playlists and podcast feeds: finding elements by name, and their
+//! text and attributes. Namespaces are matched by their prefix, as in `itunes:duration`.
+
+/// An element found by [`elements`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Element<'a> {
+    /// What's inside the start tag after the name
+    pub attributes: &'a str,
+    /// Empty for an element like `<enclosure ... />`
+    pub content: &'a str,
+}
+
+impl<'a> Element<'a> {
+    /// The text of the element, from CDATA or with its entities decoded.
+    pub fn text(&self) -> String {
+        let content = self.content.trim();
+        match content
+            .strip_prefix("<![CDATA[")
+            .and_then(|c| c.strip_suffix("]]>"))
+        {
+            Some(cdata) => cdata.to_string(),
+            None => unescape(content),
+        }
+    }
+
+    pub fn attribute(&self, name: &str) -> Option<String> {
+        let mut rest = self.attributes;
+        loop {
+            let (key, after) = rest.split_once('=')?;
+            let after = after.trim_start();
+            let quote = after.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+            let (value, next) = after[1..].split_once(quote)?;
+            if key.trim() == name {
+                return Some(unescape(value));
+            }
+            rest = next;
+        }
+    }
+}
+
+/// Each `<name>` element in `xml`, nested ones included.
+pub fn elements<'a>(xml: &'a str, name: &str) -> impl Iterator<Item = Element<'a>> {
+    let open = format!("<{}", name);
+    let close = format!("</{}>", name);
+    let mut rest = xml;
+    std::iter::from_fn(move || loop {
+        let start = rest.find(&open)?;
+        let after = &rest[start + open.len()..];
+        // `<title>` or `<title attr="">`, not `<titles>`
+        if !after.starts_with(['>', '/', ' ', '\t', '\r', '\n']) {
+            rest = after;
+            continue;
+        }
+        let tag_end = after.find('>')?;
+        if let Some(attributes) = after[..tag_end].strip_suffix('/') {
+            rest = &after[tag_end + 1..];
+            return Some(Element {
+                attributes,
+                content: "",
+            });
+        }
+        let content = &after[tag_end + 1..];
+        let end = content.find(&close)?;
+        rest = &content[end + close.len()..];
+        return Some(Element {
+            attributes: &after[..tag_end],
+            content: &content[..end],
+        });
+    })
+}
+
+/// The text of the first `<name>` element in `xml`, if it has any.
+pub fn first_text(xml: &str, name: &str) -> Option<String> {
+    let text = elements(xml, name).next()?.text();
+    (!text.trim().is_empty()).then(|| text.trim().to_string())
+}
+
+pub fn unescape(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        unescaped.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let Some(end) = rest.find(';') else {
+            break;
+        };
+        let decoded = match &rest[1..end] {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            entity => entity
+                .strip_prefix("#x")
+                .map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| entity.strip_prefix('#').map(str::parse::<u32>))
+                .and_then(Result::ok)
+                .and_then(char::from_u32),
+        };
+        match decoded {
+            Some(c) => {
+                unescaped.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                unescaped.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
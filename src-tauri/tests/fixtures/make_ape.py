@@ -0,0 +1,277 @@
+# Monkey's Audio 3.99 encoder for a test fixture, following MAC's compressor: Prepare (X/Y),
+# CPredictorCompressNormal, CNNFilter::Compress and CBitArray (EncodeValue, range coder).
+# Usage: python3 make_ape.py > stereo.ape
+import struct, sys, zlib
+
+BLOCKS_PER_FRAME = 600
+FRAMES = [600, 600, 250]
+COMPRESSION = 4000  # extra high: NN filters of 256 (13 bits) then 32 (10 bits)
+
+
+def pcm(i):
+    if i >= 1200:
+        return 0, 0
+    left = (i * i * 31 + i * 1237) % 40000 - 20000
+    right = (i * 4567) % 30011 - 15005 + left // 4
+    if i % 89 == 0:
+        left = 32767
+    if i % 97 == 0:
+        right = -32768
+    if i >= 600:
+        left, right = right, left // 2
+    return left, right
+
+
+def wrap32(v):
+    v &= 0xFFFFFFFF
+    return v - (1 << 32) if v & 0x80000000 else v
+
+
+def sat16(v):
+    return max(-32768, min(32767, v))
+
+
+def wrap16(v):
+    v &= 0xFFFF
+    return v - 0x10000 if v & 0x8000 else v
+
+
+def tdiv(a, b):
+    # C division, truncating toward zero
+    q = abs(a) // abs(b)
+    return q if (a >= 0) == (b > 0) else -q
+
+
+def adapt_sign(x):
+    return 0 if x == 0 else (1 if x < 0 else -1)
+
+
+class NNFilter:
+    def __init__(self, order, shift):
+        self.order, self.shift = order, shift
+        self.m = [0] * order
+        self.inputs = [0] * order  # the last `order` saturated inputs, oldest first
+        self.deltas = [0] * order
+        self.avg = 0
+
+    def compress(self, value):
+        dot = sum(m * x for m, x in zip(self.m, self.inputs))
+        output = value - ((wrap32(dot) + (1 << (self.shift - 1))) >> self.shift)
+        if output > 0:
+            self.m = [wrap16(m - d) for m, d in zip(self.m, self.deltas)]
+        elif output < 0:
+            self.m = [wrap16(m + d) for m, d in zip(self.m, self.deltas)]
+        a = abs(value)
+        if a > self.avg * 3:
+            delta = ((value >> 25) & 64) - 32
+        elif a > tdiv(self.avg * 4, 3):
+            delta = ((value >> 26) & 32) - 16
+        elif a > 0:
+            delta = ((value >> 27) & 16) - 8
+        else:
+            delta = 0
+        self.avg += tdiv(a - self.avg, 16)
+        self.deltas = self.deltas[1:] + [delta]
+        for back in (2, 3, 9):
+            if back <= self.order:
+                self.deltas[-back] >>= 1
+        self.inputs = self.inputs[1:] + [sat16(value)]
+        return output
+
+
+class Stage1:
+    def __init__(self):
+        self.last = 0
+
+    def compress(self, value):
+        out = value - ((self.last * 31) >> 5)
+        self.last = value
+        return out
+
+
+class Predictor:
+    def __init__(self, filters):
+        self.a = Stage1()
+        self.b = Stage1()
+        self.m = [0, 0, 0, 0, 0, 98, -109, 317, 360]
+        self.pa = [0] * 5  # the last values of A, newest last
+        self.pb = [0] * 5
+        self.adapt = [0] * 9  # rbAdapt[-8..0]
+        self.filters = [NNFilter(o, s) for o, s in filters]
+
+    def compress(self, a, b):
+        a = self.a.compress(a)
+        b = self.b.compress(b)
+        pa = self.pa  # pa[-1] = a(t-1)
+        da = [pa[-1], pa[-1] - pa[-2], pa[-2] - pa[-3], pa[-3] - pa[-4]]
+        self.pb = self.pb[1:] + [b]
+        pb = self.pb  # pb[-1] = b(t)
+        db = [pb[-1], pb[-1] - pb[-2], pb[-2] - pb[-3], pb[-3] - pb[-4], pb[-4] - pb[-5]]
+        m = self.m
+        pred_a = da[0] * m[8] + da[1] * m[7] + da[2] * m[6] + da[3] * m[5]
+        pred_b = db[0] * m[4] + db[1] * m[3] + db[2] * m[2] + db[3] * m[1] + db[4] * m[0]
+        out = a - ((wrap32(pred_a) + (wrap32(pred_b) >> 1)) >> 10)
+
+        # rbAdapt[0], [-1] from A, [-4], [-5] from B; the older ones have moved down
+        adapt = [0] * 9
+        adapt[8] = adapt_sign(da[0])
+        adapt[7] = adapt_sign(da[1])
+        adapt[6] = self.adapt[7]  # [-2] was [-1] a step ago
+        adapt[5] = self.adapt[6]
+        adapt[4] = adapt_sign(db[0])
+        adapt[3] = adapt_sign(db[1])
+        adapt[2] = self.adapt[3]
+        adapt[1] = self.adapt[2]
+        adapt[0] = self.adapt[1]
+        self.adapt = adapt
+        if out > 0:
+            self.m = [x - y for x, y in zip(m, adapt)]
+        elif out < 0:
+            self.m = [x + y for x, y in zip(m, adapt)]
+
+        self.pa = pa[1:] + [a]
+        for f in self.filters:
+            out = f.compress(out)
+        return out
+
+
+TOTALS = [0, 19578, 36160, 48417, 56323, 60899, 63265, 64435, 64971, 65232, 65351, 65416,
+          65447, 65466, 65476, 65482, 65485, 65488, 65490, 65491, 65492, 65493]
+TOTALS += [65493 + i for i in range(1, 64 - 21)]
+WIDTHS = [TOTALS[i + 1] - TOTALS[i] for i in range(21)] + [1] * (64 - 21)
+assert len(TOTALS) == 64 and TOTALS[63] == 65535
+
+TOP = 1 << 31
+BOTTOM = TOP >> 8
+SHIFT_BITS = 23
+
+
+class RangeCoder:
+    def __init__(self, out):
+        self.out = out
+        self.low, self.range, self.buffer, self.help = 0, TOP, 0, 0
+
+    def normalize(self):
+        while self.range <= BOTTOM:
+            if self.low < (0xFF << SHIFT_BITS):
+                self.out.append(self.buffer)
+                self.out.extend([0xFF] * self.help)
+                self.help = 0
+                self.buffer = self.low >> SHIFT_BITS
+            elif self.low & TOP:
+                self.out.append((self.buffer + 1) & 0xFF)
+                self.out.extend([0] * self.help)
+                self.help = 0
+                self.buffer = (self.low >> SHIFT_BITS) & 0xFF
+            else:
+                self.help += 1
+            self.low = (self.low << 8) & (TOP - 1)
+            self.range <<= 8
+
+    def fast(self, width, total, shift):
+        self.normalize()
+        temp = self.range >> shift
+        self.range = temp * width
+        self.low += temp * total
+
+    def direct(self, value, shift):
+        self.normalize()
+        self.range >>= shift
+        self.low += self.range * value
+
+    def divide(self, value, total):
+        self.normalize()
+        temp = self.range // total
+        self.range = temp
+        self.low += temp * value
+
+    def finalize(self):
+        self.normalize()
+        temp = (self.low >> SHIFT_BITS) + 1
+        if temp > 0xFF:
+            self.out.append((self.buffer + 1) & 0xFF)
+            self.out.extend([0] * self.help)
+        else:
+            self.out.append(self.buffer)
+            self.out.extend([0xFF] * self.help)
+        self.out.extend([temp & 0xFF, 0, 0, 0])
+
+
+class Rice:
+    def __init__(self):
+        self.k, self.ksum = 10, (1 << 10) * 16
+
+    def encode(self, rc, value):
+        x = value * 2 - 1 if value > 0 else -value * 2
+        original = self.ksum
+        self.ksum += (x + 1) // 2 - ((self.ksum + 16) >> 5)
+        if self.ksum < (1 << (self.k + 4) if self.k else 0):
+            self.k -= 1
+        elif self.ksum >= 1 << (self.k + 5):
+            self.k += 1
+        pivot = max(original // 32, 1)
+        overflow, base = divmod(x, pivot)
+        if overflow < 63:
+            rc.fast(WIDTHS[overflow], TOTALS[overflow], 16)
+        else:
+            rc.fast(WIDTHS[63], TOTALS[63], 16)
+            rc.direct((overflow >> 16) & 0xFFFF, 16)
+            rc.direct(overflow & 0xFFFF, 16)
+        if pivot >= 1 << 16:
+            bits = pivot.bit_length()
+            split = 1 << (bits - 16)
+            rc.divide(base // split, pivot // split + 1)
+            rc.divide(base % split, split)
+        else:
+            rc.divide(base, pivot)
+
+
+FILTERS = {1000: [], 2000: [(16, 11)], 3000: [(64, 11)], 4000: [(256, 13), (32, 10)],
+           5000: [(1280, 15), (256, 13), (16, 11)]}
+
+
+def encode_frame(out, start, blocks):
+    samples = [pcm(i) for i in range(start, start + blocks)]
+    raw = b"".join(struct.pack("<hh", l, r) for l, r in samples)
+    crc = (zlib.crc32(raw) & 0xFFFFFFFF) >> 1
+    if all(l == 0 and r == 0 for l, r in samples):
+        out.extend(struct.pack(">II", crc | 0x80000000, 3))
+        rc = RangeCoder(out)
+        rc.finalize()
+        return
+    out.extend(struct.pack(">I", crc))
+    rc = RangeCoder(out)
+    px, py = Predictor(FILTERS[COMPRESSION]), Predictor(FILTERS[COMPRESSION])
+    rx, ry = Rice(), Rice()
+    last_x = 0
+    for left, right in samples:
+        y = right - left
+        x = left + tdiv(y, 2)
+        ry.encode(rc, py.compress(y, last_x))
+        rx.encode(rc, px.compress(x, y))
+        last_x = x
+    rc.finalize()
+
+
+stream = bytearray()
+offsets = []
+start = 0
+for blocks in FRAMES:
+    offsets.append(len(stream))
+    encode_frame(stream, start, blocks)
+    start += blocks
+stream += bytes(-len(stream) % 4)
+# Written as little-endian 32-bit words
+words = b"".join(stream[i:i + 4][::-1] for i in range(0, len(stream), 4))
+
+descriptor_len, header_len = 52, 24
+seek_len = 4 * len(FRAMES)
+first = descriptor_len + header_len + seek_len
+descriptor = b"MAC " + struct.pack(
+    "<HHIIIIIII", 3990, 0, descriptor_len, header_len, seek_len, 0, len(words), 0, 0
+) + bytes(16)
+header = struct.pack(
+    "<HHIIIHHI", COMPRESSION, 0, BLOCKS_PER_FRAME, FRAMES[-1], len(FRAMES), 16, 2, 44100
+)
+seek = b"".join(struct.pack("<I", first + o) for o in offsets)
+sys.stdout.buffer.write(descriptor + header + seek + words)
@@ -0,0 +1,324 @@
+# Lossless WavPack 4 encoder for a test fixture, following libwavpack's write_words.c
+# (send_word / flush_word) and pack.c (joint stereo, decorrelation passes).
+# Usage: python3 make_wv.py > joint_stereo.wv
+import struct, sys
+
+LIMIT_ONES = 16
+DIVS = [128, 64, 32]
+
+
+def pcm(i):
+    # Silence, then a wobbly ramp with a jump big enough for the ones escape
+    if i < 6:
+        return 0, 0
+    left = (i * i * 37) % 4001 - 2000
+    right = (i * 613) % 3001 - 1500 + left // 3
+    if i == 40:
+        left = 30000
+    if i == 41:
+        right = -32768
+    return left, right
+
+
+class Bits:
+    def __init__(self):
+        self.bits = []
+
+    def put(self, bit):
+        self.bits.append(bit & 1)
+
+    def puts(self, value, count):
+        for i in range(count):
+            self.put(value >> i)
+
+    def data(self):
+        bits = self.bits + [0] * (-len(self.bits) % 16)
+        out = bytearray()
+        for i in range(0, len(bits), 8):
+            out.append(sum(b << j for j, b in enumerate(bits[i:i + 8])))
+        return bytes(out)
+
+
+class Entropy:
+    def __init__(self, median):
+        self.median = list(median)
+
+    def get(self, i):
+        return (self.median[i] >> 4) + 1
+
+    def inc(self, i):
+        self.median[i] += ((self.median[i] + DIVS[i]) // DIVS[i]) * 5
+
+    def dec(self, i):
+        self.median[i] -= ((self.median[i] + DIVS[i] - 2) // DIVS[i]) * 2
+
+
+class Words:
+    def __init__(self, medians):
+        self.c = [Entropy(m) for m in medians]
+        self.holding_zero = 0
+        self.holding_one = 0
+        self.zeros_acc = 0
+        self.pend_data = 0
+        self.pend_count = 0
+        self.bits = Bits()
+
+    def pend(self, value, count):
+        self.pend_data |= value << self.pend_count
+        self.pend_count += count
+
+    def flush(self):
+        b = self.bits
+        if self.zeros_acc:
+            cbits = self.zeros_acc.bit_length()
+            for _ in range(cbits):
+                b.put(1)
+            b.put(0)
+            while self.zeros_acc > 1:
+                b.put(self.zeros_acc & 1)
+                self.zeros_acc >>= 1
+            self.zeros_acc = 0
+        if self.holding_one:
+            if self.holding_one >= LIMIT_ONES:
+                b.puts((1 << LIMIT_ONES) - 1, LIMIT_ONES + 1)
+                self.holding_one -= LIMIT_ONES
+                cbits = self.holding_one.bit_length()
+                for _ in range(cbits):
+                    b.put(1)
+                b.put(0)
+                while self.holding_one > 1:
+                    b.put(self.holding_one & 1)
+                    self.holding_one >>= 1
+                self.holding_zero = 0
+            else:
+                b.puts((1 << self.holding_one) - 1, self.holding_one)
+            self.holding_one = 0
+        if self.holding_zero:
+            b.put(0)
+            self.holding_zero = 0
+        if self.pend_count:
+            b.puts(self.pend_data, self.pend_count)
+            self.pend_data = self.pend_count = 0
+
+    def send(self, value, chan):
+        c = self.c[chan]
+        if self.c[0].median[0] < 2 and not self.holding_zero and self.c[1].median[0] < 2:
+            if self.zeros_acc:
+                if value:
+                    self.flush()
+                else:
+                    self.zeros_acc += 1
+                    return
+            elif value:
+                self.bits.put(0)
+            else:
+                self.c[0].median = [0, 0, 0]
+                self.c[1].median = [0, 0, 0]
+                self.zeros_acc = 1
+                return
+        sign = 1 if value < 0 else 0
+        if sign:
+            value = ~value
+        if value < c.get(0):
+            ones = low = 0
+            high = c.get(0) - 1
+            c.dec(0)
+        else:
+            low = c.get(0)
+            c.inc(0)
+            if value - low < c.get(1):
+                ones = 1
+                high = low + c.get(1) - 1
+                c.dec(1)
+            else:
+                low += c.get(1)
+                c.inc(1)
+                if value - low < c.get(2):
+                    ones = 2
+                    high = low + c.get(2) - 1
+                    c.dec(2)
+                else:
+                    ones = 2 + (value - low) // c.get(2)
+                    low += (ones - 2) * c.get(2)
+                    high = low + c.get(2) - 1
+                    c.inc(2)
+        if self.holding_zero:
+            if ones:
+                self.holding_one += 1
+            self.flush()
+            if ones:
+                self.holding_zero = 1
+                ones -= 1
+            else:
+                self.holding_zero = 0
+        else:
+            self.holding_zero = 1
+        self.holding_one = ones * 2
+        if high != low:
+            maxcode, code = high - low, value - low
+            bitcount = maxcode.bit_length()
+            extras = (1 << bitcount) - maxcode - 1
+            if code < extras:
+                self.pend(code, bitcount - 1)
+            else:
+                self.pend((code + extras) >> 1, bitcount - 1)
+                self.pend((code + extras) & 1, 1)
+        self.pend(sign, 1)
+        if not self.holding_zero:
+            self.flush()
+
+
+def i32(v):
+    v &= 0xFFFFFFFF
+    return v - (1 << 32) if v & 0x80000000 else v
+
+
+def apply_weight(weight, sample):
+    return (weight * sample + 512) >> 10
+
+
+def update(weight, delta, source, result, clip=False):
+    if source and result:
+        weight = weight - delta if (source ^ result) < 0 else weight + delta
+        if clip:
+            weight = max(-1024, min(1024, weight))
+    return weight
+
+
+def restore_weight(stored):
+    w = stored * 8
+    return w + ((w + 64) >> 7) if w > 0 else w
+
+
+def exp2s_exact(log):
+    # Only logs with no fraction, so the tables don't come into it
+    assert log & 0xff == 0
+    if log < 0:
+        return -exp2s_exact(-log)
+    e = log >> 8
+    return 256 >> (9 - e) if e <= 9 else 256 << (e - 9)
+
+
+class Pass:
+    def __init__(self, term, delta):
+        self.term, self.delta = term, delta
+        self.weight = [0, 0]
+        self.samples = [[0] * 8, [0] * 8]
+
+
+def forward_channel(p, ch, values):
+    out = []
+    h = p.samples[ch]
+    if p.term in (17, 18):
+        for s in values:
+            pred = 2 * h[0] - h[1] if p.term == 17 else h[0] + ((h[0] - h[1]) >> 1)
+            r = s - apply_weight(p.weight[ch], pred)
+            p.weight[ch] = update(p.weight[ch], p.delta, pred, r)
+            h[1] = h[0]
+            h[0] = s
+            out.append(r)
+    else:
+        m, k = 0, p.term & 7
+        for s in values:
+            pred = h[m]
+            h[k] = s
+            r = s - apply_weight(p.weight[ch], pred)
+            p.weight[ch] = update(p.weight[ch], p.delta, pred, r)
+            out.append(r)
+            m, k = (m + 1) & 7, (k + 1) & 7
+    return out
+
+
+def forward(p, buf):
+    if p.term == -1:
+        out = []
+        a = p.samples[0]
+        for i in range(0, len(buf), 2):
+            left, right = buf[i], buf[i + 1]
+            r0 = left - apply_weight(p.weight[0], a[0])
+            p.weight[0] = update(p.weight[0], p.delta, a[0], r0, True)
+            r1 = right - apply_weight(p.weight[1], left)
+            p.weight[1] = update(p.weight[1], p.delta, left, r1, True)
+            a[0] = right
+            out += [r0, r1]
+        return out
+    assert p.term > 0
+    out = list(buf)
+    out[0::2] = forward_channel(p, 0, buf[0::2])
+    out[1::2] = forward_channel(p, 1, buf[1::2])
+    return out
+
+
+def sub_block(id, data):
+    odd = len(data) & 1
+    if odd:
+        data += b"\0"
+    return bytes([id | (0x40 if odd else 0), len(data) // 2]) + data
+
+
+def block(index, total, frames, terms, weights, samples, medians):
+    """terms: [(term, delta)] in stored order; weights: stored i8 per term and channel;
+    samples: stored log values in the sub-block's order; medians: stored logs per channel."""
+    passes = [Pass(t, d) for t, d in terms]
+    for p, w in zip(passes, weights):
+        p.weight = [restore_weight(x) for x in w]
+    values = iter(exp2s_exact(s) for s in samples)
+    if samples:
+        for p in passes:
+            if p.term > 8:
+                for ch in range(2):
+                    p.samples[ch][0] = next(values)
+                    p.samples[ch][1] = next(values)
+            elif p.term < 0:
+                p.samples[0][0] = next(values)
+                p.samples[1][0] = next(values)
+            else:
+                for m in range(p.term):
+                    for ch in range(2):
+                        p.samples[ch][m] = next(values)
+    words = Words([[exp2s_exact(v) for v in m] for m in medians])
+
+    buf = []
+    crc = 0xFFFFFFFF
+    for i in range(index, index + frames):
+        left, right = pcm(i)
+        crc = (crc * 3 + left) & 0xFFFFFFFF
+        crc = (crc * 3 + right) & 0xFFFFFFFF
+        # Joint stereo: the difference, and the right plus half of it
+        diff = left - right
+        buf += [diff, right + (diff >> 1)]
+    for p in passes:
+        buf = forward(p, buf)
+    for i, v in enumerate(buf):
+        words.send(v, i & 1)
+    words.flush()
+
+    body = b""
+    body += sub_block(0x2, bytes(((t + 5) & 0x1f) | (d << 5) for t, d in terms))
+    if weights:
+        body += sub_block(0x3, bytes(x & 0xff for w in weights for x in w))
+    if samples:
+        body += sub_block(0x4, b"".join(struct.pack("<h", s) for s in samples))
+    body += sub_block(0x5, b"".join(struct.pack("<H", v) for m in medians for v in m))
+    body += sub_block(0xA, words.bits.data())
+
+    # 16 bits, joint stereo with cross-channel terms, initial and final block, 44.1kHz
+    flags = 0x1 | 0x10 | 0x20 | 0x800 | 0x1000 | (9 << 23)
+    header = b"wvpk" + struct.pack(
+        "<IHBBIIIII", 24 + len(body), 0x407, 0, 0, total, index, frames, flags, crc
+    )
+    return header + body
+
+
+terms = [(18, 2), (2, 2), (-1, 2)]
+first = block(0, 64, 32, terms, [], [], [[0, 0, 0], [0, 0, 0]])
+second = block(
+    32,
+    64,
+    32,
+    terms,
+    [(48, -32), (16, 8), (-8, 24)],
+    [0x0900, -0x0800, 0x0a00, 0, 0x0800, 0x0800, -0x0900, 0x0600, 0x0700, -0x0a00],
+    [[0x0800, 0x0a00, 0x0600], [0x0900, 0x0700, 0x0500]],
+)
+sys.stdout.buffer.write(first + second)
@@ -35,6 +35,30 @@ interface FileInfo {
     lossless: boolean;
     tagType: string;
     codec: string;
+    replayGain?: ReplayGain;
+}
+
+interface ReplayGain {
+    trackGain?: number; // dB
+    trackPeak?: number;
+    albumGain?: number; // dB
+    albumPeak?: number;
+}
+
+interface LoudnessResult {
+    integratedLoudness?: number; // LUFS
+    loudnessRange?: number; // LU
+    samplePeak: number;
+    truePeak: number;
+    replayGain?: number; // dB
+}
+
+interface LoudnessAnalysisEvent {
+    track?: { path: string; loudness: LoudnessResult };
+    album?: { paths: string[]; loudness: LoudnessResult };
+    progress: number;
+    done: boolean;
+    error?: string;
 }
 
 interface Song {
@@ -55,6 +79,9 @@ interface Song {
     metadata: MetadataEntry[];
     fileInfo: FileInfo;
     originCountry?: string;
+    // Where a track from a CUE sheet is in its album's file, in seconds
+    startPos?: number;
+    endPos?: number;
     songProjectId?: number; // Link to project id
     isFavourite: boolean;
     viewModel?: {
@@ -150,6 +177,7 @@ interface ArtworkSrc {
 
 interface UserSettings {
     foldersToWatch: string[];
+    importPlaylists: boolean; // playlist files found in the watched folders
     albumArtworkFilenames: string[];
     miniPlayerLocation: MiniPlayerLocation;
     aiFeaturesEnabled: boolean;
@@ -160,9 +188,87 @@ interface UserSettings {
     downloadLocation: string;
     theme: string;
     outputDevice?: string;
-    followSystemOutput: boolean
+    followSystemOutput: boolean;
+    nativeRateSwitching: boolean;
+    crossfadeEnabled: boolean;
+    crossfadeDuration: number; // seconds
+    crossfadeCurve: CrossfadeCurve;
+    replayGainMode: ReplayGainMode;
+    replayGainPreamp: number; // dB
+    replayGainPreventClipping: boolean;
+    equalizer: EqualizerSettings;
+    skipOnPlaybackError: boolean;
+    fileOutputPath?: string;
+    fileOutputRealtime: boolean;
+    dither: boolean;
+    noiseShaping: boolean;
+    channelMix: ChannelMixSettings;
+    radioStations: RadioStation[];
+    spectrum: SpectrumSettings;
+    waveformCacheSize: number; // MB
+}
+
+type EqFilterType =
+    | "peaking"
+    | "low-shelf"
+    | "high-shelf"
+    | "low-pass"
+    | "high-pass";
+
+interface EqBand {
+    filterType: EqFilterType;
+    frequency: number; // Hz
+    gain: number; // dB
+    q: number;
+}
+
+interface EqualizerSettings {
+    enabled: boolean;
+    preamp: number; // dB
+    preset?: string;
+    bands: EqBand[];
+}
+
+interface EqualizerPreset {
+    name: string;
+    preamp: number;
+    bands: EqBand[];
 }
 
+/** Sent with the meter event while the Artist's Toolkit is enabled, levels in dBFS */
+interface MeterLevels {
+    channels: ChannelLevels[];
+}
+
+interface ChannelLevels {
+    peak: number;
+    rms: number; // over 300 ms
+    truePeak: number; // 4x oversampled
+    peakHold: number; // highest true peak of the last couple of seconds
+    clips: number; // since the track started
+}
+
+type BandScale = "log" | "third-octave";
+
+interface SpectrumSettings {
+    bands: number; // for the log scale, third-octave has 31
+    scale: BandScale;
+    minDb: number; // shown as an empty band
+    attack: number; // ms
+    decay: number; // ms
+    perChannel: boolean;
+}
+
+interface ChannelMixSettings {
+    mono: boolean;
+    swap: boolean;
+    balance: number; // -1 (left) to 1 (right)
+}
+
+type ReplayGainMode = "off" | "track" | "album";
+
+type CrossfadeCurve = "linear" | "equal-power" | "s-curve";
+
 type MiniPlayerLocation =
     | "bottom-left"
     | "bottom-right"
@@ -187,7 +293,8 @@ type SidebarItem =
     | "playlists"
     | "map"
     | "analytics"
-    | "internet-archive";
+    | "internet-archive"
+    | "podcasts";
 
 type ArtistContentItem = ArtistFileItem | ArtistLinkItem;
 
@@ -242,6 +349,22 @@ interface ToImport {
     progress: number; // After reaching 100, progress can jump back to 0 and start again (eg. processing tracks then albums)
     done: boolean;
     error?: string;
+    playlists?: PlaylistFile[];
+}
+
+interface PlaylistEntry {
+    path: string;
+    title?: string;
+    artist?: string;
+    duration?: number; // s
+    songId?: string; // the library song it was matched to
+}
+
+/** A playlist read from an M3U/M3U8, PLS or XSPF file */
+interface PlaylistFile {
+    path: string;
+    title: string;
+    entries: PlaylistEntry[];
 }
 
 interface ToImportAlbums {
@@ -341,7 +464,109 @@ interface WaveformPlayerState {
     loopEnabled: boolean;
     loopStartPos: number;
     loopEndPos: number;
+    loopCount: number; // 0 to loop forever
+    loopCrossfade: number; // ms
     markers: Marker[];
+    playbackRate: number; // 0.5 to 2
+    pitch: number; // semitones
+}
+
+type PlaybackStatus = "stopped" | "playing" | "paused";
+
+interface LoopRegionState {
+    startPos: number; // seconds
+    endPos: number; // seconds
+    crossfade: number; // ms
+    count?: number;
+}
+
+/** Returned by get_player_state and sent with the player_state event */
+interface PlayerState {
+    path?: string;
+    positionSamples: number; // frames at the track's sample rate
+    position: number; // seconds
+    duration?: number; // seconds
+    status: PlaybackStatus;
+    volume?: number; // 0 to 1
+    device?: string;
+    sampleRate?: number;
+    outputSampleRate?: number;
+    outputChannels?: number;
+    outputFormat?: string;
+    loopRegion?: LoopRegionState;
+    nextTrack?: string;
+    live: boolean; // an internet radio stream, which can't seek
+    streamTitle?: string;
+}
+
+interface RadioStation {
+    name: string;
+    url: string; // http(s) stream
+}
+
+/** Sent with the stream_metadata event when a station's ICY metadata changes */
+interface StreamMetadata {
+    url: string;
+    station?: string; // icy-name
+    title?: string; // StreamTitle, usually "Artist - Title"
+}
+
+interface Podcast {
+    url: string; // the feed's
+    title: string;
+    author?: string;
+    description?: string;
+    image?: string;
+    etag?: string;
+    lastModified?: string;
+    episodes: Episode[]; // newest first
+}
+
+interface Episode {
+    guid: string;
+    title: string;
+    url: string; // the enclosure's
+    mimeType?: string;
+    length?: number; // bytes
+    published?: string;
+    duration?: number; // seconds
+    path?: string; // once downloaded
+    position: number; // seconds, to resume from
+}
+
+/** Sent with the episode-download-progress event */
+interface EpisodeDownloadProgress {
+    guid: string;
+    progress: number; // percent
+}
+
+type PlaybackError =
+    | { kind: "file-not-found" | "io" | "unsupported" | "corrupt"; reason: string }
+    | {
+          kind: "output";
+          reason:
+              | "device-not-found-error"
+              | "open-stream-error"
+              | "play-stream-error"
+              | "stream-closed-error";
+      };
+
+interface PlaybackErrorEvent {
+    path?: string;
+    error: PlaybackError;
+    skippedTo?: string; // the track played instead
+}
+
+type RepeatMode = "off" | "one" | "all";
+
+/** The backend play queue, sent with the queue_changed event */
+interface QueueState {
+    tracks: string[]; // paths
+    order: number[]; // play order, as indices into tracks
+    current?: number; // index into tracks
+    next?: string;
+    shuffle: boolean;
+    repeat: RepeatMode;
 }
 
 interface Marker {
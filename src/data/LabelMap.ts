@@ -118,6 +118,26 @@ const genericToId3v24Map: TagFieldMap = {
 
 const id3v24ToGenericMap = inverse(genericToId3v24Map);
 
+/*
+iTunes
+-------
+MP4 ilst atoms (M4A / AAC / ALAC)
+*/
+const genericToMp4Map: TagFieldMap = {
+    title: "©nam",
+    artist: "©ART",
+    album: "©alb",
+    albumArtist: "aART",
+    composer: "©wrt",
+    genre: "©gen",
+    date: "©day",
+    trackNumber: "trkn",
+    copyright: "cprt",
+    bpm: "tmpo"
+};
+
+const mp4ToGenericMap = inverse(genericToMp4Map);
+
 function getMapForTagType(
     tagType: TagType,
     fromGeneric: boolean = true
@@ -133,6 +153,8 @@ function getMapForTagType(
             return fromGeneric ? genericToId3v23Map : id3v23ToGenericMap;
         case "ID3v2.4":
             return fromGeneric ? genericToId3v24Map : id3v24ToGenericMap;
+        case "iTunes":
+            return fromGeneric ? genericToMp4Map : mp4ToGenericMap;
         default:
             return null;
     }
@@ -146,7 +168,9 @@ function getMapForTagType(
 const codecToTagTypeMap = {
     "FLAC": "Vorbis",
     "MPEG": "ID3v2.4",
-    "MPEG 1 Layer 3": "ID3v2.4"
+    "MPEG 1 Layer 3": "ID3v2.4",
+    "MPEG-4/AAC": "iTunes",
+    "ALAC": "iTunes"
 };
 
 function getTagTypeFromCodec(codec) {
@@ -12,6 +12,7 @@ import type {
     Album,
     LookForArtResult,
     MetadataEntry,
+    PlaylistFile,
     Song,
     TagType,
     ToImport
@@ -73,7 +74,8 @@ export async function readMappedMetadataFromSong(
 export async function importPaths(
     selected: string[],
     background = true,
-    percent = 0
+    percent = 0,
+    includePlaylists = false
 ) {
     importStatus.update((importStatus) => ({
         ...importStatus,
@@ -95,7 +97,8 @@ export async function importPaths(
             paths: selected,
             recursive: true,
             process_albums: true,
-            is_async: true
+            is_async: true,
+            include_playlists: includePlaylists
         }
     });
 
@@ -278,6 +281,25 @@ export async function startImportListener() {
         }
     });
 
+    await appWindow.listen<PlaylistFile[]>("import_playlists", async (event) => {
+        for (const playlistFile of event.payload) {
+            // Playlists already imported may have been edited since
+            const existing = await db.playlists
+                .where("title")
+                .equals(playlistFile.title)
+                .first();
+            if (existing) {
+                continue;
+            }
+            await db.playlists.add({
+                title: playlistFile.title,
+                tracks: playlistFile.entries
+                    .filter((e) => e.songId)
+                    .map((e) => e.songId)
+            });
+        }
+    });
+
     await appWindow.listen<ToImportAlbums>("import_albums", async (event) => {
         console.log("import_albums", event, albumChunksToProcess.length);
         gotAllAlbums = event.payload.progress === 100;
@@ -338,6 +360,6 @@ export async function runScan() {
             text: `Scanning ${folder} ...`,
             timeout: 2000
         });
-        await importPaths([folder], true);
+        await importPaths([folder], true, 0, settings.importPlaylists);
     }
 }
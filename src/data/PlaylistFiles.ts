@@ -0,0 +1,72 @@
+import { invoke } from "@tauri-apps/api/core";
+import { open, save } from "@tauri-apps/plugin-dialog";
+import type { Playlist, PlaylistEntry, PlaylistFile } from "../App";
+import { db } from "./db";
+
+const PLAYLIST_FILTERS = [
+    { name: "Playlist", extensions: ["m3u8", "m3u", "pls", "xspf"] }
+];
+
+/**
+ * Pick an M3U/M3U8, PLS or XSPF file and add it as a playlist. Entries are
+ * matched to the library by path, or by artist and title for moved files.
+ * @returns the new playlist and how many entries weren't in the library
+ */
+export async function importPlaylistFile() {
+    const selected = await open({
+        multiple: false,
+        filters: PLAYLIST_FILTERS
+    });
+    if (selected === null || Array.isArray(selected)) return null;
+
+    const library = (await db.songs.toArray()).map((song) => ({
+        id: song.id,
+        path: song.path,
+        artist: song.artist ?? "",
+        title: song.title ?? ""
+    }));
+    const playlistFile = await invoke<PlaylistFile | null>("read_playlist", {
+        event: { path: selected, library }
+    });
+    if (!playlistFile) {
+        throw new Error(`Couldn't read ${selected}`);
+    }
+
+    const playlist = {
+        title: playlistFile.title,
+        tracks: playlistFile.entries
+            .filter((e) => e.songId)
+            .map((e) => e.songId)
+    };
+    const id = (await db.playlists.add(playlist)) as number;
+    return {
+        playlist: { id, ...playlist },
+        missing: playlistFile.entries.length - playlist.tracks.length
+    };
+}
+
+/**
+ * Save a playlist to a file, in the format of the extension picked
+ * @returns whether it was saved
+ */
+export async function exportPlaylist(playlist: Playlist) {
+    const path = await save({
+        defaultPath: `${playlist.title}.m3u8`,
+        filters: PLAYLIST_FILTERS
+    });
+    if (!path) return false;
+
+    const songs = await db.songs.bulkGet(playlist.tracks);
+    const entries: PlaylistEntry[] = songs
+        .filter((song) => song)
+        .map((song) => ({
+            path: song.path,
+            title: song.title,
+            artist: song.artist,
+            duration: song.fileInfo?.duration
+        }));
+    await invoke("write_playlist", {
+        event: { path, title: playlist.title, entries }
+    });
+    return true;
+}
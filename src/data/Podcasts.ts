@@ -0,0 +1,34 @@
+import { invoke } from "@tauri-apps/api/core";
+import type { Podcast, Song } from "../App";
+import { db } from "./db";
+
+export async function getPodcasts() {
+    return invoke<Podcast[]>("get_podcasts");
+}
+
+export async function subscribePodcast(url: string) {
+    return invoke<Podcast>("subscribe_podcast", { event: { url } });
+}
+
+export async function unsubscribePodcast(url: string) {
+    await invoke("unsubscribe_podcast", { event: { url } });
+}
+
+/**
+ * Fetch every feed again, those that haven't changed are skipped
+ */
+export async function refreshPodcasts() {
+    return invoke<Podcast[]>("refresh_podcasts");
+}
+
+/**
+ * Download an episode into the download location and add it to the library.
+ * Progress comes as episode-download-progress events.
+ */
+export async function downloadEpisode(podcast: Podcast, guid: string) {
+    const song = await invoke<Song>("download_episode", {
+        event: { feedUrl: podcast.url, guid }
+    });
+    await db.songs.put(song);
+    return song;
+}
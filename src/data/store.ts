@@ -17,6 +17,7 @@ import type {
     LastPlayedInfo,
     PlaylistType,
     QueueMode,
+    RepeatMode,
     SidebarItem,
     Song,
     StreamInfo,
@@ -86,6 +87,7 @@ export const playlistCountry = writable(null); // ISO Country code
 export const playlistType: Writable<PlaylistType> = writable("library");
 export const playlistDuration: Writable<number> = writable(0);
 export const isShuffleEnabled = writable(false);
+export const repeatMode: Writable<RepeatMode> = writable("off");
 export const songsJustAdded: Writable<Song[]> = writable([]);
 export const songJustAdded = writable(false);
 export const shouldShowToast = writable(true);
@@ -170,6 +172,7 @@ export const dragGhostReset = writable(false);
 export const isSettingsOpen = writable(false);
 const defaultSettings: UserSettings = {
     foldersToWatch: [],
+    importPlaylists: false,
     albumArtworkFilenames: ["cover.jpg", "artwork.jpg", "folder.jpg"],
     miniPlayerLocation: "bottom-left",
     llm: "ollama",
@@ -180,7 +183,52 @@ const defaultSettings: UserSettings = {
     downloadLocation: null,
     theme: "dark",
     outputDevice: null, // default system device,
-    followSystemOutput: true
+    followSystemOutput: true,
+    nativeRateSwitching: false,
+    crossfadeEnabled: false,
+    crossfadeDuration: 6,
+    crossfadeCurve: "equal-power",
+    replayGainMode: "off",
+    replayGainPreamp: 0,
+    replayGainPreventClipping: true,
+    equalizer: {
+        enabled: false,
+        preamp: 0,
+        preset: "Flat",
+        bands: [31, 62, 125, 250, 500, 1000, 2000, 4000, 8000, 16000].map(
+            (frequency, idx, all): EqBand => ({
+                filterType:
+                    idx === 0
+                        ? "low-shelf"
+                        : idx === all.length - 1
+                          ? "high-shelf"
+                          : "peaking",
+                frequency,
+                gain: 0,
+                q: 1.41
+            })
+        )
+    },
+    skipOnPlaybackError: true,
+    fileOutputPath: null, // the audio folder
+    fileOutputRealtime: true,
+    dither: true,
+    noiseShaping: false,
+    channelMix: {
+        mono: false,
+        swap: false,
+        balance: 0
+    },
+    radioStations: [],
+    spectrum: {
+        bands: 64,
+        scale: "log",
+        minDb: -80,
+        attack: 10,
+        decay: 300,
+        perChannel: false
+    },
+    waveformCacheSize: 256
 };
 
 /**
@@ -268,7 +316,11 @@ export const waveformPeaks: Writable<WaveformPlayerState> = writable({
     markers: [],
     loopEnabled: false,
     loopEndPos: 0,
-    loopStartPos: 0
+    loopStartPos: 0,
+    loopCount: 0,
+    loopCrossfade: 10,
+    playbackRate: 1,
+    pitch: 0
 });
 
 async function init() {
@@ -1,8 +1,18 @@
 import { invoke } from "@tauri-apps/api/core";
 import { get } from "svelte/store";
-import type { ArtworkSrc, LastPlayedInfo, Song, ToImport } from "../../App";
+import type {
+    ArtworkSrc,
+    LastPlayedInfo,
+    PlaybackErrorEvent,
+    QueueState,
+    RadioStation,
+    Song,
+    StreamMetadata,
+    ToImport
+} from "../../App";
 import { db } from "../../data/db";
 import {
+    bottomBarNotification,
     currentSong,
     currentSongArtworkSrc,
     currentSongIdx,
@@ -12,12 +22,13 @@ import {
     nextUpSong,
     playerTime,
     playlist,
+    repeatMode,
     seekTime,
     shuffledPlaylist,
     userSettings,
     volume
 } from "../../data/store";
-import { shuffleArray } from "../../utils/ArrayUtils";
+import { isStreamUrl } from "../../utils/FileUtils";
 
 import type { Event } from "@tauri-apps/api/event";
 import { getCurrentWebviewWindow } from "@tauri-apps/api/webviewWindow";
@@ -44,6 +55,10 @@ if (!ReadableStream.prototype[Symbol.asyncIterator]) {
     };
 }
 
+function isSamePaths(a: string[], b: string[]) {
+    return a.length === b.length && a.every((path, i) => path === b[i]);
+}
+
 class AudioPlayer {
     private static _instance: AudioPlayer;
 
@@ -65,7 +80,11 @@ class AudioPlayer {
     currentSong: Song;
     currentSongIdx: number;
     isAlreadyLoadingSong = false; // for when the 'ended' event fires
-    playlist: Song[];
+    playlist: Song[]; // in play order
+    // The backend queue, as last sent with queue_changed
+    queue: QueueState = null;
+    queuedPaths: string[] = [];
+    songsByPath = new Map<string, Song>();
     shouldPlay = false; // Whether to play immediately after loading playlist
     shouldRestoreLastPlayed: LastPlayedInfo;
     isRunningTransition = false;
@@ -85,8 +104,9 @@ class AudioPlayer {
         this.webRTCReceiver = new WebRTCReceiver();
 
         seekTime.subscribe((time) => {
-            // Tell Rust to play file with seek position
-            if (this.currentSong) this.playCurrent(time);
+            // Tell Rust to play file with seek position (live streams can't seek)
+            if (this.currentSong && !isStreamUrl(this.currentSong.path))
+                this.playCurrent(time);
         });
 
         this.setupMediaSession();
@@ -95,71 +115,46 @@ class AudioPlayer {
             this.setMediaSessionData();
         });
 
+        // The backend owns the queue: shuffle, repeat and edits are sent to it,
+        // and the stores follow its queue_changed events
         isShuffleEnabled.subscribe((enabled) => {
-            const shuffledPlylist = get(shuffledPlaylist);
-            let newCurrentSongIdx = 0;
-            // If shuffle is enabled but not yet shuffled
-            if (enabled && shuffledPlylist.length === 0) {
-                this.shuffle();
-                // If shuffle is disabled, need to unshuffle
-            } else if (!enabled && shuffledPlylist.length > 0) {
-                this.unshuffle();
-                // Restore position
-                newCurrentSongIdx = this.playlist.findIndex(
-                    (s) => s.id === this.currentSong?.id
-                );
-                if (newCurrentSongIdx === -1) {
-                    newCurrentSongIdx = 0;
-                }
-            }
-
-            this.currentSongIdx = newCurrentSongIdx;
-            currentSongIdx.set(newCurrentSongIdx);
+            if (this.queue?.shuffle === enabled) return;
+            invoke("queue_shuffle", { event: { enabled } });
+        });
 
-            this.setNextUpSong();
+        repeatMode.subscribe((mode) => {
+            if (this.queue?.repeat === mode) return;
+            invoke("queue_repeat", { event: { mode } });
         });
 
         playlist.subscribe(async (playlist) => {
-            this.playlist = playlist;
-            const shuffleEnabled = get(isShuffleEnabled);
-            const shuffledPlylist = get(shuffledPlaylist);
-            let newCurrentSongIdx = 0;
-            console.log("shuffled", shuffledPlylist);
-            // If shuffle is enabled but not yet shuffled
-            if (shuffleEnabled) {
-                this.shuffle();
-                // If shuffle is disabled, need to unshuffle
-            } else if (!shuffleEnabled && shuffledPlylist.length > 0) {
-                this.unshuffle();
-                // Restore position
-                newCurrentSongIdx = playlist.findIndex(
-                    (s) => s.id === this.currentSong?.id
-                );
-                if (newCurrentSongIdx === -1) {
-                    newCurrentSongIdx = 0;
-                }
-            } else {
-                // Shuffle was disabled already
+            const paths = playlist.map((s) => s.path);
+            // A new queue (from the library, an album...). Edits of the queue
+            // come back from the backend with the same paths
+            if (!isSamePaths(paths, this.queuedPaths)) {
+                this.queuedPaths = paths;
+                this.songsByPath = new Map(playlist.map((s) => [s.path, s]));
                 this.playlist = playlist;
 
-                newCurrentSongIdx = playlist.findIndex(
+                const index = playlist.findIndex(
                     (s) => s.id === this.currentSong?.id
                 );
-                // console.log("found index", playlist, this.currentSong);
-                if (newCurrentSongIdx === -1) {
-                    newCurrentSongIdx = 0;
+                if (paths.length) {
+                    invoke("queue_set", {
+                        event: { paths, index: index === -1 ? null : index }
+                    });
+                } else {
+                    invoke("queue_clear");
                 }
+                this.currentSongIdx = index === -1 ? 0 : index;
+                currentSongIdx.set(this.currentSongIdx);
+                console.log(
+                    "playlist: currentsongindex",
+                    this.currentSongIdx,
+                    this.currentSong
+                );
             }
 
-            this.currentSongIdx = newCurrentSongIdx;
-            currentSongIdx.set(newCurrentSongIdx);
-            console.log(
-                "playlist: currentsongindex",
-                this.currentSongIdx,
-                this.currentSong
-            );
-
-            this.setNextUpSong();
             if (this.shouldPlay) {
                 this.playCurrent();
                 this.shouldPlay = false;
@@ -169,6 +164,11 @@ class AudioPlayer {
                 this.shouldRestoreLastPlayed = null;
             }
         });
+
+        appWindow.listen("queue_changed", (event: Event<QueueState>) => {
+            this.onQueueChanged(event.payload);
+        });
+
         currentSongIdx.subscribe((idx) => {
             this.currentSongIdx = idx;
         });
@@ -185,12 +185,32 @@ class AudioPlayer {
         appWindow.listen("song_change", async (event: Event<Song>) => {
             this.currentSong = event.payload;
             currentSong.set(this.currentSong);
-            this.currentSongIdx += 1;
-            currentSongIdx.set(this.currentSongIdx);
-            this.setNextUpSong();
+            // The backend moved through the queue (and knows about repeat)
+            const queue = await invoke<QueueState>("get_queue");
+            if (queue) this.onQueueChanged(queue);
             this.isRunningTransition = false;
         });
 
+        appWindow.listen(
+            "stream_metadata",
+            async (event: Event<StreamMetadata>) => {
+                const { url, station, title } = event.payload;
+                if (this.currentSong?.path !== url) return;
+                // Show what's on the station as the current song
+                const [artist, track] = title?.includes(" - ")
+                    ? title.split(" - ", 2)
+                    : [station, title];
+                this.currentSong = {
+                    ...this.currentSong,
+                    title: track || station || this.currentSong.title,
+                    artist: artist ?? "",
+                    album: station ?? this.currentSong.album
+                };
+                currentSong.set(this.currentSong);
+                this.setMediaSessionData();
+            }
+        );
+
         appWindow.listen("timestamp", async (event: any) => {
             playerTime.set(event.payload);
         });
@@ -201,6 +221,20 @@ class AudioPlayer {
             isPlaying.set(false);
         });
 
+        appWindow.listen(
+            "playback_error",
+            async (event: Event<PlaybackErrorEvent>) => {
+                const { path, error, skippedTo } = event.payload;
+                const file = path?.split(/[\\/]/).pop();
+                bottomBarNotification.set({
+                    text: `Couldn't play ${file} (${error.kind}: ${error.reason})${
+                        skippedTo ? " - skipping" : ""
+                    }`,
+                    timeout: 4000
+                });
+            }
+        );
+
         appWindow.listen("paused", async (event: any) => {
             this.isStopped = false;
             isPlaying.set(false);
@@ -223,21 +257,6 @@ class AudioPlayer {
         console.log("player::setupBuffers()");
     }
 
-    shuffle() {
-        let toShuffle = [...this.playlist];
-        toShuffle.splice(get(currentSongIdx), 1);
-        const shuffled = [this.currentSong].concat(shuffleArray(toShuffle));
-        shuffledPlaylist.set(shuffled);
-        this.playlist = shuffled;
-        isShuffleEnabled.set(true);
-    }
-
-    unshuffle() {
-        this.playlist = get(playlist);
-        shuffledPlaylist.set([]);
-        isShuffleEnabled.set(false);
-    }
-
     onPlay() {
         isPlaying.set(true);
         if (navigator.mediaSession)
@@ -255,20 +274,92 @@ class AudioPlayer {
     }
 
     hasNext() {
-        return this.currentSongIdx + 1 < this.playlist.length;
+        return !!this.queue?.next;
     }
 
+    /**
+     * Skip to the next track of the backend queue, which plays it
+     */
     playNext() {
-        this.currentSongIdx++;
-        currentSongIdx.set(this.currentSongIdx);
-        this.playSong(this.playlist[this.currentSongIdx]);
+        this.saveEpisodePosition();
+        invoke("queue_next_track");
     }
 
+    /**
+     * Go back to the track played before, shuffled or not
+     */
     playPrevious() {
-        this.currentSongIdx--;
-        currentSongIdx.set(this.currentSongIdx);
-        this.playSong(this.playlist[this.currentSongIdx]);
-        currentSongIdx.set(this.currentSongIdx);
+        this.saveEpisodePosition();
+        invoke("queue_previous_track");
+    }
+
+    /**
+     * Add songs to the queue before the one shown at index, or at the end.
+     * When shuffled they're played at random points after the current song
+     */
+    insertIntoQueue(songs: Song[], index: number = null) {
+        songs.forEach((song) => this.songsByPath.set(song.path, song));
+        invoke("queue_insert", {
+            event: {
+                paths: songs.map((s) => s.path),
+                index: this.queue?.shuffle ? null : index
+            }
+        });
+    }
+
+    /**
+     * Move a song of the queue, both positions as shown
+     */
+    moveInQueue(from: number, to: number) {
+        invoke("queue_move", { event: { from, to } });
+    }
+
+    /**
+     * Remove the songs shown at these positions from the queue
+     */
+    async removeFromQueue(indexes: number[]) {
+        const trackIndexes = indexes
+            .map((i) => this.trackIndex(i))
+            .sort((a, b) => b - a);
+        for (const index of trackIndexes) {
+            await invoke("queue_remove", { event: { index } });
+        }
+    }
+
+    /**
+     * Follow the backend queue: the songs, their play order when shuffled,
+     * and what's current and next
+     */
+    onQueueChanged(state: QueueState) {
+        this.queue = state;
+        const songs = state.tracks.map((path) => this.songsByPath.get(path));
+        if (!isSamePaths(state.tracks, this.queuedPaths)) {
+            this.queuedPaths = state.tracks;
+            playlist.set(songs);
+        }
+        const shuffled = state.shuffle ? state.order.map((i) => songs[i]) : [];
+        shuffledPlaylist.set(shuffled);
+        this.playlist = state.shuffle ? shuffled : songs;
+        if (state.current != null) {
+            this.currentSongIdx = this.shownIndex(state.current);
+            currentSongIdx.set(this.currentSongIdx);
+        }
+        isShuffleEnabled.set(state.shuffle);
+        repeatMode.set(state.repeat);
+        this.setNextUpSong();
+    }
+
+    /**
+     * Where a track of the queue is shown: in play order when shuffled
+     */
+    shownIndex(trackIndex: number) {
+        return this.queue?.shuffle
+            ? this.queue.order.indexOf(trackIndex)
+            : trackIndex;
+    }
+
+    trackIndex(shownIndex: number) {
+        return this.queue?.shuffle ? this.queue.order[shownIndex] : shownIndex;
     }
 
     restart() {}
@@ -366,29 +457,8 @@ class AudioPlayer {
     }
 
     setNextUpSong() {
-        if (this.currentSongIdx + 1 < this.playlist?.length) {
-            const nextSong = this.playlist[this.currentSongIdx + 1];
-            nextUpSong.set(nextSong);
-            invoke("queue_next", {
-                event: {
-                    path: nextSong.path,
-                    seek: 0,
-                    file_info: nextSong.fileInfo,
-                    volume: get(volume)
-                }
-            });
-        } else {
-            nextUpSong.set(null);
-            // Tell the backend to clear the queue
-            invoke("queue_next", {
-                event: {
-                    path: null,
-                    seek: 0,
-                    file_info: null,
-                    volume: get(volume)
-                }
-            });
-        }
+        const next = this.queue?.next;
+        nextUpSong.set(next ? this.songsByPath.get(next) ?? null : null);
     }
 
     async incrementPlayCounter(song: Song) {
@@ -404,6 +474,14 @@ class AudioPlayer {
             if (get(isIAPlaying)) {
                 webAudioPlayer.pause();
             }
+            this.saveEpisodePosition();
+            // Podcast episodes carry on from where they were left
+            if (play && position === 0) {
+                position =
+                    (await invoke<number | null>("get_episode_position", {
+                        event: { path: song.path }
+                    })) ?? 0;
+            }
             // this.pause();
             this.isRunningTransition = false;
             currentSong.set(song);
@@ -441,17 +519,17 @@ class AudioPlayer {
                 this.incrementPlayCounter(song);
             }
             this.shouldPlay = play;
-            let newCurrentSongIdx =
+            // The queue carries on from here. index is as shown, in play
+            // order when shuffled
+            const trackIndex =
                 index !== null
-                    ? index
-                    : this.playlist.findIndex(
+                    ? this.trackIndex(index)
+                    : get(playlist).findIndex(
                           (s) => s.id === this.currentSong?.id
                       );
-            if (newCurrentSongIdx === -1) {
-                newCurrentSongIdx = 0;
+            if (trackIndex !== undefined && trackIndex !== -1) {
+                invoke("queue_select", { event: { index: trackIndex } });
             }
-            currentSongIdx.set(newCurrentSongIdx);
-            this.setNextUpSong();
             this.setMediaSessionData();
 
             lastPlayedInfo.set({
@@ -461,10 +539,10 @@ class AudioPlayer {
         }
     }
 
-    async handleOpenedUrls(openedUrls: string) {
+    async handleOpenedUrls(openedUrls: string[]) {
         window["openedUrls"] = null;
         console.log("handleOpenedUrls", openedUrls);
-        const paths = openedUrls.split(",").map((p) => {
+        const paths = openedUrls.map((p) => {
             // Strip file:// prefix
             if (p.startsWith("file://")) {
                 p = p.slice(7);
@@ -489,6 +567,27 @@ class AudioPlayer {
         }
     }
 
+    /**
+     * Play an internet radio station, in place of the queue
+     */
+    async playStation(station: RadioStation) {
+        const song = {
+            id: station.url,
+            path: station.url,
+            file: station.url,
+            title: station.name,
+            artist: "",
+            album: station.name,
+            genre: [],
+            composer: [],
+            metadata: [],
+            markers: [],
+            tags: []
+        } as Song;
+        this.shouldPlay = true;
+        playlist.set([song]);
+    }
+
     async play(isResume: boolean) {
         this.isStopped = false;
         if (isResume) {
@@ -507,9 +606,27 @@ class AudioPlayer {
                 decoding_active: false
             }
         });
+        this.saveEpisodePosition();
         this.onPause();
     }
 
+    /**
+     * Remember how far into the current song we are, if it's a podcast episode
+     */
+    saveEpisodePosition() {
+        if (this.currentSong && !isStreamUrl(this.currentSong.path)) {
+            const duration = this.currentSong.fileInfo?.duration ?? 0;
+            const position = get(playerTime);
+            invoke("save_episode_position", {
+                event: {
+                    path: this.currentSong.path,
+                    // Finished episodes start over next time
+                    position: duration && position >= duration - 5 ? 0 : position
+                }
+            });
+        }
+    }
+
     async onEnded() {}
 
     togglePlay() {
@@ -20,9 +20,7 @@ export class AudioVisualiser {
     private _activeAnimations: IAnimation[] = [];
     isEnabled = true;
     shouldStopAnimation = false;
-    timeDomain: Uint8Array;
-    freqDomain: Uint8Array;
-    lastTick = performance.now();
+    spectrum: Uint8Array[] = []; // band levels of each channel, 0 to 255
     color: string;
 
     constructor(canvas: HTMLCanvasElement) {
@@ -68,26 +66,24 @@ export class AudioVisualiser {
     }
 
     setupAnalyserAnimation() {
-        // this.freqDomain = new Uint8Array(
-        //     audioPlayer.audioAnalyser.frequencyBinCount
-        // );
-        this.timeDomain = new Uint8Array([
-            128, 128, 128, 128, 128, 128, 128, 128, 128, 128
-        ]);
-
-        // Append new messages to the box of incoming messages
+        this.spectrum = [];
+
+        // Each frame is the number of rows (channels), then the level of each band
         if (audioPlayer.webRTCReceiver) {
-            audioPlayer.webRTCReceiver.onSampleData = (samples) => {
-                // Get the ArrayBuffer
-                // console.log("samples", samples);
-                this.timeDomain = new Uint8Array(samples);
+            audioPlayer.webRTCReceiver.onSampleData = (frame) => {
+                const bytes = new Uint8Array(frame);
+                const rows = bytes[0] || 1;
+                const bands = (bytes.length - 1) / rows;
+                this.spectrum = Array.from({ length: rows }, (_, row) =>
+                    bytes.subarray(1 + row * bands, 1 + (row + 1) * bands)
+                );
             };
         }
 
         let tick = () => {
             this.clearCanvas();
             if (!this.shouldStopAnimation) {
-                this.drawOscilloscope();
+                this.drawSpectrum();
                 window.requestAnimationFrame(tick);
             }
         };
@@ -95,30 +91,28 @@ export class AudioVisualiser {
     }
 
     /**
-     * Draw an oscilloscope
+     * Draw the spectrum as bars, channels stacked one above the other
      */
-    drawOscilloscope() {
-        // Assuming 44100hz, TODO: Make dynamic
-        if (this.lastTick < 0.022675737) {
-            return;
-        }
-        this.lastTick = performance.now();
-        const step = this._canvasContext.canvas.width / this.timeDomain.length;
-
-        this._canvasContext.beginPath();
-        for (let i = 0; i < this.timeDomain.length; i += 1) {
-            const percent = this.timeDomain[i] / 256;
-            const x = i * step;
-            const yL = this._canvasContext.canvas.height * percent;
-            this._canvasContext.shadowColor = this.color;
-            this._canvasContext.shadowBlur = 10;
-            this._canvasContext.shadowOffsetX = 2;
-            this._canvasContext.shadowOffsetY = 2;
-            this._canvasContext.strokeStyle = this.color;
-            this._canvasContext.lineTo(x, yL);
-        }
-
-        this._canvasContext.stroke();
+    drawSpectrum() {
+        const { width, height } = this._canvasContext.canvas;
+        const rowHeight = height / (this.spectrum.length || 1);
+
+        this._canvasContext.fillStyle = this.color;
+        this._canvasContext.shadowColor = this.color;
+        this._canvasContext.shadowBlur = 10;
+        this.spectrum.forEach((levels, row) => {
+            const step = width / levels.length;
+            const bottom = (row + 1) * rowHeight;
+            for (let i = 0; i < levels.length; i++) {
+                const barHeight = (levels[i] / 255) * rowHeight;
+                this._canvasContext.fillRect(
+                    i * step,
+                    bottom - barHeight,
+                    Math.max(step - 1, 1),
+                    barHeight
+                );
+            }
+        });
     }
 
     clearCanvas() {
@@ -20,7 +20,11 @@ export function isFileOrDirectory(path) {
 }
 
 export function isAudioFile(filename: string): boolean {
-    return filename.match(/\.(mp3|ogg|aac|flac|wav|m4a)$/i) !== null;
+    return filename.match(/\.(mp3|ogg|opus|aac|flac|wav|m4a|ape|wv|dsf|dff)$/i) !== null;
+}
+
+export function isStreamUrl(path: string): boolean {
+    return path.match(/^https?:\/\//i) !== null;
 }
 
 export function isCueFile(filename: string): boolean {